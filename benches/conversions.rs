@@ -0,0 +1,42 @@
+//! Benchmarks of the conversions done by the wrapper layer on every event dispatch.
+//!
+//! The costs that can only be measured inside a JVM are covered by the `dispatch_bench` example
+//! agent.
+
+use coffee_filter::jvm::strings::{decode_modified_utf8, Utf8Policy};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+fn names() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        (
+            "ascii",
+            b"Ljava/util/concurrent/ConcurrentHashMap$Node;".to_vec(),
+        ),
+        // `U+0000` is encoded as `C0 80` in modified UTF-8.
+        ("null", b"com/example/Null\xC0\x80Name".to_vec()),
+        // `U+1F600` is encoded as a surrogate pair in modified UTF-8.
+        (
+            "supplementary",
+            b"com/example/Emoji\xED\xA0\xBD\xED\xB8\x80Name".to_vec(),
+        ),
+        ("invalid", b"com/example/Broken\xFF\xFEName".to_vec()),
+    ]
+}
+
+fn modified_utf8(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_modified_utf8");
+    for (name, bytes) in names() {
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        for policy in [Utf8Policy::Strict, Utf8Policy::Lossy] {
+            group.bench_with_input(
+                BenchmarkId::new(format!("{policy:?}"), name),
+                &bytes,
+                |b, bytes| b.iter(|| decode_modified_utf8(black_box(bytes), policy)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, modified_utf8);
+criterion_main!(benches);
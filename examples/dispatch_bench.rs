@@ -0,0 +1,341 @@
+//! A benchmark agent measuring the overhead of the wrapper layer inside a real JVM.
+//!
+//! Build it with `cargo build --release --example dispatch_bench` and run
+//! `java -agentpath:target/release/examples/libdispatch_bench.so=iterations=10000 -version`.
+//! The agent measures
+//! - the cost of a `ClassFileLoadHook` callback registered through this crate compared with a raw
+//!   `sys` callback doing the same work on the same class files,
+//! - the throughput of stack sampling on the `VMInit` thread, and
+//! - the cost of resolving the sampled frames into names,
+//!
+//! and prints a report when the VM dies.
+//!
+//! The VM calls the `ClassFileLoadHook` callbacks of all the environments one after another on
+//! the loading thread, first those of the environments that cannot retransform classes and then
+//! those of the environments that can, each in creation order. The agent creates one raw
+//! environment of the first kind and, after the typed environment, two raw environments of the
+//! second kind, so that every class file goes through
+//!
+//! 1. the `first` raw callback, which takes the time,
+//! 2. the typed callback,
+//! 3. the `second` raw callback, which takes the time and does the same work, and
+//! 4. the `third` raw callback, which takes the time.
+//!
+//! The time from 1 to 3 is the cost of the typed callback and the time from 3 to 4 that of the raw
+//! one, each including one hand-over between environments.
+
+use std::{
+    cell::Cell,
+    ffi::{c_char, c_int, c_uchar, c_void, CStr, OsStr},
+    mem::MaybeUninit,
+    os::unix::ffi::OsStrExt,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use coffee_filter::{
+    jvm::{
+        capabilities::JvmtiCapabilities,
+        events::{Handler, JvmTIEvent},
+        general::JvmTIVersion,
+        Jvm, JvmEnvironment, JvmPointer,
+    },
+    sys,
+};
+
+/// The number of frames requested per stack sample.
+const SAMPLE_DEPTH: usize = 64;
+
+/// Durations of repeated measurements of one operation.
+#[derive(Debug)]
+struct Samples(Mutex<Vec<Duration>>);
+
+/// The statistics of [`Samples`].
+#[derive(Debug, Clone, Copy)]
+struct Summary {
+    count: usize,
+    mean: Duration,
+    p50: Duration,
+    p99: Duration,
+    max: Duration,
+}
+
+impl Samples {
+    const fn new() -> Self {
+        Self(Mutex::new(Vec::new()))
+    }
+
+    fn record(&self, duration: Duration) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(duration);
+    }
+
+    fn summary(&self) -> Option<Summary> {
+        let mut samples = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        let total: Duration = samples.iter().sum();
+        let count = samples.len();
+        let percentile = |p: usize| samples[(count - 1) * p / 100];
+        Some(Summary {
+            count,
+            mean: total / u32::try_from(count).unwrap_or(u32::MAX),
+            p50: percentile(50),
+            p99: percentile(99),
+            max: samples[count - 1],
+        })
+    }
+
+    fn report(&self, name: &str) -> Option<Summary> {
+        let Some(summary) = self.summary() else {
+            println!("{name:<24} no samples");
+            return None;
+        };
+        let Summary {
+            count,
+            mean,
+            p50,
+            p99,
+            max,
+        } = summary;
+        println!(
+            "{name:<24} n={count:<8} mean={mean:>10.2?} p50={p50:>10.2?} p99={p99:>10.2?} max={max:>10.2?}"
+        );
+        Some(summary)
+    }
+}
+
+#[derive(Debug)]
+struct Measurements {
+    typed_hook: Samples,
+    raw_hook: Samples,
+    stack_sample: Samples,
+    frame_resolution: Samples,
+}
+
+/// The measurements shared with the raw callbacks, which cannot capture any state.
+static MEASUREMENTS: Measurements = Measurements {
+    typed_hook: Samples::new(),
+    raw_hook: Samples::new(),
+    stack_sample: Samples::new(),
+    frame_resolution: Samples::new(),
+};
+
+thread_local! {
+    /// When the last raw `ClassFileLoadHook` callback on the current thread took the time.
+    static MARK: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The work done by both the typed and the raw callback on every class file.
+fn checksum(class_data: &[u8]) -> u32 {
+    class_data
+        .iter()
+        .fold(0, |sum, it| sum.rotate_left(5) ^ u32::from(*it))
+}
+
+/// The parameters of a raw `ClassFileLoadHook` callback.
+type RawHook = unsafe extern "C" fn(
+    *mut sys::jvmtiEnv,
+    *mut sys::JNIEnv,
+    sys::jclass,
+    sys::jobject,
+    *const c_char,
+    sys::jobject,
+    sys::jint,
+    *const c_uchar,
+    *mut sys::jint,
+    *mut *mut c_uchar,
+);
+
+unsafe extern "C" fn first_hook(
+    _: *mut sys::jvmtiEnv,
+    _: *mut sys::JNIEnv,
+    _: sys::jclass,
+    _: sys::jobject,
+    _: *const c_char,
+    _: sys::jobject,
+    _: sys::jint,
+    _: *const c_uchar,
+    _: *mut sys::jint,
+    _: *mut *mut c_uchar,
+) {
+    MARK.with(|it| it.set(Some(Instant::now())));
+}
+
+unsafe extern "C" fn second_hook(
+    _: *mut sys::jvmtiEnv,
+    _: *mut sys::JNIEnv,
+    _: sys::jclass,
+    _: sys::jobject,
+    _: *const c_char,
+    _: sys::jobject,
+    class_data_len: sys::jint,
+    class_data: *const c_uchar,
+    _: *mut sys::jint,
+    _: *mut *mut c_uchar,
+) {
+    let now = Instant::now();
+    let Some(mark) = MARK.with(Cell::take) else {
+        return;
+    };
+    MEASUREMENTS.typed_hook.record(now - mark);
+    // Recording is left out of both measurements.
+    MARK.with(|it| it.set(Some(Instant::now())));
+    let class_data = if class_data.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(class_data, usize::try_from(class_data_len).unwrap_or(0))
+    };
+    std::hint::black_box(checksum(class_data));
+}
+
+unsafe extern "C" fn third_hook(
+    _: *mut sys::jvmtiEnv,
+    _: *mut sys::JNIEnv,
+    _: sys::jclass,
+    _: sys::jobject,
+    _: *const c_char,
+    _: sys::jobject,
+    _: sys::jint,
+    _: *const c_uchar,
+    _: *mut sys::jint,
+    _: *mut *mut c_uchar,
+) {
+    let now = Instant::now();
+    if let Some(mark) = MARK.with(Cell::take) {
+        MEASUREMENTS.raw_hook.record(now - mark);
+    }
+}
+
+/// Converts the result of a raw JVM TI function.
+fn check(error: sys::jvmtiError) -> Result<(), Box<dyn std::error::Error>> {
+    if error == sys::JVMTI_ERROR_NONE {
+        Ok(())
+    } else {
+        Err(format!("JVM TI error {error}").into())
+    }
+}
+
+/// Creates a raw environment whose `ClassFileLoadHook` callback is `hook`.
+unsafe fn raw_environment(
+    vm: JvmPointer,
+    can_retransform: bool,
+    hook: RawHook,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut env = MaybeUninit::<*mut sys::jvmtiEnv>::uninit();
+    let get_env = (**vm).GetEnv.ok_or("GetEnv is not available")?;
+    let version = sys::jint::try_from(sys::JVMTI_VERSION)?;
+    if get_env(vm, env.as_mut_ptr().cast(), version) != sys::jint::try_from(sys::JNI_OK)? {
+        return Err("cannot create a raw JVM TI environment".into());
+    }
+    let env = env.assume_init();
+    if can_retransform {
+        let mut capabilities: sys::jvmtiCapabilities = std::mem::zeroed();
+        capabilities.set_can_retransform_classes(1);
+        let add_capabilities = (**env).AddCapabilities.ok_or("AddCapabilities")?;
+        check(add_capabilities(env, &capabilities))?;
+    }
+    let mut callbacks: sys::jvmtiEventCallbacks = std::mem::zeroed();
+    callbacks.ClassFileLoadHook = Some(hook);
+    let set_event_callbacks = (**env).SetEventCallbacks.ok_or("SetEventCallbacks")?;
+    let size = sys::jint::try_from(std::mem::size_of_val(&callbacks))?;
+    check(set_event_callbacks(env, &callbacks, size))?;
+    let set_mode = (**env)
+        .SetEventNotificationMode
+        .ok_or("SetEventNotificationMode")?;
+    check(set_mode(
+        env,
+        sys::JVMTI_ENABLE,
+        sys::JVMTI_EVENT_CLASS_FILE_LOAD_HOOK,
+        std::ptr::null_mut(),
+    ))
+}
+
+/// The agent is started by hand instead of with `agent_on_load!` to create the raw environments
+/// around the typed one.
+#[no_mangle]
+unsafe extern "C" fn Agent_OnLoad(
+    vm: JvmPointer,
+    options: *const c_char,
+    _reserved: *const c_void,
+) -> c_int {
+    let options =
+        (!options.is_null()).then(|| OsStr::from_bytes(CStr::from_ptr(options).to_bytes()));
+    match agent_onload(vm, options) {
+        Ok(()) => 0,
+        Err(error) => {
+            eprintln!("dispatch_bench: {error}");
+            1
+        }
+    }
+}
+
+unsafe fn agent_onload(
+    vm: JvmPointer,
+    opts: Option<&OsStr>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let iterations: usize = opts
+        .and_then(OsStr::to_str)
+        .and_then(|it| it.strip_prefix("iterations="))
+        .map_or(Ok(10_000), str::parse)?;
+    raw_environment(vm, false, first_hook)?;
+    let JvmEnvironment::Created(jvm) = Jvm::from_jvm_ptr(vm, JvmTIVersion::LATEST)? else {
+        return Err("the agent has already been started".into());
+    };
+    // Makes the typed callback run after the `first` raw callback.
+    jvm.add_capabilities(JvmtiCapabilities::CAN_RETRANSFORM_CLASSES)?;
+    jvm.update_callbacks(|it| {
+        it.class_file_load_hook = Some(Handler::new(Box::new(move |_, _, event| {
+            std::hint::black_box(checksum(event.class_data));
+            None
+        })));
+        it.vm_init = Some(Handler::new(Box::new(move |_, _, thread| {
+            for _ in 0..iterations {
+                let start = Instant::now();
+                let Ok(frames) = thread.stack_trace(SAMPLE_DEPTH) else {
+                    continue;
+                };
+                MEASUREMENTS.stack_sample.record(start.elapsed());
+                let start = Instant::now();
+                for frame in &frames {
+                    let _ = std::hint::black_box(frame.method.name_utf8(Default::default()));
+                    let class = frame.method.declaring_class();
+                    let _ = std::hint::black_box(class.map(|it| it.signature()));
+                }
+                MEASUREMENTS.frame_resolution.record(start.elapsed());
+            }
+        })));
+        it.vm_death = Some(Handler::new(Box::new(move |_, _| report())));
+    })?;
+    jvm.enable_event(JvmTIEvent::ClassFileLoadHook, None)?;
+    jvm.enable_event(JvmTIEvent::VMInit, None)?;
+    jvm.enable_event(JvmTIEvent::VMDeath, None)?;
+    raw_environment(vm, true, second_hook)?;
+    raw_environment(vm, true, third_hook)?;
+    Ok(())
+}
+
+fn report() {
+    println!("coffee-filter dispatch benchmark");
+    let typed = MEASUREMENTS.typed_hook.report("typed ClassFileLoadHook");
+    let raw = MEASUREMENTS.raw_hook.report("raw ClassFileLoadHook");
+    if let (Some(typed), Some(raw)) = (typed, raw) {
+        let difference = |typed: Duration, raw: Duration| {
+            i128::try_from(typed.as_nanos()).unwrap_or(i128::MAX)
+                - i128::try_from(raw.as_nanos()).unwrap_or(i128::MAX)
+        };
+        println!(
+            "{:<24} mean={:>+8}ns p50={:>+8}ns p99={:>+8}ns",
+            "typed - raw",
+            difference(typed.mean, raw.mean),
+            difference(typed.p50, raw.p50),
+            difference(typed.p99, raw.p99),
+        );
+    }
+    MEASUREMENTS.stack_sample.report("stack sample");
+    MEASUREMENTS.frame_resolution.report("frame resolution");
+}
@@ -3,7 +3,10 @@ use std::ffi::OsStr;
 use coffee_filter::{
     agent_on_load,
     jvm::general::JvmTIVersion,
-    jvm::{events::JvmTIEvent, Jvm},
+    jvm::{
+        events::{Handler, JvmTIEvent},
+        Jvm,
+    },
 };
 
 agent_on_load!(agent_onload, JvmTIVersion::LATEST);
@@ -19,10 +22,10 @@ fn agent_onload(jvm: &mut Jvm, opts: Option<&OsStr>) -> Result<(), Box<dyn std::
         version.micro()
     );
     jvm.update_callbacks(|it| {
-        it.thread_start = Some(Box::new(|jvm, jni, thread| {
+        it.thread_start = Some(Handler::new(Box::new(|jvm, _, thread| {
             println!("thread.info(): {:?}", thread.info());
             println!("loaded classes: {:?}", jvm.get_loaded_classes());
-        }));
+        })));
     })?;
     jvm.enable_event(JvmTIEvent::ThreadStart, None)?;
     Ok(())
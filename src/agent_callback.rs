@@ -3,7 +3,7 @@ use std::{
     os::unix::prelude::OsStrExt,
 };
 
-use crate::jvm::{general::JvmTIVersion, Jvm, JvmPointer};
+use crate::jvm::{general::JvmTIVersion, Jvm, JvmEnvironment, JvmPointer, JvmTICreationError};
 
 /// Defines the `Agent_OnLoad` function. This macro should be used at most once in a lib.
 /// # Example
@@ -13,7 +13,7 @@ use crate::jvm::{general::JvmTIVersion, Jvm, JvmPointer};
 ///
 /// agent_on_load!(agent_onload, JvmTIVersion::LATEST);
 ///
-/// fn agent_onload(jvm: Jvm, opts: Option<&OsStr>) -> Result<(), Box<dyn std::error::Error>> {
+/// fn agent_onload(jvm: &mut Jvm, opts: Option<&OsStr>) -> Result<(), Box<dyn std::error::Error>> {
 ///     println!("Hello from coffee-filter");
 ///     println!("options: {:?}", opts);
 ///     let version = jvm.get_version()?;
@@ -42,6 +42,9 @@ macro_rules! agent_on_load {
 
 /// Defines the `Agent_OnAttach` function. This macro should be used at most once in a lib.
 /// See [`agent_on_load!`] for an example.
+///
+/// If `Agent_OnLoad` has already started the agent in the VM, the callback is not called again
+/// and the environment set up by the first callback is kept, see [`Jvm::from_jvm_ptr`].
 #[macro_export]
 macro_rules! agent_on_attach {
     ($callback:ident, $version:expr) => {
@@ -73,8 +76,17 @@ pub unsafe fn on_agent_startup<E>(
         let c_options = unsafe { CStr::from_ptr(options) };
         Some(OsStr::from_bytes(c_options.to_bytes()))
     };
-    let jvmti = Jvm::from_jvm_ptr(vm, version).expect("Fail to initialize JVM TI");
+    let jvmti = match Jvm::from_jvm_ptr(vm, version) {
+        Ok(JvmEnvironment::Created(jvmti)) => jvmti,
+        // The agent has already been started in this VM by another entry point, and its callback
+        // has set up the environment that is shared from then on.
+        Ok(JvmEnvironment::Existing(_)) => return 0,
+        // Another entry point is still running its callback.
+        Err(JvmTICreationError::AlreadyInitialized) => return 1,
+        Err(error) => panic!("Fail to initialize JVM TI: {error}"),
+    };
     if callback(jvmti, options).is_ok() {
+        jvmti.publish();
         0
     } else {
         1
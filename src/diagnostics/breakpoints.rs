@@ -0,0 +1,540 @@
+//! Self-limiting breakpoints with hit statistics.
+//!
+//! Breakpoints set through a [`BreakpointManager`] count their hits and follow a [`HitPolicy`],
+//! so that production debugging sessions can ignore the first hits of a hot location and clear
+//! breakpoints automatically instead of stalling the application forever. Call
+//! [`BreakpointManager::on_breakpoint`] from the `Breakpoint` event and only act on the hit if it
+//! returns `true`.
+//!
+//! [`LineBreakpoints`] sets breakpoints by class name, method name, and source line instead of by
+//! method and location, deferring them until the class is prepared if it is not loaded yet.
+//!
+//! Setting breakpoints requires the `can_generate_breakpoint_events` capability, and setting them
+//! by source line also requires the `can_get_line_numbers` capability.
+
+use std::{
+    collections::HashMap,
+    os::unix::prelude::OsStrExt,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{
+    jvm::{
+        class::Class,
+        errors::{BreakpointError, JvmTIError, MethodError},
+        methods::Method,
+        strings::{encode_modified_utf8, ModifiedUtf8Ext},
+        Jvm,
+    },
+    sys,
+};
+
+use super::{binary_name, class_signature};
+
+/// Decides which hits of a breakpoint trigger it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HitPolicy {
+    /// The number of hits ignored before the breakpoint triggers.
+    pub ignore_count: u64,
+    /// The number of triggers after which the breakpoint is cleared, or `None` to keep it.
+    pub disable_after: Option<u64>,
+}
+
+impl HitPolicy {
+    /// Triggers on every hit.
+    pub const ALWAYS: Self = Self {
+        ignore_count: 0,
+        disable_after: None,
+    };
+
+    /// Ignores the first `hits` hits and triggers on every hit afterwards.
+    #[must_use]
+    pub const fn after_hits(hits: u64) -> Self {
+        Self {
+            ignore_count: hits,
+            disable_after: None,
+        }
+    }
+
+    /// Triggers on the first `triggers` hits and clears the breakpoint afterwards.
+    #[must_use]
+    pub const fn disable_after(triggers: u64) -> Self {
+        Self {
+            ignore_count: 0,
+            disable_after: Some(triggers),
+        }
+    }
+}
+
+/// The statistics of a breakpoint managed by a [`BreakpointManager`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakpointStats {
+    /// The binary name of the class declaring the method.
+    pub class_name: String,
+    /// The name of the method.
+    pub method_name: String,
+    /// The location of the breakpoint in the method.
+    pub location: i64,
+    /// The policy of the breakpoint.
+    pub policy: HitPolicy,
+    /// How many times the breakpoint has been hit.
+    pub hits: u64,
+    /// How many of the hits triggered the breakpoint.
+    pub triggers: u64,
+    /// Whether the breakpoint is still set.
+    pub enabled: bool,
+}
+
+impl BreakpointStats {
+    /// Records a hit and returns whether it triggers the breakpoint. Disables the breakpoint once
+    /// its policy says so, which is before the first trigger if it may trigger `0` times.
+    fn record_hit(&mut self) -> bool {
+        self.hits += 1;
+        if !self.enabled || self.hits <= self.policy.ignore_count {
+            return false;
+        }
+        let exhausted = |triggers| {
+            self.policy
+                .disable_after
+                .is_some_and(|limit| triggers >= limit)
+        };
+        if exhausted(self.triggers) {
+            self.enabled = false;
+            return false;
+        }
+        self.triggers += 1;
+        if exhausted(self.triggers) {
+            self.enabled = false;
+        }
+        true
+    }
+}
+
+/// Identifies a breakpoint by its `jmethodID` and location.
+type BreakpointKey = (usize, sys::jlocation);
+
+/// Sets breakpoints and applies their [`HitPolicy`] when they are hit.
+#[derive(Debug, Default)]
+pub struct BreakpointManager {
+    breakpoints: Mutex<HashMap<BreakpointKey, BreakpointStats>>,
+}
+
+impl BreakpointManager {
+    /// Creates a manager without breakpoints.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn key(method: &Method<'_>, location: sys::jlocation) -> BreakpointKey {
+        (method.as_raw() as usize, location)
+    }
+
+    /// Sets a breakpoint at `location` in `method` following `policy`.
+    /// Setting a breakpoint that is already managed replaces its policy and resets its statistics.
+    /// # Errors
+    /// See [`BreakpointError`] for more information.
+    pub fn set(
+        &self,
+        method: &Method<'_>,
+        location: sys::jlocation,
+        policy: HitPolicy,
+    ) -> Result<(), BreakpointError> {
+        let mut breakpoints = self
+            .breakpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let key = Self::key(method, location);
+        if !breakpoints.get(&key).is_some_and(|it| it.enabled) {
+            method.set_breakpoint(location)?;
+        }
+        let class_name = method
+            .declaring_class()
+            .ok()
+            .and_then(|it| it.signature().ok())
+            .map_or_else(|| "<unknown>".to_owned(), |it| binary_name(&it));
+        let method_name = method.name().map_or_else(
+            |_| "<unknown>".to_owned(),
+            |it| it.to_utf8_lossy().into_owned(),
+        );
+        breakpoints.insert(
+            key,
+            BreakpointStats {
+                class_name,
+                method_name,
+                location,
+                policy,
+                hits: 0,
+                triggers: 0,
+                enabled: true,
+            },
+        );
+        Ok(())
+    }
+
+    /// Clears the breakpoint at `location` in `method` and forgets its statistics.
+    /// Returns `false` if the breakpoint is not managed by this manager.
+    /// # Errors
+    /// See [`BreakpointError`] for more information.
+    pub fn clear(
+        &self,
+        method: &Method<'_>,
+        location: sys::jlocation,
+    ) -> Result<bool, BreakpointError> {
+        let mut breakpoints = self
+            .breakpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let key = Self::key(method, location);
+        match breakpoints.get(&key) {
+            None => Ok(false),
+            Some(stats) => {
+                if stats.enabled {
+                    method.clear_breakpoint(location)?;
+                }
+                breakpoints.remove(&key);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Records a hit of the breakpoint at `location` in `method` and returns whether the hit
+    /// triggers the breakpoint according to its policy. Clears the breakpoint once its policy
+    /// says so. Hits of breakpoints not managed by this manager always trigger.
+    pub fn on_breakpoint(&self, method: &Method<'_>, location: sys::jlocation) -> bool {
+        let mut breakpoints = self
+            .breakpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Some(stats) = breakpoints.get_mut(&Self::key(method, location)) else {
+            return true;
+        };
+        let enabled = stats.enabled;
+        let triggered = stats.record_hit();
+        if enabled && !stats.enabled {
+            // The breakpoint may already have been cleared by someone else, in which case it is
+            // disabled all the same.
+            let _ = method.clear_breakpoint(location);
+        }
+        triggered
+    }
+
+    /// Gets the statistics of the breakpoint at `location` in `method`.
+    pub fn stats(&self, method: &Method<'_>, location: sys::jlocation) -> Option<BreakpointStats> {
+        self.breakpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&Self::key(method, location))
+            .cloned()
+    }
+
+    /// Gets the statistics of all the managed breakpoints, including the ones disabled by their
+    /// policy.
+    pub fn all_stats(&self) -> Vec<BreakpointStats> {
+        self.breakpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+/// The outcome of [`LineBreakpoints::set_breakpoint_at`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineBreakpointState {
+    /// The class is loaded and the breakpoint has been set at `location` in the method.
+    Installed {
+        /// The location of the first instruction of the line.
+        location: sys::jlocation,
+    },
+    /// The class is not prepared yet, so the breakpoint will be set when it is.
+    Deferred,
+}
+
+/// A breakpoint waiting for its class to be prepared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PendingBreakpoint {
+    /// The JNI type signature of the class, e.g. `Lcom/example/Foo;`, in modified UTF-8.
+    class_signature: Vec<u8>,
+    /// The name of the method in modified UTF-8, or `None` for any method.
+    method_name: Option<Vec<u8>>,
+    line: u32,
+}
+
+/// A breakpoint set by a [`LineBreakpoints`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct InstalledBreakpoint {
+    class_signature: Vec<u8>,
+    /// The address of the `jmethodID` of the method.
+    method: usize,
+    location: sys::jlocation,
+}
+
+/// Sets breakpoints by class name, method name, and source line.
+///
+/// Breakpoints in classes that are not prepared yet are kept pending until
+/// [`LineBreakpoints::on_class_prepare`] is called with the class, which should be done from the
+/// `ClassPrepare` event. The event has to be enabled for deferred breakpoints to be set.
+/// Once set, the breakpoints are ordinary breakpoints that stay set until they are cleared with
+/// [`LineBreakpoints::clear_class`] or [`Method::clear_breakpoint`].
+#[derive(Debug, Default)]
+pub struct LineBreakpoints {
+    pending: Mutex<Vec<PendingBreakpoint>>,
+    installed: Mutex<Vec<InstalledBreakpoint>>,
+}
+
+impl LineBreakpoints {
+    /// Creates a resolver without pending breakpoints.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a breakpoint at the first instruction of `line` in the method `method_name` of the
+    /// class with the binary name `class_name`, e.g. `com.example.Foo`. If several methods have
+    /// that name, the first one whose code spans `line` is used. If the class is not loaded or
+    /// not prepared yet, the breakpoint is set when it is.
+    /// # Errors
+    /// Returns [`BreakpointError::InvalidMethodId`] if the class has no method named
+    /// `method_name` and [`BreakpointError::InvalidLocation`] if no such method has code on
+    /// `line`. See [`BreakpointError`] for more information.
+    pub fn set_breakpoint_at(
+        &self,
+        jvm: &Jvm,
+        class_name: &str,
+        method_name: &str,
+        line: u32,
+    ) -> Result<LineBreakpointState, BreakpointError> {
+        self.set(
+            jvm,
+            PendingBreakpoint {
+                class_signature: class_signature(class_name),
+                method_name: Some(encode_modified_utf8(method_name).into_bytes()),
+                line,
+            },
+        )
+    }
+
+    /// Sets a breakpoint at the first instruction of `line` in whichever method of the class with
+    /// the binary name `class_name` has code on that line, as debuggers do for a line of a source
+    /// file. If the class is not loaded or not prepared yet, the breakpoint is set when it is.
+    /// # Errors
+    /// Returns [`BreakpointError::InvalidLocation`] if no method of the class has code on `line`.
+    /// See [`BreakpointError`] for more information.
+    pub fn set_breakpoint_at_line(
+        &self,
+        jvm: &Jvm,
+        class_name: &str,
+        line: u32,
+    ) -> Result<LineBreakpointState, BreakpointError> {
+        self.set(
+            jvm,
+            PendingBreakpoint {
+                class_signature: class_signature(class_name),
+                method_name: None,
+                line,
+            },
+        )
+    }
+
+    fn set(
+        &self,
+        jvm: &Jvm,
+        breakpoint: PendingBreakpoint,
+    ) -> Result<LineBreakpointState, BreakpointError> {
+        // The lock is held while looking for the class so that a concurrent `ClassPrepare` event
+        // cannot miss the breakpoint.
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let classes = jvm.get_loaded_classes()?;
+        let mut installed = None;
+        for class in &classes {
+            if class.signature().map_err(JvmTIError::from)?.as_bytes()
+                != breakpoint.class_signature.as_slice()
+            {
+                continue;
+            }
+            match self.install(&breakpoint, class) {
+                Ok(location) => installed = installed.or(Some(location)),
+                Err(BreakpointError::Other(JvmTIError::ClassNotPrepared)) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        if let Some(location) = installed {
+            Ok(LineBreakpointState::Installed { location })
+        } else {
+            pending.push(breakpoint);
+            Ok(LineBreakpointState::Deferred)
+        }
+    }
+
+    /// Sets the pending breakpoints in `class`, which has just been prepared, and returns how
+    /// many were set. Call this from the `ClassPrepare` event.
+    /// Breakpoints that cannot be set in `class`, e.g. because the line has no code, are dropped
+    /// and the first such error is returned after the others have been set.
+    /// # Errors
+    /// See [`BreakpointError`] for more information.
+    pub fn on_class_prepare(&self, class: &Class<'_>) -> Result<usize, BreakpointError> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let signature = class.signature().map_err(JvmTIError::from)?;
+        let mut installed = 0;
+        let mut first_error = None;
+        pending.retain(|breakpoint| {
+            if breakpoint.class_signature.as_slice() != signature.as_bytes() {
+                return true;
+            }
+            match self.install(breakpoint, class) {
+                Ok(_) => installed += 1,
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+            false
+        });
+        first_error.map_or(Ok(installed), Err)
+    }
+
+    /// Clears all the breakpoints set or pending in the class with the binary name `class_name`
+    /// and returns how many were cleared, e.g. before setting the breakpoints of a source file
+    /// anew.
+    /// # Errors
+    /// See [`BreakpointError`] for more information.
+    pub fn clear_class(&self, jvm: &Jvm, class_name: &str) -> Result<usize, BreakpointError> {
+        let signature = class_signature(class_name);
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let pending_before = pending.len();
+        pending.retain(|it| it.class_signature != signature);
+        let mut cleared = pending_before - pending.len();
+        let mut installed = self
+            .installed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !installed.iter().any(|it| it.class_signature == signature) {
+            return Ok(cleared);
+        }
+        for class in jvm.get_loaded_classes()? {
+            if class.signature().map_err(JvmTIError::from)?.as_bytes() != signature.as_slice() {
+                continue;
+            }
+            // The method IDs are only used once they are known to belong to a loaded class.
+            for method in class.methods().map_err(JvmTIError::from)? {
+                let id = method.as_raw().addr();
+                for breakpoint in installed.iter().filter(|it| it.method == id) {
+                    match method.clear_breakpoint(breakpoint.location) {
+                        Ok(()) => cleared += 1,
+                        Err(BreakpointError::NotFound) => {}
+                        Err(error) => return Err(error),
+                    }
+                }
+            }
+        }
+        installed.retain(|it| it.class_signature != signature);
+        Ok(cleared)
+    }
+
+    /// Gets the number of breakpoints waiting for their class to be prepared.
+    pub fn pending_count(&self) -> usize {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Sets `breakpoint` in `class`, records it, and returns its location.
+    fn install(
+        &self,
+        breakpoint: &PendingBreakpoint,
+        class: &Class<'_>,
+    ) -> Result<sys::jlocation, BreakpointError> {
+        let mut found_method = false;
+        for method in class.methods().map_err(JvmTIError::from)? {
+            if let Some(name) = &breakpoint.method_name {
+                if method.name().map_err(JvmTIError::from)?.as_bytes() != name.as_slice() {
+                    continue;
+                }
+            }
+            found_method = true;
+            let lines = match method.line_number_table() {
+                Ok(it) => it,
+                Err(MethodError::AbsentInformation | MethodError::NativeMethod) => continue,
+                Err(error) => return Err(JvmTIError::from(error).into()),
+            };
+            let location = lines
+                .iter()
+                .filter(|it| it.line_number == breakpoint.line)
+                .map(|it| it.start_location)
+                .min();
+            if let Some(location) = location {
+                method.set_breakpoint(location)?;
+                self.installed
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .push(InstalledBreakpoint {
+                        class_signature: breakpoint.class_signature.clone(),
+                        method: method.as_raw().addr(),
+                        location,
+                    });
+                return Ok(location);
+            }
+        }
+        Err(if found_method || breakpoint.method_name.is_none() {
+            BreakpointError::InvalidLocation
+        } else {
+            BreakpointError::InvalidMethodId
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(policy: HitPolicy) -> BreakpointStats {
+        BreakpointStats {
+            class_name: "Foo".to_owned(),
+            method_name: "bar".to_owned(),
+            location: 0,
+            policy,
+            hits: 0,
+            triggers: 0,
+            enabled: true,
+        }
+    }
+
+    fn record(stats: &mut BreakpointStats, hits: usize) -> Vec<bool> {
+        (0..hits).map(|_| stats.record_hit()).collect()
+    }
+
+    #[test]
+    fn always_triggers_on_every_hit() {
+        let mut stats = stats(HitPolicy::ALWAYS);
+        assert_eq!(record(&mut stats, 3), [true, true, true]);
+        assert!(stats.enabled);
+    }
+
+    #[test]
+    fn ignores_the_first_hits() {
+        let mut stats = stats(HitPolicy::after_hits(2));
+        assert_eq!(record(&mut stats, 4), [false, false, true, true]);
+        assert_eq!((stats.hits, stats.triggers), (4, 2));
+    }
+
+    #[test]
+    fn disables_after_the_limit() {
+        let mut stats = stats(HitPolicy::disable_after(2));
+        assert_eq!(record(&mut stats, 3), [true, true, false]);
+        assert_eq!(stats.triggers, 2);
+        assert!(!stats.enabled);
+    }
+
+    #[test]
+    fn disable_after_zero_never_triggers() {
+        let mut stats = stats(HitPolicy::disable_after(0));
+        assert_eq!(record(&mut stats, 2), [false, false]);
+        assert_eq!(stats.triggers, 0);
+        assert!(!stats.enabled);
+    }
+}
@@ -0,0 +1,256 @@
+//! Records which threads and application frames trigger class loading and exports the resulting
+//! "who loads what" graph as Graphviz DOT or JSON.
+
+use std::{
+    fmt::Write,
+    io,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::jvm::{class::Class, errors::JvmTIError, thread_filter::ThreadFilter, threads::Thread};
+
+use super::{
+    binary_name, call_sites, correlation::CorrelationId, escape_dot, escape_json, sink::ReportSink,
+    CallSite,
+};
+
+/// The package prefixes treated as library code when looking for application frames.
+pub const DEFAULT_LIBRARY_PREFIXES: &[&str] = &["java.", "javax.", "jdk.", "sun.", "com.sun."];
+
+/// A single class load observed by a [`ClassLoadGraph`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassLoadRecord {
+    /// The binary name of the loaded class.
+    pub class_name: String,
+    /// The name of the thread that loaded the class, if it could be determined.
+    pub thread_name: Option<String>,
+    /// The top application frames at the time of loading, innermost first.
+    pub sites: Vec<CallSite>,
+    /// The correlation ID attached to the loading thread.
+    pub correlation_id: Option<CorrelationId>,
+}
+
+impl ClassLoadRecord {
+    /// The node that triggered the load: the innermost application frame's class, or the
+    /// loading thread if no application frame was found.
+    fn trigger(&self) -> String {
+        match (self.sites.first(), &self.thread_name) {
+            (Some(site), _) => site.class_name.clone(),
+            (None, Some(thread_name)) => format!("[thread {thread_name}]"),
+            (None, None) => "[unknown]".to_owned(),
+        }
+    }
+
+    /// Serializes the record as a JSON object.
+    fn to_json(&self) -> String {
+        let mut json = String::new();
+        let _ = write!(
+            json,
+            "{{\"class\":{},\"trigger\":{},\"thread\":{},\"correlation_id\":{},\"sites\":[",
+            escape_json(&self.class_name),
+            escape_json(&self.trigger()),
+            self.thread_name
+                .as_deref()
+                .map_or_else(|| "null".to_owned(), escape_json),
+            CorrelationId::to_json(self.correlation_id)
+        );
+        for (index, site) in self.sites.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"class\":{},\"method\":{}}}",
+                escape_json(&site.class_name),
+                escape_json(&site.method_name)
+            );
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// Collects [`ClassLoadRecord`]s from `ClassLoad` events.
+/// Call [`ClassLoadGraph::record`] from the `class_load` callback and export the graph with
+/// [`ClassLoadGraph::to_dot`] or [`ClassLoadGraph::to_json`].
+#[derive(Debug)]
+pub struct ClassLoadGraph {
+    max_sites: usize,
+    library_prefixes: Vec<String>,
+    filter: ThreadFilter,
+    records: Mutex<Vec<ClassLoadRecord>>,
+}
+
+impl ClassLoadGraph {
+    /// Creates a graph that keeps up to `max_sites` application frames per class load and treats
+    /// the [`DEFAULT_LIBRARY_PREFIXES`] as library code.
+    #[must_use]
+    pub fn new(max_sites: usize) -> Self {
+        Self::with_library_prefixes(max_sites, DEFAULT_LIBRARY_PREFIXES.iter().copied())
+    }
+
+    /// Creates a graph that treats classes whose binary names start with any of `prefixes` as
+    /// library code.
+    pub fn with_library_prefixes<I, S>(max_sites: usize, prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            max_sites,
+            library_prefixes: prefixes.into_iter().map(Into::into).collect(),
+            filter: ThreadFilter::default(),
+            records: Mutex::default(),
+        }
+    }
+
+    /// Makes the graph ignore the class loads on the threads excluded by `filter`.
+    #[must_use]
+    pub fn with_thread_filter(mut self, filter: ThreadFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Records that `class` has been loaded on `thread`, unless the thread is excluded by the
+    /// filter of the graph.
+    /// The call stack is only available in the live phase; class loads observed earlier are
+    /// recorded without application frames.
+    /// # Errors
+    /// Returns an error if the class signature cannot be retrieved.
+    pub fn record(&self, thread: &Thread<'_>, class: &Class<'_>) -> Result<(), JvmTIError> {
+        if self.filter.excludes(thread) {
+            return Ok(());
+        }
+        let class_name = binary_name(&class.signature()?);
+        let thread_name = thread
+            .info()
+            .ok()
+            .map(|info| info.name.to_string_lossy().into_owned());
+        let sites = call_sites(thread, self.max_sites, &self.library_prefixes).unwrap_or_default();
+        let correlation_id = CorrelationId::stamp(thread);
+        self.records
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(ClassLoadRecord {
+                class_name,
+                thread_name,
+                sites,
+                correlation_id,
+            });
+        Ok(())
+    }
+
+    /// Returns a copy of the records collected so far.
+    pub fn records(&self) -> Vec<ClassLoadRecord> {
+        self.records
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Exports the graph in the Graphviz DOT format.
+    /// Each edge points from the class (or thread) that triggered a load to the loaded class.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph class_loads {\n");
+        for record in self.records() {
+            let label = record
+                .sites
+                .first()
+                .map_or_else(String::new, |site| site.method_name.clone());
+            let _ = writeln!(
+                dot,
+                "    \"{}\" -> \"{}\" [label=\"{}\"];",
+                escape_dot(&record.trigger()),
+                escape_dot(&record.class_name),
+                escape_dot(&label)
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Exports the graph as a JSON object with a `loads` array holding one entry per class load.
+    pub fn to_json(&self) -> String {
+        let loads: Vec<_> = self
+            .records()
+            .iter()
+            .map(ClassLoadRecord::to_json)
+            .collect();
+        format!("{{\"loads\":[{}]}}", loads.join(","))
+    }
+
+    /// Writes each record collected so far to `sink` as a JSON object, in the format of the
+    /// entries of [`ClassLoadGraph::to_json`], and flushes the sink.
+    /// # Errors
+    /// Returns an error if the sink fails.
+    pub fn write_records<S: ReportSink + ?Sized>(&self, sink: &mut S) -> io::Result<()> {
+        for record in self.records() {
+            sink.write_record(record.to_json().as_bytes())?;
+        }
+        sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> ClassLoadGraph {
+        let graph = ClassLoadGraph::new(2);
+        graph.records.lock().unwrap().extend([
+            ClassLoadRecord {
+                class_name: "com.example.Loaded".to_owned(),
+                thread_name: Some("main".to_owned()),
+                sites: vec![CallSite {
+                    class_name: "com.example.Main".to_owned(),
+                    method_name: "main".to_owned(),
+                }],
+                correlation_id: None,
+            },
+            ClassLoadRecord {
+                class_name: "com.example.Early".to_owned(),
+                thread_name: Some("worker \"1\"".to_owned()),
+                sites: Vec::new(),
+                correlation_id: None,
+            },
+            ClassLoadRecord {
+                class_name: "com.example.Orphan".to_owned(),
+                thread_name: None,
+                sites: Vec::new(),
+                correlation_id: None,
+            },
+        ]);
+        graph
+    }
+
+    #[test]
+    fn dot_has_an_edge_per_load_from_its_trigger() {
+        assert_eq!(
+            graph().to_dot(),
+            concat!(
+                "digraph class_loads {\n",
+                "    \"com.example.Main\" -> \"com.example.Loaded\" [label=\"main\"];\n",
+                "    \"[thread worker \\\"1\\\"]\" -> \"com.example.Early\" [label=\"\"];\n",
+                "    \"[unknown]\" -> \"com.example.Orphan\" [label=\"\"];\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn json_has_an_entry_per_load() {
+        assert_eq!(
+            graph().to_json(),
+            concat!(
+                r#"{"loads":["#,
+                r#"{"class":"com.example.Loaded","trigger":"com.example.Main","thread":"main","#,
+                r#""correlation_id":null,"sites":[{"class":"com.example.Main","method":"main"}]},"#,
+                r#"{"class":"com.example.Early","trigger":"[thread worker \"1\"]","#,
+                r#""thread":"worker \"1\"","correlation_id":null,"sites":[]},"#,
+                r#"{"class":"com.example.Orphan","trigger":"[unknown]","thread":null,"#,
+                r#""correlation_id":null,"sites":[]}"#,
+                "]}",
+            )
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Correlation IDs attached to Java threads.
+//!
+//! A correlation ID is an opaque value, such as a trace or request ID of a distributed tracing
+//! system, that the agent associates with a thread while the thread works on behalf of a request.
+//! The diagnostic subsystems stamp the ID onto every record they produce for that thread so the
+//! agent data can be joined with the traces afterwards.
+//!
+//! The ID is kept in the JVM TI thread-local storage of the environment, so agents using this
+//! module must not use `SetThreadLocalStorage` for other purposes.
+
+use std::{ffi::c_void, num::NonZeroU64, ptr::null_mut};
+
+use crate::jvm::{errors::ThreadError, threads::Thread, Jvm};
+
+use super::escape_json;
+
+/// An opaque correlation ID attached to a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CorrelationId(NonZeroU64);
+
+impl CorrelationId {
+    /// Creates a new [`CorrelationId`], or returns `None` if `id` is zero.
+    #[must_use]
+    pub const fn new(id: u64) -> Option<Self> {
+        match NonZeroU64::new(id) {
+            Some(id) => Some(Self(id)),
+            None => None,
+        }
+    }
+
+    /// Returns the value of the ID.
+    #[must_use]
+    pub const fn get(self) -> u64 {
+        self.0.get()
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn into_storage(id: Option<Self>) -> *const c_void {
+        // The ID is stored as the pointer value itself, so no allocation has to be released when
+        // the thread ends.
+        id.map_or(null_mut(), |id| id.get() as usize as *mut c_void)
+    }
+
+    fn from_storage(data: *mut c_void) -> Option<Self> {
+        Self::new(data as usize as u64)
+    }
+
+    /// Gets the ID to stamp onto a record produced for `thread`, treating a thread whose ID cannot
+    /// be read, e.g. because it has ended, as having none.
+    pub(crate) fn stamp(thread: &Thread<'_>) -> Option<Self> {
+        thread.correlation_id().ok().flatten()
+    }
+
+    /// Serializes `id` as a JSON string, or `null` if there is no ID.
+    pub(crate) fn to_json(id: Option<Self>) -> String {
+        id.map_or_else(|| "null".to_owned(), |id| escape_json(&id.to_string()))
+    }
+}
+
+impl std::fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.get())
+    }
+}
+
+impl Thread<'_> {
+    /// Gets the correlation ID attached to the thread.
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn correlation_id(&self) -> Result<Option<CorrelationId>, ThreadError> {
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        let data = unsafe { self.jvm.get_thread_local_storage(self.jthread) }?;
+        Ok(CorrelationId::from_storage(data))
+    }
+
+    /// Attaches `id` to the thread, or detaches the current ID if `id` is `None`.
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn set_correlation_id(&self, id: Option<CorrelationId>) -> Result<(), ThreadError> {
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        unsafe {
+            self.jvm
+                .set_thread_local_storage(self.jthread, CorrelationId::into_storage(id))
+        }?;
+        Ok(())
+    }
+}
+
+impl Jvm {
+    /// Gets the correlation ID attached to the current thread.
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn current_correlation_id(&self) -> Result<Option<CorrelationId>, ThreadError> {
+        // SAFETY: A null thread denotes the current thread.
+        let data = unsafe { self.get_thread_local_storage(null_mut()) }?;
+        Ok(CorrelationId::from_storage(data))
+    }
+
+    /// Attaches `id` to the current thread, or detaches the current ID if `id` is `None`.
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn set_current_correlation_id(&self, id: Option<CorrelationId>) -> Result<(), ThreadError> {
+        // SAFETY: A null thread denotes the current thread.
+        unsafe { self.set_thread_local_storage(null_mut(), CorrelationId::into_storage(id)) }?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_is_not_an_id() {
+        assert_eq!(CorrelationId::new(0), None);
+        assert_eq!(CorrelationId::new(42).map(CorrelationId::get), Some(42));
+    }
+
+    #[test]
+    fn displays_as_fixed_width_hex() {
+        let id = CorrelationId::new(0xABC).unwrap();
+        assert_eq!(id.to_string(), "0000000000000abc");
+        assert_eq!(CorrelationId::to_json(Some(id)), r#""0000000000000abc""#);
+        assert_eq!(CorrelationId::to_json(None), "null");
+    }
+
+    #[test]
+    fn round_trips_through_storage() {
+        for id in [
+            None,
+            CorrelationId::new(1),
+            CorrelationId::new(u64::from(u32::MAX)),
+        ] {
+            let storage = CorrelationId::into_storage(id).cast_mut();
+            assert_eq!(CorrelationId::from_storage(storage), id);
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! A minimal JSON value, enough to read and write the messages of the Debug Adapter Protocol.
+
+use std::fmt;
+
+use crate::diagnostics::escape_json;
+
+/// A JSON value. Object members keep their order.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parses `input`, or returns `None` if it is not a single valid JSON value.
+    pub(super) fn parse(input: &str) -> Option<Self> {
+        let mut parser = Parser {
+            input: input.as_bytes(),
+            position: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        (parser.position == parser.input.len()).then_some(value)
+    }
+
+    /// Builds an object from its members.
+    pub(super) fn object<'a>(members: impl IntoIterator<Item = (&'a str, Json)>) -> Self {
+        Self::Object(
+            members
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value))
+                .collect(),
+        )
+    }
+
+    /// Gets the member `key` of an object.
+    pub(super) fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Self::Object(members) => members.iter().find(|(it, _)| it == key).map(|(_, it)| it),
+            _ => None,
+        }
+    }
+
+    pub(super) fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Self::Number(value) if value.fract() == 0.0 => Some(value as i64),
+            _ => None,
+        }
+    }
+
+    pub(super) fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Self::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<i64> for Json {
+    #[allow(clippy::cast_precision_loss)]
+    fn from(value: i64) -> Self {
+        Self::Number(value as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_owned())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Self {
+        Self::Array(values)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(value) => write!(f, "{value}"),
+            Self::Number(value) if value.is_finite() => write!(f, "{value}"),
+            // JSON has no representation of infinities and NaN.
+            Self::Null | Self::Number(_) => f.write_str("null"),
+            Self::String(value) => f.write_str(&escape_json(value)),
+            Self::Array(values) => {
+                f.write_str("[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_str("]")
+            }
+            Self::Object(members) => {
+                f.write_str("{")?;
+                for (index, (key, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}:{value}", escape_json(key))?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+/// A recursive descent parser over the bytes of a JSON text.
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &[u8]) -> Option<()> {
+        let end = self.position + literal.len();
+        (self.input.get(self.position..end)? == literal).then(|| self.position = end)
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        match self.peek()? {
+            b'n' => self.expect(b"null").map(|()| Json::Null),
+            b't' => self.expect(b"true").map(|()| Json::Bool(true)),
+            b'f' => self.expect(b"false").map(|()| Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => self.array(),
+            b'{' => self.object(),
+            _ => self.number(),
+        }
+    }
+
+    fn array(&mut self) -> Option<Json> {
+        self.expect(b"[")?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek()? == b']' {
+            self.position += 1;
+            return Some(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {}
+                b']' => return Some(Json::Array(values)),
+                _ => return None,
+            }
+        }
+    }
+
+    fn object(&mut self) -> Option<Json> {
+        self.expect(b"{")?;
+        let mut members = Vec::new();
+        self.skip_whitespace();
+        if self.peek()? == b'}' {
+            self.position += 1;
+            return Some(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b":")?;
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next()? {
+                b',' => {}
+                b'}' => return Some(Json::Object(members)),
+                _ => return None,
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let start = self.position;
+        while matches!(
+            self.peek(),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.position += 1;
+        }
+        let text = std::str::from_utf8(&self.input[start..self.position]).ok()?;
+        text.parse().ok().map(Json::Number)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect(b"\"")?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.next()? {
+                b'"' => return String::from_utf8(bytes).ok(),
+                b'\\' => {
+                    let escaped = match self.next()? {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return None,
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(escaped.encode_utf8(&mut buffer).as_bytes());
+                }
+                byte => bytes.push(byte),
+            }
+        }
+    }
+
+    /// Decodes the code point of a `\u` escape whose `\u` has been consumed, combining a pair of
+    /// escaped UTF-16 surrogates.
+    fn unicode_escape(&mut self) -> Option<char> {
+        let first = self.hex4()?;
+        if (0xD800..0xDC00).contains(&first) {
+            self.expect(b"\\u")?;
+            let second = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&second) {
+                return None;
+            }
+            char::from_u32(0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00))
+        } else {
+            char::from_u32(first)
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = self.input.get(self.position..self.position + 4)?;
+        if !digits.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        let value = u32::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+        self.position += 4;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    #[test]
+    fn parses_scalars() {
+        assert_eq!(Json::parse("null"), Some(Json::Null));
+        assert_eq!(Json::parse(" true "), Some(Json::Bool(true)));
+        assert_eq!(Json::parse("false"), Some(Json::Bool(false)));
+        assert_eq!(Json::parse("-12.5e1"), Some(Json::Number(-125.0)));
+        assert_eq!(Json::parse(r#""text""#), Some(Json::from("text")));
+    }
+
+    #[test]
+    fn parses_nested_values_in_order() {
+        let value =
+            Json::parse(r#"{"b": [1, {"c": null}], "a": "x", "empty": {}, "none": []}"#).unwrap();
+        assert_eq!(
+            value,
+            Json::object([
+                (
+                    "b",
+                    Json::from(vec![Json::from(1), Json::object([("c", Json::Null)])])
+                ),
+                ("a", Json::from("x")),
+                ("empty", Json::object([])),
+                ("none", Json::from(vec![])),
+            ])
+        );
+        assert_eq!(value.get("a").and_then(Json::as_str), Some("x"));
+        assert_eq!(value.get("missing"), None);
+        assert_eq!(
+            value.get("b").and_then(Json::as_array).map(<[_]>::len),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn parses_string_escapes() {
+        assert_eq!(
+            Json::parse(r#""a\"b\\c\/d\b\f\n\r\t""#),
+            Some(Json::from("a\"b\\c/d\u{8}\u{c}\n\r\t"))
+        );
+        assert_eq!(
+            Json::parse(r#""\u00e9\u4E2D""#),
+            Some(Json::from("\u{e9}\u{4e2d}"))
+        );
+        assert_eq!(
+            Json::parse(r#""\ud83d\ude00""#),
+            Some(Json::from("\u{1F600}"))
+        );
+        assert_eq!(Json::parse("\"caf\u{e9}\""), Some(Json::from("caf\u{e9}")));
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        for input in [
+            "",
+            "nul",
+            "[1,",
+            "[1 2]",
+            "{\"a\" 1}",
+            "{1: 2}",
+            "\"open",
+            r#""\x""#,
+            r#""\u12""#,
+            r#""\u+123""#,
+            r#""\ud83d""#,
+            r#""\ud83d\u0041""#,
+            "1 2",
+            "-",
+        ] {
+            assert_eq!(Json::parse(input), None, "{input}");
+        }
+    }
+
+    #[test]
+    fn converts_integers() {
+        assert_eq!(Json::from(42).as_i64(), Some(42));
+        assert_eq!(Json::Number(1.5).as_i64(), None);
+        assert_eq!(Json::from("42").as_i64(), None);
+    }
+
+    #[test]
+    fn serializes_values() {
+        let value = Json::object([
+            ("name", Json::from("a\"b\n")),
+            (
+                "values",
+                Json::from(vec![Json::from(1), Json::Number(0.5), Json::Null]),
+            ),
+            ("ok", Json::from(true)),
+            ("nan", Json::Number(f64::NAN)),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"a\"b\n","values":[1,0.5,null],"ok":true,"nan":null}"#
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let text =
+            r#"{"seq":1,"type":"request","arguments":{"lines":[3,5],"path":"C:\\a\"b\u0001"}}"#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(value.to_string(), text);
+        assert_eq!(Json::parse(&value.to_string()), Some(value));
+    }
+}
@@ -0,0 +1,790 @@
+//! A Debug Adapter Protocol server, so that an agent can be driven from an IDE such as VS Code.
+//!
+//! [`Jvm::start_dap_server`] listens on a TCP address once the VM is initialized and serves one
+//! client at a time over the [Debug Adapter Protocol](https://microsoft.github.io/debug-adapter-protocol/).
+//! It exposes the breakpoints of [`LineBreakpoints`], the stepping of [`StepSession`], the call
+//! stacks symbolized by [`Symbolizer`], and the local variables of each frame. Point the client
+//! at the server with an `attach` configuration using `debugServer`.
+//!
+//! Source files are mapped to classes through the [`DapConfig::source_roots`]: a breakpoint in
+//! `<root>/com/example/Foo.java` is set in the class `com.example.Foo`. Breakpoints in nested
+//! classes declared in the same file are not supported.
+//!
+//! A thread that hits a breakpoint or completes a step stops by suspending itself, and the client
+//! inspects it while it is suspended. Pausing a thread stops it at the next instruction it
+//! executes, so a thread blocked in a wait or in native code only pauses once it runs Java code
+//! again. Other threads keep running while a thread is stopped.
+
+mod json;
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::Duration,
+};
+
+use crate::{
+    jvm::{
+        errors::{BreakpointError, JvmTIError, MethodError, ThreadError},
+        events::{self, EventMode, Handler, JvmTIEvent},
+        jni::{GlobalRef, JNI},
+        methods::Method,
+        objects::Object,
+        presets::Preset,
+        stack::Frame,
+        strings::ModifiedUtf8Ext,
+        threads::Thread,
+        values::JValue,
+        Jvm,
+    },
+    sys,
+};
+
+use super::{
+    binary_name,
+    breakpoints::{LineBreakpointState, LineBreakpoints},
+    stepping::{StepKind, StepSession},
+    symbolize::Symbolizer,
+};
+
+use json::Json;
+
+/// The maximum number of frames reported in a single `stackTrace` response.
+const MAX_FRAMES: usize = 1024;
+
+/// The number of low bits of a frame ID holding the depth of the frame; the others hold the
+/// thread ID.
+const FRAME_DEPTH_BITS: u32 = 16;
+
+/// How many times a stopped thread is resumed before giving up when it has not suspended itself
+/// yet, one millisecond apart.
+const RESUME_ATTEMPTS: usize = 1000;
+
+/// The configuration of the server started by [`Jvm::start_dap_server`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DapConfig {
+    /// The address to listen on, e.g. `127.0.0.1:5005`.
+    pub address: String,
+    /// The directories containing the source files, laid out by package, e.g. `src/main/java`.
+    pub source_roots: Vec<PathBuf>,
+}
+
+impl DapConfig {
+    /// Creates a configuration listening on `address` without source roots.
+    #[must_use]
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            source_roots: Vec::new(),
+        }
+    }
+
+    /// Adds `root` to the directories searched for source files.
+    #[must_use]
+    pub fn source_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.source_roots.push(root.into());
+        self
+    }
+}
+
+impl Jvm {
+    /// Starts a Debug Adapter Protocol server configured by `config`. See the
+    /// [module documentation](crate::diagnostics::dap).
+    ///
+    /// This applies [`Preset::Debugger`] and registers the callbacks of the events the server
+    /// handles, so it should be called from `Agent_OnLoad`. The server starts listening in an
+    /// agent thread when the VM is initialized.
+    /// # Errors
+    /// Returns [`JvmTIError::NotAvailable`] if the VM cannot provide the capabilities of
+    /// [`Preset::Debugger`]. See [`Jvm::update_callbacks`] for other possible errors.
+    pub fn start_dap_server(&mut self, config: DapConfig) -> Result<(), JvmTIError> {
+        self.apply_preset(Preset::Debugger)?;
+        self.enable_event(JvmTIEvent::FramePop, None)?;
+        let server = Arc::new(Server::new(config));
+        self.update_callbacks(|it| {
+            let vm_init = Arc::clone(&server);
+            it.vm_init
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |jvm, jni, _| {
+                    let server = Arc::clone(&vm_init);
+                    let spawned = jvm.spawn_agent_thread(
+                        jni,
+                        "coffee-filter-dap",
+                        Thread::NORM_PRIORITY,
+                        move |jvm, jni| server.serve(jvm, jni),
+                    );
+                    if let Err(error) = spawned {
+                        eprintln!("coffee-filter: cannot start the DAP server: {error}");
+                    }
+                }));
+            let class_prepare = Arc::clone(&server);
+            it.class_prepare
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |_, _, _, class| {
+                    if let Err(error) = class_prepare.breakpoints.on_class_prepare(class) {
+                        class_prepare.output(&format!("Cannot set a breakpoint: {error}"));
+                    }
+                }));
+            let breakpoint = Arc::clone(&server);
+            it.breakpoint
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |jvm, jni, thread, method, location| {
+                    breakpoint.on_breakpoint(jvm, jni, thread, method, location);
+                }));
+            let single_step = Arc::clone(&server);
+            it.single_step
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |jvm, jni, thread, method, location| {
+                    single_step.on_single_step(jvm, jni, thread, method, location);
+                }));
+            it.frame_pop
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |_, jni, thread, _, _| {
+                    STEP.with_borrow(|step| {
+                        if let Some(session) = step {
+                            // A failure surfaces as a step that never completes.
+                            let _ = session.on_frame_pop(jni, thread);
+                        }
+                    });
+                }));
+        })
+    }
+}
+
+thread_local! {
+    /// The step in progress on the current thread, which its `SingleStep` and `FramePop` events
+    /// are forwarded to.
+    static STEP: RefCell<Option<StepSession<'static>>> = const { RefCell::new(None) };
+    /// The method ID and the location where the current thread completed its last step, so that
+    /// a breakpoint at the same location does not stop it a second time.
+    static STEPPED_TO: Cell<Option<(usize, sys::jlocation)>> = const { Cell::new(None) };
+}
+
+/// The result of a request: the body of the response, or the error message.
+type Response = Result<Json, String>;
+
+/// The state shared by the server thread and the event callbacks.
+#[derive(Debug)]
+struct Server {
+    config: DapConfig,
+    /// The connected client, written to by whichever thread sends a message.
+    client: Mutex<Option<TcpStream>>,
+    /// The sequence number of the last message sent.
+    seq: AtomicI64,
+    breakpoints: LineBreakpoints,
+    /// The classes with breakpoints set by the client.
+    classes: Mutex<HashSet<String>>,
+    /// The threads known to the client; the ID of a thread is its index plus one.
+    threads: Mutex<Vec<Arc<GlobalRef<Thread<'static>>>>>,
+    /// The threads that stopped and wait for the client to resume them.
+    stopped: Mutex<HashSet<i64>>,
+    /// The steps to start when the threads are resumed.
+    steps: Mutex<HashMap<i64, StepKind>>,
+    /// The threads to stop at their next instruction.
+    pauses: Mutex<HashSet<i64>>,
+}
+
+impl Server {
+    fn new(config: DapConfig) -> Self {
+        Self {
+            config,
+            client: Mutex::new(None),
+            seq: AtomicI64::new(0),
+            breakpoints: LineBreakpoints::new(),
+            classes: Mutex::new(HashSet::new()),
+            threads: Mutex::new(Vec::new()),
+            stopped: Mutex::new(HashSet::new()),
+            steps: Mutex::new(HashMap::new()),
+            pauses: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Accepts clients one after the other, for the life of the VM.
+    fn serve(&self, jvm: &Jvm, jni: &JNI) {
+        let listener = match TcpListener::bind(self.config.address.as_str()) {
+            Ok(it) => it,
+            Err(error) => {
+                eprintln!(
+                    "coffee-filter: the DAP server cannot listen on {}: {error}",
+                    self.config.address
+                );
+                return;
+            }
+        };
+        for stream in listener.incoming().flatten() {
+            // The connection is dropped on I/O errors, as if the client had disconnected.
+            let _ = self.serve_client(jvm, jni, stream);
+            self.detach(jvm);
+        }
+    }
+
+    fn serve_client(&self, jvm: &Jvm, jni: &JNI, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        *lock(&self.client) = Some(stream);
+        while let Some(message) = read_message(&mut reader)? {
+            if let Some(request) = Json::parse(&message) {
+                if !self.handle(jvm, jni, &request) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the client, clearing its breakpoints and resuming the threads it stopped.
+    fn detach(&self, jvm: &Jvm) {
+        *lock(&self.client) = None;
+        for class_name in lock(&self.classes).drain() {
+            let _ = self.breakpoints.clear_class(jvm, &class_name);
+        }
+        lock(&self.steps).clear();
+        lock(&self.pauses).clear();
+        let stopped: Vec<_> = lock(&self.stopped).drain().collect();
+        for id in stopped {
+            if let Ok(thread) = self.thread(id) {
+                let _ = resume_stopped(&thread.get(jvm));
+            }
+        }
+    }
+
+    /// Handles `request` and returns whether to keep serving the client.
+    fn handle(&self, jvm: &Jvm, jni: &JNI, request: &Json) -> bool {
+        let command = request
+            .get("command")
+            .and_then(Json::as_str)
+            .unwrap_or_default();
+        let arguments = request.get("arguments").unwrap_or(&Json::Null);
+        let response = match command {
+            "initialize" => Ok(Json::object([(
+                "supportsConfigurationDoneRequest",
+                true.into(),
+            )])),
+            "launch"
+            | "attach"
+            | "configurationDone"
+            | "setExceptionBreakpoints"
+            | "disconnect" => Ok(Json::Null),
+            "setBreakpoints" => self.set_breakpoints(jvm, arguments),
+            "threads" => self.threads(jvm, jni),
+            "stackTrace" => self.stack_trace(jvm, arguments),
+            "scopes" => scopes(arguments),
+            "variables" => self.variables(jvm, jni, arguments),
+            "continue" => self
+                .resume(jvm, arguments, None)
+                .map(|()| Json::object([("allThreadsContinued", false.into())])),
+            "next" => self
+                .resume(jvm, arguments, Some(StepKind::Over))
+                .map(|()| Json::Null),
+            "stepIn" => self
+                .resume(jvm, arguments, Some(StepKind::Into))
+                .map(|()| Json::Null),
+            "stepOut" => self
+                .resume(jvm, arguments, Some(StepKind::Out))
+                .map(|()| Json::Null),
+            "pause" => self.pause(jvm, arguments),
+            _ => Err(format!("Unsupported request {command}")),
+        };
+        let request_seq = request.get("seq").cloned().unwrap_or(Json::Null);
+        let mut members = vec![
+            ("request_seq", request_seq),
+            ("success", response.is_ok().into()),
+            ("command", command.into()),
+        ];
+        match response {
+            Ok(Json::Null) => {}
+            Ok(body) => members.push(("body", body)),
+            Err(message) => members.push(("message", message.into())),
+        }
+        self.send("response", members);
+        match command {
+            "initialize" => self.send_event("initialized", Json::Null),
+            "disconnect" => return false,
+            _ => {}
+        }
+        true
+    }
+
+    fn set_breakpoints(&self, jvm: &Jvm, arguments: &Json) -> Response {
+        let path = arguments
+            .get("source")
+            .and_then(|it| it.get("path"))
+            .and_then(Json::as_str)
+            .ok_or("Missing argument source.path")?;
+        let lines: Vec<i64> = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|it| it.get("line")?.as_i64())
+            .collect();
+        let Some(class_name) = self.class_for_source(Path::new(path)) else {
+            let breakpoints = lines
+                .iter()
+                .map(|&line| unverified(line, "The source is not under a source root"))
+                .collect::<Vec<_>>();
+            return Ok(Json::object([("breakpoints", breakpoints.into())]));
+        };
+        self.breakpoints
+            .clear_class(jvm, &class_name)
+            .map_err(|it| it.to_string())?;
+        if lines.is_empty() {
+            lock(&self.classes).remove(&class_name);
+        } else {
+            lock(&self.classes).insert(class_name.clone());
+        }
+        let breakpoints = lines
+            .iter()
+            .map(|&line| {
+                let state = u32::try_from(line)
+                    .map_err(|_| BreakpointError::InvalidLocation)
+                    .and_then(|it| {
+                        self.breakpoints
+                            .set_breakpoint_at_line(jvm, &class_name, it)
+                    });
+                match state {
+                    Ok(LineBreakpointState::Installed { .. }) => {
+                        Json::object([("verified", true.into()), ("line", line.into())])
+                    }
+                    Ok(LineBreakpointState::Deferred) => {
+                        unverified(line, "The class is not loaded yet")
+                    }
+                    Err(error) => unverified(line, &error.to_string()),
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(Json::object([("breakpoints", breakpoints.into())]))
+    }
+
+    fn threads(&self, jvm: &Jvm, jni: &JNI) -> Response {
+        let mut threads = Vec::new();
+        for thread in jvm.get_all_threads().map_err(|it| it.to_string())? {
+            if jvm.is_agent_thread(jni, &thread) {
+                continue;
+            }
+            let Some(id) = self.register_thread(jvm, jni, &thread) else {
+                continue;
+            };
+            let name = thread.info().map_or_else(
+                |_| format!("Thread {id}"),
+                |it| it.name.to_utf8_lossy().into_owned(),
+            );
+            threads.push(Json::object([("id", id.into()), ("name", name.into())]));
+        }
+        Ok(Json::object([("threads", threads.into())]))
+    }
+
+    fn stack_trace(&self, jvm: &Jvm, arguments: &Json) -> Response {
+        let id = argument(arguments, "threadId")?;
+        let global = self.thread(id)?;
+        let thread = global.get(jvm);
+        let start = arguments
+            .get("startFrame")
+            .and_then(Json::as_i64)
+            .and_then(|it| usize::try_from(it).ok())
+            .unwrap_or(0);
+        let levels = arguments
+            .get("levels")
+            .and_then(Json::as_i64)
+            .and_then(|it| usize::try_from(it).ok())
+            .filter(|&it| it > 0)
+            .map_or(MAX_FRAMES, |it| it.min(MAX_FRAMES));
+        let total = thread.frame_count().map_err(|it| it.to_string())?;
+        let frames = thread
+            .stack_trace_from(start.cast_signed(), levels)
+            .map_err(|it| it.to_string())?;
+        let mut names = Symbolizer::new();
+        let mut stack_frames = Vec::with_capacity(frames.len());
+        for (offset, frame) in frames.iter().enumerate() {
+            let symbolized = names.symbolize(frame).map_err(|it| it.to_string())?;
+            let mut members = vec![
+                ("id", frame_id(id, start + offset).into()),
+                (
+                    "name",
+                    format!("{}.{}", symbolized.class_name, symbolized.method_name).into(),
+                ),
+                ("line", symbolized.line_number.map_or(0, i64::from).into()),
+                ("column", 1.into()),
+            ];
+            if let Some(path) = self.source_for_class(&symbolized.class_name) {
+                let name = path
+                    .file_name()
+                    .map(|it| it.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let source = Json::object([
+                    ("name", name.into()),
+                    ("path", path.to_string_lossy().into_owned().into()),
+                ]);
+                members.push(("source", source));
+            }
+            stack_frames.push(Json::object(members));
+        }
+        Ok(Json::object([
+            ("stackFrames", stack_frames.into()),
+            (
+                "totalFrames",
+                i64::try_from(total).unwrap_or(i64::MAX).into(),
+            ),
+        ]))
+    }
+
+    fn variables(&self, jvm: &Jvm, jni: &JNI, arguments: &Json) -> Response {
+        let reference = argument(arguments, "variablesReference")?;
+        let id = reference >> FRAME_DEPTH_BITS;
+        let depth = usize::try_from(reference & ((1 << FRAME_DEPTH_BITS) - 1))
+            .map_err(|it| it.to_string())?;
+        let global = self.thread(id)?;
+        let thread = global.get(jvm);
+        let frame = thread.frame(depth).map_err(|it| it.to_string())?;
+        let Frame { method, location } = frame.location().map_err(|it| it.to_string())?;
+        let mut variables = Vec::new();
+        if let Some(this) = frame.this_object().map_err(|it| it.to_string())? {
+            variables.push(variable("this", describe_object(jni, &this)));
+        }
+        let table = match method.local_variable_table() {
+            Ok(it) => it,
+            Err(MethodError::AbsentInformation | MethodError::NativeMethod) => Vec::new(),
+            Err(error) => return Err(error.to_string()),
+        };
+        for local in table.iter().filter(|it| it.is_live_at(location)) {
+            let name = local.name.to_utf8_lossy();
+            if name == "this" {
+                continue;
+            }
+            let value = match local.ty().map(|ty| frame.get_local_as(local.slot, ty)) {
+                Some(Ok(value)) => describe(jni, &value),
+                Some(Err(error)) => format!("<{error}>"),
+                None => "<unknown type>".to_owned(),
+            };
+            variables.push(variable(&name, value));
+        }
+        Ok(Json::object([("variables", variables.into())]))
+    }
+
+    /// Resumes the stopped thread given in `arguments`, starting a step of `step` if any.
+    fn resume(&self, jvm: &Jvm, arguments: &Json, step: Option<StepKind>) -> Result<(), String> {
+        let id = argument(arguments, "threadId")?;
+        let global = self.thread(id)?;
+        if !lock(&self.stopped).remove(&id) {
+            return Err("The thread is not stopped".to_owned());
+        }
+        if let Some(kind) = step {
+            lock(&self.steps).insert(id, kind);
+        }
+        let thread = global.get(jvm);
+        resume_stopped(&thread).map_err(|it| it.to_string())
+    }
+
+    fn pause(&self, jvm: &Jvm, arguments: &Json) -> Response {
+        let id = argument(arguments, "threadId")?;
+        let global = self.thread(id)?;
+        lock(&self.pauses).insert(id);
+        jvm.set_thread_event_mode(EventMode::Enable, JvmTIEvent::SingleStep, &global.get(jvm))
+            .map_err(|it| it.to_string())?;
+        Ok(Json::Null)
+    }
+
+    fn on_breakpoint(
+        self: &Arc<Self>,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        method: &Method<'_>,
+        location: sys::jlocation,
+    ) {
+        if STEPPED_TO.take() == Some((method.as_raw().addr(), location)) {
+            return;
+        }
+        // Hitting a breakpoint ends the step in progress, as in other debuggers.
+        STEP.with_borrow_mut(Option::take);
+        self.stop(jvm, jni, thread, "breakpoint");
+    }
+
+    fn on_single_step(
+        self: &Arc<Self>,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        method: &Method<'_>,
+        location: sys::jlocation,
+    ) {
+        let done = STEP.with_borrow(|step| {
+            step.as_ref().map(|session| {
+                // A step that cannot go on stops where it is.
+                session
+                    .on_single_step(jni, thread, method, location)
+                    .unwrap_or(true)
+            })
+        });
+        match done {
+            Some(true) => {
+                STEP.with_borrow_mut(Option::take);
+                STEPPED_TO.set(Some((method.as_raw().addr(), location)));
+                self.stop(jvm, jni, thread, "step");
+            }
+            Some(false) => {}
+            None => {
+                // Without a step in progress, the thread only single-steps to be paused.
+                let _ =
+                    jvm.set_thread_event_mode(EventMode::Disable, JvmTIEvent::SingleStep, thread);
+                let paused = self
+                    .register_thread(jvm, jni, thread)
+                    .is_some_and(|id| lock(&self.pauses).remove(&id));
+                if paused {
+                    self.stop(jvm, jni, thread, "pause");
+                }
+            }
+        }
+    }
+
+    /// Reports that the current thread `thread` stopped for `reason` and suspends it until the
+    /// client resumes it, then starts the step the client requested if any.
+    ///
+    /// The thread is suspended once the event callbacks have returned, so that a stopped thread
+    /// does not hold up the events of the other threads.
+    fn stop(self: &Arc<Self>, jvm: &Jvm, jni: &JNI, thread: &Thread<'_>, reason: &str) {
+        if lock(&self.client).is_none() {
+            return;
+        }
+        let Some(id) = self.register_thread(jvm, jni, thread) else {
+            return;
+        };
+        lock(&self.pauses).remove(&id);
+        lock(&self.stopped).insert(id);
+        self.send_event(
+            "stopped",
+            Json::object([
+                ("reason", reason.into()),
+                ("threadId", id.into()),
+                ("allThreadsStopped", false.into()),
+            ]),
+        );
+        let server = Arc::clone(self);
+        // The step session outlives the callback, so it needs a `Jvm` that lives as long.
+        let jvm = jvm.to_static();
+        let jni = jni.jni_ptr();
+        let thread = thread.as_raw();
+        events::after_dispatch(Box::new(move || {
+            // SAFETY: The action runs before the event callback returns, so the JNI environment
+            // and the thread reference given to the callback are still valid.
+            let (jni, thread) = unsafe { (JNI::from_ptr(jni), Thread::from_ptr(jvm, thread)) };
+            server.park(jvm, &jni, &thread, id);
+        }));
+    }
+
+    /// Suspends the current thread `thread` stopped as `id` until the client resumes it, then
+    /// starts the step the client requested if any.
+    fn park(&self, jvm: &'static Jvm, jni: &JNI, thread: &Thread<'_>, id: i64) {
+        if thread.suspend().is_err() {
+            lock(&self.stopped).remove(&id);
+            return;
+        }
+        let Some(kind) = lock(&self.steps).remove(&id) else {
+            return;
+        };
+        match StepSession::start(jvm, jni, thread, kind) {
+            Ok(session) => STEP.set(Some(session)),
+            Err(error) => self.output(&format!("Cannot step: {error}")),
+        }
+    }
+
+    /// Gets the ID of `thread`, assigning one if the thread is new to the client.
+    fn register_thread(&self, jvm: &Jvm, jni: &JNI, thread: &Thread<'_>) -> Option<i64> {
+        let mut threads = lock(&self.threads);
+        if let Some(index) = threads
+            .iter()
+            .position(|it| it.get(jvm).is_same(jni, thread))
+        {
+            return i64::try_from(index + 1).ok();
+        }
+        // SAFETY: `thread.jthread` is a valid, non-null reference.
+        threads.push(Arc::new(unsafe {
+            GlobalRef::from_raw(jni, thread.jthread)
+        }?));
+        i64::try_from(threads.len()).ok()
+    }
+
+    fn thread(&self, id: i64) -> Result<Arc<GlobalRef<Thread<'static>>>, String> {
+        usize::try_from(id - 1)
+            .ok()
+            .and_then(|index| lock(&self.threads).get(index).cloned())
+            .ok_or_else(|| format!("Unknown thread {id}"))
+    }
+
+    /// Gets the binary name of the class declared by the source file at `path`.
+    fn class_for_source(&self, path: &Path) -> Option<String> {
+        self.config.source_roots.iter().find_map(|root| {
+            let relative = path.strip_prefix(root).ok()?.with_extension("");
+            let segments = relative
+                .iter()
+                .map(|it| it.to_str())
+                .collect::<Option<Vec<_>>>()?;
+            Some(segments.join("."))
+        })
+    }
+
+    /// Finds the source file declaring the class with the binary name `class_name`.
+    fn source_for_class(&self, class_name: &str) -> Option<PathBuf> {
+        let top_level = class_name.split('$').next()?;
+        let relative = PathBuf::from(format!("{}.java", top_level.replace('.', "/")));
+        self.config
+            .source_roots
+            .iter()
+            .map(|root| root.join(&relative))
+            .find(|it| it.is_file())
+    }
+
+    /// Shows `text` in the debug console of the client.
+    fn output(&self, text: &str) {
+        self.send_event(
+            "output",
+            Json::object([
+                ("category", "console".into()),
+                ("output", format!("{text}\n").into()),
+            ]),
+        );
+    }
+
+    fn send_event(&self, event: &str, body: Json) {
+        let mut members = vec![("event", event.into())];
+        if body != Json::Null {
+            members.push(("body", body));
+        }
+        self.send("event", members);
+    }
+
+    /// Sends a message of type `kind` to the client, if any.
+    fn send<'a>(&self, kind: &str, members: impl IntoIterator<Item = (&'a str, Json)>) {
+        let mut client = lock(&self.client);
+        let Some(stream) = client.as_mut() else {
+            return;
+        };
+        // The sequence number is taken under the lock so that messages are sent in order.
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        let message = Json::object(
+            [("seq", seq.into()), ("type", kind.into())]
+                .into_iter()
+                .chain(members),
+        )
+        .to_string();
+        let written = write!(stream, "Content-Length: {}\r\n\r\n{message}", message.len())
+            .and_then(|()| stream.flush());
+        if written.is_err() {
+            *client = None;
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reads the content of the next message, or returns `None` once the client has disconnected.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut content = vec![0; length.unwrap_or_default()];
+    reader.read_exact(&mut content)?;
+    String::from_utf8(content)
+        .map(Some)
+        .map_err(|it| io::Error::new(io::ErrorKind::InvalidData, it))
+}
+
+/// Resumes `thread`, which may not have suspended itself yet after reporting that it stopped.
+fn resume_stopped(thread: &Thread<'_>) -> Result<(), ThreadError> {
+    for _ in 0..RESUME_ATTEMPTS {
+        match thread.resume() {
+            Err(ThreadError::ThreadNotSuspended) => std::thread::sleep(Duration::from_millis(1)),
+            result => return result,
+        }
+    }
+    Err(ThreadError::ThreadNotSuspended)
+}
+
+fn argument(arguments: &Json, name: &str) -> Result<i64, String> {
+    arguments
+        .get(name)
+        .and_then(Json::as_i64)
+        .ok_or_else(|| format!("Missing argument {name}"))
+}
+
+/// Identifies the frame at `depth` of the thread `id`, which also serves as the reference to the
+/// local variables of the frame.
+fn frame_id(id: i64, depth: usize) -> i64 {
+    (id << FRAME_DEPTH_BITS) | i64::try_from(depth).unwrap_or_default()
+}
+
+fn scopes(arguments: &Json) -> Response {
+    let frame_id = argument(arguments, "frameId")?;
+    let locals = Json::object([
+        ("name", "Locals".into()),
+        ("variablesReference", frame_id.into()),
+        ("expensive", false.into()),
+    ]);
+    Ok(Json::object([("scopes", vec![locals].into())]))
+}
+
+fn unverified(line: i64, message: &str) -> Json {
+    Json::object([
+        ("verified", false.into()),
+        ("line", line.into()),
+        ("message", message.into()),
+    ])
+}
+
+fn variable(name: &str, value: String) -> Json {
+    Json::object([
+        ("name", name.into()),
+        ("value", value.into()),
+        ("variablesReference", 0.into()),
+    ])
+}
+
+/// Formats `value` as a debugger shows it.
+fn describe(jni: &JNI, value: &JValue<'_>) -> String {
+    match value {
+        JValue::Boolean(value) => value.to_string(),
+        JValue::Byte(value) => value.to_string(),
+        JValue::Char(value) => char::from_u32(u32::from(*value))
+            .map_or_else(|| format!("'\\u{value:04x}'"), |it| format!("'{it}'")),
+        JValue::Short(value) => value.to_string(),
+        JValue::Int(value) => value.to_string(),
+        JValue::Long(value) => value.to_string(),
+        JValue::Float(value) => value.to_string(),
+        JValue::Double(value) => value.to_string(),
+        JValue::Object(None) => "null".to_owned(),
+        JValue::Object(Some(object)) => describe_object(jni, object),
+    }
+}
+
+/// Formats `object` as its class name and identity hash code, e.g. `com.example.Foo@1b6d3586`.
+fn describe_object(jni: &JNI, object: &Object<'_>) -> String {
+    let class_name = object
+        .class(jni)
+        .signature()
+        .map_or_else(|_| "?".to_owned(), |it| binary_name(&it));
+    match object.identity_hash() {
+        Ok(hash) => format!("{class_name}@{hash:x}"),
+        Err(_) => class_name,
+    }
+}
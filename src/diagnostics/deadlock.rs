@@ -0,0 +1,188 @@
+//! Detection of threads deadlocked on Java monitors.
+//!
+//! [`Jvm::detect_deadlocks`] builds the waits-for graph of the live platform threads, in which a
+//! thread blocked on entering a monitor points to the thread owning that monitor, and reports the
+//! cycles in it. Only monitors, i.e. `synchronized` blocks and methods, are considered; threads
+//! waiting on `java.util.concurrent` locks are parked and do not show up in the graph.
+//!
+//! Detection requires the `can_get_owned_monitor_info` and `can_get_current_contended_monitor`
+//! capabilities.
+
+use crate::jvm::{
+    errors::{JvmTIError, ThreadError},
+    jni::JNI,
+    objects::Object,
+    stack::Frame,
+    threads::Thread,
+    Jvm,
+};
+
+use super::{correlation::CorrelationId, STACK_SCAN_DEPTH};
+
+/// A thread that is part of a deadlock.
+#[derive(Debug)]
+pub struct DeadlockedThread<'j> {
+    /// The thread.
+    pub thread: Thread<'j>,
+    /// The correlation ID attached to the thread.
+    pub correlation_id: Option<CorrelationId>,
+    /// The monitor the thread is blocked on, which is owned by the next thread in the cycle.
+    pub waiting_for: Object<'j>,
+    /// The frames on the stack of the thread, innermost first.
+    pub frames: Vec<Frame<'j>>,
+}
+
+/// A cycle of threads each blocked on a monitor owned by the next one.
+#[derive(Debug)]
+pub struct Deadlock<'j> {
+    /// The threads in the cycle, each waiting for a monitor owned by the next one and the last
+    /// one waiting for a monitor owned by the first one.
+    pub threads: Vec<DeadlockedThread<'j>>,
+}
+
+/// A live thread with its monitors.
+struct Node<'j> {
+    thread: Thread<'j>,
+    owned: Vec<Object<'j>>,
+    contended: Option<Object<'j>>,
+}
+
+impl Jvm {
+    /// Finds the cycles of threads blocked on monitors owned by each other.
+    ///
+    /// The threads are not suspended while the graph is built. This is harmless for deadlocked
+    /// threads, which cannot make progress, but a thread that briefly contends for a monitor may
+    /// rarely be reported as part of a cycle that has already resolved.
+    /// # Errors
+    /// Returns an error if the threads cannot be listed or their monitors cannot be inspected,
+    /// e.g. because a required capability is missing.
+    pub fn detect_deadlocks(&self, jni: &JNI) -> Result<Vec<Deadlock<'_>>, JvmTIError> {
+        let mut nodes = Vec::new();
+        for thread in self.get_all_threads()? {
+            match monitors_of(&thread) {
+                Ok((owned, contended)) => nodes.push(Node {
+                    thread,
+                    owned,
+                    contended,
+                }),
+                // The thread ended after it was listed, so it cannot be deadlocked.
+                Err(ThreadError::ThreadNotAlive) => {}
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        // Each blocked thread waits for exactly one monitor, so it has at most one successor.
+        let successors: Vec<Option<usize>> = nodes
+            .iter()
+            .map(|node| {
+                let contended = node.contended.as_ref()?;
+                nodes.iter().position(|owner| {
+                    owner
+                        .owned
+                        .iter()
+                        .any(|monitor| monitor.is_same(jni, contended))
+                })
+            })
+            .collect();
+
+        let mut nodes: Vec<_> = nodes.into_iter().map(Some).collect();
+        Ok(find_cycles(&successors)
+            .into_iter()
+            .map(|cycle| Deadlock {
+                threads: cycle
+                    .into_iter()
+                    .filter_map(|index| nodes[index].take())
+                    .filter_map(|node| {
+                        Some(DeadlockedThread {
+                            frames: node
+                                .thread
+                                .stack_trace(STACK_SCAN_DEPTH)
+                                .unwrap_or_default(),
+                            waiting_for: node.contended?,
+                            correlation_id: CorrelationId::stamp(&node.thread),
+                            thread: node.thread,
+                        })
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+}
+
+/// Gets the monitors owned by `thread` and, if it is blocked on entering one, the contended one.
+fn monitors_of<'j>(
+    thread: &Thread<'j>,
+) -> Result<(Vec<Object<'j>>, Option<Object<'j>>), ThreadError> {
+    let state = thread.state()?;
+    if !state.is_alive() {
+        return Err(ThreadError::ThreadNotAlive);
+    }
+    let owned = thread.owned_monitors()?;
+    // A thread in `Object.wait()` also reports the monitor, but it does not hold up its owner.
+    let contended = if state.is_blocked_on_monitor() {
+        thread.contended_monitor()?
+    } else {
+        None
+    };
+    Ok((owned, contended))
+}
+
+/// Finds the cycles of the graph in which each node points to at most one successor.
+fn find_cycles(successors: &[Option<usize>]) -> Vec<Vec<usize>> {
+    let mut cycles = Vec::new();
+    // The index of the walk that first visited each node.
+    let mut visited_by: Vec<Option<usize>> = vec![None; successors.len()];
+    for start in 0..successors.len() {
+        let mut current = Some(start);
+        while let Some(index) = current {
+            match visited_by[index] {
+                Some(walk) if walk == start => {
+                    cycles.push(cycle_from(index, successors));
+                    break;
+                }
+                Some(_) => break,
+                None => {
+                    visited_by[index] = Some(start);
+                    current = successors[index];
+                }
+            }
+        }
+    }
+    cycles
+}
+
+/// Collects the nodes of the cycle containing `start`.
+fn cycle_from(start: usize, successors: &[Option<usize>]) -> Vec<usize> {
+    let mut cycle = vec![start];
+    let mut current = successors[start];
+    while let Some(index) = current.filter(|&it| it != start) {
+        cycle.push(index);
+        current = successors[index];
+    }
+    cycle
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_cycles_once() {
+        // 0 -> 1 -> 2 -> 0, 3 -> 1, 4 -> 5 -> 4, 6
+        let successors = [Some(1), Some(2), Some(0), Some(1), Some(5), Some(4), None];
+        assert_eq!(find_cycles(&successors), vec![vec![0, 1, 2], vec![4, 5]]);
+    }
+
+    #[test]
+    fn finds_no_cycle_in_chains() {
+        assert!(find_cycles(&[Some(1), Some(2), None]).is_empty());
+        assert!(find_cycles(&[]).is_empty());
+    }
+
+    #[test]
+    fn walks_cycle_from_any_node() {
+        let successors = [Some(1), Some(2), Some(0)];
+        assert_eq!(cycle_from(1, &successors), vec![1, 2, 0]);
+        assert_eq!(cycle_from(0, &[Some(0)]), vec![0]);
+    }
+}
@@ -0,0 +1,585 @@
+//! A flight recorder that keeps the most recent events of every thread in memory.
+//!
+//! A [`FlightRecorder`] continuously records events such as method samples, thrown exceptions,
+//! and monitor waits into a bounded ring buffer per thread, so recording only takes an
+//! uncontended lock and never allocates once a buffer is full. When an incident happens, the
+//! events of the last seconds can be dumped to answer what happened right before it.
+//!
+//! Dumps can be taken directly with [`FlightRecorder::dump`], or requested through a
+//! [`DumpTrigger`] of a background [`Dumper`]. Firing a trigger only sets an atomic flag, so it
+//! may be done from a signal handler installed by the agent or from the `ResourceExhausted`
+//! event. A dumper can also accept dump requests on a control socket, see [`Dumper::listen`].
+//!
+//! The buffer of a thread is kept until [`FlightRecorder::on_thread_end`] is called on it, which
+//! should be done from the `ThreadEnd` event so that the buffers of ended threads do not
+//! accumulate.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write as _},
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+use crate::jvm::{
+    class::Class, errors::JvmTIError, objects::Object, strings::ModifiedUtf8Ext,
+    thread_filter::ThreadFilter, threads::Thread,
+};
+
+use super::{
+    binary_name, call_sites,
+    correlation::CorrelationId,
+    escape_json,
+    sink::{FileSink, ReportSink},
+    CallSite,
+};
+
+/// How often a [`Dumper`] checks for dump requests.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// An event recorded by a [`FlightRecorder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlightEvent {
+    /// A sample of the call stack of the thread.
+    MethodSample {
+        /// The top frames of the stack, innermost first.
+        sites: Vec<CallSite>,
+    },
+    /// An exception has been thrown.
+    Exception {
+        /// The binary name of the class of the exception.
+        class_name: String,
+        /// The top frames of the stack at the throw site, innermost first.
+        sites: Vec<CallSite>,
+    },
+    /// The thread is about to wait on a monitor.
+    MonitorWait {
+        /// The identity hash code of the monitor, if it could be determined.
+        monitor: Option<i32>,
+        /// The timeout of the wait in milliseconds, `0` for no timeout.
+        timeout_ms: i64,
+    },
+    /// An event defined by the agent.
+    Custom(String),
+}
+
+/// A [`FlightEvent`] together with when and where it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlightRecord {
+    /// When the event was recorded.
+    pub time: SystemTime,
+    /// The name of the thread the event was recorded on, if it could be determined.
+    pub thread_name: Option<String>,
+    /// The correlation ID attached to the thread when the event was recorded.
+    pub correlation_id: Option<CorrelationId>,
+    /// The event.
+    pub event: FlightEvent,
+}
+
+impl FlightRecord {
+    /// Serializes the record as a JSON object.
+    fn to_json(&self) -> String {
+        let time = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        let mut json = format!(
+            "{{\"time_us\":{time},\"thread\":{},\"correlation_id\":{},",
+            self.thread_name
+                .as_deref()
+                .map_or_else(|| "null".to_owned(), escape_json),
+            CorrelationId::to_json(self.correlation_id)
+        );
+        let sites = match &self.event {
+            FlightEvent::MethodSample { sites } => {
+                json.push_str("\"kind\":\"method_sample\"");
+                sites.as_slice()
+            }
+            FlightEvent::Exception { class_name, sites } => {
+                let _ = write!(
+                    json,
+                    "\"kind\":\"exception\",\"class\":{}",
+                    escape_json(class_name)
+                );
+                sites.as_slice()
+            }
+            FlightEvent::MonitorWait {
+                monitor,
+                timeout_ms,
+            } => {
+                let _ = write!(
+                    json,
+                    "\"kind\":\"monitor_wait\",\"monitor\":{},\"timeout_ms\":{timeout_ms}",
+                    monitor.map_or_else(|| "null".to_owned(), |it| it.to_string())
+                );
+                &[]
+            }
+            FlightEvent::Custom(message) => {
+                let _ = write!(
+                    json,
+                    "\"kind\":\"custom\",\"message\":{}",
+                    escape_json(message)
+                );
+                &[]
+            }
+        };
+        json.push_str(",\"sites\":[");
+        for (index, site) in sites.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"class\":{},\"method\":{}}}",
+                escape_json(&site.class_name),
+                escape_json(&site.method_name)
+            );
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+#[derive(Debug)]
+struct Ring {
+    thread_name: Option<String>,
+    events: VecDeque<(SystemTime, Option<CorrelationId>, FlightEvent)>,
+}
+
+thread_local! {
+    /// The ring buffers of the current thread keyed by the ID of their recorder, or `None` if the
+    /// recorder excludes the thread.
+    static RINGS: RefCell<HashMap<usize, Option<Arc<Mutex<Ring>>>>> = RefCell::default();
+}
+
+static NEXT_RECORDER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Records the recent events of every thread into bounded ring buffers.
+#[derive(Debug)]
+pub struct FlightRecorder {
+    id: usize,
+    capacity: usize,
+    max_sites: usize,
+    filter: ThreadFilter,
+    rings: Mutex<Vec<Arc<Mutex<Ring>>>>,
+}
+
+impl FlightRecorder {
+    /// Creates a recorder that keeps the last `capacity` events of each thread and captures up to
+    /// `max_sites` frames per stack.
+    #[must_use]
+    pub fn new(capacity: usize, max_sites: usize) -> Self {
+        Self {
+            id: NEXT_RECORDER_ID.fetch_add(1, Ordering::Relaxed),
+            capacity: capacity.max(1),
+            max_sites,
+            filter: ThreadFilter::default(),
+            rings: Mutex::default(),
+        }
+    }
+
+    /// Makes the recorder ignore the events of the threads excluded by `filter`.
+    /// A thread is checked against the filter when it records its first event.
+    #[must_use]
+    pub fn with_thread_filter(mut self, filter: ThreadFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Gets the ring buffer of the current thread, which is `thread`, or `None` if the thread is
+    /// excluded.
+    fn ring(&self, thread: &Thread<'_>) -> Option<Arc<Mutex<Ring>>> {
+        RINGS.with(|rings| {
+            let mut rings = rings.borrow_mut();
+            if !rings.contains_key(&self.id) {
+                // The buffers only referenced from here belong to recorders that have been
+                // dropped.
+                rings.retain(|_, ring| ring.as_ref().is_none_or(|it| Arc::strong_count(it) > 1));
+            }
+            rings
+                .entry(self.id)
+                .or_insert_with(|| {
+                    if self.filter.excludes(thread) {
+                        return None;
+                    }
+                    let ring = Arc::new(Mutex::new(Ring {
+                        thread_name: thread
+                            .info()
+                            .ok()
+                            .map(|info| info.name.to_utf8_lossy().into_owned()),
+                        events: VecDeque::with_capacity(self.capacity),
+                    }));
+                    self.rings
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push(Arc::clone(&ring));
+                    Some(ring)
+                })
+                .clone()
+        })
+    }
+
+    /// Forgets the buffer of the current thread and its events, e.g. from the `ThreadEnd` event.
+    pub fn on_thread_end(&self) {
+        let ring = RINGS
+            .try_with(|rings| rings.borrow_mut().remove(&self.id))
+            .ok()
+            .flatten()
+            .flatten();
+        if let Some(ring) = ring {
+            self.rings
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|it| !Arc::ptr_eq(it, &ring));
+        }
+    }
+
+    /// Records `event` on the current thread, which is `thread`, evicting the oldest event of the
+    /// thread if its buffer is full.
+    pub fn record(&self, thread: &Thread<'_>, event: FlightEvent) {
+        let time = SystemTime::now();
+        let Some(ring) = self.ring(thread) else {
+            return;
+        };
+        let correlation_id = CorrelationId::stamp(thread);
+        let mut ring = ring.lock().unwrap_or_else(PoisonError::into_inner);
+        if ring.events.len() == self.capacity {
+            ring.events.pop_front();
+        }
+        ring.events.push_back((time, correlation_id, event));
+    }
+
+    /// Records a sample of the call stack of the current thread, which is `thread`.
+    /// # Errors
+    /// Returns an error if the stack cannot be inspected.
+    pub fn sample_method(&self, thread: &Thread<'_>) -> Result<(), JvmTIError> {
+        if self.ring(thread).is_none() {
+            return Ok(());
+        }
+        let sites = call_sites(thread, self.max_sites, &[])?;
+        self.record(thread, FlightEvent::MethodSample { sites });
+        Ok(())
+    }
+
+    /// Records that an exception of `exception_class` has been thrown on `thread`, e.g. from the
+    /// `Exception` event.
+    /// # Errors
+    /// Returns an error if the class signature cannot be retrieved.
+    pub fn record_exception(
+        &self,
+        thread: &Thread<'_>,
+        exception_class: &Class<'_>,
+    ) -> Result<(), JvmTIError> {
+        if self.ring(thread).is_none() {
+            return Ok(());
+        }
+        let class_name = binary_name(&exception_class.signature()?);
+        let sites = call_sites(thread, self.max_sites, &[]).unwrap_or_default();
+        self.record(thread, FlightEvent::Exception { class_name, sites });
+        Ok(())
+    }
+
+    /// Records that `thread` is about to wait on `monitor` for up to `timeout_ms` milliseconds,
+    /// e.g. from the `MonitorWait` event.
+    pub fn record_monitor_wait(&self, thread: &Thread<'_>, monitor: &Object<'_>, timeout_ms: i64) {
+        let monitor = monitor.identity_hash().ok();
+        self.record(
+            thread,
+            FlightEvent::MonitorWait {
+                monitor,
+                timeout_ms,
+            },
+        );
+    }
+
+    /// Returns the events of all threads recorded within the last `window`, oldest first.
+    pub fn events(&self, window: Duration) -> Vec<FlightRecord> {
+        let since = SystemTime::now()
+            .checked_sub(window)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let rings = self.rings.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut records = Vec::new();
+        for ring in rings.iter() {
+            let ring = ring.lock().unwrap_or_else(PoisonError::into_inner);
+            records.extend(
+                ring.events
+                    .iter()
+                    .filter(|(time, _, _)| *time >= since)
+                    .map(|(time, correlation_id, event)| FlightRecord {
+                        time: *time,
+                        thread_name: ring.thread_name.clone(),
+                        correlation_id: *correlation_id,
+                        event: event.clone(),
+                    }),
+            );
+        }
+        records.sort_by_key(|it| it.time);
+        records
+    }
+
+    /// Writes the events recorded within the last `window` to `sink` as JSON records, oldest
+    /// first, and flushes the sink.
+    /// # Errors
+    /// Returns an error if the sink fails.
+    pub fn dump<S: ReportSink + ?Sized>(&self, window: Duration, sink: &mut S) -> io::Result<()> {
+        for record in self.events(window) {
+            sink.write_record(record.to_json().as_bytes())?;
+        }
+        sink.flush()
+    }
+
+    /// Dumps the events recorded within the last `window` to a new file in `directory` and
+    /// returns its path.
+    /// # Errors
+    /// Returns an error if the file cannot be written.
+    pub fn dump_to_directory(&self, directory: &Path, window: Duration) -> io::Result<PathBuf> {
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = directory.join(format!("flight-{millis}.jsonl"));
+        self.dump(window, &mut FileSink::open(&path)?)?;
+        Ok(path)
+    }
+}
+
+/// Requests a dump from a [`Dumper`].
+#[derive(Debug, Clone)]
+pub struct DumpTrigger {
+    requested: Arc<AtomicBool>,
+}
+
+impl DumpTrigger {
+    /// Requests a dump. This only sets an atomic flag and is async-signal-safe.
+    pub fn fire(&self) {
+        self.requested.store(true, Ordering::Release);
+    }
+}
+
+/// A background thread that dumps a [`FlightRecorder`] to disk whenever its [`DumpTrigger`] fires.
+/// The thread stops when the dumper is dropped.
+#[derive(Debug)]
+pub struct Dumper {
+    trigger: DumpTrigger,
+    stopped: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Dumper {
+    /// Starts a thread that dumps the events of the last `window` of `recorder` into a new file in
+    /// `directory` whenever a dump is requested.
+    /// # Errors
+    /// Returns an error if the thread cannot be spawned.
+    pub fn spawn(
+        recorder: Arc<FlightRecorder>,
+        directory: impl Into<PathBuf>,
+        window: Duration,
+    ) -> io::Result<Self> {
+        let directory = directory.into();
+        let trigger = DumpTrigger {
+            requested: Arc::default(),
+        };
+        let stopped = Arc::<AtomicBool>::default();
+        let requested = Arc::clone(&trigger.requested);
+        let stop = Arc::clone(&stopped);
+        let thread = std::thread::Builder::new()
+            .name("coffee-filter-flight-dumper".to_owned())
+            .spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    if requested.swap(false, Ordering::AcqRel) {
+                        // There is nobody to report a failed dump to.
+                        let _ = recorder.dump_to_directory(&directory, window);
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+            })?;
+        Ok(Self {
+            trigger,
+            stopped,
+            threads: vec![thread],
+        })
+    }
+
+    /// Returns a trigger requesting a dump from this dumper.
+    #[must_use]
+    pub fn trigger(&self) -> DumpTrigger {
+        self.trigger.clone()
+    }
+
+    /// Accepts dump requests on a TCP control socket bound to `address` and returns the bound
+    /// address. Every line reading `dump` sent over a connection requests a dump.
+    /// # Errors
+    /// Returns an error if the socket cannot be bound or the thread cannot be spawned.
+    pub fn listen(&mut self, address: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let local_address = listener.local_addr()?;
+        let trigger = self.trigger();
+        let stop = Arc::clone(&self.stopped);
+        let thread = std::thread::Builder::new()
+            .name("coffee-filter-flight-control".to_owned())
+            .spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            // Connections are served one at a time; a dump request is a single line.
+                            let _ = stream.set_nonblocking(false);
+                            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                            let Ok(mut reply) = stream.try_clone() else {
+                                continue;
+                            };
+                            for line in BufReader::new(stream).lines() {
+                                let Ok(line) = line else { break };
+                                if line.trim() == "dump" {
+                                    trigger.fire();
+                                    let _ = reply.write_all(b"ok\n");
+                                } else {
+                                    let _ = reply.write_all(b"unknown command\n");
+                                }
+                            }
+                        }
+                        Err(_) => std::thread::sleep(POLL_INTERVAL),
+                    }
+                }
+            })?;
+        self.threads.push(thread);
+        Ok(local_address)
+    }
+}
+
+impl Drop for Dumper {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Release);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::sink::CallbackSink;
+
+    fn ring(recorder: &FlightRecorder, thread_name: &str, events: Vec<(SystemTime, FlightEvent)>) {
+        let events = events
+            .into_iter()
+            .map(|(time, event)| (time, None, event))
+            .collect();
+        recorder
+            .rings
+            .lock()
+            .unwrap()
+            .push(Arc::new(Mutex::new(Ring {
+                thread_name: Some(thread_name.to_owned()),
+                events,
+            })));
+    }
+
+    #[test]
+    fn serializes_each_kind_of_event() {
+        let record = |event| FlightRecord {
+            time: SystemTime::UNIX_EPOCH + Duration::from_micros(1_500),
+            thread_name: Some("main".to_owned()),
+            correlation_id: CorrelationId::new(0x2a),
+            event,
+        };
+        let site = CallSite {
+            class_name: "com.example.Main".to_owned(),
+            method_name: "run".to_owned(),
+        };
+        assert_eq!(
+            record(FlightEvent::MethodSample {
+                sites: vec![site.clone()]
+            })
+            .to_json(),
+            concat!(
+                r#"{"time_us":1500,"thread":"main","correlation_id":"000000000000002a","#,
+                r#""kind":"method_sample","sites":[{"class":"com.example.Main","method":"run"}]}"#
+            )
+        );
+        assert_eq!(
+            record(FlightEvent::Exception {
+                class_name: "java.lang.IllegalStateException".to_owned(),
+                sites: vec![site],
+            })
+            .to_json(),
+            concat!(
+                r#"{"time_us":1500,"thread":"main","correlation_id":"000000000000002a","#,
+                r#""kind":"exception","class":"java.lang.IllegalStateException","#,
+                r#""sites":[{"class":"com.example.Main","method":"run"}]}"#
+            )
+        );
+        assert_eq!(
+            record(FlightEvent::MonitorWait {
+                monitor: None,
+                timeout_ms: 100,
+            })
+            .to_json(),
+            concat!(
+                r#"{"time_us":1500,"thread":"main","correlation_id":"000000000000002a","#,
+                r#""kind":"monitor_wait","monitor":null,"timeout_ms":100,"sites":[]}"#
+            )
+        );
+        assert_eq!(
+            record(FlightEvent::Custom("a \"b\"".to_owned())).to_json(),
+            concat!(
+                r#"{"time_us":1500,"thread":"main","correlation_id":"000000000000002a","#,
+                r#""kind":"custom","message":"a \"b\"","sites":[]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn merges_the_recent_events_of_all_threads() {
+        let recorder = FlightRecorder::new(8, 4);
+        let now = SystemTime::now();
+        let custom = |message: &str| FlightEvent::Custom(message.to_owned());
+        ring(
+            &recorder,
+            "main",
+            vec![
+                (now - Duration::from_secs(30), custom("stale")),
+                (now - Duration::from_secs(3), custom("first")),
+                (now - Duration::from_secs(1), custom("third")),
+            ],
+        );
+        ring(
+            &recorder,
+            "worker",
+            vec![(now - Duration::from_secs(2), custom("second"))],
+        );
+
+        let events: Vec<_> = recorder
+            .events(Duration::from_secs(10))
+            .into_iter()
+            .map(|it| (it.thread_name.unwrap(), it.event))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("main".to_owned(), custom("first")),
+                ("worker".to_owned(), custom("second")),
+                ("main".to_owned(), custom("third")),
+            ]
+        );
+
+        let mut dumped = Vec::new();
+        recorder
+            .dump(
+                Duration::from_secs(10),
+                &mut CallbackSink::new(|it: &[u8]| dumped.push(it.to_vec())),
+            )
+            .expect("dump the events");
+        assert_eq!(dumped.len(), 3);
+    }
+}
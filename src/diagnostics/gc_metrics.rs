@@ -0,0 +1,305 @@
+//! Garbage collection pause and frequency metrics.
+//!
+//! A [`GcMetrics`] collector is driven by the `GarbageCollectionStart` and
+//! `GarbageCollectionFinish` events, which run while the VM is in a restricted state where almost
+//! no JVM TI functions may be used and other threads may be stopped while holding locks. The
+//! collector therefore only reads the clock and updates atomic counters in the callbacks, and
+//! [`GcMetrics::snapshot`] can be taken from any thread at any time.
+
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "gc-events")]
+use std::sync::Arc;
+
+#[cfg(feature = "gc-events")]
+use crate::jvm::{
+    errors::JvmTIError,
+    events::{Handler, JvmTIEvent},
+    Jvm,
+};
+
+use super::sink::ReportSink;
+
+/// The metrics of the collections observed by a [`GcMetrics`] collector at some point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcMetricsSnapshot {
+    /// The time since the collector was created.
+    pub elapsed: Duration,
+    /// The number of finished collections.
+    pub collections: u64,
+    /// The total duration of the collections.
+    pub total_pause: Duration,
+    /// The duration of the longest collection.
+    pub max_pause: Duration,
+    /// The duration of the last collection, or `None` if none has finished.
+    pub last_pause: Option<Duration>,
+    /// The time between the starts of the last two collections, or `None` if fewer than two
+    /// collections have started.
+    pub last_interval: Option<Duration>,
+    /// The number of intervals between the starts of consecutive collections.
+    pub intervals: u64,
+    /// The total time between the starts of consecutive collections.
+    pub total_interval: Duration,
+}
+
+impl GcMetricsSnapshot {
+    /// Gets the average duration of the collections, or `None` if none has finished.
+    #[must_use]
+    pub fn mean_pause(&self) -> Option<Duration> {
+        mean(self.total_pause, self.collections)
+    }
+
+    /// Gets the average time between the starts of consecutive collections, or `None` if fewer
+    /// than two collections have started.
+    #[must_use]
+    pub fn mean_interval(&self) -> Option<Duration> {
+        mean(self.total_interval, self.intervals)
+    }
+
+    /// Gets the fraction of the elapsed time spent in collections, between `0` and `1`.
+    #[must_use]
+    pub fn pause_ratio(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        (self.total_pause.as_secs_f64() / self.elapsed.as_secs_f64()).min(1.0)
+    }
+
+    /// Serializes the snapshot as a JSON object with the durations in microseconds, e.g.
+    /// `{"elapsed_us":..,"collections":..,"total_pause_us":..,"max_pause_us":..,...}`. Durations
+    /// that are not known yet are `null`.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let micros = |it: Option<Duration>| {
+            it.map_or_else(|| "null".to_owned(), |it| it.as_micros().to_string())
+        };
+        format!(
+            "{{\"elapsed_us\":{},\"collections\":{},\"total_pause_us\":{},\"max_pause_us\":{},\"last_pause_us\":{},\"mean_pause_us\":{},\"last_interval_us\":{},\"mean_interval_us\":{},\"pause_ratio\":{}}}",
+            self.elapsed.as_micros(),
+            self.collections,
+            self.total_pause.as_micros(),
+            self.max_pause.as_micros(),
+            micros(self.last_pause),
+            micros(self.mean_pause()),
+            micros(self.last_interval),
+            micros(self.mean_interval()),
+            self.pause_ratio()
+        )
+    }
+
+    /// Writes the snapshot to `sink` as a single JSON record, see
+    /// [`GcMetricsSnapshot::to_json`], and flushes the sink.
+    /// # Errors
+    /// Returns an error if the sink fails.
+    pub fn write_to<S: ReportSink + ?Sized>(&self, sink: &mut S) -> io::Result<()> {
+        sink.write_record(self.to_json().as_bytes())?;
+        sink.flush()
+    }
+}
+
+/// Collects the pause durations, counts, and intervals of garbage collections.
+///
+/// Call [`GcMetrics::on_gc_start`] and [`GcMetrics::on_gc_finish`] from the corresponding events,
+/// or let [`GcMetrics::subscribe`] do it. The events are sent for every stop-the-world pause,
+/// including young collections, but not for collections that run concurrently with the
+/// application.
+#[derive(Debug)]
+pub struct GcMetrics {
+    origin: Instant,
+    /// The start of the collection in progress in nanoseconds since `origin` plus one, or `0` if
+    /// no collection is in progress.
+    started_at: AtomicU64,
+    /// The start of the last collection in nanoseconds since `origin` plus one, or `0` if no
+    /// collection has started.
+    last_started_at: AtomicU64,
+    collections: AtomicU64,
+    total_pause: AtomicU64,
+    max_pause: AtomicU64,
+    last_pause: AtomicU64,
+    intervals: AtomicU64,
+    total_interval: AtomicU64,
+    last_interval: AtomicU64,
+}
+
+impl Default for GcMetrics {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            started_at: AtomicU64::new(0),
+            last_started_at: AtomicU64::new(0),
+            collections: AtomicU64::new(0),
+            total_pause: AtomicU64::new(0),
+            max_pause: AtomicU64::new(0),
+            last_pause: AtomicU64::new(0),
+            intervals: AtomicU64::new(0),
+            total_interval: AtomicU64::new(0),
+            last_interval: AtomicU64::new(0),
+        }
+    }
+}
+
+impl GcMetrics {
+    /// Creates a collector that has not observed any collection.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a garbage collection has started.
+    /// This only reads the clock and updates atomic counters, and is safe to call from the
+    /// `GarbageCollectionStart` event.
+    pub fn on_gc_start(&self) {
+        let now = self.now() + 1;
+        self.started_at.store(now, Ordering::Relaxed);
+        let previous = self.last_started_at.swap(now, Ordering::Relaxed);
+        if previous != 0 {
+            let interval = now.saturating_sub(previous);
+            self.intervals.fetch_add(1, Ordering::Relaxed);
+            self.total_interval.fetch_add(interval, Ordering::Relaxed);
+            self.last_interval.store(interval, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that a garbage collection has finished. A finish without a recorded start, e.g.
+    /// when the collector was subscribed during a collection, is ignored.
+    /// This only reads the clock and updates atomic counters, and is safe to call from the
+    /// `GarbageCollectionFinish` event.
+    pub fn on_gc_finish(&self) {
+        let started_at = self.started_at.swap(0, Ordering::Relaxed);
+        if started_at == 0 {
+            return;
+        }
+        let pause = (self.now() + 1).saturating_sub(started_at);
+        self.collections.fetch_add(1, Ordering::Relaxed);
+        self.total_pause.fetch_add(pause, Ordering::Relaxed);
+        self.max_pause.fetch_max(pause, Ordering::Relaxed);
+        self.last_pause.store(pause, Ordering::Relaxed);
+    }
+
+    /// Gets the metrics of the collections observed so far. The counters are read one by one, so
+    /// a collection finishing meanwhile may only be partly reflected.
+    #[must_use]
+    pub fn snapshot(&self) -> GcMetricsSnapshot {
+        let collections = self.collections.load(Ordering::Relaxed);
+        let intervals = self.intervals.load(Ordering::Relaxed);
+        GcMetricsSnapshot {
+            elapsed: self.origin.elapsed(),
+            collections,
+            total_pause: Duration::from_nanos(self.total_pause.load(Ordering::Relaxed)),
+            max_pause: Duration::from_nanos(self.max_pause.load(Ordering::Relaxed)),
+            last_pause: (collections > 0)
+                .then(|| Duration::from_nanos(self.last_pause.load(Ordering::Relaxed))),
+            last_interval: (intervals > 0)
+                .then(|| Duration::from_nanos(self.last_interval.load(Ordering::Relaxed))),
+            intervals,
+            total_interval: Duration::from_nanos(self.total_interval.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Gets the time since `origin` in nanoseconds.
+    fn now(&self) -> u64 {
+        u64::try_from(self.origin.elapsed().as_nanos()).unwrap_or(u64::MAX - 1)
+    }
+}
+
+#[cfg(feature = "gc-events")]
+impl GcMetrics {
+    /// Subscribes the collector to the `GarbageCollectionStart` and `GarbageCollectionFinish`
+    /// events and enables them.
+    /// # Errors
+    /// Returns [`JvmTIError::MustPossessCapability`] if the
+    /// `can_generate_garbage_collection_events` capability is missing. See
+    /// [`Jvm::update_callbacks`] for other possible errors.
+    pub fn subscribe(self: &Arc<Self>, jvm: &mut Jvm) -> Result<(), JvmTIError> {
+        let (on_start, on_finish) = (Arc::clone(self), Arc::clone(self));
+        jvm.update_callbacks(move |it| {
+            it.garbage_collection_start
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |_| on_start.on_gc_start()));
+            it.garbage_collection_finish
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |_| on_finish.on_gc_finish()));
+        })?;
+        jvm.enable_event(JvmTIEvent::GarbageCollectionStart, None)?;
+        jvm.enable_event(JvmTIEvent::GarbageCollectionFinish, None)?;
+        Ok(())
+    }
+}
+
+fn mean(total: Duration, count: u64) -> Option<Duration> {
+    let count = u32::try_from(count).ok().filter(|&it| it > 0)?;
+    Some(total / count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_collections_and_intervals() {
+        let metrics = GcMetrics::new();
+        // A finish without a start is ignored.
+        metrics.on_gc_finish();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.collections, 0);
+        assert_eq!(snapshot.last_pause, None);
+        assert_eq!(snapshot.mean_pause(), None);
+
+        metrics.on_gc_start();
+        metrics.on_gc_finish();
+        metrics.on_gc_start();
+        metrics.on_gc_finish();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.collections, 2);
+        assert_eq!(snapshot.intervals, 1);
+        assert!(snapshot.last_interval.is_some());
+        assert!(snapshot.max_pause >= snapshot.last_pause.unwrap());
+        assert!(snapshot.total_pause >= snapshot.max_pause);
+        assert!(snapshot.elapsed >= snapshot.total_pause);
+    }
+
+    #[test]
+    fn summarizes_snapshots() {
+        let snapshot = GcMetricsSnapshot {
+            elapsed: Duration::from_secs(10),
+            collections: 4,
+            total_pause: Duration::from_secs(1),
+            max_pause: Duration::from_millis(400),
+            last_pause: Some(Duration::from_millis(100)),
+            last_interval: Some(Duration::from_secs(2)),
+            intervals: 3,
+            total_interval: Duration::from_secs(9),
+        };
+        assert_eq!(snapshot.mean_pause(), Some(Duration::from_millis(250)));
+        assert_eq!(snapshot.mean_interval(), Some(Duration::from_secs(3)));
+        assert!((snapshot.pause_ratio() - 0.1).abs() < 1e-9);
+        assert_eq!(
+            snapshot.to_json(),
+            concat!(
+                r#"{"elapsed_us":10000000,"collections":4,"total_pause_us":1000000,"#,
+                r#""max_pause_us":400000,"last_pause_us":100000,"mean_pause_us":250000,"#,
+                r#""last_interval_us":2000000,"mean_interval_us":3000000,"pause_ratio":0.1}"#
+            )
+        );
+    }
+
+    #[test]
+    fn pause_ratio_is_zero_before_any_time_elapsed() {
+        let snapshot = GcMetricsSnapshot {
+            elapsed: Duration::ZERO,
+            collections: 0,
+            total_pause: Duration::ZERO,
+            max_pause: Duration::ZERO,
+            last_pause: None,
+            last_interval: None,
+            intervals: 0,
+            total_interval: Duration::ZERO,
+        };
+        assert!(snapshot.pause_ratio().abs() < f64::EPSILON);
+        assert!(snapshot.to_json().contains(r#""last_pause_us":null"#));
+    }
+}
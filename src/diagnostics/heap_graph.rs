@@ -0,0 +1,465 @@
+//! Captures the object reference graph of the heap and exports it as Graphviz DOT or JSON.
+//!
+//! [`Jvm::heap_graph`] follows the references from the heap roots, or from a single object,
+//! tagging the visited objects to identify them. The classes are tagged like for
+//! [`Jvm::heap_histogram`], and the tags set by the traversal are cleared once the graph is
+//! captured. Objects that were already tagged keep their tags, which then serve as their IDs.
+//!
+//! Capturing a graph requires the `can_tag_objects` capability.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use crate::{
+    jvm::{
+        errors::JvmTIError,
+        heap::{HeapFilter, HeapObject, HeapReference, HeapVisitor, Referrer, VisitControl},
+        objects::Object,
+        tags::reserve_tags,
+        Jvm,
+    },
+    sys,
+};
+
+use super::{escape_dot, escape_json, ClassTags};
+
+/// The default maximum number of objects in a [`HeapGraph`].
+pub const DEFAULT_MAX_NODES: usize = 100_000;
+
+/// Selects the objects captured by [`Jvm::heap_graph`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapGraphOptions {
+    packages: Vec<String>,
+    max_nodes: usize,
+}
+
+impl Default for HeapGraphOptions {
+    fn default() -> Self {
+        Self {
+            packages: Vec::new(),
+            max_nodes: DEFAULT_MAX_NODES,
+        }
+    }
+}
+
+impl HeapGraphOptions {
+    /// Creates options that keep all the objects, up to [`DEFAULT_MAX_NODES`].
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keeps the objects whose class binary names start with `prefix`, e.g. `com.example.`.
+    /// Without any package, objects of all classes are kept. The objects of other classes are
+    /// still traversed, so that the references among the kept objects are found, but they are
+    /// left out of the graph together with their references.
+    #[must_use]
+    pub fn package(mut self, prefix: impl Into<String>) -> Self {
+        self.packages.push(prefix.into());
+        self
+    }
+
+    /// Stops the traversal once `max_nodes` objects have been visited.
+    #[must_use]
+    pub fn max_nodes(mut self, max_nodes: usize) -> Self {
+        self.max_nodes = max_nodes;
+        self
+    }
+
+    fn keeps(&self, class_name: &str) -> bool {
+        self.packages.is_empty()
+            || self
+                .packages
+                .iter()
+                .any(|prefix| class_name.starts_with(prefix.as_str()))
+    }
+}
+
+/// An object in a [`HeapGraph`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapNode {
+    /// The ID of the object, which is its tag during the traversal.
+    pub id: sys::jlong,
+    /// The binary name of the class of the object, or `<unknown>` for a class loaded during the
+    /// traversal.
+    pub class_name: String,
+    /// The size of the object in bytes.
+    pub size: u64,
+}
+
+/// A reference in a [`HeapGraph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapEdge {
+    /// The ID of the referring object, or `None` if the reference is a heap root.
+    pub from: Option<sys::jlong>,
+    /// The ID of the referenced object.
+    pub to: sys::jlong,
+    /// The kind of the reference.
+    pub kind: HeapReference,
+}
+
+/// A snapshot of the object reference graph captured by [`Jvm::heap_graph`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapGraph {
+    /// The captured objects.
+    pub nodes: Vec<HeapNode>,
+    /// The references among the captured objects and from the heap roots to them.
+    pub edges: Vec<HeapEdge>,
+    /// Whether the traversal stopped at the maximum number of objects.
+    pub truncated: bool,
+}
+
+impl HeapGraph {
+    /// Exports the graph in the Graphviz DOT format. Heap roots point from a single `roots` node.
+    #[must_use]
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph heap {\n");
+        if self.edges.iter().any(|it| it.from.is_none()) {
+            dot.push_str("    roots [shape=box];\n");
+        }
+        for node in &self.nodes {
+            let _ = writeln!(
+                dot,
+                "    n{} [label=\"{}\\n{} bytes\"];",
+                node.id,
+                escape_dot(&node.class_name),
+                node.size
+            );
+        }
+        for edge in &self.edges {
+            let from = edge
+                .from
+                .map_or_else(|| "roots".to_owned(), |it| format!("n{it}"));
+            let _ = writeln!(
+                dot,
+                "    {from} -> n{} [label=\"{}\"];",
+                edge.to,
+                reference_label(&edge.kind)
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Exports the graph as a JSON object with a `nodes` array, an `edges` array whose roots have
+    /// a `null` source, and a `truncated` flag.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"nodes\":[");
+        for (index, node) in self.nodes.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"id\":{},\"class\":{},\"size\":{}}}",
+                node.id,
+                escape_json(&node.class_name),
+                node.size
+            );
+        }
+        json.push_str("],\"edges\":[");
+        for (index, edge) in self.edges.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"from\":{},\"to\":{},\"kind\":{}}}",
+                edge.from
+                    .map_or_else(|| "null".to_owned(), |it| it.to_string()),
+                edge.to,
+                escape_json(&reference_label(&edge.kind))
+            );
+        }
+        let _ = write!(json, "],\"truncated\":{}}}", self.truncated);
+        json
+    }
+}
+
+impl Jvm {
+    /// Captures the references among the objects reachable from `root`, or from the heap roots
+    /// if `root` is `None`, keeping the objects selected by `options`.
+    /// # Errors
+    /// Returns [`JvmTIError::MustPossessCapability`] if the `can_tag_objects` capability cannot be
+    /// added, or [`JvmTIError::UnAttachedThread`] if the current thread is not attached to the VM.
+    /// See [`JvmTIError`] for other possible errors.
+    pub fn heap_graph(
+        &self,
+        options: &HeapGraphOptions,
+        root: Option<&Object<'_>>,
+    ) -> Result<HeapGraph, JvmTIError> {
+        let classes = ClassTags::new(self)?;
+        let mut capture = Capture {
+            max_nodes: options.max_nodes,
+            nodes: HashMap::new(),
+            tagged: HashSet::new(),
+            edges: Vec::new(),
+            truncated: false,
+        };
+        let mut result = Ok(());
+        if let Some(root) = root {
+            // The root is only reported as a referrer, which carries no size.
+            result = root.tag().and_then(|tag| {
+                let size = root.size()?;
+                let tag = if tag == 0 {
+                    let tag = reserve_tags(1);
+                    root.set_tag(tag)?;
+                    capture.tagged.insert(tag);
+                    tag
+                } else {
+                    tag
+                };
+                capture.nodes.insert(tag, (0, size));
+                Ok(())
+            });
+        }
+        if result.is_ok() {
+            result = self.follow_references(HeapFilter::empty(), None, root, &mut capture);
+        }
+        if !capture.tagged.is_empty() {
+            let cleared = self.iterate_heap(HeapFilter::UNTAGGED, None, |_, _, tag, _| {
+                if capture.tagged.contains(tag) {
+                    *tag = 0;
+                }
+                VisitControl::empty()
+            });
+            result = result.and(cleared);
+        }
+        result?;
+
+        let used: HashSet<_> = capture
+            .nodes
+            .values()
+            .filter_map(|&(class_tag, _)| classes.index(class_tag))
+            .collect();
+        let names = classes.names(|index| used.contains(&index))?;
+        let mut nodes = Vec::new();
+        for (&id, &(class_tag, size)) in &capture.nodes {
+            // Classes unloaded since they were tagged have no name anymore.
+            let class_name = classes
+                .index(class_tag)
+                .and_then(|index| names.get(&index))
+                .map_or_else(|| "<unknown>".to_owned(), String::clone);
+            if options.keeps(&class_name) {
+                nodes.push(HeapNode {
+                    id,
+                    class_name,
+                    size,
+                });
+            }
+        }
+        nodes.sort_by_key(|it| it.id);
+        let kept: HashSet<_> = nodes.iter().map(|it| it.id).collect();
+        let edges = capture
+            .edges
+            .into_iter()
+            .filter(|it| kept.contains(&it.to) && it.from.is_none_or(|from| kept.contains(&from)))
+            .collect();
+        Ok(HeapGraph {
+            nodes,
+            edges,
+            truncated: capture.truncated,
+        })
+    }
+}
+
+/// The state of the traversal of [`Jvm::heap_graph`].
+struct Capture {
+    max_nodes: usize,
+    /// The class tag and size of the visited objects by tag.
+    nodes: HashMap<sys::jlong, (sys::jlong, u64)>,
+    /// The tags set by the traversal, which are cleared afterwards.
+    tagged: HashSet<sys::jlong>,
+    edges: Vec<HeapEdge>,
+    truncated: bool,
+}
+
+impl Capture {
+    /// Gets the ID of an object, tagging it if needed, or `None` if the graph is full.
+    fn visit(
+        &mut self,
+        tag: &mut sys::jlong,
+        class_tag: sys::jlong,
+        size: u64,
+    ) -> Option<sys::jlong> {
+        if let Some(node) = self.nodes.get_mut(tag).filter(|_| *tag != 0) {
+            // The class of the root is only known once it is reported as a referrer.
+            if node.0 == 0 {
+                node.0 = class_tag;
+            }
+            return Some(*tag);
+        }
+        if self.nodes.len() >= self.max_nodes {
+            self.truncated = true;
+            return None;
+        }
+        if *tag == 0 {
+            *tag = reserve_tags(1);
+            self.tagged.insert(*tag);
+        }
+        self.nodes.insert(*tag, (class_tag, size));
+        Some(*tag)
+    }
+}
+
+impl HeapVisitor for Capture {
+    fn visit_reference(
+        &mut self,
+        kind: HeapReference,
+        referrer: Option<Referrer<'_>>,
+        referee: HeapObject<'_>,
+    ) -> VisitControl {
+        let Some(to) = self.visit(referee.tag, referee.class_tag, referee.size) else {
+            return VisitControl::ABORT;
+        };
+        let from = match referrer {
+            None => None,
+            // The referrer has been visited as a referee before, unless it is the root.
+            Some(Referrer {
+                class_tag,
+                tag: Some(tag),
+            }) => match self.visit(tag, class_tag, 0) {
+                Some(from) => Some(from),
+                None => return VisitControl::ABORT,
+            },
+            // The object refers to itself.
+            Some(Referrer { tag: None, .. }) => Some(to),
+        };
+        self.edges.push(HeapEdge { from, to, kind });
+        VisitControl::VISIT_OBJECTS
+    }
+}
+
+/// Describes the kind of a reference for the exported graphs, e.g. `field 2`.
+fn reference_label(kind: &HeapReference) -> String {
+    match kind {
+        HeapReference::Class => "class".to_owned(),
+        HeapReference::Field { index } => format!("field {index}"),
+        HeapReference::ArrayElement { index } => format!("element {index}"),
+        HeapReference::ClassLoader => "class loader".to_owned(),
+        HeapReference::Signers => "signers".to_owned(),
+        HeapReference::ProtectionDomain => "protection domain".to_owned(),
+        HeapReference::Interface => "interface".to_owned(),
+        HeapReference::StaticField { index } => format!("static field {index}"),
+        HeapReference::ConstantPool { index } => format!("constant pool {index}"),
+        HeapReference::Superclass => "superclass".to_owned(),
+        HeapReference::JniGlobal => "JNI global".to_owned(),
+        HeapReference::SystemClass => "system class".to_owned(),
+        HeapReference::Monitor => "monitor".to_owned(),
+        HeapReference::StackLocal { depth, slot, .. } => {
+            format!("stack local {slot} at depth {depth}")
+        }
+        HeapReference::JniLocal { depth, .. } => format!("JNI local at depth {depth}"),
+        HeapReference::Thread => "thread".to_owned(),
+        HeapReference::Other => "other".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> HeapGraph {
+        HeapGraph {
+            nodes: vec![
+                HeapNode {
+                    id: 1,
+                    class_name: "com.example.Node".to_owned(),
+                    size: 24,
+                },
+                HeapNode {
+                    id: 2,
+                    class_name: "java.lang.String".to_owned(),
+                    size: 16,
+                },
+            ],
+            edges: vec![
+                HeapEdge {
+                    from: None,
+                    to: 1,
+                    kind: HeapReference::JniGlobal,
+                },
+                HeapEdge {
+                    from: Some(1),
+                    to: 2,
+                    kind: HeapReference::Field { index: 3 },
+                },
+            ],
+            truncated: true,
+        }
+    }
+
+    #[test]
+    fn exports_dot() {
+        assert_eq!(
+            graph().to_dot(),
+            concat!(
+                "digraph heap {\n",
+                "    roots [shape=box];\n",
+                "    n1 [label=\"com.example.Node\\n24 bytes\"];\n",
+                "    n2 [label=\"java.lang.String\\n16 bytes\"];\n",
+                "    roots -> n1 [label=\"JNI global\"];\n",
+                "    n1 -> n2 [label=\"field 3\"];\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn exports_json() {
+        assert_eq!(
+            graph().to_json(),
+            concat!(
+                r#"{"nodes":[{"id":1,"class":"com.example.Node","size":24},"#,
+                r#"{"id":2,"class":"java.lang.String","size":16}],"#,
+                r#""edges":[{"from":null,"to":1,"kind":"JNI global"},"#,
+                r#"{"from":1,"to":2,"kind":"field 3"}],"truncated":true}"#
+            )
+        );
+        assert_eq!(
+            HeapGraph::default().to_json(),
+            r#"{"nodes":[],"edges":[],"truncated":false}"#
+        );
+    }
+
+    #[test]
+    fn options_keep_the_selected_packages() {
+        assert!(HeapGraphOptions::new().keeps("java.lang.String"));
+        let options = HeapGraphOptions::new()
+            .package("com.example.")
+            .package("org.example.");
+        assert!(options.keeps("com.example.Node"));
+        assert!(options.keeps("org.example.Node"));
+        assert!(!options.keeps("java.lang.String"));
+    }
+
+    #[test]
+    fn capture_tags_new_objects_up_to_the_limit() {
+        let mut capture = Capture {
+            max_nodes: 2,
+            nodes: HashMap::new(),
+            tagged: HashSet::new(),
+            edges: Vec::new(),
+            truncated: false,
+        };
+        // An object tagged by others keeps its tag.
+        let mut tagged = 7;
+        assert_eq!(capture.visit(&mut tagged, 100, 16), Some(7));
+        assert_eq!(tagged, 7);
+        assert_eq!(capture.visit(&mut tagged, 100, 16), Some(7));
+
+        let mut untagged = 0;
+        let id = capture.visit(&mut untagged, 100, 24).unwrap();
+        assert_eq!(untagged, id);
+        assert!(capture.tagged.contains(&id));
+        assert!(!capture.tagged.contains(&7));
+
+        let mut another = 0;
+        assert_eq!(capture.visit(&mut another, 100, 8), None);
+        assert_eq!(another, 0);
+        assert!(capture.truncated);
+    }
+}
@@ -0,0 +1,281 @@
+//! Per-class instance counts and shallow sizes of the heap, like `jmap -histo`.
+//!
+//! [`Jvm::heap_histogram`] tags every loaded class, iterates through the heap counting the
+//! objects by the tag of their class, and resolves the class names afterwards. The tags the
+//! classes had before are restored once the histogram is built.
+//!
+//! [`Jvm::heap_histogram_parallel`] does the counting on worker threads fed through a
+//! [`HeapPipeline`], so that the heap iteration callback only queues the visited objects.
+//!
+//! Building a histogram requires the `can_tag_objects` capability.
+
+use std::{collections::HashMap, fmt, io};
+
+use crate::jvm::{
+    errors::JvmTIError,
+    heap::{HeapFilter, VisitControl},
+    Jvm,
+};
+
+use super::{
+    escape_json,
+    heap_pipeline::{HeapPipeline, HeapPipelineError, HeapWorker, DEFAULT_QUEUE_CAPACITY},
+    sink::ReportSink,
+    ClassTags,
+};
+
+/// The objects of one class in a [`HeapHistogram`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramEntry {
+    /// The binary name of the class, e.g. `java.lang.String`, or its signature for array classes,
+    /// e.g. `[I`.
+    pub class_name: String,
+    /// The number of instances of the class.
+    pub instances: u64,
+    /// The total shallow size of the instances in bytes.
+    pub bytes: u64,
+}
+
+impl HistogramEntry {
+    /// Serializes the entry as a JSON object.
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"class\":{},\"instances\":{},\"bytes\":{}}}",
+            escape_json(&self.class_name),
+            self.instances,
+            self.bytes
+        )
+    }
+}
+
+/// An error returned by [`Jvm::heap_histogram_parallel`].
+#[derive(Debug, thiserror::Error)]
+pub enum ParallelHistogramError {
+    /// Iterating through the heap failed.
+    #[error(transparent)]
+    JvmTI(#[from] JvmTIError),
+    /// A worker thread could not be spawned.
+    #[error("Cannot spawn a heap pipeline worker: {0}")]
+    Spawn(#[from] io::Error),
+    /// A worker thread panicked.
+    #[error(transparent)]
+    Pipeline(#[from] HeapPipelineError),
+}
+
+/// The instance counts and shallow sizes of the objects in the heap by class, largest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapHistogram {
+    /// The classes with at least one instance, sorted by decreasing total size.
+    pub entries: Vec<HistogramEntry>,
+}
+
+impl HeapHistogram {
+    /// Gets the number of objects in the heap.
+    #[must_use]
+    pub fn total_instances(&self) -> u64 {
+        self.entries.iter().map(|it| it.instances).sum()
+    }
+
+    /// Gets the total shallow size of the objects in the heap in bytes.
+    #[must_use]
+    pub fn total_bytes(&self) -> u64 {
+        self.entries.iter().map(|it| it.bytes).sum()
+    }
+
+    /// Writes each entry to `sink` as a JSON object with the `class`, `instances`, and `bytes` of
+    /// the entry, largest first, and flushes the sink.
+    /// # Errors
+    /// Returns an error if the sink fails.
+    pub fn write_records<S: ReportSink + ?Sized>(&self, sink: &mut S) -> io::Result<()> {
+        for entry in &self.entries {
+            sink.write_record(entry.to_json().as_bytes())?;
+        }
+        sink.flush()
+    }
+}
+
+/// Formats the histogram as a table in the layout of `jmap -histo`.
+impl fmt::Display for HeapHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, " num     #instances         #bytes  class name (module)")?;
+        writeln!(f, "-------------------------------------------------------")?;
+        for (rank, entry) in self.entries.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>4}: {:>14} {:>14}  {}",
+                rank + 1,
+                entry.instances,
+                entry.bytes,
+                entry.class_name
+            )?;
+        }
+        write!(
+            f,
+            "Total {:>14} {:>14}",
+            self.total_instances(),
+            self.total_bytes()
+        )
+    }
+}
+
+impl Jvm {
+    /// Counts the objects in the heap and their shallow sizes by class. Objects of classes loaded
+    /// while the histogram is built are counted under `<unknown>`.
+    /// # Errors
+    /// Returns [`JvmTIError::MustPossessCapability`] if the `can_tag_objects` capability cannot be
+    /// added, or [`JvmTIError::UnAttachedThread`] if the current thread is not attached to the VM.
+    /// See [`JvmTIError`] for other possible errors.
+    pub fn heap_histogram(&self) -> Result<HeapHistogram, JvmTIError> {
+        let classes = ClassTags::new(self)?;
+        // Objects of classes loaded meanwhile and of classes tagged by others land in the last
+        // bucket.
+        let mut counts = vec![(0_u64, 0_u64); classes.len() + 1];
+        self.iterate_heap(HeapFilter::empty(), None, |class_tag, size, _, _| {
+            let bucket = classes.index(class_tag).unwrap_or(classes.len());
+            counts[bucket].0 += 1;
+            counts[bucket].1 += size;
+            VisitControl::empty()
+        })?;
+        histogram(&classes, &counts)
+    }
+
+    /// Like [`Jvm::heap_histogram`], but counts the objects on `workers` threads. The heap
+    /// iteration callback only queues the visited objects, which keeps the VM stopped for less
+    /// time on large heaps when the workers keep up.
+    /// # Errors
+    /// Returns [`ParallelHistogramError::JvmTI`] with [`JvmTIError::MustPossessCapability`] if
+    /// the `can_tag_objects` capability cannot be added, or with [`JvmTIError::UnAttachedThread`]
+    /// if the current thread is not attached to the VM. See [`ParallelHistogramError`] for other
+    /// possible errors.
+    pub fn heap_histogram_parallel(
+        &self,
+        workers: usize,
+    ) -> Result<HeapHistogram, ParallelHistogramError> {
+        let classes = ClassTags::new(self)?;
+        let mut pipeline = HeapPipeline::new(workers, DEFAULT_QUEUE_CAPACITY, |_| {
+            BucketCounter::default()
+        })?;
+        let mut submitted = Ok(());
+        let iterated = self.iterate_heap(HeapFilter::empty(), None, |class_tag, size, _, _| {
+            let bucket = classes.index(class_tag).unwrap_or(classes.len());
+            // All the objects of a class are counted by the same worker, so the partial counts
+            // need no merging.
+            submitted = pipeline.submit_keyed(&bucket, (bucket, size));
+            if submitted.is_ok() {
+                VisitControl::empty()
+            } else {
+                VisitControl::ABORT
+            }
+        });
+        let partials = pipeline.finish();
+        iterated.map_err(JvmTIError::from)?;
+        submitted?;
+        let mut counts = vec![(0_u64, 0_u64); classes.len() + 1];
+        for (bucket, count) in partials?.into_iter().flatten() {
+            counts[bucket] = count;
+        }
+        Ok(histogram(&classes, &counts)?)
+    }
+}
+
+/// Counts the objects of the buckets assigned to one worker of [`Jvm::heap_histogram_parallel`].
+#[derive(Default)]
+struct BucketCounter(HashMap<usize, (u64, u64)>);
+
+impl HeapWorker<(usize, u64)> for BucketCounter {
+    type Output = HashMap<usize, (u64, u64)>;
+
+    fn process(&mut self, (bucket, size): (usize, u64)) {
+        let count = self.0.entry(bucket).or_default();
+        count.0 += 1;
+        count.1 += size;
+    }
+
+    fn finish(self) -> Self::Output {
+        self.0
+    }
+}
+
+/// Builds a histogram from the instance counts and sizes of each class of `classes`, followed by
+/// those of the objects of unknown classes.
+fn histogram(classes: &ClassTags<'_>, counts: &[(u64, u64)]) -> Result<HeapHistogram, JvmTIError> {
+    let mut names = classes.names(|index| counts[index].0 > 0)?;
+    let mut entries = Vec::new();
+    for (index, &(instances, bytes)) in counts.iter().enumerate() {
+        if instances == 0 {
+            continue;
+        }
+        // Classes unloaded since they were tagged have no name anymore.
+        let class_name = names
+            .remove(&index)
+            .unwrap_or_else(|| "<unknown>".to_owned());
+        entries.push(HistogramEntry {
+            class_name,
+            instances,
+            bytes,
+        });
+    }
+    entries.sort_by(|a, b| {
+        b.bytes
+            .cmp(&a.bytes)
+            .then(b.instances.cmp(&a.instances))
+            .then_with(|| a.class_name.cmp(&b.class_name))
+    });
+    Ok(HeapHistogram { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::sink::CallbackSink;
+
+    #[test]
+    fn writes_one_record_per_entry() {
+        let histogram = HeapHistogram {
+            entries: vec![
+                HistogramEntry {
+                    class_name: "[B".to_owned(),
+                    instances: 3,
+                    bytes: 96,
+                },
+                HistogramEntry {
+                    class_name: "com.example.\"Quoted\"".to_owned(),
+                    instances: 1,
+                    bytes: 16,
+                },
+            ],
+        };
+        let mut records = Vec::new();
+        histogram
+            .write_records(&mut CallbackSink::new(|it: &[u8]| {
+                records.push(it.to_vec());
+            }))
+            .expect("write the records");
+        assert_eq!(
+            records,
+            [
+                br#"{"class":"[B","instances":3,"bytes":96}"#.to_vec(),
+                br#"{"class":"com.example.\"Quoted\"","instances":1,"bytes":16}"#.to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn keyed_workers_count_each_bucket_once() {
+        let mut pipeline =
+            HeapPipeline::new(3, 2, |_| BucketCounter::default()).expect("spawn the workers");
+        for (bucket, size) in [(0, 16), (1, 24), (0, 16), (2, 8), (1, 32)] {
+            pipeline
+                .submit_keyed(&bucket, (bucket, size))
+                .expect("submit an object");
+        }
+        let mut counts: Vec<_> = pipeline
+            .finish()
+            .expect("join the workers")
+            .into_iter()
+            .flatten()
+            .collect();
+        counts.sort_unstable();
+        assert_eq!(counts, [(0, (2, 32)), (1, (2, 56)), (2, (1, 8))]);
+    }
+}
@@ -0,0 +1,212 @@
+//! Parallel post-processing of heap traversal results.
+//!
+//! Heap iteration callbacks run while the VM is stopped and must return quickly, yet hashing,
+//! aggregating, or serializing millions of visited objects is expensive. A [`HeapPipeline`]
+//! moves that work off the callback: the callback only [submits](HeapPipeline::submit) the visited
+//! items into bounded queues that are drained by worker threads. When the workers fall behind the
+//! queues fill up and submitting blocks, which throttles the traversal instead of buffering the
+//! whole heap in memory. [`Jvm::heap_histogram_parallel`](crate::jvm::Jvm::heap_histogram_parallel)
+//! counts the objects of the heap this way.
+
+use std::{
+    hash::{BuildHasher, Hash, RandomState},
+    io,
+    sync::mpsc::{sync_channel, SyncSender, TrySendError},
+    thread::JoinHandle,
+};
+
+/// The default capacity of the queue of each worker.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 4096;
+
+/// Processes the items submitted to a [`HeapPipeline`] on one worker thread.
+pub trait HeapWorker<T>: Send + 'static {
+    /// The result of the worker, e.g. a partial aggregate.
+    type Output: Send + 'static;
+
+    /// Processes one item.
+    fn process(&mut self, item: T);
+
+    /// Finishes the processing after all the items have been submitted.
+    fn finish(self) -> Self::Output;
+}
+
+/// An error returned by a [`HeapPipeline`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum HeapPipelineError {
+    /// A worker panicked while processing an item.
+    #[error("A heap pipeline worker panicked")]
+    WorkerPanicked,
+}
+
+/// Bounded queues drained by a pool of worker threads.
+#[derive(Debug)]
+pub struct HeapPipeline<T, R> {
+    senders: Vec<SyncSender<T>>,
+    workers: Vec<JoinHandle<R>>,
+    next: usize,
+    hasher: RandomState,
+    stalls: u64,
+}
+
+impl<T, R> HeapPipeline<T, R>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+{
+    /// Starts `workers` worker threads, each created by `make_worker` with its index and fed by a
+    /// queue holding up to `capacity` items.
+    /// # Errors
+    /// Returns an error if a worker thread cannot be spawned.
+    pub fn new<W, F>(workers: usize, capacity: usize, mut make_worker: F) -> io::Result<Self>
+    where
+        W: HeapWorker<T, Output = R>,
+        F: FnMut(usize) -> W,
+    {
+        let workers = workers.max(1);
+        let mut senders = Vec::with_capacity(workers);
+        let mut handles = Vec::with_capacity(workers);
+        for index in 0..workers {
+            let (sender, receiver) = sync_channel(capacity.max(1));
+            let mut worker = make_worker(index);
+            let handle = std::thread::Builder::new()
+                .name(format!("coffee-filter-heap-{index}"))
+                .spawn(move || {
+                    for item in receiver {
+                        worker.process(item);
+                    }
+                    worker.finish()
+                })?;
+            senders.push(sender);
+            handles.push(handle);
+        }
+        Ok(Self {
+            senders,
+            workers: handles,
+            next: 0,
+            hasher: RandomState::new(),
+            stalls: 0,
+        })
+    }
+
+    /// Submits `item` to the workers in a round-robin fashion, blocking while the queue of the
+    /// chosen worker is full.
+    /// # Errors
+    /// Returns [`HeapPipelineError::WorkerPanicked`] if the chosen worker has panicked.
+    pub fn submit(&mut self, item: T) -> Result<(), HeapPipelineError> {
+        let index = self.next;
+        self.next = (self.next + 1) % self.senders.len();
+        self.send(index, item)
+    }
+
+    /// Submits `item` to the worker selected by `key`, so that all the items with the same key are
+    /// processed by the same worker, e.g. to aggregate them by class without merging afterwards.
+    /// # Errors
+    /// Returns [`HeapPipelineError::WorkerPanicked`] if the chosen worker has panicked.
+    pub fn submit_keyed<K: Hash>(&mut self, key: &K, item: T) -> Result<(), HeapPipelineError> {
+        let hash = self.hasher.hash_one(key);
+        let index = usize::try_from(hash % self.senders.len() as u64).unwrap_or_default();
+        self.send(index, item)
+    }
+
+    /// Returns how many times submitting had to wait for a worker to catch up.
+    #[must_use]
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+
+    /// Waits for the workers to process all the submitted items and returns their results in
+    /// worker order.
+    /// # Errors
+    /// Returns [`HeapPipelineError::WorkerPanicked`] if any worker has panicked.
+    pub fn finish(self) -> Result<Vec<R>, HeapPipelineError> {
+        // Closing the queues ends the loops of the workers.
+        drop(self.senders);
+        self.workers
+            .into_iter()
+            .map(|it| it.join().map_err(|_| HeapPipelineError::WorkerPanicked))
+            .collect()
+    }
+
+    fn send(&mut self, index: usize, item: T) -> Result<(), HeapPipelineError> {
+        let sender = &self.senders[index];
+        match sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) => {
+                self.stalls += 1;
+                sender
+                    .send(item)
+                    .map_err(|_| HeapPipelineError::WorkerPanicked)
+            }
+            Err(TrySendError::Disconnected(_)) => Err(HeapPipelineError::WorkerPanicked),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects the items it processes, or panics on the item `0`.
+    struct Collector(Vec<u32>);
+
+    impl HeapWorker<u32> for Collector {
+        type Output = Vec<u32>;
+
+        fn process(&mut self, item: u32) {
+            assert_ne!(item, 0, "the worker fails on 0");
+            self.0.push(item);
+        }
+
+        fn finish(self) -> Self::Output {
+            self.0
+        }
+    }
+
+    #[test]
+    fn distributes_items_round_robin() {
+        let mut pipeline =
+            HeapPipeline::new(3, 1, |_| Collector(Vec::new())).expect("spawn the workers");
+        for item in 1..=7 {
+            pipeline.submit(item).expect("submit an item");
+        }
+        assert_eq!(
+            pipeline.finish().expect("finish the workers"),
+            [vec![1, 4, 7], vec![2, 5], vec![3, 6]]
+        );
+    }
+
+    #[test]
+    fn keeps_items_with_the_same_key_together() {
+        let mut pipeline =
+            HeapPipeline::new(4, 1, |_| Collector(Vec::new())).expect("spawn the workers");
+        for item in 1..=20 {
+            pipeline
+                .submit_keyed(&(item % 2), item)
+                .expect("submit an item");
+        }
+        let results = pipeline.finish().expect("finish the workers");
+        for key in 0..2 {
+            let has_key = |item: &u32| item % 2 == key;
+            // Both keys may be assigned to the same worker.
+            let holders: Vec<_> = results.iter().filter(|it| it.iter().any(has_key)).collect();
+            assert_eq!(holders.len(), 1);
+            assert_eq!(
+                holders[0]
+                    .iter()
+                    .copied()
+                    .filter(has_key)
+                    .collect::<Vec<_>>(),
+                (1..=20).filter(has_key).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn reports_panicked_workers() {
+        let mut pipeline =
+            HeapPipeline::new(1, 1, |_| Collector(Vec::new())).expect("spawn the workers");
+        // The worker may not have taken the item yet, so submitting may still succeed.
+        let _ = pipeline.submit(0);
+        assert_eq!(pipeline.finish(), Err(HeapPipelineError::WorkerPanicked));
+    }
+}
@@ -0,0 +1,294 @@
+//! Leak detection by following the objects of selected classes from allocation to collection.
+//!
+//! A [`LeakTracker`] tags the objects of the selected classes when they are allocated, together
+//! with their allocation stacks, forgets them when the `ObjectFree` event reports them collected,
+//! and counts the garbage collections they survive. The objects still alive after a given number
+//! of collections are reported as [`LeakSuspect`]s, grouped by class and allocation site.
+//!
+//! Allocations are usually reported by the `SampledObjectAlloc` event, so only a sample of the
+//! objects is tracked unless the sampling interval is lowered with
+//! [`Jvm::set_heap_sampling_interval`]. Tagging requires the `can_tag_objects` capability.
+
+use std::{collections::HashMap, fmt::Write, io};
+
+#[cfg(all(feature = "alloc-events", feature = "gc-events"))]
+use std::sync::Arc;
+
+#[cfg(all(feature = "alloc-events", feature = "gc-events"))]
+use crate::jvm::events::{Handler, JvmTIEvent};
+use crate::{
+    jvm::{class::Class, errors::JvmTIError, objects::Object, threads::Thread, Jvm},
+    sys,
+};
+
+use super::{
+    binary_name, call_sites, correlation::CorrelationId, escape_json, object_age::AgeTable,
+    sink::ReportSink, CallSite,
+};
+
+/// A tracked object, as recorded at its allocation.
+#[derive(Debug, Clone)]
+struct Allocation {
+    class_name: String,
+    size: u64,
+    sites: Vec<CallSite>,
+    correlation_id: Option<CorrelationId>,
+}
+
+/// The tracked objects of a class allocated at the same site that survived a number of
+/// collections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakSuspect {
+    /// The binary name of the class of the objects.
+    pub class_name: String,
+    /// The top frames of the allocation site, innermost first.
+    pub sites: Vec<CallSite>,
+    /// The number of surviving objects.
+    pub count: usize,
+    /// The total size of the surviving objects in bytes, as reported at their allocation.
+    pub bytes: u64,
+    /// The number of collections survived by the oldest object.
+    pub max_age: u64,
+    /// The distinct correlation IDs attached to the allocating threads, in ascending order.
+    pub correlation_ids: Vec<CorrelationId>,
+}
+
+impl LeakSuspect {
+    /// Serializes the suspect as a JSON object with the `class`, the allocation `sites`, the
+    /// `count`, `bytes`, and `max_age` of the surviving objects, and their `correlation_ids`.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\"class\":{},\"sites\":[", escape_json(&self.class_name));
+        for (index, site) in self.sites.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"class\":{},\"method\":{}}}",
+                escape_json(&site.class_name),
+                escape_json(&site.method_name)
+            );
+        }
+        let correlation_ids: Vec<_> = self
+            .correlation_ids
+            .iter()
+            .map(|it| CorrelationId::to_json(Some(*it)))
+            .collect();
+        let _ = write!(
+            json,
+            "],\"count\":{},\"bytes\":{},\"max_age\":{},\"correlation_ids\":[{}]}}",
+            self.count,
+            self.bytes,
+            self.max_age,
+            correlation_ids.join(",")
+        );
+        json
+    }
+}
+
+/// Tracks the objects of selected classes to find the ones that survive many garbage
+/// collections.
+///
+/// The tracker counts the collections like an [`ObjectAgeTracker`] and draws its tags from a
+/// [`TagMap`], so it can be combined with other tag maps but not with other users of object tags
+/// on the same objects.
+///
+/// [`ObjectAgeTracker`]: super::object_age::ObjectAgeTracker
+/// [`TagMap`]: crate::jvm::tags::TagMap
+#[derive(Debug)]
+pub struct LeakTracker {
+    class_prefixes: Vec<String>,
+    max_sites: usize,
+    objects: AgeTable<Allocation>,
+}
+
+impl LeakTracker {
+    /// Creates a tracker of the objects of the classes whose binary names start with any of
+    /// `class_prefixes`, e.g. `com.example.` or `com.example.Session`, which identifies
+    /// allocation sites by up to `max_sites` top frames.
+    pub fn new<I, S>(class_prefixes: I, max_sites: usize) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            class_prefixes: class_prefixes.into_iter().map(Into::into).collect(),
+            max_sites,
+            objects: AgeTable::new(),
+        }
+    }
+
+    /// Starts tracking `object` of `class` and `size` bytes, which has just been allocated on
+    /// `thread`, if its class is selected. Call this from the `SampledObjectAlloc` or
+    /// `VMObjectAlloc` event.
+    /// # Errors
+    /// Returns an error if the class signature cannot be retrieved or the object cannot be
+    /// tagged.
+    pub fn on_allocation(
+        &self,
+        thread: &Thread<'_>,
+        object: &Object<'_>,
+        class: &Class<'_>,
+        size: u64,
+    ) -> Result<(), JvmTIError> {
+        let class_name = binary_name(&class.signature()?);
+        if !self.selects(&class_name) {
+            return Ok(());
+        }
+        let sites = call_sites(thread, self.max_sites, &[]).unwrap_or_default();
+        let allocation = Allocation {
+            class_name,
+            size,
+            sites,
+            correlation_id: CorrelationId::stamp(thread),
+        };
+        self.objects.track(object, allocation)
+    }
+
+    /// Returns whether the objects of the class with the binary name `class_name` are tracked.
+    fn selects(&self, class_name: &str) -> bool {
+        self.class_prefixes
+            .iter()
+            .any(|prefix| class_name.starts_with(prefix.as_str()))
+    }
+
+    /// Records that a garbage collection has finished.
+    /// This only updates an atomic counter and is safe to call from the `GarbageCollectionFinish`
+    /// event, where almost no JVM TI functions may be used.
+    pub fn on_gc_finish(&self) {
+        self.objects.on_gc_finish();
+    }
+
+    /// Stops tracking the object with the given tag, which has been collected. Call this from the
+    /// `ObjectFree` event.
+    pub fn on_object_free(&self, tag: sys::jlong) {
+        self.objects.on_object_free(tag);
+    }
+
+    /// Returns the number of garbage collections observed so far.
+    pub fn gc_count(&self) -> u64 {
+        self.objects.gc_count()
+    }
+
+    /// Returns the number of tracked objects, including the collected objects whose `ObjectFree`
+    /// event has not been handled yet.
+    pub fn tracked(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Reports the tracked objects that are still alive and survived at least `min_gcs`
+    /// collections, grouped by class and allocation site, largest groups first. The collected
+    /// objects are forgotten.
+    /// # Errors
+    /// Returns an error if the liveness check fails.
+    pub fn survivors(&self, jvm: &Jvm, min_gcs: u64) -> Result<Vec<LeakSuspect>, JvmTIError> {
+        let mut suspects: HashMap<(String, Vec<CallSite>), LeakSuspect> = HashMap::new();
+        self.objects.survivors(jvm, min_gcs, |age, allocation| {
+            let suspect = suspects
+                .entry((allocation.class_name.clone(), allocation.sites.clone()))
+                .or_insert_with_key(|(class_name, sites)| LeakSuspect {
+                    class_name: class_name.clone(),
+                    sites: sites.clone(),
+                    count: 0,
+                    bytes: 0,
+                    max_age: 0,
+                    correlation_ids: Vec::new(),
+                });
+            suspect.count += 1;
+            suspect.bytes += allocation.size;
+            suspect.max_age = suspect.max_age.max(age);
+            suspect.correlation_ids.extend(allocation.correlation_id);
+        })?;
+        let mut suspects: Vec<_> = suspects.into_values().collect();
+        for suspect in &mut suspects {
+            suspect.correlation_ids.sort_unstable();
+            suspect.correlation_ids.dedup();
+        }
+        suspects.sort_by(|a, b| b.count.cmp(&a.count).then(b.bytes.cmp(&a.bytes)));
+        Ok(suspects)
+    }
+
+    /// Writes the [survivors](LeakTracker::survivors) to `sink` as JSON records, see
+    /// [`LeakSuspect::to_json`], and flushes the sink.
+    /// # Errors
+    /// Returns an error if the liveness check or the sink fails.
+    pub fn write_survivors<S: ReportSink + ?Sized>(
+        &self,
+        jvm: &Jvm,
+        min_gcs: u64,
+        sink: &mut S,
+    ) -> io::Result<()> {
+        for suspect in self.survivors(jvm, min_gcs).map_err(io::Error::other)? {
+            sink.write_record(suspect.to_json().as_bytes())?;
+        }
+        sink.flush()
+    }
+}
+
+#[cfg(all(feature = "alloc-events", feature = "gc-events"))]
+impl LeakTracker {
+    /// Subscribes the tracker to the `SampledObjectAlloc`, `GarbageCollectionFinish`, and
+    /// `ObjectFree` events and enables them. Objects that cannot be tracked are skipped.
+    /// # Errors
+    /// Returns [`JvmTIError::MustPossessCapability`] if the capabilities of the events are
+    /// missing. See [`Jvm::update_callbacks`] for other possible errors.
+    pub fn subscribe(self: &Arc<Self>, jvm: &mut Jvm) -> Result<(), JvmTIError> {
+        let (on_allocation, on_gc_finish, on_object_free) =
+            (Arc::clone(self), Arc::clone(self), Arc::clone(self));
+        jvm.update_callbacks(move |it| {
+            it.sampled_object_alloc
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |_, _, thread, object, class, size| {
+                    let _ = on_allocation.on_allocation(thread, object, class, size);
+                }));
+            it.garbage_collection_finish
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |_| on_gc_finish.on_gc_finish()));
+            it.object_free
+                .get_or_insert_with(Handler::default)
+                .subscribe(Box::new(move |_, tag| on_object_free.on_object_free(tag)));
+        })?;
+        for event in [
+            JvmTIEvent::SampledObjectAlloc,
+            JvmTIEvent::GarbageCollectionFinish,
+            JvmTIEvent::ObjectFree,
+        ] {
+            jvm.enable_event(event, None)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_classes_by_prefix() {
+        let tracker = LeakTracker::new(["com.example.", "java.util.HashMap"], 4);
+        assert!(tracker.selects("com.example.Session"));
+        assert!(tracker.selects("java.util.HashMap$Node"));
+        assert!(!tracker.selects("java.util.ArrayList"));
+        assert!(!LeakTracker::new(Vec::<String>::new(), 4).selects("com.example.Session"));
+    }
+
+    #[test]
+    fn serializes_suspects_as_json() {
+        let suspect = LeakSuspect {
+            class_name: "com.example.Session".to_owned(),
+            sites: vec![CallSite {
+                class_name: "com.example.Pool".to_owned(),
+                method_name: "open".to_owned(),
+            }],
+            count: 3,
+            bytes: 96,
+            max_age: 7,
+            correlation_ids: CorrelationId::new(1).into_iter().collect(),
+        };
+        assert_eq!(
+            suspect.to_json(),
+            r#"{"class":"com.example.Session","sites":[{"class":"com.example.Pool","method":"open"}],"count":3,"bytes":96,"max_age":7,"correlation_ids":["0000000000000001"]}"#
+        );
+    }
+}
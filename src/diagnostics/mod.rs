@@ -0,0 +1,273 @@
+//! Diagnostic subsystems built on top of the JVM TI bindings.
+
+use std::{collections::HashMap, ffi::OsStr, fmt::Write};
+
+use crate::{
+    jvm::{
+        chunks::LocalChunks,
+        class::Class,
+        errors::JvmTIError,
+        jni::JNI,
+        objects::Object,
+        strings::{encode_modified_utf8, ModifiedUtf8Ext},
+        tags::reserve_tags,
+        threads::Thread,
+        Jvm,
+    },
+    sys,
+};
+
+pub mod breakpoints;
+pub mod class_graph;
+pub mod correlation;
+#[cfg(feature = "dap")]
+pub mod dap;
+pub mod deadlock;
+pub mod flight_recorder;
+pub mod gc_metrics;
+pub mod heap_graph;
+pub mod heap_histogram;
+pub mod heap_pipeline;
+pub mod leaks;
+pub mod object_age;
+pub mod retry;
+pub mod sink;
+pub mod snapshot;
+pub mod stepping;
+pub mod symbolize;
+pub mod thread_dump;
+pub mod watchpoints;
+
+/// The maximum number of frames inspected when summarizing a call stack.
+const STACK_SCAN_DEPTH: usize = 64;
+
+/// A method on the call stack, identified by its declaring class and name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CallSite {
+    /// The binary name of the class declaring the method, e.g. `com.example.Foo`.
+    pub class_name: String,
+    /// The name of the method.
+    pub method_name: String,
+}
+
+/// Collects up to `max_sites` frames from the top of the call stack of `thread`, skipping frames
+/// whose declaring class starts with any of `skip_prefixes`.
+fn call_sites(
+    thread: &Thread<'_>,
+    max_sites: usize,
+    skip_prefixes: &[String],
+) -> Result<Vec<CallSite>, JvmTIError> {
+    let mut sites = Vec::with_capacity(max_sites);
+    for frame in thread.stack_trace(STACK_SCAN_DEPTH)? {
+        if sites.len() >= max_sites {
+            break;
+        }
+        let class_name = binary_name(&frame.method.declaring_class()?.signature()?);
+        if skip_prefixes
+            .iter()
+            .any(|prefix| class_name.starts_with(prefix.as_str()))
+        {
+            continue;
+        }
+        let method_name = frame.method.name()?.to_utf8_lossy().into_owned();
+        sites.push(CallSite {
+            class_name,
+            method_name,
+        });
+    }
+    Ok(sites)
+}
+
+/// Converts a JNI type signature such as `Lcom/example/Foo;` into a binary name such as
+/// `com.example.Foo`. Array and primitive signatures are returned unchanged.
+fn binary_name(signature: &OsStr) -> String {
+    let signature = signature.to_utf8_lossy();
+    signature
+        .strip_prefix('L')
+        .and_then(|it| it.strip_suffix(';'))
+        .map_or_else(|| signature.to_string(), |it| it.replace('/', "."))
+}
+
+/// Converts a binary name such as `com.example.Foo` into the JNI type signature of the class in
+/// modified UTF-8, such as `Lcom/example/Foo;`. This is the inverse of [`binary_name`].
+fn class_signature(binary_name: &str) -> Vec<u8> {
+    encode_modified_utf8(&format!("L{};", binary_name.replace('.', "/"))).into_bytes()
+}
+
+/// The number of classes whose local references [`ClassTags`] holds at a time.
+const CLASS_CHUNK_SIZE: usize = 256;
+
+/// Tags all the loaded classes for the duration of a heap traversal, so that the class tags
+/// reported for the visited objects identify their classes. The previous tags of the classes are
+/// restored when dropped.
+///
+/// The classes are visited in chunks of local references, so only their tags are kept. Looking
+/// up names or restoring the tags visits the loaded classes again and recognizes them by tag.
+struct ClassTags<'a> {
+    jvm: &'a Jvm,
+    jni: JNI,
+    first_tag: sys::jlong,
+    previous_tags: Vec<sys::jlong>,
+}
+
+impl<'a> ClassTags<'a> {
+    /// Tags the classes loaded in `jvm`.
+    /// # Errors
+    /// Returns [`JvmTIError::UnAttachedThread`] if the current thread is not attached to the VM.
+    fn new(jvm: &'a Jvm) -> Result<Self, JvmTIError> {
+        let mut tags = Self {
+            jvm,
+            jni: jvm.current_jni().ok_or(JvmTIError::UnAttachedThread)?,
+            first_tag: 0,
+            previous_tags: Vec::new(),
+        };
+        let mut chunks = jvm.loaded_classes_chunked(&tags.jni, CLASS_CHUNK_SIZE)?;
+        tags.first_tag = reserve_tags(chunks.remaining());
+        tags.previous_tags.reserve(chunks.remaining());
+        let mut next_tag = tags.first_tag;
+        while let Some(chunk) = next_chunk(&mut chunks)? {
+            for class in chunk {
+                let object = class_object(class);
+                let previous = object.tag()?;
+                object.set_tag(next_tag)?;
+                tags.previous_tags.push(previous);
+                next_tag += 1;
+            }
+        }
+        drop(chunks);
+        Ok(tags)
+    }
+
+    /// Gets the number of tagged classes.
+    fn len(&self) -> usize {
+        self.previous_tags.len()
+    }
+
+    /// Gets the index of the class with the given tag, or `None` if the class was loaded after
+    /// the classes were tagged.
+    fn index(&self, class_tag: sys::jlong) -> Option<usize> {
+        usize::try_from(class_tag.wrapping_sub(self.first_tag))
+            .ok()
+            .filter(|&it| it < self.len())
+    }
+
+    /// Calls `f` with every tagged class that is still loaded and its index.
+    fn for_each_tagged<F>(&self, mut f: F) -> Result<(), JvmTIError>
+    where
+        F: FnMut(&Class<'_>, usize) -> Result<(), JvmTIError>,
+    {
+        let mut chunks = self
+            .jvm
+            .loaded_classes_chunked(&self.jni, CLASS_CHUNK_SIZE)?;
+        while let Some(chunk) = next_chunk(&mut chunks)? {
+            for class in chunk {
+                if let Some(index) = self.index(class_object(class).tag()?) {
+                    f(class, index)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the binary names of the classes whose indices are `wanted`. Classes unloaded since
+    /// they were tagged are missing from the result.
+    fn names<F>(&self, mut wanted: F) -> Result<HashMap<usize, String>, JvmTIError>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        let mut names = HashMap::new();
+        self.for_each_tagged(|class, index| {
+            if wanted(index) {
+                names.insert(index, binary_name(&class.signature()?));
+            }
+            Ok(())
+        })?;
+        Ok(names)
+    }
+}
+
+impl Drop for ClassTags<'_> {
+    fn drop(&mut self) {
+        // Unloaded classes need no restoring, and nothing sensible can be done if visiting the
+        // classes fails in a destructor.
+        let _ = self.for_each_tagged(|class, index| {
+            let _ = class_object(class).set_tag(self.previous_tags[index]);
+            Ok(())
+        });
+    }
+}
+
+/// Advances `chunks` to its next chunk of classes.
+fn next_chunk<'c, 'j>(
+    chunks: &'c mut LocalChunks<'j, Class<'j>>,
+) -> Result<Option<&'c [Class<'j>]>, JvmTIError> {
+    // Creating the local frame of a chunk only fails when the VM runs out of memory.
+    chunks.next_chunk().map_err(|_| JvmTIError::OutOfMemory)
+}
+
+/// Gets `class` as an object, e.g. to tag it.
+fn class_object<'a>(class: &Class<'a>) -> Object<'a> {
+    // SAFETY: The class is a valid, non-null local reference.
+    unsafe { Object::from_ptr(class.jvm, class.jclass) }
+}
+
+/// Quotes `value` as a JSON string.
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Escapes `value` for use inside a quoted DOT identifier.
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_dot_escapes_quotes_and_backslashes() {
+        assert_eq!(escape_dot("java.lang.Object"), "java.lang.Object");
+        assert_eq!(escape_dot(r#"a "b" \c"#), r#"a \"b\" \\c"#);
+        assert_eq!(escape_dot(r"\\"), r"\\\\");
+    }
+
+    #[test]
+    fn escape_json_quotes_and_escapes() {
+        assert_eq!(escape_json("java.lang.Object"), r#""java.lang.Object""#);
+        assert_eq!(escape_json(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(escape_json("a\nb\tc\r"), r#""a\nb\tc\r""#);
+        assert_eq!(escape_json("\u{1}\u{7f}"), r#""\u0001\u007f""#);
+        assert_eq!(escape_json("caf\u{e9}"), "\"caf\u{e9}\"");
+    }
+
+    #[test]
+    fn binary_name_converts_class_signatures() {
+        assert_eq!(
+            binary_name(OsStr::new("Ljava/lang/Object;")),
+            "java.lang.Object"
+        );
+        assert_eq!(binary_name(OsStr::new("[I")), "[I");
+        assert_eq!(binary_name(OsStr::new("I")), "I");
+        assert_eq!(
+            class_signature("java.lang.Object"),
+            b"Ljava/lang/Object;".to_vec()
+        );
+    }
+}
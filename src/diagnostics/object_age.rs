@@ -0,0 +1,203 @@
+//! Tracks how many garbage collections tagged objects survive.
+//!
+//! Objects that keep surviving collections long after their allocation site stopped allocating
+//! are a practical heuristic for leaked object cohorts. An [`ObjectAgeTracker`] tags the objects
+//! handed to [`ObjectAgeTracker::track`], counts collections reported via
+//! [`ObjectAgeTracker::on_gc_finish`], and groups the survivors by allocation site on demand.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    jvm::{errors::JvmTIError, objects::Object, tags::TagMap, threads::Thread, Jvm},
+    sys,
+};
+
+use super::{call_sites, correlation::CorrelationId, CallSite};
+
+/// A tracked object, as recorded when the tracking started.
+#[derive(Debug)]
+struct Aged<T> {
+    birth_gc: u64,
+    value: T,
+}
+
+/// The tagged objects and the collection counter behind the trackers of object ages, i.e. of the
+/// number of garbage collections the objects survived.
+#[derive(Debug)]
+pub(crate) struct AgeTable<T> {
+    gc_count: AtomicU64,
+    objects: TagMap<Aged<T>>,
+}
+
+impl<T> AgeTable<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            gc_count: AtomicU64::new(0),
+            objects: TagMap::new(),
+        }
+    }
+
+    /// Starts tracking `object` with `value`, replacing the value if it is already tracked.
+    pub(crate) fn track(&self, object: &Object<'_>, value: T) -> Result<(), JvmTIError> {
+        let birth_gc = self.gc_count();
+        self.objects.insert(object, Aged { birth_gc, value })?;
+        Ok(())
+    }
+
+    pub(crate) fn on_gc_finish(&self) {
+        self.gc_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_object_free(&self, tag: sys::jlong) {
+        self.objects.on_object_free(tag);
+    }
+
+    pub(crate) fn gc_count(&self) -> u64 {
+        self.gc_count.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Forgets the collected objects and calls `f` with the age and the value of each live object
+    /// that survived at least `min_age` collections.
+    pub(crate) fn survivors(
+        &self,
+        jvm: &Jvm,
+        min_age: u64,
+        mut f: impl FnMut(u64, &T),
+    ) -> Result<(), JvmTIError> {
+        let gc_count = self.gc_count();
+        self.objects.retain_live(jvm, |object| {
+            let age = gc_count.saturating_sub(object.birth_gc);
+            if age >= min_age {
+                f(age, &object.value);
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Where and on behalf of what a tracked object was allocated.
+#[derive(Debug)]
+struct Origin {
+    sites: Vec<CallSite>,
+    correlation_id: Option<CorrelationId>,
+}
+
+/// A group of tracked objects allocated at the same site that survived a number of collections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SurvivorCohort {
+    /// The top frames of the allocation site, innermost first.
+    pub sites: Vec<CallSite>,
+    /// The number of surviving objects allocated at the site.
+    pub count: usize,
+    /// The number of collections survived by the oldest object of the cohort.
+    pub max_age: u64,
+    /// The distinct correlation IDs attached to the allocating threads, in ascending order.
+    pub correlation_ids: Vec<CorrelationId>,
+}
+
+/// Maintains an "age in garbage collections" metric for tracked objects.
+///
+/// The tracker draws its tags from a [`TagMap`], so it can be combined with other tag maps but
+/// not with other users of object tags on the same objects. Tagging requires the
+/// `can_tag_objects` capability.
+#[derive(Debug)]
+pub struct ObjectAgeTracker {
+    max_sites: usize,
+    objects: AgeTable<Origin>,
+}
+
+impl ObjectAgeTracker {
+    /// Creates a tracker that identifies allocation sites by up to `max_sites` top frames.
+    #[must_use]
+    pub fn new(max_sites: usize) -> Self {
+        Self {
+            max_sites,
+            objects: AgeTable::new(),
+        }
+    }
+
+    /// Starts tracking `object`, which has just been allocated on `thread`.
+    /// # Errors
+    /// Returns an error if the object cannot be tagged.
+    pub fn track(&self, thread: &Thread<'_>, object: &Object<'_>) -> Result<(), JvmTIError> {
+        let origin = Origin {
+            sites: call_sites(thread, self.max_sites, &[]).unwrap_or_default(),
+            correlation_id: CorrelationId::stamp(thread),
+        };
+        self.objects.track(object, origin)
+    }
+
+    /// Records that a garbage collection has finished.
+    /// This only updates an atomic counter and is safe to call from the `GarbageCollectionFinish`
+    /// event, where almost no JVM TI functions may be used.
+    pub fn on_gc_finish(&self) {
+        self.objects.on_gc_finish();
+    }
+
+    /// Stops tracking the object with the given tag, e.g. from the `ObjectFree` event.
+    pub fn on_object_free(&self, tag: sys::jlong) {
+        self.objects.on_object_free(tag);
+    }
+
+    /// Returns the number of garbage collections observed so far.
+    pub fn gc_count(&self) -> u64 {
+        self.objects.gc_count()
+    }
+
+    /// Checks which tracked objects are still alive, forgets the dead ones, and reports the
+    /// survivors of at least `min_age` collections grouped by allocation site, largest cohorts
+    /// first.
+    /// # Errors
+    /// Returns an error if the liveness check fails.
+    pub fn survivors(&self, jvm: &Jvm, min_age: u64) -> Result<Vec<SurvivorCohort>, JvmTIError> {
+        let mut cohorts: HashMap<Vec<CallSite>, SurvivorCohort> = HashMap::new();
+        self.objects.survivors(jvm, min_age, |age, origin| {
+            let cohort = cohorts
+                .entry(origin.sites.clone())
+                .or_insert_with_key(|sites| SurvivorCohort {
+                    sites: sites.clone(),
+                    count: 0,
+                    max_age: 0,
+                    correlation_ids: Vec::new(),
+                });
+            cohort.count += 1;
+            cohort.max_age = cohort.max_age.max(age);
+            cohort.correlation_ids.extend(origin.correlation_id);
+        })?;
+        let mut cohorts: Vec<_> = cohorts.into_values().collect();
+        for cohort in &mut cohorts {
+            cohort.correlation_ids.sort_unstable();
+            cohort.correlation_ids.dedup();
+        }
+        cohorts.sort_by(|a, b| b.count.cmp(&a.count).then(b.max_age.cmp(&a.max_age)));
+        Ok(cohorts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_collections() {
+        let tracker = ObjectAgeTracker::new(4);
+        assert_eq!(tracker.gc_count(), 0);
+        tracker.on_gc_finish();
+        tracker.on_gc_finish();
+        assert_eq!(tracker.gc_count(), 2);
+    }
+
+    #[test]
+    fn ignores_frees_of_untracked_objects() {
+        let table = AgeTable::<()>::new();
+        table.on_object_free(42);
+        assert_eq!(table.len(), 0);
+    }
+}
@@ -0,0 +1,154 @@
+//! Handling of transient races in bulk operations over live threads.
+//!
+//! Threads keep running and ending while a bulk operation such as a [snapshot](super::snapshot)
+//! lists and then inspects them, so individual queries routinely fail with errors like
+//! [`ThreadNotAlive`](JvmTIError::ThreadNotAlive) or [`NoMoreFrames`](JvmTIError::NoMoreFrames).
+//! A [`RetryPolicy`] decides whether such a query is retried, skipped, or fails the whole
+//! operation.
+
+use crate::jvm::errors::{JvmTIError, StackError, ThreadError};
+
+/// An error that may be caused by a race with a running thread rather than a real failure.
+pub trait TransientError {
+    /// Returns whether the error may be caused by a thread ending or its frames changing.
+    fn is_transient(&self) -> bool;
+}
+
+impl TransientError for JvmTIError {
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::ThreadNotAlive | Self::InvalidThread | Self::NoMoreFrames
+        )
+    }
+}
+
+impl TransientError for ThreadError {
+    fn is_transient(&self) -> bool {
+        JvmTIError::from(*self).is_transient()
+    }
+}
+
+impl TransientError for StackError {
+    fn is_transient(&self) -> bool {
+        JvmTIError::from(*self).is_transient()
+    }
+}
+
+/// Decides how a bulk operation handles a query failing with a [`TransientError`].
+/// Errors that are not transient always fail the operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The number of times a query is retried after a transient failure.
+    pub retries: u32,
+    /// Whether the item is skipped, rather than failing the whole operation, when the query still
+    /// fails after the retries.
+    pub skip: bool,
+}
+
+impl RetryPolicy {
+    /// Fails the whole operation on the first transient failure.
+    pub const FAIL: Self = Self {
+        retries: 0,
+        skip: false,
+    };
+
+    /// Skips the item on the first transient failure.
+    pub const SKIP: Self = Self {
+        retries: 0,
+        skip: true,
+    };
+
+    /// Retries a query up to `retries` times and skips the item if it keeps failing.
+    #[must_use]
+    pub const fn retry(retries: u32) -> Self {
+        Self {
+            retries,
+            skip: true,
+        }
+    }
+
+    /// Runs `query` according to the policy.
+    /// Returns `None` if the item is to be skipped.
+    /// # Errors
+    /// Returns the error of `query` if it is not transient, or if it is transient and the policy
+    /// does not skip the item.
+    pub fn run<T, E, F>(&self, mut query: F) -> Result<Option<T>, E>
+    where
+        E: TransientError,
+        F: FnMut() -> Result<T, E>,
+    {
+        let mut attempt = 0;
+        loop {
+            match query() {
+                Ok(value) => return Ok(Some(value)),
+                Err(error) if error.is_transient() && attempt < self.retries => attempt += 1,
+                Err(error) if error.is_transient() && self.skip => return Ok(None),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Skips the item on the first transient failure.
+    fn default() -> Self {
+        Self::SKIP
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `policy` on a query failing with `errors` in turn before succeeding, and returns the
+    /// result together with the number of attempts.
+    fn run(policy: RetryPolicy, errors: &[JvmTIError]) -> (Result<Option<u32>, JvmTIError>, usize) {
+        let mut attempts = 0;
+        let result = policy.run(|| {
+            attempts += 1;
+            errors.get(attempts - 1).map_or(Ok(42), |&error| Err(error))
+        });
+        (result, attempts)
+    }
+
+    #[test]
+    fn classifies_thread_races_as_transient() {
+        assert!(JvmTIError::ThreadNotAlive.is_transient());
+        assert!(JvmTIError::NoMoreFrames.is_transient());
+        assert!(ThreadError::InvalidThread.is_transient());
+        assert!(!JvmTIError::OutOfMemory.is_transient());
+    }
+
+    #[test]
+    fn succeeds_without_retrying() {
+        assert_eq!(run(RetryPolicy::FAIL, &[]), (Ok(Some(42)), 1));
+    }
+
+    #[test]
+    fn fails_or_skips_on_the_first_transient_failure() {
+        let errors = [JvmTIError::ThreadNotAlive];
+        assert_eq!(
+            run(RetryPolicy::FAIL, &errors),
+            (Err(JvmTIError::ThreadNotAlive), 1)
+        );
+        assert_eq!(run(RetryPolicy::SKIP, &errors), (Ok(None), 1));
+        assert_eq!(RetryPolicy::default(), RetryPolicy::SKIP);
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let errors = [JvmTIError::ThreadNotAlive, JvmTIError::NoMoreFrames];
+        assert_eq!(run(RetryPolicy::retry(2), &errors), (Ok(Some(42)), 3));
+        assert_eq!(run(RetryPolicy::retry(1), &errors), (Ok(None), 2));
+    }
+
+    #[test]
+    fn never_retries_other_failures() {
+        let errors = [JvmTIError::OutOfMemory];
+        assert_eq!(
+            run(RetryPolicy::retry(3), &errors),
+            (Err(JvmTIError::OutOfMemory), 1)
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! Destinations for the reports produced by the diagnostic subsystems.
+//!
+//! A [`ReportSink`] receives serialized records one at a time, e.g. the JSON objects written by
+//! [`ClassLoadGraph::write_records`](super::class_graph::ClassLoadGraph::write_records) or
+//! [`HeapHistogram::write_records`](super::heap_histogram::HeapHistogram::write_records), so that
+//! agents can route their output into files, sockets, or their own pipelines. The sinks provided
+//! here separate records with newlines where the transport is a byte stream.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    path::{Path, PathBuf},
+};
+
+/// A destination for serialized report records.
+pub trait ReportSink: Send {
+    /// Writes one record. The record must not contain the record separator of the sink, if any.
+    /// # Errors
+    /// Returns an error if the record cannot be written.
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()>;
+
+    /// Flushes the records written so far to the underlying destination.
+    /// # Errors
+    /// Returns an error if the records cannot be flushed.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Closes the current output and starts a new one, e.g. to keep log files small.
+    /// Sinks without a notion of separate outputs ignore this.
+    /// # Errors
+    /// Returns an error if the output cannot be rotated.
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: ReportSink + ?Sized> ReportSink for &mut S {
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        (**self).write_record(record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        (**self).rotate()
+    }
+}
+
+impl<S: ReportSink + ?Sized> ReportSink for Box<S> {
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        (**self).write_record(record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        (**self).rotate()
+    }
+}
+
+/// Returns the path a rotated output is moved to: `path` with `.1` appended.
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Appends newline-separated records to a file.
+/// Rotating moves the file to the same path with `.1` appended, replacing the previous one.
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl FileSink {
+    /// Opens `path` for appending, creating the file if it does not exist.
+    /// # Errors
+    /// Returns an error if the file cannot be opened.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let writer = BufWriter::new(append(&path)?);
+        Ok(Self { path, writer })
+    }
+}
+
+impl ReportSink for FileSink {
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.writer.write_all(record)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        self.writer = BufWriter::new(append(&self.path)?);
+        Ok(())
+    }
+}
+
+/// Writes newline-separated records to a gzip-compressed file.
+/// Rotating finishes the compressed stream and moves the file to the same path with `.1`
+/// appended, replacing the previous one.
+#[cfg(feature = "gzip")]
+#[derive(Debug)]
+pub struct GzipFileSink {
+    path: PathBuf,
+    level: flate2::Compression,
+    encoder: flate2::write::GzEncoder<BufWriter<File>>,
+}
+
+#[cfg(feature = "gzip")]
+impl GzipFileSink {
+    /// Creates or truncates `path` and compresses the records with the default level.
+    /// # Errors
+    /// Returns an error if the file cannot be created.
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        Self::with_level(path, flate2::Compression::default())
+    }
+
+    /// Creates or truncates `path` and compresses the records with the given level.
+    /// # Errors
+    /// Returns an error if the file cannot be created.
+    pub fn with_level(path: impl Into<PathBuf>, level: flate2::Compression) -> io::Result<Self> {
+        let path = path.into();
+        let encoder = Self::encoder(&path, level)?;
+        Ok(Self {
+            path,
+            level,
+            encoder,
+        })
+    }
+
+    fn encoder(
+        path: &Path,
+        level: flate2::Compression,
+    ) -> io::Result<flate2::write::GzEncoder<BufWriter<File>>> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(flate2::write::GzEncoder::new(file, level))
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl ReportSink for GzipFileSink {
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.encoder.write_all(record)?;
+        self.encoder.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.encoder.try_finish()?;
+        self.encoder.get_mut().flush()?;
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        self.encoder = Self::encoder(&self.path, self.level)?;
+        Ok(())
+    }
+}
+
+/// Sends each record as one UDP datagram.
+#[derive(Debug)]
+pub struct UdpSink {
+    socket: UdpSocket,
+}
+
+impl UdpSink {
+    /// Creates a sink sending to `address` from an ephemeral local port.
+    ///
+    /// Each address `address` resolves to is tried in turn, binding the socket to the unspecified
+    /// address of the same family.
+    /// # Errors
+    /// Returns an error if `address` cannot be resolved, or the socket cannot be bound or
+    /// connected.
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let mut last_error = None;
+        for address in address.to_socket_addrs()? {
+            let local: SocketAddr = if address.is_ipv4() {
+                (Ipv4Addr::UNSPECIFIED, 0).into()
+            } else {
+                (Ipv6Addr::UNSPECIFIED, 0).into()
+            };
+            let socket = UdpSocket::bind(local).and_then(|socket| {
+                socket.connect(address)?;
+                Ok(socket)
+            });
+            match socket {
+                Ok(socket) => return Ok(Self { socket }),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+}
+
+impl ReportSink for UdpSink {
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.socket.send(record).map(|_| ())
+    }
+}
+
+/// Streams newline-separated records over a TCP connection.
+#[derive(Debug)]
+pub struct TcpSink {
+    writer: BufWriter<TcpStream>,
+}
+
+impl TcpSink {
+    /// Connects to `address`.
+    /// # Errors
+    /// Returns an error if the connection cannot be established.
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        Ok(Self {
+            writer: BufWriter::new(stream),
+        })
+    }
+}
+
+impl ReportSink for TcpSink {
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.writer.write_all(record)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Hands each record to a callback, e.g. to forward it to an in-process pipeline.
+pub struct CallbackSink<F> {
+    callback: F,
+}
+
+impl<F> CallbackSink<F>
+where
+    F: FnMut(&[u8]) + Send,
+{
+    /// Creates a sink invoking `callback` with every record.
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F> ReportSink for CallbackSink<F>
+where
+    F: FnMut(&[u8]) + Send,
+{
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        (self.callback)(record);
+        Ok(())
+    }
+}
+
+impl<F> std::fmt::Debug for CallbackSink<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CallbackSink").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Creates an empty directory for the files of one test.
+    fn scratch_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("coffee-filter-sink-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).expect("create the test directory");
+        directory
+    }
+
+    #[test]
+    fn file_sink_appends_lines_and_rotates() {
+        let directory = scratch_directory("file");
+        let path = directory.join("report.jsonl");
+        let mut sink = FileSink::open(&path).expect("open the sink");
+        sink.write_record(b"{\"a\":1}").unwrap();
+        sink.rotate().unwrap();
+        sink.write_record(b"{\"b\":2}").unwrap();
+        sink.flush().unwrap();
+        assert_eq!(fs::read(rotated_path(&path)).unwrap(), b"{\"a\":1}\n");
+        assert_eq!(fs::read(&path).unwrap(), b"{\"b\":2}\n");
+
+        // Reopening appends to the existing file.
+        let mut sink = FileSink::open(&path).expect("reopen the sink");
+        sink.write_record(b"{\"c\":3}").unwrap();
+        sink.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"{\"b\":2}\n{\"c\":3}\n");
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_sink_compresses_lines() {
+        use std::io::Read;
+
+        let directory = scratch_directory("gzip");
+        let path = directory.join("report.jsonl.gz");
+        let mut sink = GzipFileSink::create(&path).expect("create the sink");
+        sink.write_record(b"first").unwrap();
+        sink.write_record(b"second").unwrap();
+        sink.rotate().unwrap();
+        let mut lines = String::new();
+        flate2::read::GzDecoder::new(File::open(rotated_path(&path)).unwrap())
+            .read_to_string(&mut lines)
+            .unwrap();
+        assert_eq!(lines, "first\nsecond\n");
+        let _ = fs::remove_dir_all(directory);
+    }
+
+    #[test]
+    fn udp_sink_sends_a_datagram_per_record() {
+        let receiver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut sink = UdpSink::connect(receiver.local_addr().unwrap()).expect("connect the sink");
+        sink.write_record(b"record").unwrap();
+        let mut buffer = [0; 16];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"record");
+    }
+
+    #[test]
+    fn tcp_sink_streams_lines() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut sink = TcpSink::connect(listener.local_addr().unwrap()).expect("connect the sink");
+        let (stream, _) = listener.accept().unwrap();
+        sink.write_record(b"first").unwrap();
+        sink.write_record(b"second").unwrap();
+        sink.flush().unwrap();
+        drop(sink);
+        let lines: Vec<_> = BufReader::new(stream).lines().map(Result::unwrap).collect();
+        assert_eq!(lines, ["first", "second"]);
+    }
+
+    #[test]
+    fn callback_sink_forwards_through_references_and_boxes() {
+        fn write_through<S: ReportSink>(mut sink: S, record: &[u8]) {
+            sink.write_record(record).unwrap();
+        }
+
+        let mut records = Vec::new();
+        {
+            let mut sink = CallbackSink::new(|it: &[u8]| records.push(it.to_vec()));
+            write_through(&mut sink, b"borrowed");
+            let mut boxed: Box<dyn ReportSink + '_> = Box::new(sink);
+            boxed.write_record(b"boxed").unwrap();
+            boxed.rotate().unwrap();
+        }
+        assert_eq!(records, [b"borrowed".to_vec(), b"boxed".to_vec()]);
+    }
+}
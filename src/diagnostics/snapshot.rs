@@ -0,0 +1,379 @@
+//! Consistent snapshots of the whole VM.
+//!
+//! [`snapshot`] suspends all the other threads, collects their states, stacks, and monitors
+//! together with a class and heap summary, and resumes them, so that the data in a [`VmSnapshot`]
+//! comes from a single instant. This lets tools reliably correlate threads with the locks they
+//! hold and wait for.
+//!
+//! Taking a snapshot requires the `can_suspend` capability. The monitor information requires the
+//! `can_get_owned_monitor_info` and `can_get_current_contended_monitor` capabilities, and the heap
+//! summary requires the `can_tag_objects` capability; the corresponding parts of the snapshot are
+//! left empty when they are missing.
+
+use std::{fmt::Write, io, time::SystemTime};
+
+use crate::jvm::{
+    errors::{JvmTIError, ThreadError},
+    jni::JNI,
+    objects::Object,
+    strings::ModifiedUtf8Ext,
+    threads::{Thread, ThreadState},
+    Jvm,
+};
+
+use super::{
+    binary_name, correlation::CorrelationId, escape_json, retry::RetryPolicy, sink::ReportSink,
+};
+
+/// A frame on the stack of a thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameSnapshot {
+    /// The binary name of the class declaring the method.
+    pub class_name: String,
+    /// The name of the method.
+    pub method_name: String,
+    /// The location of the executing instruction, or `-1` for native methods.
+    pub location: i64,
+}
+
+/// The state of a thread at the time of a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadSnapshot {
+    /// The name of the thread.
+    pub name: String,
+    /// The correlation ID attached to the thread.
+    pub correlation_id: Option<CorrelationId>,
+    /// The state of the thread.
+    pub state: ThreadState,
+    /// Whether the thread was suspended while the snapshot was taken. The data of threads that
+    /// could not be suspended, e.g. the thread taking the snapshot, may be inconsistent.
+    pub suspended: bool,
+    /// The frames on the stack of the thread, innermost first.
+    pub frames: Vec<FrameSnapshot>,
+    /// The identity hash codes of the monitors owned by the thread, or `None` if unavailable.
+    pub owned_monitors: Option<Vec<i32>>,
+    /// The identity hash code of the monitor the thread is waiting for, if any.
+    pub contended_monitor: Option<i32>,
+}
+
+/// A summary of the objects in the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapSummary {
+    /// The number of objects.
+    pub objects: u64,
+    /// The total size of the objects in bytes.
+    pub bytes: u64,
+}
+
+/// A consistent snapshot of the threads, classes, and heap of the VM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSnapshot {
+    /// When the snapshot was taken.
+    pub taken_at: SystemTime,
+    /// The live platform threads.
+    pub threads: Vec<ThreadSnapshot>,
+    /// The number of loaded classes.
+    pub loaded_classes: usize,
+    /// A summary of the heap, or `None` if unavailable.
+    pub heap: Option<HeapSummary>,
+}
+
+impl VmSnapshot {
+    /// Serializes the snapshot as JSON. Monitors are identified by their identity hash codes.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let taken_at = self
+            .taken_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut json = format!(
+            "{{\"taken_at_ms\":{taken_at},\"loaded_classes\":{},\"heap\":",
+            self.loaded_classes
+        );
+        match self.heap {
+            Some(heap) => {
+                let _ = write!(
+                    json,
+                    "{{\"objects\":{},\"bytes\":{}}}",
+                    heap.objects, heap.bytes
+                );
+            }
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"threads\":[");
+        for (index, thread) in self.threads.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"name\":{},\"correlation_id\":{},\"state\":{},\"suspended\":{},\"owned_monitors\":{},\"contended_monitor\":{},\"frames\":[",
+                escape_json(&thread.name),
+                CorrelationId::to_json(thread.correlation_id),
+                thread.state.bits(),
+                thread.suspended,
+                thread.owned_monitors.as_ref().map_or_else(
+                    || "null".to_owned(),
+                    |it| format!(
+                        "[{}]",
+                        it.iter().map(ToString::to_string).collect::<Vec<_>>().join(",")
+                    )
+                ),
+                thread
+                    .contended_monitor
+                    .map_or_else(|| "null".to_owned(), |it| it.to_string()),
+            );
+            for (index, frame) in thread.frames.iter().enumerate() {
+                if index > 0 {
+                    json.push(',');
+                }
+                let _ = write!(
+                    json,
+                    "{{\"class\":{},\"method\":{},\"location\":{}}}",
+                    escape_json(&frame.class_name),
+                    escape_json(&frame.method_name),
+                    frame.location
+                );
+            }
+            json.push_str("]}");
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Writes the snapshot to `sink` as a single JSON record and flushes the sink.
+    /// # Errors
+    /// Returns an error if the sink fails.
+    pub fn write_to<S: ReportSink + ?Sized>(&self, sink: &mut S) -> io::Result<()> {
+        sink.write_record(self.to_json().as_bytes())?;
+        sink.flush()
+    }
+}
+
+/// The live platform threads of the VM while all of them, except the calling thread and the
+/// threads started with [`Jvm::spawn_agent_thread`], are suspended.
+/// See [`Jvm::with_all_threads_suspended`].
+#[derive(Debug)]
+pub struct SuspendedThreads<'j> {
+    jvm: &'j Jvm,
+    threads: Vec<Thread<'j>>,
+    suspended: Vec<bool>,
+}
+
+impl<'j> SuspendedThreads<'j> {
+    /// Gets all the live platform threads, including those that are not suspended.
+    #[must_use]
+    pub fn threads(&self) -> &[Thread<'j>] {
+        &self.threads
+    }
+
+    /// Iterates over the threads that are suspended.
+    pub fn suspended(&self) -> impl Iterator<Item = &Thread<'j>> {
+        self.iter()
+            .filter_map(|(thread, suspended)| suspended.then_some(thread))
+    }
+
+    /// Iterates over all the threads together with whether each of them is suspended. The data
+    /// of the threads that are not suspended, e.g. the calling thread, may be inconsistent.
+    pub fn iter(&self) -> impl Iterator<Item = (&Thread<'j>, bool)> {
+        self.threads.iter().zip(self.suspended.iter().copied())
+    }
+}
+
+impl Drop for SuspendedThreads<'_> {
+    fn drop(&mut self) {
+        let suspended: Vec<_> = self.suspended().collect();
+        // Nothing sensible can be done if resuming fails in a destructor.
+        let _ = self.jvm.resume_thread_list(&suspended);
+    }
+}
+
+impl Jvm {
+    /// Suspends all the live platform threads except the calling thread and the threads started
+    /// with [`Jvm::spawn_agent_thread`], runs `body`, and resumes them, even if `body` panics.
+    /// Threads that end or cannot be suspended in the meantime are reported as not suspended.
+    ///
+    /// `body` must not wait for any of the suspended threads, e.g. by entering a monitor they own.
+    /// # Errors
+    /// Returns an error if the threads cannot be listed or suspended, e.g. because the
+    /// `can_suspend` capability is missing. See [`ThreadError`] for more information.
+    pub fn with_all_threads_suspended<R>(
+        &self,
+        jni: &JNI,
+        body: impl FnOnce(&SuspendedThreads<'_>) -> R,
+    ) -> Result<R, ThreadError> {
+        let threads = self.get_all_threads()?;
+        let current = self.current_thread()?;
+        let targets: Vec<_> = threads
+            .iter()
+            .enumerate()
+            .filter(|(_, thread)| {
+                let is_current = current
+                    .as_ref()
+                    .is_some_and(|current| thread.is_same(jni, current));
+                !is_current && !self.is_agent_thread(jni, thread)
+            })
+            .collect();
+        let results =
+            self.suspend_thread_list(&targets.iter().map(|&(_, it)| it).collect::<Vec<_>>())?;
+        let mut suspended = vec![false; threads.len()];
+        for (&(index, _), result) in targets.iter().zip(results) {
+            suspended[index] = result.is_ok();
+        }
+        let suspended = SuspendedThreads {
+            jvm: self,
+            threads,
+            suspended,
+        };
+        Ok(body(&suspended))
+    }
+}
+
+/// Takes a consistent snapshot of the VM with up to `max_depth` frames per thread.
+/// The threads are suspended with [`Jvm::with_all_threads_suspended`] while the data is
+/// collected. Threads that end before they are inspected are left out.
+/// # Errors
+/// Returns an error if the threads cannot be listed or suspended.
+pub fn snapshot(jvm: &Jvm, jni: &JNI, max_depth: usize) -> Result<VmSnapshot, JvmTIError> {
+    snapshot_with_policy(jvm, jni, max_depth, RetryPolicy::default())
+}
+
+/// Takes a consistent snapshot like [`snapshot`], handling threads that race with the snapshot
+/// according to `policy`.
+/// # Errors
+/// Returns an error if the threads cannot be listed or suspended, or if inspecting a thread fails
+/// and `policy` does not skip it.
+pub fn snapshot_with_policy(
+    jvm: &Jvm,
+    jni: &JNI,
+    max_depth: usize,
+    policy: RetryPolicy,
+) -> Result<VmSnapshot, JvmTIError> {
+    jvm.with_all_threads_suspended(jni, |threads| {
+        let taken_at = SystemTime::now();
+        let mut thread_snapshots = Vec::with_capacity(threads.threads().len());
+        for (thread, suspended) in threads.iter() {
+            if let Some(snapshot) = policy.run(|| thread_snapshot(thread, suspended, max_depth))? {
+                thread_snapshots.push(snapshot);
+            }
+        }
+        let loaded_classes = jvm.get_loaded_classes()?.len();
+        let heap = jvm
+            .heap_totals()
+            .ok()
+            .map(|(objects, bytes)| HeapSummary { objects, bytes });
+        Ok(VmSnapshot {
+            taken_at,
+            threads: thread_snapshots,
+            loaded_classes,
+            heap,
+        })
+    })?
+}
+
+fn thread_snapshot(
+    thread: &Thread<'_>,
+    suspended: bool,
+    max_depth: usize,
+) -> Result<ThreadSnapshot, ThreadError> {
+    let info = thread.info()?;
+    let state = thread.state()?;
+    let frames = thread
+        .stack_trace(max_depth)
+        .map_err(|error| ThreadError::from(JvmTIError::from(error)))?
+        .into_iter()
+        .map(|frame| FrameSnapshot {
+            class_name: frame
+                .method
+                .declaring_class()
+                .ok()
+                .and_then(|it| it.signature().ok())
+                .map_or_else(|| "<unknown>".to_owned(), |it| binary_name(&it)),
+            method_name: frame.method.name().map_or_else(
+                |_| "<unknown>".to_owned(),
+                |it| it.to_utf8_lossy().into_owned(),
+            ),
+            location: frame.location,
+        })
+        .collect();
+    let monitor_id = |monitor: &Object<'_>| monitor.identity_hash().ok();
+    let owned_monitors = thread
+        .owned_monitors()
+        .ok()
+        .map(|it| it.iter().filter_map(monitor_id).collect());
+    let contended_monitor = thread
+        .contended_monitor()
+        .ok()
+        .flatten()
+        .as_ref()
+        .and_then(monitor_id);
+    Ok(ThreadSnapshot {
+        name: info.name.to_utf8_lossy().into_owned(),
+        correlation_id: CorrelationId::stamp(thread),
+        state,
+        suspended,
+        frames,
+        owned_monitors,
+        contended_monitor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::diagnostics::sink::CallbackSink;
+
+    fn snapshot() -> VmSnapshot {
+        VmSnapshot {
+            taken_at: SystemTime::UNIX_EPOCH + Duration::from_millis(42),
+            threads: vec![ThreadSnapshot {
+                name: "main".to_owned(),
+                correlation_id: None,
+                state: ThreadState::ALIVE | ThreadState::RUNNABLE,
+                suspended: true,
+                frames: vec![FrameSnapshot {
+                    class_name: "app.Main".to_owned(),
+                    method_name: "main".to_owned(),
+                    location: 3,
+                }],
+                owned_monitors: Some(vec![1, 2]),
+                contended_monitor: None,
+            }],
+            loaded_classes: 7,
+            heap: None,
+        }
+    }
+
+    #[test]
+    fn serializes_snapshots_as_json() {
+        let mut snapshot = snapshot();
+        assert_eq!(
+            snapshot.to_json(),
+            r#"{"taken_at_ms":42,"loaded_classes":7,"heap":null,"threads":[{"name":"main","correlation_id":null,"state":5,"suspended":true,"owned_monitors":[1,2],"contended_monitor":null,"frames":[{"class":"app.Main","method":"main","location":3}]}]}"#
+        );
+        snapshot.heap = Some(HeapSummary {
+            objects: 10,
+            bytes: 160,
+        });
+        snapshot.threads.clear();
+        assert_eq!(
+            snapshot.to_json(),
+            r#"{"taken_at_ms":42,"loaded_classes":7,"heap":{"objects":10,"bytes":160},"threads":[]}"#
+        );
+    }
+
+    #[test]
+    fn writes_one_record() {
+        let snapshot = snapshot();
+        let mut records = Vec::new();
+        snapshot
+            .write_to(&mut CallbackSink::new(|it: &[u8]| {
+                records.push(it.to_vec());
+            }))
+            .expect("the sink does not fail");
+        assert_eq!(records, [snapshot.to_json().into_bytes()]);
+    }
+}
@@ -0,0 +1,220 @@
+//! Single-stepping of a thread with step-into, step-over, and step-out semantics.
+//!
+//! A [`StepSession`] enables the `SingleStep` event for exactly one thread and decides, from the
+//! frame count and the source line of each step, when the step requested by a [`StepKind`] is
+//! complete. Calls are stepped over by waiting for the `FramePop` event of the callee instead of
+//! single-stepping through it. The event is disabled when the step completes or the session is
+//! dropped.
+//!
+//! Forward the `SingleStep` and `FramePop` events to [`StepSession::on_single_step`] and
+//! [`StepSession::on_frame_pop`]. Stepping requires the `can_generate_single_step_events` and
+//! `can_generate_frame_pop_events` capabilities, and stepping by source line requires the
+//! `can_get_line_numbers` capability; without it, every instruction counts as a new line.
+
+use std::sync::{Mutex, PoisonError};
+
+use crate::{
+    jvm::{
+        errors::JvmTIError,
+        events::{EventMode, JvmTIEvent},
+        jni::{GlobalRef, JNI},
+        methods::Method,
+        threads::Thread,
+        Jvm,
+    },
+    sys,
+};
+
+/// How far a [`StepSession`] steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StepKind {
+    /// Stops at the next line, including the first line of a called method.
+    Into,
+    /// Stops at the next line of the current method, running called methods to completion.
+    Over,
+    /// Stops once the current method has returned to its caller.
+    Out,
+}
+
+/// Where a [`StepSession`] stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// The `SingleStep` event is enabled and each step is checked for completion.
+    Stepping,
+    /// The `SingleStep` event is disabled until the frame at the given frame count is popped.
+    AwaitingFramePop { frame_count: usize },
+    /// The step is complete.
+    Done,
+}
+
+/// Where the step started and how far it got.
+#[derive(Debug)]
+struct StepState {
+    phase: Phase,
+    /// The number of frames on the stack when the step started.
+    frame_count: usize,
+    /// The `jmethodID` of the method executing when the step started.
+    method: usize,
+    /// The location and the source line when the step started.
+    location: sys::jlocation,
+    line: Option<u32>,
+}
+
+/// A step of a single thread. See the [module documentation](self).
+#[derive(Debug)]
+pub struct StepSession<'j> {
+    jvm: &'j Jvm,
+    thread: GlobalRef<Thread<'static>>,
+    kind: StepKind,
+    state: Mutex<StepState>,
+}
+
+impl<'j> StepSession<'j> {
+    /// Starts a step of `kind` from the current location of `thread`, which must be the current
+    /// thread or suspended. The step starts running when the thread runs.
+    /// # Errors
+    /// Returns an error if the stack of the thread cannot be inspected or the events cannot be
+    /// enabled, e.g. because a required capability is missing.
+    pub fn start(
+        jvm: &'j Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        kind: StepKind,
+    ) -> Result<Self, JvmTIError> {
+        let frame_count = thread.frame_count()?;
+        let frame = thread.frame(0)?.location()?;
+        let line = frame.method.line_number_at(frame.location).ok().flatten();
+        // SAFETY: `thread.jthread` is a valid, non-null reference.
+        let global =
+            unsafe { GlobalRef::from_raw(jni, thread.jthread) }.ok_or(JvmTIError::OutOfMemory)?;
+        let phase = if kind == StepKind::Out {
+            thread.notify_frame_pop(0)?;
+            Phase::AwaitingFramePop { frame_count }
+        } else {
+            jvm.set_thread_event_mode(EventMode::Enable, JvmTIEvent::SingleStep, thread)?;
+            Phase::Stepping
+        };
+        Ok(Self {
+            jvm,
+            thread: global,
+            kind,
+            state: Mutex::new(StepState {
+                phase,
+                frame_count,
+                method: frame.method.as_raw().addr(),
+                location: frame.location,
+                line,
+            }),
+        })
+    }
+
+    /// Gets the kind of the step.
+    #[must_use]
+    pub fn kind(&self) -> StepKind {
+        self.kind
+    }
+
+    /// Returns whether the step is complete.
+    pub fn is_done(&self) -> bool {
+        self.lock().phase == Phase::Done
+    }
+
+    /// Handles a `SingleStep` event and returns whether it completes the step, in which case the
+    /// thread is at the location where a debugger should stop. Events of other threads are
+    /// ignored.
+    /// # Errors
+    /// Returns an error if the stack of the thread cannot be inspected or the events cannot be
+    /// controlled.
+    pub fn on_single_step(
+        &self,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        method: &Method<'_>,
+        location: sys::jlocation,
+    ) -> Result<bool, JvmTIError> {
+        if !thread.is_same(jni, &self.thread.get(self.jvm)) {
+            return Ok(false);
+        }
+        let mut state = self.lock();
+        if state.phase != Phase::Stepping {
+            return Ok(false);
+        }
+        let frame_count = thread.frame_count()?;
+        let done = match self.kind {
+            StepKind::Out => frame_count < state.frame_count,
+            StepKind::Into => {
+                frame_count != state.frame_count || state.is_new_line(method, location)
+            }
+            StepKind::Over if frame_count > state.frame_count => {
+                // Run the callee to completion instead of stepping through it.
+                thread.notify_frame_pop(0)?;
+                self.jvm.set_thread_event_mode(
+                    EventMode::Disable,
+                    JvmTIEvent::SingleStep,
+                    thread,
+                )?;
+                state.phase = Phase::AwaitingFramePop { frame_count };
+                return Ok(false);
+            }
+            StepKind::Over => {
+                frame_count < state.frame_count || state.is_new_line(method, location)
+            }
+        };
+        if done {
+            state.phase = Phase::Done;
+            self.jvm
+                .set_thread_event_mode(EventMode::Disable, JvmTIEvent::SingleStep, thread)?;
+        }
+        Ok(done)
+    }
+
+    /// Handles a `FramePop` event, resuming single-stepping once the frame the step waits for
+    /// is popped. Events of other threads and other frames are ignored.
+    /// # Errors
+    /// Returns an error if the stack of the thread cannot be inspected or the events cannot be
+    /// controlled.
+    pub fn on_frame_pop(&self, jni: &JNI, thread: &Thread<'_>) -> Result<(), JvmTIError> {
+        if !thread.is_same(jni, &self.thread.get(self.jvm)) {
+            return Ok(());
+        }
+        let mut state = self.lock();
+        let Phase::AwaitingFramePop { frame_count } = state.phase else {
+            return Ok(());
+        };
+        // The popped frame is still on the stack while the event is reported.
+        if thread.frame_count()? == frame_count {
+            self.jvm
+                .set_thread_event_mode(EventMode::Enable, JvmTIEvent::SingleStep, thread)?;
+            state.phase = Phase::Stepping;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, StepState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl StepState {
+    /// Returns whether `location` in `method` is on another line than the start of the step.
+    fn is_new_line(&self, method: &Method<'_>, location: sys::jlocation) -> bool {
+        if method.as_raw().addr() != self.method {
+            return true;
+        }
+        match (self.line, method.line_number_at(location).ok().flatten()) {
+            (Some(start), Some(current)) => start != current,
+            _ => location != self.location,
+        }
+    }
+}
+
+impl Drop for StepSession<'_> {
+    fn drop(&mut self) {
+        // The thread may have ended, in which case there is nothing left to disable.
+        let _ = self.jvm.set_thread_event_mode(
+            EventMode::Disable,
+            JvmTIEvent::SingleStep,
+            &self.thread.get(self.jvm),
+        );
+    }
+}
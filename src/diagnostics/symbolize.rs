@@ -0,0 +1,219 @@
+//! Resolution of raw stack frames into readable call sites.
+//!
+//! A [`Frame`] only carries a method ID and a bytecode location. [`Symbolizer`] turns it into a
+//! [`SymbolizedFrame`] with the declaring class, the method name and signature, and the source
+//! line, and caches what it learns about each method so that symbolizing many stack traces only
+//! queries the VM once per method.
+//!
+//! The line numbers require the `can_get_line_numbers` capability; they are left out when it is
+//! missing or the class was compiled without them.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+};
+
+use crate::jvm::{
+    errors::{JvmTIError, MethodError},
+    methods::{LineNumberEntry, Method},
+    stack::Frame,
+    strings::ModifiedUtf8Ext,
+};
+
+use super::binary_name;
+
+/// A stack frame resolved into its class, method, and source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolizedFrame {
+    /// The binary name of the class declaring the method, e.g. `com.example.Foo`.
+    pub class_name: String,
+    /// The name of the method.
+    pub method_name: String,
+    /// The signature of the method, e.g. `(I)V`.
+    pub signature: String,
+    /// The location of the executing instruction, or `-1` for native methods.
+    pub location: i64,
+    /// The source line of the executing instruction, or `None` if unknown.
+    pub line_number: Option<u32>,
+}
+
+impl SymbolizedFrame {
+    /// Returns whether the method is native.
+    #[must_use]
+    pub fn is_native(&self) -> bool {
+        self.location == -1
+    }
+}
+
+/// Formats the frame as `ClassName.methodName(signature) : lineNumber`, with `native` or
+/// `unknown` in place of the line number when there is none.
+impl fmt::Display for SymbolizedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}{} : ",
+            self.class_name, self.method_name, self.signature
+        )?;
+        match self.line_number {
+            Some(line) => write!(f, "{line}"),
+            None if self.is_native() => f.write_str("native"),
+            None => f.write_str("unknown"),
+        }
+    }
+}
+
+/// What is known about a method.
+#[derive(Debug)]
+struct ResolvedMethod {
+    class_name: String,
+    method_name: String,
+    signature: String,
+    /// The line number table sorted by location, empty if unavailable.
+    lines: Vec<LineNumberEntry>,
+}
+
+impl ResolvedMethod {
+    fn resolve(method: &Method<'_>) -> Result<Self, JvmTIError> {
+        let class_name = binary_name(&method.declaring_class()?.signature()?);
+        let method_name = method.name()?.to_utf8_lossy().into_owned();
+        let signature = method.signature()?.to_utf8_lossy().into_owned();
+        let mut lines = match method.line_number_table() {
+            Ok(it) => it,
+            Err(
+                MethodError::AbsentInformation
+                | MethodError::NativeMethod
+                | MethodError::MustPossessCapability,
+            ) => Vec::new(),
+            Err(error) => return Err(error.into()),
+        };
+        lines.sort_unstable_by_key(|it| it.start_location);
+        Ok(Self {
+            class_name,
+            method_name,
+            signature,
+            lines,
+        })
+    }
+
+    /// Gets the line containing `location`, i.e. the last line starting at or before it.
+    fn line_at(&self, location: i64) -> Option<u32> {
+        let index = self
+            .lines
+            .partition_point(|it| it.start_location <= location);
+        index.checked_sub(1).map(|it| self.lines[it].line_number)
+    }
+}
+
+/// Resolves stack frames into [`SymbolizedFrame`]s, caching the resolved methods.
+///
+/// Method IDs are only valid while their class is loaded and may be reused after it is unloaded,
+/// so a long-lived symbolizer should be [cleared](Symbolizer::clear) when classes are unloaded.
+#[derive(Debug, Default)]
+pub struct Symbolizer {
+    methods: HashMap<usize, ResolvedMethod>,
+}
+
+impl Symbolizer {
+    /// Creates a symbolizer with an empty cache.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolves `frame`, querying the VM only if its method has not been resolved before.
+    /// # Errors
+    /// Returns an error if the method cannot be inspected, e.g. because its class was unloaded.
+    pub fn symbolize(&mut self, frame: &Frame<'_>) -> Result<SymbolizedFrame, JvmTIError> {
+        let key = frame.method.as_raw().addr();
+        let method = match self.methods.entry(key) {
+            Entry::Occupied(it) => it.into_mut(),
+            Entry::Vacant(it) => it.insert(ResolvedMethod::resolve(&frame.method)?),
+        };
+        Ok(SymbolizedFrame {
+            class_name: method.class_name.clone(),
+            method_name: method.method_name.clone(),
+            signature: method.signature.clone(),
+            location: frame.location,
+            line_number: method.line_at(frame.location),
+        })
+    }
+
+    /// Resolves all of `frames`, e.g. a stack trace from [`Thread::stack_trace`].
+    /// # Errors
+    /// Returns an error if any of the methods cannot be inspected.
+    ///
+    /// [`Thread::stack_trace`]: crate::jvm::threads::Thread::stack_trace
+    pub fn symbolize_all(
+        &mut self,
+        frames: &[Frame<'_>],
+    ) -> Result<Vec<SymbolizedFrame>, JvmTIError> {
+        frames.iter().map(|it| self.symbolize(it)).collect()
+    }
+
+    /// Gets the number of cached methods.
+    #[must_use]
+    pub fn cached_methods(&self) -> usize {
+        self.methods.len()
+    }
+
+    /// Forgets all the resolved methods.
+    pub fn clear(&mut self) {
+        self.methods.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(location: i64, line_number: Option<u32>) -> SymbolizedFrame {
+        SymbolizedFrame {
+            class_name: "com.example.Foo".to_owned(),
+            method_name: "bar".to_owned(),
+            signature: "(I)V".to_owned(),
+            location,
+            line_number,
+        }
+    }
+
+    #[test]
+    fn displays_frames() {
+        assert_eq!(
+            frame(4, Some(12)).to_string(),
+            "com.example.Foo.bar(I)V : 12"
+        );
+        assert_eq!(
+            frame(-1, None).to_string(),
+            "com.example.Foo.bar(I)V : native"
+        );
+        assert_eq!(
+            frame(4, None).to_string(),
+            "com.example.Foo.bar(I)V : unknown"
+        );
+    }
+
+    #[test]
+    fn finds_the_line_of_a_location() {
+        let method = ResolvedMethod {
+            class_name: String::new(),
+            method_name: String::new(),
+            signature: String::new(),
+            lines: [(0, 10), (5, 11), (9, 14)]
+                .into_iter()
+                .map(|(start_location, line_number)| LineNumberEntry {
+                    start_location,
+                    line_number,
+                })
+                .collect(),
+        };
+        let lines: Vec<_> = [0, 4, 5, 8, 9, 100]
+            .into_iter()
+            .map(|it| method.line_at(it))
+            .collect();
+        assert_eq!(
+            lines,
+            [Some(10), Some(10), Some(11), Some(11), Some(14), Some(14)]
+        );
+        assert_eq!(method.line_at(-1), None);
+    }
+}
@@ -0,0 +1,415 @@
+//! Thread dumps in the text format of `jstack`.
+//!
+//! [`thread_dump`] collects the state, the stack, and the monitors of every live platform thread
+//! into a [`ThreadDump`], which can be inspected directly or rendered with
+//! [`ThreadDump::to_jstack`] for tools and people used to `jstack` output.
+//!
+//! The threads are suspended while the dump is taken if the `can_suspend` capability is available.
+//! The monitors require the `can_get_owned_monitor_stack_depth_info` and
+//! `can_get_current_contended_monitor` capabilities; they are left out when these are missing.
+
+use std::{fmt::Write, io, time::SystemTime};
+
+use crate::jvm::{
+    errors::{JvmTIError, ThreadError},
+    jni::JNI,
+    objects::Object,
+    strings::ModifiedUtf8Ext,
+    threads::{Thread, ThreadState},
+    Jvm,
+};
+
+use super::{binary_name, correlation::CorrelationId, escape_json, sink::ReportSink};
+
+/// A monitor in a thread dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpedMonitor {
+    /// The identity hash code of the monitor, or `None` if unavailable.
+    pub hash_code: Option<i32>,
+    /// The binary name of the class of the monitor.
+    pub class_name: String,
+}
+
+/// A frame in a thread dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpedFrame {
+    /// The binary name of the class declaring the method.
+    pub class_name: String,
+    /// The name of the method.
+    pub method_name: String,
+    /// The location of the executing instruction, or `-1` for native methods.
+    pub location: i64,
+    /// The monitors locked in this frame.
+    pub locked: Vec<DumpedMonitor>,
+}
+
+/// A thread in a thread dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpedThread {
+    /// The name of the thread.
+    pub name: String,
+    /// The correlation ID attached to the thread.
+    pub correlation_id: Option<CorrelationId>,
+    /// The priority of the thread.
+    pub priority: i32,
+    /// Whether the thread is a daemon thread.
+    pub is_daemon: bool,
+    /// The state of the thread.
+    pub state: ThreadState,
+    /// The frames on the stack of the thread, innermost first.
+    pub frames: Vec<DumpedFrame>,
+    /// The monitor the thread is waiting to enter or waiting on, if any.
+    pub contended: Option<DumpedMonitor>,
+    /// The monitors owned by the thread that were not locked by a frame, e.g. with JNI
+    /// `MonitorEnter`.
+    pub other_locked: Vec<DumpedMonitor>,
+}
+
+/// The threads of the VM at one point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadDump {
+    /// When the dump was taken.
+    pub taken_at: SystemTime,
+    /// The live platform threads.
+    pub threads: Vec<DumpedThread>,
+}
+
+impl ThreadDump {
+    /// Renders the dump in the text format of `jstack`. Monitors are identified by their identity
+    /// hash codes instead of their addresses, and the line numbers are not known. The correlation
+    /// ID of a thread is appended to its header line as `correlation_id=<id>`.
+    #[must_use]
+    pub fn to_jstack(&self) -> String {
+        let mut text = String::from("Full thread dump:\n");
+        for thread in &self.threads {
+            let daemon = if thread.is_daemon { " daemon" } else { "" };
+            let correlation_id = thread
+                .correlation_id
+                .map_or_else(String::new, |id| format!(" correlation_id={id}"));
+            let _ = write!(
+                text,
+                "\n\"{}\"{daemon} prio={}{correlation_id}\n   java.lang.Thread.State: {}\n",
+                thread.name,
+                thread.priority,
+                java_state(thread.state)
+            );
+            for (depth, frame) in thread.frames.iter().enumerate() {
+                let source = if frame.location == -1 {
+                    "Native Method"
+                } else {
+                    "Unknown Source"
+                };
+                let _ = writeln!(
+                    text,
+                    "\tat {}.{}({source})",
+                    frame.class_name, frame.method_name
+                );
+                if depth == 0 {
+                    if let Some(monitor) = &thread.contended {
+                        let action = if thread.state.is_blocked_on_monitor() {
+                            "waiting to lock"
+                        } else {
+                            "waiting on"
+                        };
+                        let _ = writeln!(text, "\t- {action} {}", describe(monitor));
+                    }
+                }
+                for monitor in &frame.locked {
+                    let _ = writeln!(text, "\t- locked {}", describe(monitor));
+                }
+            }
+        }
+        text
+    }
+}
+
+impl ThreadDump {
+    /// Writes each thread to `sink` as a JSON object with the time of the dump, the `name`,
+    /// `correlation_id`, `priority`, `daemon` flag, and `state` of the thread, its `frames`, and the
+    /// monitor it is `contended` on, and flushes the sink. The state is spelled like in
+    /// [`ThreadDump::to_jstack`].
+    /// # Errors
+    /// Returns an error if the sink fails.
+    pub fn write_records<S: ReportSink + ?Sized>(&self, sink: &mut S) -> io::Result<()> {
+        let taken_at = self
+            .taken_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        for thread in &self.threads {
+            sink.write_record(thread.to_json(taken_at).as_bytes())?;
+        }
+        sink.flush()
+    }
+}
+
+impl DumpedThread {
+    /// Serializes the thread as a JSON object, stamped with `taken_at` in milliseconds since the
+    /// Unix epoch.
+    fn to_json(&self, taken_at: u128) -> String {
+        let mut json = format!(
+            "{{\"taken_at_ms\":{taken_at},\"name\":{},\"correlation_id\":{},\"priority\":{},\"daemon\":{},\"state\":{},\"frames\":[",
+            escape_json(&self.name),
+            CorrelationId::to_json(self.correlation_id),
+            self.priority,
+            self.is_daemon,
+            escape_json(java_state(self.state))
+        );
+        for (index, frame) in self.frames.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"class\":{},\"method\":{},\"location\":{},\"locked\":{}}}",
+                escape_json(&frame.class_name),
+                escape_json(&frame.method_name),
+                frame.location,
+                monitors_json(&frame.locked)
+            );
+        }
+        let _ = write!(
+            json,
+            "],\"contended\":{},\"other_locked\":{}}}",
+            self.contended
+                .as_ref()
+                .map_or_else(|| "null".to_owned(), DumpedMonitor::to_json),
+            monitors_json(&self.other_locked)
+        );
+        json
+    }
+}
+
+impl DumpedMonitor {
+    /// Serializes the monitor as a JSON object.
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"hash_code\":{},\"class\":{}}}",
+            self.hash_code
+                .map_or_else(|| "null".to_owned(), |it| it.to_string()),
+            escape_json(&self.class_name)
+        )
+    }
+}
+
+/// Serializes `monitors` as a JSON array.
+fn monitors_json(monitors: &[DumpedMonitor]) -> String {
+    let monitors: Vec<_> = monitors.iter().map(DumpedMonitor::to_json).collect();
+    format!("[{}]", monitors.join(","))
+}
+
+/// Formats `monitor` like `<0x1b6d3586> (a java.lang.Object)`.
+fn describe(monitor: &DumpedMonitor) -> String {
+    let id = monitor
+        .hash_code
+        .map_or_else(|| "<unknown>".to_owned(), |it| format!("<0x{it:08x}>"));
+    format!("{id} (a {})", monitor.class_name)
+}
+
+/// Gets the `java.lang.Thread.State` of `state` as `jstack` prints it.
+fn java_state(state: ThreadState) -> &'static str {
+    if state.is_new() {
+        "NEW"
+    } else if state.is_terminated() {
+        "TERMINATED"
+    } else if state.is_blocked_on_monitor() {
+        "BLOCKED (on object monitor)"
+    } else if state.is_waiting() {
+        let timed = state.contains(ThreadState::WAITING_WITH_TIMEOUT);
+        match (timed, state) {
+            (true, it) if it.is_sleeping() => "TIMED_WAITING (sleeping)",
+            (true, it) if it.is_parked() => "TIMED_WAITING (parking)",
+            (true, it) if it.contains(ThreadState::IN_OBJECT_WAIT) => {
+                "TIMED_WAITING (on object monitor)"
+            }
+            (true, _) => "TIMED_WAITING",
+            (false, it) if it.is_parked() => "WAITING (parking)",
+            (false, it) if it.contains(ThreadState::IN_OBJECT_WAIT) => {
+                "WAITING (on object monitor)"
+            }
+            (false, _) => "WAITING",
+        }
+    } else {
+        "RUNNABLE"
+    }
+}
+
+/// Takes a thread dump with up to `max_depth` frames per thread. Threads that end before they are
+/// inspected are left out.
+/// # Errors
+/// Returns an error if the threads cannot be listed or inspected.
+pub fn thread_dump(jvm: &Jvm, jni: &JNI, max_depth: usize) -> Result<ThreadDump, JvmTIError> {
+    let dump = |threads: &mut dyn Iterator<Item = &Thread<'_>>| {
+        let taken_at = SystemTime::now();
+        let mut dumped = Vec::new();
+        for thread in threads {
+            match dump_thread(jni, thread, max_depth) {
+                Ok(it) => dumped.push(it),
+                Err(ThreadError::ThreadNotAlive | ThreadError::InvalidThread) => {}
+                Err(error) => return Err(JvmTIError::from(error)),
+            }
+        }
+        Ok(ThreadDump {
+            taken_at,
+            threads: dumped,
+        })
+    };
+    match jvm.with_all_threads_suspended(jni, |threads| dump(&mut threads.threads().iter())) {
+        Err(ThreadError::MustPossessCapability) => dump(&mut jvm.get_all_threads()?.iter()),
+        result => result?,
+    }
+}
+
+fn dump_thread(
+    jni: &JNI,
+    thread: &Thread<'_>,
+    max_depth: usize,
+) -> Result<DumpedThread, ThreadError> {
+    let info = thread.info()?;
+    let state = thread.state()?;
+    let monitor = |monitor: &Object<'_>| DumpedMonitor {
+        hash_code: monitor.identity_hash().ok(),
+        class_name: monitor
+            .class(jni)
+            .signature()
+            .map_or_else(|_| "<unknown>".to_owned(), |it| binary_name(&it)),
+    };
+    let mut frames: Vec<_> = thread
+        .stack_trace(max_depth)
+        .map_err(|error| ThreadError::from(JvmTIError::from(error)))?
+        .into_iter()
+        .map(|frame| DumpedFrame {
+            class_name: frame
+                .method
+                .declaring_class()
+                .ok()
+                .and_then(|it| it.signature().ok())
+                .map_or_else(|| "<unknown>".to_owned(), |it| binary_name(&it)),
+            method_name: frame.method.name().map_or_else(
+                |_| "<unknown>".to_owned(),
+                |it| it.to_utf8_lossy().into_owned(),
+            ),
+            location: frame.location,
+            locked: Vec::new(),
+        })
+        .collect();
+    let mut other_locked = Vec::new();
+    for owned in thread.owned_monitor_stack_depths().unwrap_or_default() {
+        let dumped = monitor(&owned.monitor);
+        match owned.stack_depth.and_then(|it| frames.get_mut(it)) {
+            Some(frame) => frame.locked.push(dumped),
+            None => other_locked.push(dumped),
+        }
+    }
+    let contended = thread
+        .contended_monitor()
+        .ok()
+        .flatten()
+        .as_ref()
+        .map(monitor);
+    Ok(DumpedThread {
+        name: info.name.to_utf8_lossy().into_owned(),
+        correlation_id: CorrelationId::stamp(thread),
+        priority: info.priority,
+        is_daemon: info.is_daemon,
+        state,
+        frames,
+        contended,
+        other_locked,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn lock() -> DumpedMonitor {
+        DumpedMonitor {
+            hash_code: Some(0x1b6d_3586),
+            class_name: "java.lang.Object".to_owned(),
+        }
+    }
+
+    fn dump() -> ThreadDump {
+        ThreadDump {
+            taken_at: SystemTime::UNIX_EPOCH + Duration::from_millis(1500),
+            threads: vec![DumpedThread {
+                name: "worker \"1\"".to_owned(),
+                correlation_id: CorrelationId::new(0xABC),
+                priority: 5,
+                is_daemon: true,
+                state: ThreadState::ALIVE | ThreadState::BLOCKED_ON_MONITOR_ENTER,
+                frames: vec![
+                    DumpedFrame {
+                        class_name: "app.Worker".to_owned(),
+                        method_name: "run".to_owned(),
+                        location: 12,
+                        locked: vec![DumpedMonitor {
+                            hash_code: None,
+                            class_name: "app.Queue".to_owned(),
+                        }],
+                    },
+                    DumpedFrame {
+                        class_name: "java.lang.Thread".to_owned(),
+                        method_name: "start0".to_owned(),
+                        location: -1,
+                        locked: Vec::new(),
+                    },
+                ],
+                contended: Some(lock()),
+                other_locked: Vec::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn renders_jstack() {
+        assert_eq!(
+            dump().to_jstack(),
+            "Full thread dump:\n\
+             \n\"worker \"1\"\" daemon prio=5 correlation_id=0000000000000abc\n   \
+             java.lang.Thread.State: BLOCKED (on object monitor)\n\
+             \tat app.Worker.run(Unknown Source)\n\
+             \t- waiting to lock <0x1b6d3586> (a java.lang.Object)\n\
+             \t- locked <unknown> (a app.Queue)\n\
+             \tat java.lang.Thread.start0(Native Method)\n"
+        );
+    }
+
+    #[test]
+    fn serializes_threads_as_json() {
+        let dump = dump();
+        assert_eq!(
+            dump.threads[0].to_json(1500),
+            r#"{"taken_at_ms":1500,"name":"worker \"1\"","correlation_id":"0000000000000abc","priority":5,"daemon":true,"state":"BLOCKED (on object monitor)","frames":[{"class":"app.Worker","method":"run","location":12,"locked":[{"hash_code":null,"class":"app.Queue"}]},{"class":"java.lang.Thread","method":"start0","location":-1,"locked":[]}],"contended":{"hash_code":460141958,"class":"java.lang.Object"},"other_locked":[]}"#
+        );
+    }
+
+    #[test]
+    fn names_java_states() {
+        let waiting = ThreadState::ALIVE | ThreadState::WAITING;
+        let timed = waiting | ThreadState::WAITING_WITH_TIMEOUT;
+        let indefinite = waiting | ThreadState::WAITING_INDEFINITELY;
+        for (state, expected) in [
+            (ThreadState::empty(), "NEW"),
+            (ThreadState::TERMINATED, "TERMINATED"),
+            (ThreadState::ALIVE | ThreadState::RUNNABLE, "RUNNABLE"),
+            (timed | ThreadState::SLEEPING, "TIMED_WAITING (sleeping)"),
+            (timed | ThreadState::PARKED, "TIMED_WAITING (parking)"),
+            (
+                timed | ThreadState::IN_OBJECT_WAIT,
+                "TIMED_WAITING (on object monitor)",
+            ),
+            (indefinite | ThreadState::PARKED, "WAITING (parking)"),
+            (
+                indefinite | ThreadState::IN_OBJECT_WAIT,
+                "WAITING (on object monitor)",
+            ),
+            (indefinite, "WAITING"),
+        ] {
+            assert_eq!(java_state(state), expected, "{state:?}");
+        }
+    }
+}
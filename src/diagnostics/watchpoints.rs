@@ -0,0 +1,190 @@
+//! Field watchpoints by class and field name.
+//!
+//! [`FieldWatches`] watches fields for reads or writes by the binary name of their class and
+//! their name instead of by `jfieldID`, deferring the watch until the class is prepared if it is
+//! not loaded yet. The accesses are reported through the `FieldAccess` and `FieldModification`
+//! events.
+//!
+//! Watching fields requires the `can_generate_field_access_events` or the
+//! `can_generate_field_modification_events` capability, depending on the [`WatchKind`].
+
+use std::{
+    os::unix::prelude::OsStrExt,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::jvm::{
+    class::Class,
+    errors::{BreakpointError, JvmTIError},
+    fields::WatchKind,
+    strings::encode_modified_utf8,
+    Jvm,
+};
+
+use super::class_signature;
+
+/// The outcome of [`FieldWatches::watch_field`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchState {
+    /// The class is loaded and the field is watched.
+    Installed,
+    /// The class is not prepared yet, so the field will be watched when it is.
+    Deferred,
+}
+
+/// A watch on a field, which may wait for its class to be prepared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FieldWatch {
+    /// The JNI type signature of the class, e.g. `Lcom/example/Foo;`, in modified UTF-8.
+    class_signature: Vec<u8>,
+    /// The name of the field in modified UTF-8.
+    field_name: Vec<u8>,
+    kind: WatchKind,
+}
+
+impl FieldWatch {
+    /// Returns whether `class` is the class of the watch.
+    fn matches(&self, class: &Class<'_>) -> Result<bool, JvmTIError> {
+        Ok(class.signature()?.as_bytes() == self.class_signature.as_slice())
+    }
+
+    /// Watches the field in `class`.
+    fn install(&self, class: &Class<'_>) -> Result<(), BreakpointError> {
+        let field = class
+            .find_field(&self.field_name)?
+            .ok_or(BreakpointError::InvalidFieldId)?;
+        field.watch(self.kind)
+    }
+}
+
+/// Watches fields by class and field name.
+///
+/// Watches on fields of classes that are not prepared yet are kept pending until
+/// [`FieldWatches::on_class_prepare`] is called with the class, which should be done from the
+/// `ClassPrepare` event. The event has to be enabled for deferred watches to be set.
+#[derive(Debug, Default)]
+pub struct FieldWatches {
+    pending: Mutex<Vec<FieldWatch>>,
+}
+
+impl FieldWatches {
+    /// Creates a resolver without pending watches.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Watches the field `field_name` of the class with the binary name `class_name`, e.g.
+    /// `com.example.Config`, for accesses of `kind`. If the class is not loaded or not prepared
+    /// yet, the field is watched when it is.
+    /// # Errors
+    /// Returns [`BreakpointError::InvalidFieldId`] if the class has no field named `field_name`
+    /// and [`BreakpointError::Duplicate`] if the field is already watched for `kind`.
+    /// See [`BreakpointError`] for more information.
+    pub fn watch_field(
+        &self,
+        jvm: &Jvm,
+        class_name: &str,
+        field_name: &str,
+        kind: WatchKind,
+    ) -> Result<WatchState, BreakpointError> {
+        let watch = FieldWatch {
+            class_signature: class_signature(class_name),
+            field_name: encode_modified_utf8(field_name).into_bytes(),
+            kind,
+        };
+        // The lock is held while looking for the class so that a concurrent `ClassPrepare` event
+        // cannot miss the watch.
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut installed = false;
+        for class in jvm.get_loaded_classes()? {
+            if !watch.matches(&class)? {
+                continue;
+            }
+            match watch.install(&class) {
+                Ok(()) => installed = true,
+                Err(BreakpointError::Other(JvmTIError::ClassNotPrepared)) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        if installed {
+            Ok(WatchState::Installed)
+        } else {
+            pending.push(watch);
+            Ok(WatchState::Deferred)
+        }
+    }
+
+    /// Stops watching the field `field_name` of the class with the binary name `class_name` for
+    /// accesses of `kind`, including a pending watch. Returns whether the field was watched.
+    /// # Errors
+    /// See [`BreakpointError`] for more information.
+    pub fn unwatch_field(
+        &self,
+        jvm: &Jvm,
+        class_name: &str,
+        field_name: &str,
+        kind: WatchKind,
+    ) -> Result<bool, BreakpointError> {
+        let watch = FieldWatch {
+            class_signature: class_signature(class_name),
+            field_name: encode_modified_utf8(field_name).into_bytes(),
+            kind,
+        };
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let pending_before = pending.len();
+        pending.retain(|it| *it != watch);
+        let mut cleared = pending.len() != pending_before;
+        for class in jvm.get_loaded_classes()? {
+            if !watch.matches(&class)? {
+                continue;
+            }
+            let Some(field) = class.find_field(&watch.field_name)? else {
+                continue;
+            };
+            match field.unwatch(kind) {
+                Ok(()) => cleared = true,
+                Err(BreakpointError::NotFound) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(cleared)
+    }
+
+    /// Sets the pending watches on fields of `class`, which has just been prepared, and returns
+    /// how many were set. Call this from the `ClassPrepare` event.
+    /// Watches that cannot be set in `class`, e.g. because it has no such field, are dropped and
+    /// the first such error is returned after the others have been set.
+    /// # Errors
+    /// See [`BreakpointError`] for more information.
+    pub fn on_class_prepare(&self, class: &Class<'_>) -> Result<usize, BreakpointError> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        let signature = class.signature().map_err(JvmTIError::from)?;
+        let mut installed = 0;
+        let mut first_error = None;
+        pending.retain(|watch| {
+            if watch.class_signature.as_slice() != signature.as_bytes() {
+                return true;
+            }
+            match watch.install(class) {
+                Ok(()) => installed += 1,
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+            false
+        });
+        first_error.map_or(Ok(installed), Err)
+    }
+
+    /// Gets the number of watches waiting for their class to be prepared.
+    pub fn pending_count(&self) -> usize {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+}
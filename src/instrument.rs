@@ -0,0 +1,430 @@
+//! A facade over the class transformation functions of the JVM TI mirroring `java.lang.instrument`.
+//!
+//! An [`Instrumentation`] owns the `ClassFileLoadHook` callback of the environment and dispatches
+//! it to the registered [`ClassFileTransformer`]s in registration order, each one receiving the
+//! output of the previous one, just like the transformers of a Java agent.
+//!
+//! Hot-patching agents can additionally [retain](Instrumentation::set_retain_original_bytes) the
+//! original class files seen at load time and [roll back](Instrumentation::rollback) a class to
+//! them when a patch misbehaves.
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+};
+
+use crate::{
+    jvm::{
+        class::{Class, ClassDefinition},
+        errors::{ClassError, JvmTIError, RedefineError},
+        events::{ClassFileLoadEvent, Handler, JvmTIEvent},
+        jni::{WeakGlobalRef, JNI},
+        objects::Object,
+        strings::ModifiedUtf8Ext,
+        Jvm,
+    },
+    sys,
+};
+
+/// A transformer of class files, like `java.lang.instrument.ClassFileTransformer`.
+pub trait ClassFileTransformer: Send + Sync {
+    /// Transforms the class file `class_bytes` of the class `class_name`, e.g. `java/lang/String`.
+    /// Returns `None` to leave the class file unchanged.
+    fn transform(
+        &self,
+        loader: Option<&Object<'_>>,
+        class_name: Option<&str>,
+        class_being_redefined: Option<&Class<'_>>,
+        protection_domain: Option<&Object<'_>>,
+        class_bytes: &[u8],
+    ) -> Option<Vec<u8>>;
+}
+
+impl<F> ClassFileTransformer for F
+where
+    F: Fn(
+            Option<&Object<'_>>,
+            Option<&str>,
+            Option<&Class<'_>>,
+            Option<&Object<'_>>,
+            &[u8],
+        ) -> Option<Vec<u8>>
+        + Send
+        + Sync,
+{
+    fn transform(
+        &self,
+        loader: Option<&Object<'_>>,
+        class_name: Option<&str>,
+        class_being_redefined: Option<&Class<'_>>,
+        protection_domain: Option<&Object<'_>>,
+        class_bytes: &[u8],
+    ) -> Option<Vec<u8>> {
+        self(
+            loader,
+            class_name,
+            class_being_redefined,
+            protection_domain,
+            class_bytes,
+        )
+    }
+}
+
+/// Identifies a transformer registered with [`Instrumentation::add_transformer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransformerHandle(u64);
+
+struct RegisteredTransformer {
+    handle: TransformerHandle,
+    can_retransform: bool,
+    transformer: Box<dyn ClassFileTransformer>,
+}
+
+/// The class file a class had when it was loaded.
+struct Original {
+    /// The defining loader of the class, or `None` for the bootstrap class loader. The reference
+    /// is weak so that retaining the class file does not keep the class from being unloaded.
+    loader: Option<WeakGlobalRef>,
+    class_bytes: Arc<[u8]>,
+}
+
+impl Original {
+    fn is_defined_by(&self, jni: &JNI, loader: Option<&Object<'_>>) -> bool {
+        match (&self.loader, loader) {
+            (None, None) => true,
+            (Some(it), Some(loader)) => it.refers_to(jni, loader),
+            _ => false,
+        }
+    }
+
+    /// Returns whether the class has been unloaded, which happens together with its loader.
+    fn is_unloaded(&self, jni: &JNI) -> bool {
+        self.loader.as_ref().is_some_and(|it| it.is_collected(jni))
+    }
+}
+
+/// The number of retained class files below which they are not swept for unloaded classes.
+const MIN_SWEEP_LEN: usize = 1024;
+
+/// The retained original class files.
+#[derive(Default)]
+struct Originals {
+    /// The class files by the internal name of the class, e.g. `java/lang/String`. Classes of
+    /// different loaders may share a name.
+    by_name: HashMap<String, Vec<Original>>,
+    len: usize,
+    /// The number of class files left by the last sweep.
+    swept_len: usize,
+}
+
+impl Originals {
+    fn get(&self, jni: &JNI, name: &str, loader: Option<&Object<'_>>) -> Option<Arc<[u8]>> {
+        self.by_name
+            .get(name)?
+            .iter()
+            .find(|it| it.is_defined_by(jni, loader))
+            .map(|it| Arc::clone(&it.class_bytes))
+    }
+
+    fn retain(&mut self, jni: &JNI, name: &str, loader: Option<&Object<'_>>, class_bytes: &[u8]) {
+        let originals = self.by_name.entry(name.to_owned()).or_default();
+        if originals.iter().any(|it| it.is_defined_by(jni, loader)) {
+            return;
+        }
+        // Without a reference to the loader the class file could not be found again.
+        let Ok(loader) = loader.map(|it| WeakGlobalRef::new(jni, it)).transpose() else {
+            return;
+        };
+        originals.push(Original {
+            loader,
+            class_bytes: class_bytes.into(),
+        });
+        self.len += 1;
+        // Sweeping whenever the count doubles keeps the cost per class constant.
+        if self.len >= (self.swept_len * 2).max(MIN_SWEEP_LEN) {
+            self.sweep(jni);
+        }
+    }
+
+    /// Drops the class files of the classes that have been unloaded.
+    fn sweep(&mut self, jni: &JNI) {
+        self.by_name.retain(|_, originals| {
+            originals.retain(|it| !it.is_unloaded(jni));
+            !originals.is_empty()
+        });
+        self.len = self.by_name.values().map(Vec::len).sum();
+        self.swept_len = self.len;
+    }
+}
+
+#[derive(Default)]
+struct Transformers {
+    next_handle: AtomicU64,
+    registered: RwLock<Vec<RegisteredTransformer>>,
+    retain_originals: AtomicBool,
+    originals: Mutex<Originals>,
+}
+
+thread_local! {
+    /// Whether the current thread is inside [`Instrumentation::retransform_classes`].
+    static RETRANSFORMING: Cell<bool> = const { Cell::new(false) };
+}
+
+impl Transformers {
+    fn transform(&self, jni: &JNI, event: &ClassFileLoadEvent<'_>) -> Option<Vec<u8>> {
+        let ClassFileLoadEvent {
+            class_being_redefined,
+            name,
+            loader,
+            protection_domain,
+            class_data: class_bytes,
+            ..
+        } = *event;
+        let retransforming = RETRANSFORMING.with(Cell::get);
+        let class_name = name.map(ModifiedUtf8Ext::to_utf8_lossy);
+        if class_being_redefined.is_none() && self.retain_originals.load(Ordering::Relaxed) {
+            if let Some(class_name) = &class_name {
+                self.originals
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .retain(jni, class_name, loader, class_bytes);
+            }
+        }
+        let registered = self
+            .registered
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut transformed: Option<Vec<u8>> = None;
+        for it in registered
+            .iter()
+            .filter(|it| it.can_retransform || !retransforming)
+        {
+            let input = transformed.as_deref().unwrap_or(class_bytes);
+            if let Some(output) = it.transformer.transform(
+                loader,
+                class_name.as_deref(),
+                class_being_redefined,
+                protection_domain,
+                input,
+            ) {
+                transformed = Some(output);
+            }
+        }
+        transformed
+    }
+}
+
+impl std::fmt::Debug for Transformers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let registered = self
+            .registered
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let originals = self
+            .originals
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        f.debug_struct("Transformers")
+            .field("registered", &registered.len())
+            .field("originals", &originals.len)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A high-level API for instrumenting classes, like `java.lang.instrument.Instrumentation`.
+#[derive(Debug)]
+pub struct Instrumentation<'j> {
+    jvm: &'j Jvm,
+    transformers: Arc<Transformers>,
+    redefine_supported: bool,
+    retransform_supported: bool,
+}
+
+impl<'j> Instrumentation<'j> {
+    /// Creates an [`Instrumentation`] for `jvm`.
+    ///
+    /// This acquires the capabilities to redefine and retransform classes if they are available,
+    /// installs the `ClassFileLoadHook` callback, replacing any previously registered one, and
+    /// enables the event. The capabilities are usually only available in the `OnLoad` phase.
+    /// # Errors
+    /// Returns an error if the capabilities cannot be acquired or the event cannot be enabled.
+    pub fn new(jvm: &'j mut Jvm) -> Result<Self, JvmTIError> {
+        let potential = jvm.potential_raw_capabilities()?;
+        // SAFETY: `jvmtiCapabilities` is a plain bit field for which all zeros means no capability.
+        let mut capabilities: sys::jvmtiCapabilities = unsafe { std::mem::zeroed() };
+        capabilities.set_can_redefine_classes(potential.can_redefine_classes());
+        capabilities.set_can_retransform_classes(potential.can_retransform_classes());
+        jvm.add_raw_capabilities(&capabilities)?;
+
+        let transformers = Arc::<Transformers>::default();
+        let hook = Arc::clone(&transformers);
+        jvm.update_callbacks(|it| {
+            it.class_file_load_hook = Some(Handler::new(Box::new(move |_, jni, event| {
+                hook.transform(jni, event)
+            })));
+        })?;
+        jvm.enable_event(JvmTIEvent::ClassFileLoadHook, None)?;
+        Ok(Self {
+            jvm,
+            transformers,
+            redefine_supported: capabilities.can_redefine_classes() != 0,
+            retransform_supported: capabilities.can_retransform_classes() != 0,
+        })
+    }
+
+    /// Registers `transformer`. Only transformers registered with `can_retransform` are invoked
+    /// when classes are retransformed.
+    pub fn add_transformer<T>(&self, transformer: T, can_retransform: bool) -> TransformerHandle
+    where
+        T: ClassFileTransformer + 'static,
+    {
+        let handle = TransformerHandle(
+            self.transformers
+                .next_handle
+                .fetch_add(1, Ordering::Relaxed),
+        );
+        self.transformers
+            .registered
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(RegisteredTransformer {
+                handle,
+                can_retransform,
+                transformer: Box::new(transformer),
+            });
+        handle
+    }
+
+    /// Unregisters the transformer identified by `handle`.
+    /// Returns `false` if the transformer is not registered.
+    pub fn remove_transformer(&self, handle: TransformerHandle) -> bool {
+        let mut registered = self
+            .transformers
+            .registered
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let count = registered.len();
+        registered.retain(|it| it.handle != handle);
+        registered.len() != count
+    }
+
+    /// Returns whether the JVM supports redefining classes.
+    #[must_use]
+    pub fn is_redefine_classes_supported(&self) -> bool {
+        self.redefine_supported
+    }
+
+    /// Returns whether the JVM supports retransforming classes.
+    #[must_use]
+    pub fn is_retransform_classes_supported(&self) -> bool {
+        self.retransform_supported
+    }
+
+    /// Retransforms `classes` by running the retransformation capable transformers on them.
+    /// # Errors
+    /// See [`RedefineError`] for more information.
+    pub fn retransform_classes(&self, classes: &[&Class<'_>]) -> Result<(), RedefineError> {
+        // The `ClassFileLoadHook` events of a retransformation are sent on the calling thread.
+        let retransforming = RETRANSFORMING.with(|it| it.replace(true));
+        let result = self.jvm.retransform_classes(classes);
+        RETRANSFORMING.with(|it| it.set(retransforming));
+        result
+    }
+
+    /// Redefines classes with the given class files.
+    /// # Errors
+    /// See [`RedefineError`] for more information.
+    pub fn redefine_classes(
+        &self,
+        definitions: &[ClassDefinition<'_, '_>],
+    ) -> Result<(), RedefineError> {
+        self.jvm.redefine_classes(definitions)
+    }
+
+    /// Sets whether the original class files of the classes loaded from now on are retained for
+    /// [`Instrumentation::rollback`]. Only classes loaded while this is enabled can be rolled back,
+    /// so it is best enabled right after creating the [`Instrumentation`] in the `OnLoad` phase.
+    /// The retained class files are kept in memory until their classes are unloaded.
+    pub fn set_retain_original_bytes(&self, retain: bool) {
+        self.transformers
+            .retain_originals
+            .store(retain, Ordering::Relaxed);
+    }
+
+    /// Gets the class file `class` had when it was loaded, if it has been retained.
+    /// # Errors
+    /// See [`ClassError`] for more information.
+    pub fn original_bytes(&self, class: &Class<'_>) -> Result<Option<Arc<[u8]>>, ClassError> {
+        // A thread that is not attached to the VM cannot hold a class.
+        let Some(jni) = self.jvm.current_jni() else {
+            return Ok(None);
+        };
+        let signature = class.signature()?;
+        let signature = signature.to_utf8_lossy();
+        let name = signature
+            .strip_prefix('L')
+            .and_then(|it| it.strip_suffix(';'))
+            .unwrap_or(&signature);
+        let loader = class.class_loader()?;
+        Ok(self
+            .transformers
+            .originals
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&jni, name, loader.as_ref()))
+    }
+
+    /// Restores `class` to the class file it had when it was loaded, undoing all redefinitions and
+    /// transformations. Returns `false` if the original class file has not been retained.
+    ///
+    /// The restored class file goes through the registered transformers like any redefinition, so
+    /// the transformer of a misbehaving patch should be removed before rolling back.
+    /// # Errors
+    /// See [`RedefineError`] for more information.
+    pub fn rollback(&self, class: &Class<'_>) -> Result<bool, RedefineError> {
+        let original = self
+            .original_bytes(class)
+            .map_err(|error| RedefineError::from(JvmTIError::from(error)))?;
+        let Some(original) = original else {
+            return Ok(false);
+        };
+        self.jvm.redefine_classes(&[ClassDefinition {
+            class,
+            class_bytes: &original,
+        }])?;
+        Ok(true)
+    }
+
+    /// Checks whether `class` can be redefined or retransformed.
+    /// # Errors
+    /// See [`ClassError`] for more information.
+    pub fn is_modifiable_class(&self, class: &Class<'_>) -> Result<bool, ClassError> {
+        class.is_modifiable()
+    }
+
+    /// Gets all the classes loaded by the JVM.
+    /// # Errors
+    /// See [`JvmTIError`] for more information.
+    pub fn get_all_loaded_classes(&self) -> Result<Vec<Class<'j>>, JvmTIError> {
+        self.jvm.get_loaded_classes()
+    }
+
+    /// Appends a JAR file to the search path of the bootstrap class loader.
+    /// # Errors
+    /// See [`JvmTIError`] for more information.
+    pub fn append_to_bootstrap_class_loader_search(&self, jar: &Path) -> Result<(), JvmTIError> {
+        self.jvm.add_to_bootstrap_class_loader_search(jar)
+    }
+
+    /// Appends a JAR file to the search path of the system class loader.
+    /// # Errors
+    /// See [`JvmTIError`] for more information.
+    pub fn append_to_system_class_loader_search(&self, jar: &Path) -> Result<(), JvmTIError> {
+        self.jvm.add_to_system_class_loader_search(jar)
+    }
+}
@@ -0,0 +1,367 @@
+//! APIs for working with JVM TI capabilities.
+//! See [the JVMTI documentation](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#capability) for more information.
+
+use std::{mem::MaybeUninit, sync::atomic::Ordering};
+
+use crate::{macros::call_jvmti, sys};
+
+use super::{errors::CapabilityError, events::JvmTIEvent, Jvm};
+
+/// The number of capabilities defined by the JVM TI.
+const CAPABILITY_COUNT: usize = 45;
+
+bitflags::bitflags! {
+    /// A set of capabilities, e.g.
+    /// `JvmtiCapabilities::CAN_TAG_OBJECTS | JvmtiCapabilities::CAN_RETRANSFORM_CLASSES`.
+    ///
+    /// Each flag is at the bit position of the corresponding field of `jvmtiCapabilities`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    pub struct JvmtiCapabilities: u64 {
+        /// Can tag objects, e.g. with `SetTag`.
+        const CAN_TAG_OBJECTS = 1 << 0;
+        /// Can set watchpoints on field modification.
+        const CAN_GENERATE_FIELD_MODIFICATION_EVENTS = 1 << 1;
+        /// Can set watchpoints on field access.
+        const CAN_GENERATE_FIELD_ACCESS_EVENTS = 1 << 2;
+        /// Can get the bytecodes of a method.
+        const CAN_GET_BYTECODES = 1 << 3;
+        /// Can test whether a field or method is synthetic.
+        const CAN_GET_SYNTHETIC_ATTRIBUTE = 1 << 4;
+        /// Can get information about the ownership of monitors.
+        const CAN_GET_OWNED_MONITOR_INFO = 1 << 5;
+        /// Can get the monitor a thread is contending for.
+        const CAN_GET_CURRENT_CONTENDED_MONITOR = 1 << 6;
+        /// Can get information about a monitor.
+        const CAN_GET_MONITOR_INFO = 1 << 7;
+        /// Can pop frames off the stack.
+        const CAN_POP_FRAME = 1 << 8;
+        /// Can redefine classes.
+        const CAN_REDEFINE_CLASSES = 1 << 9;
+        /// Can send stop or interrupt to threads.
+        const CAN_SIGNAL_THREAD = 1 << 10;
+        /// Can get the source file name of a class.
+        const CAN_GET_SOURCE_FILE_NAME = 1 << 11;
+        /// Can get the line number table of a method.
+        const CAN_GET_LINE_NUMBERS = 1 << 12;
+        /// Can get the source debug extension of a class.
+        const CAN_GET_SOURCE_DEBUG_EXTENSION = 1 << 13;
+        /// Can get and set local variables.
+        const CAN_ACCESS_LOCAL_VARIABLES = 1 << 14;
+        /// Can return methods in the order they occur in the class file.
+        const CAN_MAINTAIN_ORIGINAL_METHOD_ORDER = 1 << 15;
+        /// Can get `SingleStep` events.
+        const CAN_GENERATE_SINGLE_STEP_EVENTS = 1 << 16;
+        /// Can get `Exception` and `ExceptionCatch` events.
+        const CAN_GENERATE_EXCEPTION_EVENTS = 1 << 17;
+        /// Can get `FramePop` events.
+        const CAN_GENERATE_FRAME_POP_EVENTS = 1 << 18;
+        /// Can get `Breakpoint` events.
+        const CAN_GENERATE_BREAKPOINT_EVENTS = 1 << 19;
+        /// Can suspend and resume threads.
+        const CAN_SUSPEND = 1 << 20;
+        /// Can redefine any modifiable class.
+        const CAN_REDEFINE_ANY_CLASS = 1 << 21;
+        /// Can get the CPU time of the current thread.
+        const CAN_GET_CURRENT_THREAD_CPU_TIME = 1 << 22;
+        /// Can get the CPU time of any thread.
+        const CAN_GET_THREAD_CPU_TIME = 1 << 23;
+        /// Can get `MethodEntry` events.
+        const CAN_GENERATE_METHOD_ENTRY_EVENTS = 1 << 24;
+        /// Can get `MethodExit` events.
+        const CAN_GENERATE_METHOD_EXIT_EVENTS = 1 << 25;
+        /// Can get `ClassFileLoadHook` events for every loaded class.
+        const CAN_GENERATE_ALL_CLASS_HOOK_EVENTS = 1 << 26;
+        /// Can get `CompiledMethodLoad` events.
+        const CAN_GENERATE_COMPILED_METHOD_LOAD_EVENTS = 1 << 27;
+        /// Can get the monitor events, e.g. `MonitorWait`.
+        const CAN_GENERATE_MONITOR_EVENTS = 1 << 28;
+        /// Can get `VMObjectAlloc` events.
+        const CAN_GENERATE_VM_OBJECT_ALLOC_EVENTS = 1 << 29;
+        /// Can get `NativeMethodBind` events.
+        const CAN_GENERATE_NATIVE_METHOD_BIND_EVENTS = 1 << 30;
+        /// Can get `GarbageCollectionStart` and `GarbageCollectionFinish` events.
+        const CAN_GENERATE_GARBAGE_COLLECTION_EVENTS = 1 << 31;
+        /// Can get `ObjectFree` events.
+        const CAN_GENERATE_OBJECT_FREE_EVENTS = 1 << 32;
+        /// Can return early from a method.
+        const CAN_FORCE_EARLY_RETURN = 1 << 33;
+        /// Can get information about owned monitors with their stack depth.
+        const CAN_GET_OWNED_MONITOR_STACK_DEPTH_INFO = 1 << 34;
+        /// Can get the constant pool of a class.
+        const CAN_GET_CONSTANT_POOL = 1 << 35;
+        /// Can set a prefix to be applied when native methods cannot be resolved.
+        const CAN_SET_NATIVE_METHOD_PREFIX = 1 << 36;
+        /// Can retransform classes.
+        const CAN_RETRANSFORM_CLASSES = 1 << 37;
+        /// Can retransform any modifiable class.
+        const CAN_RETRANSFORM_ANY_CLASS = 1 << 38;
+        /// Can get `ResourceExhausted` events when the VM runs out of Java heap memory.
+        const CAN_GENERATE_RESOURCE_EXHAUSTION_HEAP_EVENTS = 1 << 39;
+        /// Can get `ResourceExhausted` events when the VM cannot create a thread.
+        const CAN_GENERATE_RESOURCE_EXHAUSTION_THREADS_EVENTS = 1 << 40;
+        /// Can get the `VMStart` event early, before the base classes are initialized.
+        const CAN_GENERATE_EARLY_VMSTART = 1 << 41;
+        /// Can get `ClassFileLoadHook` events in the primordial phase.
+        const CAN_GENERATE_EARLY_CLASS_HOOK_EVENTS = 1 << 42;
+        /// Can get `SampledObjectAlloc` events.
+        const CAN_GENERATE_SAMPLED_OBJECT_ALLOC_EVENTS = 1 << 43;
+        /// Can support virtual threads.
+        const CAN_SUPPORT_VIRTUAL_THREADS = 1 << 44;
+    }
+}
+
+impl From<sys::jvmtiCapabilities> for JvmtiCapabilities {
+    fn from(capabilities: sys::jvmtiCapabilities) -> Self {
+        (0..CAPABILITY_COUNT)
+            .filter(|&bit| capabilities._bitfield_1.get(bit, 1) != 0)
+            .fold(Self::empty(), |it, bit| {
+                it | Self::from_bits_retain(1 << bit)
+            })
+    }
+}
+
+impl From<JvmtiCapabilities> for sys::jvmtiCapabilities {
+    fn from(capabilities: JvmtiCapabilities) -> Self {
+        // SAFETY: `jvmtiCapabilities` is a plain bit field for which all zeros means no capability.
+        let mut raw: sys::jvmtiCapabilities = unsafe { std::mem::zeroed() };
+        for bit in (0..CAPABILITY_COUNT).filter(|&it| capabilities.bits() & (1 << it) != 0) {
+            raw._bitfield_1.set(bit, 1, 1);
+        }
+        raw
+    }
+}
+
+impl JvmTIEvent {
+    /// Gets the capabilities required to enable the event.
+    #[must_use]
+    pub fn required_capabilities(self) -> JvmtiCapabilities {
+        match self {
+            Self::Exception | Self::ExceptionCatch => {
+                JvmtiCapabilities::CAN_GENERATE_EXCEPTION_EVENTS
+            }
+            Self::SingleStep => JvmtiCapabilities::CAN_GENERATE_SINGLE_STEP_EVENTS,
+            Self::FramePop => JvmtiCapabilities::CAN_GENERATE_FRAME_POP_EVENTS,
+            Self::Breakpoint => JvmtiCapabilities::CAN_GENERATE_BREAKPOINT_EVENTS,
+            Self::FieldAccess => JvmtiCapabilities::CAN_GENERATE_FIELD_ACCESS_EVENTS,
+            Self::FieldModification => JvmtiCapabilities::CAN_GENERATE_FIELD_MODIFICATION_EVENTS,
+            Self::MethodEntry => JvmtiCapabilities::CAN_GENERATE_METHOD_ENTRY_EVENTS,
+            Self::MethodExit => JvmtiCapabilities::CAN_GENERATE_METHOD_EXIT_EVENTS,
+            Self::NativeMethodBind => JvmtiCapabilities::CAN_GENERATE_NATIVE_METHOD_BIND_EVENTS,
+            Self::CompiledMethodLoad | Self::CompiledMethodUnload => {
+                JvmtiCapabilities::CAN_GENERATE_COMPILED_METHOD_LOAD_EVENTS
+            }
+            Self::MonitorWait
+            | Self::MonitorWaited
+            | Self::MonitorContendedEnter
+            | Self::MonitorContendedEntered => JvmtiCapabilities::CAN_GENERATE_MONITOR_EVENTS,
+            Self::GarbageCollectionStart | Self::GarbageCollectionFinish => {
+                JvmtiCapabilities::CAN_GENERATE_GARBAGE_COLLECTION_EVENTS
+            }
+            Self::ObjectFree => JvmtiCapabilities::CAN_GENERATE_OBJECT_FREE_EVENTS,
+            Self::VMObjectAlloc => JvmtiCapabilities::CAN_GENERATE_VM_OBJECT_ALLOC_EVENTS,
+            Self::SampledObjectAlloc => JvmtiCapabilities::CAN_GENERATE_SAMPLED_OBJECT_ALLOC_EVENTS,
+            Self::VirtualThreadStart | Self::VirtualThreadEnd => {
+                JvmtiCapabilities::CAN_SUPPORT_VIRTUAL_THREADS
+            }
+            _ => JvmtiCapabilities::empty(),
+        }
+    }
+}
+
+impl Jvm {
+    /// Sets whether enabling an event or calling a function that requires capabilities, e.g.
+    /// setting a breakpoint, first adds the capabilities the environment does not possess yet.
+    /// It is off by default.
+    ///
+    /// Most capabilities can only be added during the `OnLoad` phase. If some of them cannot be
+    /// added, the call fails with an error naming them, e.g. [`EventError::MissingCapabilities`].
+    ///
+    /// [`EventError::MissingCapabilities`]: super::errors::EventError::MissingCapabilities
+    pub fn set_auto_capabilities(&mut self, enabled: bool) {
+        self.auto_capabilities = enabled;
+    }
+
+    /// Adds the capabilities in `required` that the environment does not possess yet if
+    /// [`Jvm::set_auto_capabilities`] is on. Returns the capabilities that could not be added.
+    ///
+    /// Capabilities acquired once are remembered until they are relinquished, so that functions
+    /// called for every object, e.g. [`Object::tag`](super::objects::Object::tag), do not look up
+    /// the capabilities of the environment on every call.
+    pub(crate) fn acquire_capabilities(
+        &self,
+        required: JvmtiCapabilities,
+    ) -> Result<(), JvmtiCapabilities> {
+        if !self.auto_capabilities || required.is_empty() {
+            return Ok(());
+        }
+        let acquired =
+            JvmtiCapabilities::from_bits_retain(self.acquired_capabilities.load(Ordering::Relaxed));
+        if acquired.contains(required) {
+            return Ok(());
+        }
+        let missing = self
+            .capabilities()
+            .map_or(required, |possessed| required - possessed);
+        if missing.is_empty() || self.add_capabilities(missing).is_ok() {
+            self.acquired_capabilities
+                .fetch_or(required.bits(), Ordering::Relaxed);
+            return Ok(());
+        }
+        // Name the capabilities that are not available, or all of them if the failure is due to
+        // something else.
+        let unavailable = self
+            .potential_capabilities()
+            .map_or(missing, |potential| missing - potential);
+        Err(if unavailable.is_empty() {
+            missing
+        } else {
+            unavailable
+        })
+    }
+
+    /// Adds `capabilities` to the environment. Capabilities already possessed are kept.
+    /// See [`AddCapabilities`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#AddCapabilities).
+    /// # Errors
+    /// Returns [`CapabilityError::NotAvailable`] if any of the capabilities cannot be possessed,
+    /// in which case none of them is added.
+    /// See [`CapabilityError`] for more information.
+    pub fn add_capabilities(&self, capabilities: JvmtiCapabilities) -> Result<(), CapabilityError> {
+        self.add_raw_capabilities(&capabilities.into())
+    }
+
+    /// Relinquishes `capabilities`, e.g. expensive ones only needed during a start-up analysis.
+    /// Capabilities not possessed are ignored.
+    /// See [`RelinquishCapabilities`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#RelinquishCapabilities).
+    /// # Errors
+    /// See [`CapabilityError`] for more information.
+    pub fn relinquish_capabilities(
+        &self,
+        capabilities: JvmtiCapabilities,
+    ) -> Result<(), CapabilityError> {
+        // The capabilities are forgotten first, so that they are looked up again even if
+        // relinquishing them fails halfway.
+        self.acquired_capabilities
+            .fetch_and(!capabilities.bits(), Ordering::Relaxed);
+        let capabilities: sys::jvmtiCapabilities = capabilities.into();
+        // SAFETY: `capabilities` is a valid `jvmtiCapabilities` that outlives the call.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                RelinquishCapabilities,
+                std::ptr::from_ref(&capabilities)
+            )
+        }?;
+        Ok(())
+    }
+
+    /// Gets the capabilities that the environment can possess at this time, which agents can
+    /// check before adding capabilities that are not supported by every VM.
+    /// See [`GetPotentialCapabilities`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetPotentialCapabilities).
+    /// # Errors
+    /// See [`CapabilityError`] for more information.
+    pub fn potential_capabilities(&self) -> Result<JvmtiCapabilities, CapabilityError> {
+        self.potential_raw_capabilities().map(Into::into)
+    }
+
+    /// Gets the capabilities that the environment currently possesses.
+    /// See [`GetCapabilities`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetCapabilities).
+    /// # Errors
+    /// See [`CapabilityError`] for more information.
+    pub fn capabilities(&self) -> Result<JvmtiCapabilities, CapabilityError> {
+        let mut capabilities = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe { call_jvmti!(self.jvmti_ptr, GetCapabilities, capabilities.as_mut_ptr()) }?;
+        // SAFETY: A successful result indicates that `capabilities` has been initialized.
+        Ok(unsafe { capabilities.assume_init() }.into())
+    }
+
+    /// Gets the capabilities that the environment can possess at this time.
+    /// See [`GetPotentialCapabilities`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetPotentialCapabilities).
+    pub(crate) fn potential_raw_capabilities(
+        &self,
+    ) -> Result<sys::jvmtiCapabilities, CapabilityError> {
+        let mut capabilities = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetPotentialCapabilities,
+                capabilities.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `capabilities` has been initialized.
+        Ok(unsafe { capabilities.assume_init() })
+    }
+
+    /// Adds the capabilities set in `capabilities` to the environment.
+    /// See [`AddCapabilities`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#AddCapabilities).
+    pub(crate) fn add_raw_capabilities(
+        &self,
+        capabilities: &sys::jvmtiCapabilities,
+    ) -> Result<(), CapabilityError> {
+        // SAFETY: `capabilities` is a valid `jvmtiCapabilities` that outlives the call.
+        unsafe { call_jvmti!(self.jvmti_ptr, AddCapabilities, capabilities) }?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zeroed() -> sys::jvmtiCapabilities {
+        // SAFETY: `jvmtiCapabilities` is a plain bit field for which all zeros means no capability.
+        unsafe { std::mem::zeroed() }
+    }
+
+    #[test]
+    fn flags_match_the_fields_of_jvmti_capabilities() {
+        let mut raw = zeroed();
+        raw.set_can_tag_objects(1);
+        raw.set_can_suspend(1);
+        raw.set_can_support_virtual_threads(1);
+        assert_eq!(
+            JvmtiCapabilities::from(raw),
+            JvmtiCapabilities::CAN_TAG_OBJECTS
+                | JvmtiCapabilities::CAN_SUSPEND
+                | JvmtiCapabilities::CAN_SUPPORT_VIRTUAL_THREADS
+        );
+        let raw = sys::jvmtiCapabilities::from(JvmtiCapabilities::CAN_GENERATE_BREAKPOINT_EVENTS);
+        assert_eq!(raw.can_generate_breakpoint_events(), 1);
+        assert_eq!(raw.can_generate_single_step_events(), 0);
+    }
+
+    #[test]
+    fn converts_every_flag_both_ways() {
+        assert_eq!(
+            JvmtiCapabilities::all().bits().count_ones() as usize,
+            CAPABILITY_COUNT
+        );
+        let raw = sys::jvmtiCapabilities::from(JvmtiCapabilities::all());
+        assert_eq!(JvmtiCapabilities::from(raw), JvmtiCapabilities::all());
+        assert_eq!(
+            JvmtiCapabilities::from(zeroed()),
+            JvmtiCapabilities::empty()
+        );
+    }
+
+    #[test]
+    fn events_require_their_generating_capabilities() {
+        assert_eq!(
+            JvmTIEvent::Breakpoint.required_capabilities(),
+            JvmtiCapabilities::CAN_GENERATE_BREAKPOINT_EVENTS
+        );
+        assert_eq!(
+            JvmTIEvent::MonitorContendedEntered.required_capabilities(),
+            JvmtiCapabilities::CAN_GENERATE_MONITOR_EVENTS
+        );
+        assert_eq!(
+            JvmTIEvent::VirtualThreadEnd.required_capabilities(),
+            JvmtiCapabilities::CAN_SUPPORT_VIRTUAL_THREADS
+        );
+        assert!(JvmTIEvent::VMInit.required_capabilities().is_empty());
+        assert!(JvmTIEvent::ClassFileLoadHook
+            .required_capabilities()
+            .is_empty());
+    }
+}
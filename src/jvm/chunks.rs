@@ -0,0 +1,267 @@
+//! Lazy, chunked processing of bulk results that consist of local references.
+//!
+//! Functions such as [`GetLoadedClasses`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetLoadedClasses)
+//! return one local reference per element. Wrapping all of them at once and keeping them alive
+//! while scanning can exhaust the local reference table, especially when processing an element
+//! creates further local references. A [`LocalChunks`] instead wraps a chunk of elements at a time
+//! inside a `PushLocalFrame`/`PopLocalFrame` scope and releases the chunk before moving to the next.
+
+use std::mem::MaybeUninit;
+
+use crate::{macros::call_jvmti, sys};
+
+use super::{
+    capabilities::JvmtiCapabilities,
+    class::Class,
+    errors::{HeapError, JvmTIError, ThreadError},
+    jni::{JNIError, JNI},
+    objects::Object,
+    threads::Thread,
+    Jvm,
+};
+
+/// A chunked view over a JVM TI allocated array of local references.
+///
+/// Each chunk is processed inside its own local frame, so local references created while
+/// processing the elements of a chunk are released when advancing to the next chunk.
+pub struct LocalChunks<'j, T> {
+    jvm: &'j Jvm,
+    jni: &'j JNI,
+    refs: *mut sys::jobject,
+    len: usize,
+    position: usize,
+    chunk_size: usize,
+    make_item: Box<dyn FnMut(sys::jobject, usize) -> T + 'j>,
+    chunk: Vec<T>,
+    frame_pushed: bool,
+}
+
+impl<'j, T> LocalChunks<'j, T> {
+    /// # Safety
+    /// `refs` must be a JVM TI allocated array of `len` local references, which is owned by the
+    /// returned [`LocalChunks`] afterwards.
+    unsafe fn new(
+        jvm: &'j Jvm,
+        jni: &'j JNI,
+        refs: *mut sys::jobject,
+        len: usize,
+        chunk_size: usize,
+        make_item: Box<dyn FnMut(sys::jobject, usize) -> T + 'j>,
+    ) -> Self {
+        Self {
+            jvm,
+            jni,
+            refs,
+            len,
+            position: 0,
+            chunk_size: chunk_size.max(1),
+            make_item,
+            chunk: Vec::new(),
+            frame_pushed: false,
+        }
+    }
+
+    /// Returns the number of elements that have not been yielded yet.
+    #[must_use]
+    pub fn remaining(&self) -> usize {
+        self.len - self.position
+    }
+
+    /// Releases the current chunk and returns the next one, or `None` if all the elements have
+    /// been yielded.
+    /// # Errors
+    /// Returns [`JNIError::OutOfMemory`] if the local frame for the chunk cannot be created.
+    pub fn next_chunk(&mut self) -> Result<Option<&[T]>, JNIError> {
+        self.release_chunk();
+        if self.position >= self.len {
+            return Ok(None);
+        }
+        let end = self.len.min(self.position + self.chunk_size);
+        let capacity = sys::jint::try_from(end - self.position).unwrap_or(sys::jint::MAX);
+        self.jni.push_local_frame(capacity)?;
+        self.frame_pushed = true;
+        for index in self.position..end {
+            // SAFETY: `index` is in bounds and the reference at `index` has not been deleted yet.
+            // The reference is moved into the new frame so that popping it releases the element.
+            let reference = unsafe {
+                let original = *self.refs.add(index);
+                let reference = self.jni.new_local_ref(original);
+                self.jni.delete_local_ref(original);
+                reference
+            };
+            self.chunk.push((self.make_item)(reference, index));
+        }
+        self.position = end;
+        Ok(Some(&self.chunk))
+    }
+
+    /// Calls `f` on every remaining element, chunk by chunk.
+    /// # Errors
+    /// Returns [`JNIError::OutOfMemory`] if the local frame for a chunk cannot be created.
+    pub fn for_each<F>(mut self, mut f: F) -> Result<(), JNIError>
+    where
+        F: FnMut(&T),
+    {
+        while let Some(chunk) = self.next_chunk()? {
+            chunk.iter().for_each(&mut f);
+        }
+        Ok(())
+    }
+
+    fn release_chunk(&mut self) {
+        self.chunk.clear();
+        if self.frame_pushed {
+            // SAFETY: The frame was pushed by `next_chunk` and has not been popped yet.
+            unsafe { self.jni.pop_local_frame() };
+            self.frame_pushed = false;
+        }
+    }
+}
+
+impl<T> Drop for LocalChunks<'_, T> {
+    fn drop(&mut self) {
+        self.release_chunk();
+        for index in self.position..self.len {
+            // SAFETY: The references that have not been yielded are still owned by `self`.
+            unsafe { self.jni.delete_local_ref(*self.refs.add(index)) };
+        }
+        // SAFETY: `self.refs` was allocated by the JVM TI and is not used afterwards.
+        // Nothing sensible can be done if the deallocation fails in a destructor.
+        let _ = unsafe { self.jvm.deallocate(self.refs) };
+    }
+}
+
+impl<T> std::fmt::Debug for LocalChunks<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalChunks")
+            .field("len", &self.len)
+            .field("position", &self.position)
+            .field("chunk_size", &self.chunk_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Jvm {
+    /// Gets all the loaded classes, processed in chunks of `chunk_size` classes.
+    /// See [`GetLoadedClasses`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetLoadedClasses).
+    /// # Errors
+    /// See [`JvmTIError`] for more information.
+    pub fn loaded_classes_chunked<'j>(
+        &'j self,
+        jni: &'j JNI,
+        chunk_size: usize,
+    ) -> Result<LocalChunks<'j, Class<'j>>, JvmTIError> {
+        let mut count = MaybeUninit::uninit();
+        let mut classes = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetLoadedClasses,
+                count.as_mut_ptr(),
+                classes.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `classes` points to an array of `count`
+        // local references to classes.
+        Ok(unsafe {
+            LocalChunks::new(
+                self,
+                jni,
+                classes.assume_init(),
+                usize::try_from(count.assume_init()).unwrap_or_default(),
+                chunk_size,
+                Box::new(move |reference, _| Class::from_ptr(self, reference)),
+            )
+        })
+    }
+
+    /// Gets all the live platform threads, processed in chunks of `chunk_size` threads.
+    /// See [`GetAllThreads`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetAllThreads).
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn all_threads_chunked<'j>(
+        &'j self,
+        jni: &'j JNI,
+        chunk_size: usize,
+    ) -> Result<LocalChunks<'j, Thread<'j>>, ThreadError> {
+        let mut count = MaybeUninit::uninit();
+        let mut threads = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetAllThreads,
+                count.as_mut_ptr(),
+                threads.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `threads` points to an array of `count`
+        // local references to threads.
+        Ok(unsafe {
+            LocalChunks::new(
+                self,
+                jni,
+                threads.assume_init(),
+                usize::try_from(count.assume_init()).unwrap_or_default(),
+                chunk_size,
+                Box::new(move |reference, _| Thread::from_ptr(self, reference)),
+            )
+        })
+    }
+
+    /// Gets the live objects tagged with any of `tags` together with their tags, processed in
+    /// chunks of `chunk_size` objects.
+    /// See [`GetObjectsWithTags`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetObjectsWithTags).
+    /// # Errors
+    /// Returns [`HeapError::MissingCapabilities`] if the `can_tag_objects` capability cannot be
+    /// added. See [`HeapError`] for more information.
+    pub fn objects_with_tags_chunked<'j>(
+        &'j self,
+        jni: &'j JNI,
+        tags: &[sys::jlong],
+        chunk_size: usize,
+    ) -> Result<LocalChunks<'j, (Object<'j>, sys::jlong)>, HeapError> {
+        self.acquire_capabilities(JvmtiCapabilities::CAN_TAG_OBJECTS)
+            .map_err(HeapError::MissingCapabilities)?;
+        let tag_count = sys::jint::try_from(tags.len()).map_err(|_| HeapError::IllegalArgument)?;
+        let mut count = MaybeUninit::uninit();
+        let mut objects = MaybeUninit::uninit();
+        let mut tag_result = MaybeUninit::uninit();
+        // SAFETY: `tags` holds `tag_count` tags.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetObjectsWithTags,
+                tag_count,
+                tags.as_ptr(),
+                count.as_mut_ptr(),
+                objects.as_mut_ptr(),
+                tag_result.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `objects` and `tag_result` point to arrays of
+        // `count` local references and tags respectively.
+        unsafe {
+            let count = usize::try_from(count.assume_init()).unwrap_or_default();
+            let tag_result = tag_result.assume_init();
+            let found_tags = if count == 0 {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(tag_result, count).to_vec()
+            };
+            let chunks = LocalChunks::new(
+                self,
+                jni,
+                objects.assume_init(),
+                count,
+                chunk_size,
+                Box::new(move |reference, index| {
+                    (Object::from_ptr(self, reference), found_tags[index])
+                }),
+            );
+            self.deallocate(tag_result)?;
+            Ok(chunks)
+        }
+    }
+}
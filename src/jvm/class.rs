@@ -1,26 +1,579 @@
 //! APIs for working with Java classes.
 
-use std::mem::MaybeUninit;
+use std::{
+    collections::VecDeque,
+    ffi::{CString, OsString},
+    mem::{ManuallyDrop, MaybeUninit},
+    os::unix::prelude::{OsStrExt, OsStringExt},
+    path::Path,
+    ptr::null_mut,
+};
 
 use crate::{macros::call_jvmti, sys};
 
-use super::{errors::JvmTIError, Jvm};
+use super::{
+    errors::{ClassError, JvmTIError, RedefineError},
+    jni::{GlobalRef, JNIError, JNI},
+    methods::Method,
+    strings::{ModifiedUtf8Ext, StringError, Utf8Policy},
+    Jvm,
+};
+
+#[cfg(feature = "class-events")]
+use super::objects::Object;
+
+bitflags::bitflags! {
+    /// The status of a class, as returned by [`Class::status`].
+    /// See [`GetClassStatus`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetClassStatus).
+    ///
+    /// The methods and fields of a class can only be inspected once it is
+    /// [`ClassStatus::PREPARED`]. Array and primitive classes have none of the other bits set.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ClassStatus: u32 {
+        /// The bytecode of the class has been verified.
+        const VERIFIED = sys::JVMTI_CLASS_STATUS_VERIFIED;
+        /// The class has been prepared, so its methods and fields are available.
+        const PREPARED = sys::JVMTI_CLASS_STATUS_PREPARED;
+        /// The static initializer of the class has run.
+        const INITIALIZED = sys::JVMTI_CLASS_STATUS_INITIALIZED;
+        /// The initialization of the class has failed, so it is unusable.
+        const ERROR = sys::JVMTI_CLASS_STATUS_ERROR;
+        /// The class is an array class.
+        const ARRAY = sys::JVMTI_CLASS_STATUS_ARRAY;
+        /// The class is a primitive class, e.g. `java.lang.Integer.TYPE`.
+        const PRIMITIVE = sys::JVMTI_CLASS_STATUS_PRIMITIVE;
+    }
+}
+
+bitflags::bitflags! {
+    /// The access flags of a class, as returned by [`Class::modifiers`].
+    /// See [`GetClassModifiers`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetClassModifiers).
+    ///
+    /// The flags follow the `access_flags` of the class file, and include the flags of the
+    /// `InnerClasses` attribute for nested classes, like `Class.getModifiers`. Unknown bits are
+    /// kept as they are.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ClassModifiers: u32 {
+        /// The class is `public`.
+        const PUBLIC = 0x0001;
+        /// The nested class is `private`.
+        const PRIVATE = 0x0002;
+        /// The nested class is `protected`.
+        const PROTECTED = 0x0004;
+        /// The nested class is `static`.
+        const STATIC = 0x0008;
+        /// The class is `final`.
+        const FINAL = 0x0010;
+        /// The class uses the modern semantics of `invokespecial`.
+        const SUPER = 0x0020;
+        /// The class is an interface.
+        const INTERFACE = 0x0200;
+        /// The class is `abstract`.
+        const ABSTRACT = 0x0400;
+        /// The class is not present in the source code.
+        const SYNTHETIC = 0x1000;
+        /// The class is an annotation interface.
+        const ANNOTATION = 0x2000;
+        /// The class is an enum class.
+        const ENUM = 0x4000;
+        /// The class file defines a module rather than a class.
+        const MODULE = 0x8000;
+    }
+}
+
+/// The signatures of a class returned by [`Class::signatures`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassSignature {
+    /// The JNI type signature of the class, e.g. `Ljava/util/List;`.
+    pub signature: OsString,
+    /// The generic signature of the class, e.g. `<E:Ljava/lang/Object;>Ljava/lang/Object;`, or
+    /// `None` if the class has no generic signature.
+    pub generic_signature: Option<OsString>,
+}
 
 /// A Java class.
+///
+/// A handle wraps a JNI local reference of the thread it was obtained on. Handles given to event
+/// callbacks, or created with [`Class::from_raw`], borrow a reference owned by someone else and are
+/// only valid while it is, e.g. until the callback returns. Handles returned by the functions of
+/// this crate own the local reference they were given and delete it when dropped, so that long
+/// loops do not exhaust the local reference table. Either way, a handle must not be used after its
+/// thread leaves the native method or callback it was obtained in; use [`Class::to_global`] to keep
+/// the class longer or to use it on another thread.
 #[derive(Debug)]
 pub struct Class<'j> {
-    jvm: &'j Jvm,
-    jclass: sys::jclass,
+    pub(crate) jvm: &'j Jvm,
+    pub(crate) jclass: sys::jclass,
+    /// Whether the handle owns its local reference and deletes it when dropped.
+    owned: bool,
 }
 
 impl Class<'_> {
     pub(crate) unsafe fn from_ptr<'j>(jvm: &'j Jvm, jclass: sys::jclass) -> Class<'j> {
         assert!(!jclass.is_null(), "The class pointer must not be null");
-        Class { jvm, jclass }
+        Class {
+            jvm,
+            jclass,
+            owned: false,
+        }
+    }
+
+    /// Creates a handle that owns `jclass`, a local reference created by this crate, and deletes it
+    /// when dropped.
+    /// # Safety
+    /// `jclass` must be a valid local reference of the current thread that is not used elsewhere.
+    pub(crate) unsafe fn from_local(jvm: &Jvm, jclass: sys::jclass) -> Class<'_> {
+        Class {
+            owned: true,
+            ..Self::from_ptr(jvm, jclass)
+        }
+    }
+
+    /// Creates a [`Class`] from a raw JNI reference to the class, e.g. one obtained from other JNI code.
+    /// # Safety
+    /// `jclass` must be a valid reference to a `java.lang.Class` that stays valid while the returned handle is used.
+    /// # Panics
+    /// Panics if `jclass` is null.
+    pub unsafe fn from_raw(jvm: &Jvm, jclass: sys::jclass) -> Class<'_> {
+        Self::from_ptr(jvm, jclass)
+    }
+
+    /// Gets the raw JNI reference to the class without giving up the handle.
+    #[must_use]
+    pub fn as_raw(&self) -> sys::jclass {
+        self.jclass
+    }
+
+    /// Gives up the handle and returns the raw JNI reference to the class. The reference is not
+    /// deleted, so the caller becomes responsible for a local reference owned by the handle.
+    #[must_use]
+    pub fn into_raw(self) -> sys::jclass {
+        ManuallyDrop::new(self).jclass
+    }
+
+    /// Creates a global reference to the class, which can outlive the handle and be sent to other
+    /// threads.
+    /// See [`NewGlobalRef`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#newglobalref).
+    /// # Errors
+    /// Returns [`JNIError::OutOfMemory`] if the VM runs out of memory.
+    pub fn to_global(&self, jni: &JNI) -> Result<GlobalRef<Class<'static>>, JNIError> {
+        GlobalRef::new(jni, self)
+    }
+
+    /// Returns whether `self` and `other` refer to the same class. Handles are local references,
+    /// so two handles for the same class are usually different pointers.
+    /// See [`IsSameObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#issameobject).
+    #[must_use]
+    pub fn is_same(&self, jni: &JNI, other: &Class<'_>) -> bool {
+        // SAFETY: Both are valid class references.
+        unsafe { jni.is_same_object(self.jclass, other.jclass) }
+    }
+
+    /// Gets the JNI type signature of the class, e.g. `Ljava/lang/String;`.
+    /// See [`Class::signatures`] to get the generic signature as well.
+    /// See [`GetClassSignature`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetClassSignature).
+    /// # Errors
+    /// See [`ClassError`] for more information.
+    pub fn signature(&self) -> Result<OsString, ClassError> {
+        let mut signature_ptr = MaybeUninit::uninit();
+        // SAFETY: `self.jclass` is a valid `jclass` and the generic signature is not requested.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetClassSignature,
+                self.jclass,
+                signature_ptr.as_mut_ptr(),
+                null_mut()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `signature_ptr` points to a JVM TI allocated string.
+        let signature = unsafe { self.jvm.take_string(signature_ptr.assume_init()) }?;
+        Ok(signature)
+    }
+
+    /// Gets the JNI type signature and the generic signature of the class.
+    /// See [`GetClassSignature`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetClassSignature).
+    /// # Errors
+    /// See [`ClassError`] for more information.
+    pub fn signatures(&self) -> Result<ClassSignature, ClassError> {
+        let mut signature_ptr = MaybeUninit::uninit();
+        let mut generic_ptr = MaybeUninit::uninit();
+        // SAFETY: `self.jclass` is a valid `jclass`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetClassSignature,
+                self.jclass,
+                signature_ptr.as_mut_ptr(),
+                generic_ptr.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `signature_ptr` points to a JVM TI allocated
+        // string and that `generic_ptr` is null or points to one. Both are taken before checking
+        // for errors so that neither is leaked.
+        let (signature, generic_signature) = unsafe {
+            let generic_ptr = generic_ptr.assume_init();
+            (
+                self.jvm.take_string(signature_ptr.assume_init()),
+                (!generic_ptr.is_null()).then(|| self.jvm.take_string(generic_ptr)),
+            )
+        };
+        Ok(ClassSignature {
+            signature: signature?,
+            generic_signature: generic_signature.transpose()?,
+        })
+    }
+
+    /// Gets the name of the class as returned by `Class.getName`, e.g. `java.lang.String`,
+    /// `[Ljava.lang.String;`, or `int`.
+    /// See [`Class::signature`] for the form used by JNI and JVM TI.
+    /// # Errors
+    /// See [`ClassError`] for more information.
+    pub fn name(&self) -> Result<OsString, ClassError> {
+        let signature = self.signature()?;
+        Ok(OsString::from_vec(class_name(signature.as_bytes())))
+    }
+
+    /// Gets the JNI type signature of the class decoded according to `policy`.
+    /// See [`Class::signature`] for the byte-level form.
+    /// # Errors
+    /// See [`StringError`] for more information.
+    pub fn signature_utf8(&self, policy: Utf8Policy) -> Result<String, StringError<ClassError>> {
+        let signature = self.signature()?;
+        signature
+            .to_utf8(policy)
+            .map(Into::into)
+            .map_err(StringError::Utf8)
+    }
+
+    /// Checks whether the class can be redefined or retransformed.
+    /// See [`IsModifiableClass`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#IsModifiableClass).
+    /// # Errors
+    /// See [`ClassError`] for more information.
+    pub fn is_modifiable(&self) -> Result<bool, ClassError> {
+        let mut is_modifiable = MaybeUninit::uninit();
+        // SAFETY: `self.jclass` is a valid `jclass`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                IsModifiableClass,
+                self.jclass,
+                is_modifiable.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `is_modifiable` has been initialized.
+        Ok(unsafe { is_modifiable.assume_init() } != 0)
+    }
+
+    /// Gets the access flags of the class, e.g. whether it is public or an interface.
+    /// See [`GetClassModifiers`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetClassModifiers).
+    /// # Errors
+    /// See [`ClassError`] for more information.
+    pub fn modifiers(&self) -> Result<ClassModifiers, ClassError> {
+        let mut modifiers = MaybeUninit::uninit();
+        // SAFETY: `self.jclass` is a valid `jclass`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetClassModifiers,
+                self.jclass,
+                modifiers.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `modifiers` has been initialized.
+        Ok(ClassModifiers::from_bits_retain(
+            unsafe { modifiers.assume_init() }.cast_unsigned(),
+        ))
+    }
+
+    /// Gets the status of the class, e.g. whether it has been prepared or initialized.
+    /// See [`GetClassStatus`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetClassStatus).
+    /// # Errors
+    /// See [`ClassError`] for more information.
+    pub fn status(&self) -> Result<ClassStatus, ClassError> {
+        let mut status = MaybeUninit::uninit();
+        // SAFETY: `self.jclass` is a valid `jclass`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetClassStatus,
+                self.jclass,
+                status.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `status` has been initialized.
+        Ok(ClassStatus::from_bits_retain(
+            unsafe { status.assume_init() }.cast_unsigned(),
+        ))
     }
 }
 
+impl<'j> Class<'j> {
+    /// Gets the methods declared by the class, including constructors and static initializers,
+    /// but not inherited methods.
+    /// See [`GetClassMethods`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetClassMethods).
+    /// # Errors
+    /// Returns [`ClassError::ClassNotPrepared`] if the class is not prepared yet.
+    /// See [`ClassError`] for more information.
+    pub fn methods(&self) -> Result<Vec<Method<'j>>, ClassError> {
+        let mut count = MaybeUninit::uninit();
+        let mut methods = MaybeUninit::uninit();
+        // SAFETY: `self.jclass` is a valid `jclass`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetClassMethods,
+                self.jclass,
+                count.as_mut_ptr(),
+                methods.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `methods` points to `count` method IDs.
+        let methods = unsafe {
+            self.jvm
+                .take_array(methods.assume_init(), count.assume_init())
+        }?;
+        Ok(methods
+            .into_iter()
+            // SAFETY: The method IDs are valid while the class is loaded.
+            .map(|it| unsafe { Method::from_ptr(self.jvm, it) })
+            .collect())
+    }
+
+    /// Gets the class loader of the class, or `None` for the bootstrap class loader.
+    /// See [`GetClassLoader`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetClassLoader).
+    /// # Errors
+    /// See [`ClassError`] for more information.
+    #[cfg(feature = "class-events")]
+    pub(crate) fn class_loader(&self) -> Result<Option<Object<'j>>, ClassError> {
+        let mut loader_ptr = MaybeUninit::uninit();
+        // SAFETY: `self.jclass` is a valid `jclass`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetClassLoader,
+                self.jclass,
+                loader_ptr.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `loader_ptr` has been initialized.
+        let loader_ptr = unsafe { loader_ptr.assume_init() };
+        // SAFETY: A non-null `loader_ptr` is a valid local reference to the class loader.
+        Ok((!loader_ptr.is_null()).then(|| unsafe { Object::from_local(self.jvm, loader_ptr) }))
+    }
+
+    /// Gets the interfaces directly implemented by the class, or directly extended by an
+    /// interface, in the order of its declaration.
+    /// See [`GetImplementedInterfaces`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetImplementedInterfaces).
+    /// # Errors
+    /// Returns [`ClassError::ClassNotPrepared`] if the class is not prepared yet.
+    /// See [`ClassError`] for more information.
+    pub fn interfaces(&self) -> Result<Vec<Class<'j>>, ClassError> {
+        let mut count = MaybeUninit::uninit();
+        let mut interfaces = MaybeUninit::uninit();
+        // SAFETY: `self.jclass` is a valid `jclass`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetImplementedInterfaces,
+                self.jclass,
+                count.as_mut_ptr(),
+                interfaces.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `interfaces` points to `count` local
+        // references.
+        let interfaces = unsafe {
+            self.jvm
+                .take_array(interfaces.assume_init(), count.assume_init())
+        }?;
+        Ok(interfaces
+            .into_iter()
+            // SAFETY: Each element is a new local reference to an interface.
+            .map(|it| unsafe { Class::from_local(self.jvm, it) })
+            .collect())
+    }
+
+    /// Gets the superclass of the class, or `None` if the class is an interface, a primitive
+    /// class, or `java.lang.Object`. The superclass of an array class is `java.lang.Object`.
+    /// See [`GetSuperclass`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#getsuperclass).
+    #[must_use]
+    pub fn superclass(&self, jni: &JNI) -> Option<Class<'j>> {
+        // SAFETY: `self.jclass` is a valid `jclass`.
+        let superclass = unsafe { jni.get_superclass(self.jclass) };
+        // SAFETY: A non-null `superclass` is a new local reference.
+        (!superclass.is_null()).then(|| unsafe { Class::from_local(self.jvm, superclass) })
+    }
+
+    /// Gets an iterator over all the supertypes of the class, i.e. its superclasses and the
+    /// interfaces they implement, directly or through other interfaces. See [`Supertypes`].
+    #[must_use]
+    pub fn supertypes<'a>(&self, jni: &'a JNI) -> Supertypes<'j, 'a> {
+        let mut supertypes = Supertypes {
+            jni,
+            pending: VecDeque::new(),
+            seen: Vec::new(),
+            failed: None,
+        };
+        supertypes.expand(self);
+        supertypes
+    }
+}
+
+/// An iterator over the supertypes of a class, returned by [`Class::supertypes`].
+///
+/// The supertypes are visited breadth first, starting with the direct superclass and the direct
+/// interfaces, and each of them is yielded once. An error is yielded in place of the supertypes of
+/// a class whose interfaces cannot be retrieved, e.g. because it is not prepared yet.
+#[derive(Debug)]
+pub struct Supertypes<'j, 'a> {
+    jni: &'a JNI,
+    /// The supertypes that have been found but not yielded yet.
+    pending: VecDeque<Class<'j>>,
+    /// The supertypes that have been yielded.
+    seen: Vec<Class<'j>>,
+    /// The error of the last expansion, which is yielded next.
+    failed: Option<ClassError>,
+}
+
+impl<'j> Supertypes<'j, '_> {
+    /// Queues the direct supertypes of `class` that have not been found yet.
+    fn expand(&mut self, class: &Class<'j>) {
+        let interfaces = match class.interfaces() {
+            Ok(interfaces) => interfaces,
+            Err(error) => {
+                self.failed = Some(error);
+                Vec::new()
+            }
+        };
+        for supertype in class.superclass(self.jni).into_iter().chain(interfaces) {
+            let known = self
+                .seen
+                .iter()
+                .chain(&self.pending)
+                .any(|it| it.is_same(self.jni, &supertype));
+            if !known {
+                self.pending.push_back(supertype);
+            }
+        }
+    }
+}
+
+impl<'j> Iterator for Supertypes<'j, '_> {
+    type Item = Result<Class<'j>, ClassError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(error) = self.failed.take() {
+            return Some(Err(error));
+        }
+        let class = self.pending.pop_front()?;
+        self.expand(&class);
+        // SAFETY: `class.jclass` is a valid reference, and the new local reference is owned by
+        // the yielded handle.
+        let yielded = unsafe { Class::from_local(class.jvm, self.jni.new_local_ref(class.jclass)) };
+        self.seen.push(class);
+        Some(Ok(yielded))
+    }
+}
+
+/// A new definition of a class for [`Jvm::redefine_classes`].
+#[derive(Debug, Clone, Copy)]
+pub struct ClassDefinition<'a, 'j> {
+    /// The class to redefine.
+    pub class: &'a Class<'j>,
+    /// The new class file bytes.
+    pub class_bytes: &'a [u8],
+}
+
 impl Jvm {
+    /// Retransforms the given classes, which sends the `ClassFileLoadHook` event for each of them
+    /// to the retransformation capable environments.
+    /// See [`RetransformClasses`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#RetransformClasses).
+    /// # Errors
+    /// See [`RedefineError`] for more information.
+    pub fn retransform_classes(&self, classes: &[&Class<'_>]) -> Result<(), RedefineError> {
+        let jclasses: Vec<_> = classes.iter().map(|it| it.jclass).collect();
+        let class_count =
+            sys::jint::try_from(jclasses.len()).map_err(|_| RedefineError::IllegalArgument)?;
+        // SAFETY: `jclasses` holds `class_count` valid `jclass`es.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                RetransformClasses,
+                class_count,
+                jclasses.as_ptr()
+            )
+        }?;
+        Ok(())
+    }
+
+    /// Redefines the given classes with new class file bytes.
+    /// See [`RedefineClasses`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#RedefineClasses).
+    /// # Errors
+    /// See [`RedefineError`] for more information.
+    pub fn redefine_classes(
+        &self,
+        definitions: &[ClassDefinition<'_, '_>],
+    ) -> Result<(), RedefineError> {
+        let definitions = definitions
+            .iter()
+            .map(|it| {
+                Ok(sys::jvmtiClassDefinition {
+                    klass: it.class.jclass,
+                    class_byte_count: sys::jint::try_from(it.class_bytes.len())
+                        .map_err(|_| RedefineError::IllegalArgument)?,
+                    class_bytes: it.class_bytes.as_ptr(),
+                })
+            })
+            .collect::<Result<Vec<_>, RedefineError>>()?;
+        let class_count =
+            sys::jint::try_from(definitions.len()).map_err(|_| RedefineError::IllegalArgument)?;
+        // SAFETY: `definitions` holds `class_count` definitions whose bytes outlive the call.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                RedefineClasses,
+                class_count,
+                definitions.as_ptr()
+            )
+        }?;
+        Ok(())
+    }
+
+    /// Adds a JAR file to the search path of the bootstrap class loader.
+    /// See [`AddToBootstrapClassLoaderSearch`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#AddToBootstrapClassLoaderSearch).
+    /// # Errors
+    /// See [`JvmTIError`] for more information.
+    pub fn add_to_bootstrap_class_loader_search(&self, segment: &Path) -> Result<(), JvmTIError> {
+        let segment = CString::new(segment.as_os_str().as_bytes())
+            .map_err(|_| JvmTIError::IllegalArgument)?;
+        // SAFETY: `segment` is a null-terminated string that outlives the call.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                AddToBootstrapClassLoaderSearch,
+                segment.as_ptr()
+            )
+        }
+    }
+
+    /// Adds a JAR file to the search path of the system class loader.
+    /// See [`AddToSystemClassLoaderSearch`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#AddToSystemClassLoaderSearch).
+    /// # Errors
+    /// See [`JvmTIError`] for more information.
+    pub fn add_to_system_class_loader_search(&self, segment: &Path) -> Result<(), JvmTIError> {
+        let segment = CString::new(segment.as_os_str().as_bytes())
+            .map_err(|_| JvmTIError::IllegalArgument)?;
+        // SAFETY: `segment` is a null-terminated string that outlives the call.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                AddToSystemClassLoaderSearch,
+                segment.as_ptr()
+            )
+        }
+    }
+
     /// Gets all the loaded classes.
     /// See [`GetLoadedClasses`](https://docs.oracle.com/javase/8/docs/platform/jvmti/jvmti.html#GetLoadedClasses).
     /// # Errors
@@ -45,8 +598,80 @@ impl Jvm {
             let classes = unsafe { std::slice::from_raw_parts(classes, class_count) };
             classes
                 .iter()
-                .map(|&jclass| Class { jvm: self, jclass })
+                .map(|&jclass| unsafe { Class::from_local(self, jclass) })
                 .collect()
         })
     }
 }
+
+impl Drop for Class<'_> {
+    fn drop(&mut self) {
+        if self.owned {
+            // SAFETY: An owned handle is the only user of its local reference.
+            unsafe { self.jvm.delete_local_ref(self.jclass) };
+        }
+    }
+}
+
+/// Converts the JNI type signature of a class into its name as returned by `Class.getName`.
+fn class_name(signature: &[u8]) -> Vec<u8> {
+    let dotted = |it: &[u8]| -> Vec<u8> {
+        it.iter()
+            .map(|&byte| if byte == b'/' { b'.' } else { byte })
+            .collect()
+    };
+    match signature {
+        [b'L', name @ .., b';'] => dotted(name),
+        [b'[', ..] => dotted(signature),
+        b"Z" => b"boolean".to_vec(),
+        b"B" => b"byte".to_vec(),
+        b"C" => b"char".to_vec(),
+        b"S" => b"short".to_vec(),
+        b"I" => b"int".to_vec(),
+        b"J" => b"long".to_vec(),
+        b"F" => b"float".to_vec(),
+        b"D" => b"double".to_vec(),
+        b"V" => b"void".to_vec(),
+        _ => signature.to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_class_file_access_flags() {
+        // The `access_flags` of `public @interface Deprecated` and `public enum Thread.State`.
+        assert_eq!(
+            ClassModifiers::from_bits_retain(0x2601),
+            ClassModifiers::PUBLIC
+                | ClassModifiers::INTERFACE
+                | ClassModifiers::ABSTRACT
+                | ClassModifiers::ANNOTATION
+        );
+        assert_eq!(
+            ClassModifiers::from_bits_retain(0x4019),
+            ClassModifiers::PUBLIC
+                | ClassModifiers::STATIC
+                | ClassModifiers::FINAL
+                | ClassModifiers::ENUM
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_modifier_bits() {
+        let modifiers = ClassModifiers::from_bits_retain(0x0001 | 0x0800);
+        assert!(modifiers.contains(ClassModifiers::PUBLIC));
+        assert_eq!(modifiers.bits(), 0x0801);
+    }
+
+    #[test]
+    fn names_classes_from_signatures() {
+        assert_eq!(class_name(b"Ljava/lang/String;"), b"java.lang.String");
+        assert_eq!(class_name(b"[Ljava/lang/String;"), b"[Ljava.lang.String;");
+        assert_eq!(class_name(b"[[I"), b"[[I");
+        assert_eq!(class_name(b"I"), b"int");
+        assert_eq!(class_name(b"V"), b"void");
+    }
+}
@@ -0,0 +1,177 @@
+//! Selection of classes by name for the `ClassFileLoadHook` event.
+//!
+//! Instrumentation agents usually transform only the classes of a few packages. A
+//! [`ClassNameFilter`] attached with [`Handler::for_classes`](super::events::Handler::for_classes)
+//! is checked before any callback is called, so the other classes are loaded at almost no cost.
+
+/// A part of a compiled pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// Bytes matched literally.
+    Literal(Vec<u8>),
+    /// `?`, matching any single byte except `/`.
+    One,
+    /// `*`, matching any sequence of bytes without `/`.
+    Star,
+    /// `**`, matching any sequence of bytes.
+    DoubleStar,
+}
+
+/// A compiled class name pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    tokens: Vec<Token>,
+}
+
+impl Pattern {
+    fn compile(pattern: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut bytes = pattern.bytes().peekable();
+        while let Some(byte) = bytes.next() {
+            let token = match byte {
+                b'*' if bytes.next_if_eq(&b'*').is_some() => Token::DoubleStar,
+                b'*' => Token::Star,
+                b'?' => Token::One,
+                // The patterns may use the binary names of the classes, e.g. `com.example.*`.
+                b'.' => Token::Literal(vec![b'/']),
+                _ => Token::Literal(vec![byte]),
+            };
+            match (tokens.last_mut(), token) {
+                (Some(Token::Literal(literal)), Token::Literal(byte)) => literal.extend(byte),
+                (_, token) => tokens.push(token),
+            }
+        }
+        Self { tokens }
+    }
+
+    fn matches(&self, name: &[u8]) -> bool {
+        matches_tokens(&self.tokens, name)
+    }
+}
+
+fn matches_tokens(tokens: &[Token], name: &[u8]) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return name.is_empty();
+    };
+    match token {
+        Token::Literal(literal) => {
+            name.starts_with(literal) && matches_tokens(rest, &name[literal.len()..])
+        }
+        Token::One => {
+            name.first().is_some_and(|&it| it != b'/') && matches_tokens(rest, &name[1..])
+        }
+        Token::Star => {
+            let segment_end = name.iter().position(|&it| it == b'/').unwrap_or(name.len());
+            (0..=segment_end).any(|it| matches_tokens(rest, &name[it..]))
+        }
+        Token::DoubleStar => (0..=name.len()).any(|it| matches_tokens(rest, &name[it..])),
+    }
+}
+
+/// Selects classes by matching their internal names, e.g. `java/lang/String`, against glob
+/// patterns. In a pattern, `?` matches any single character except `/`, `*` matches any sequence
+/// of characters without `/`, and `**` matches any sequence of characters, e.g. `com/example/**`.
+///
+/// A class is selected if its name matches none of the excluded patterns and, unless there are no
+/// included patterns, at least one of the included patterns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClassNameFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl ClassNameFilter {
+    /// Creates a filter that selects all classes.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a filter from a list of patterns, in which the patterns starting with `!` are
+    /// excluded and the others are included, e.g. `["com/example/**", "!com/example/generated/**"]`.
+    #[must_use]
+    pub fn from_patterns<I, S>(patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        patterns.into_iter().fold(Self::new(), |filter, pattern| {
+            match pattern.as_ref().strip_prefix('!') {
+                Some(pattern) => filter.exclude(pattern),
+                None => filter.include(pattern.as_ref()),
+            }
+        })
+    }
+
+    /// Selects the classes whose names match `pattern`.
+    #[must_use]
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(Pattern::compile(pattern));
+        self
+    }
+
+    /// Rejects the classes whose names match `pattern`.
+    #[must_use]
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(Pattern::compile(pattern));
+        self
+    }
+
+    /// Returns whether the class named `name` is selected. Classes without a name, e.g. hidden
+    /// classes, are only selected by filters without included patterns.
+    #[must_use]
+    pub fn selects(&self, name: Option<&[u8]>) -> bool {
+        let Some(name) = name else {
+            return self.include.is_empty();
+        };
+        !self.exclude.iter().any(|it| it.matches(name))
+            && (self.include.is_empty() || self.include.iter().any(|it| it.matches(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        Pattern::compile(pattern).matches(name.as_bytes())
+    }
+
+    #[test]
+    fn wildcards_respect_package_separators() {
+        assert!(matches("java/lang/String", "java/lang/String"));
+        assert!(!matches("java/lang/String", "java/lang/StringBuilder"));
+        assert!(matches("java/lang/*", "java/lang/String"));
+        assert!(!matches("java/lang/*", "java/lang/invoke/MethodHandle"));
+        assert!(matches("java/**", "java/lang/invoke/MethodHandle"));
+        assert!(matches("java/lang/Strin?", "java/lang/String"));
+        assert!(!matches("java/lang?String", "java/lang/String"));
+        assert!(matches("**/*Test", "com/example/FooTest"));
+    }
+
+    #[test]
+    fn binary_names_match_internal_names() {
+        assert!(matches("com.example.*", "com/example/Foo"));
+        assert!(!matches("com.example.*", "com/exampleXFoo"));
+    }
+
+    #[test]
+    fn exclusions_take_precedence_over_inclusions() {
+        let filter =
+            ClassNameFilter::from_patterns(["com/example/**", "!com/example/generated/**"]);
+        assert!(filter.selects(Some(b"com/example/Foo")));
+        assert!(!filter.selects(Some(b"com/example/generated/Foo")));
+        assert!(!filter.selects(Some(b"org/example/Foo")));
+        assert!(!filter.selects(None));
+    }
+
+    #[test]
+    fn filters_without_inclusions_select_everything_else() {
+        assert!(ClassNameFilter::new().selects(Some(b"java/lang/String")));
+        assert!(ClassNameFilter::new().selects(None));
+        let filter = ClassNameFilter::new().exclude("jdk/**");
+        assert!(!filter.selects(Some(b"jdk/internal/Misc")));
+        assert!(filter.selects(Some(b"java/lang/String")));
+        assert!(filter.selects(None));
+    }
+}
@@ -1,9 +1,14 @@
 //! APIs for errors returned by the JVM Tool Interface (JVM TI).
 use crate::sys;
 
+use super::capabilities::JvmtiCapabilities;
+
 /// The error occurrec when calling a JVM Tool Interface (JVM TI) function.
 /// See [the JVMTI documentation](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#jvmtierror) for more information.
-#[derive(Debug, thiserror::Error)]
+/// Functions whose possible errors are known more precisely return one of the function group
+/// errors defined in this module, such as [`ThreadError`] or [`RedefineError`], instead. They can
+/// be converted from and into a [`JvmTIError`] with [`From`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
 #[repr(u32)]
 #[allow(missing_docs)]
 pub enum JvmTIError {
@@ -173,3 +178,225 @@ impl JvmTIError {
         std::mem::transmute(code)
     }
 }
+
+/// Defines an error type for a group of JVM TI functions holding only the errors specific to
+/// that group, plus an `Other` variant for the universal errors and anything unexpected.
+/// A trailing `+ MissingCapabilities` adds a variant naming the capabilities that could not be
+/// acquired automatically, see [`Jvm::set_auto_capabilities`](super::Jvm::set_auto_capabilities).
+macro_rules! function_group_error {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident),* $(,)? } $(+ $missing:ident)?) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+        #[allow(missing_docs)]
+        #[non_exhaustive]
+        pub enum $name {
+            $(
+                #[error("{}", JvmTIError::$variant)]
+                $variant,
+            )*
+            $(
+                /// The capabilities required by the function could not be added.
+                #[error("{}: cannot add {:?}", JvmTIError::MustPossessCapability, .0)]
+                $missing(JvmtiCapabilities),
+            )?
+            /// An error that is not specific to this group of functions, such as a universal error.
+            #[error(transparent)]
+            Other(JvmTIError),
+        }
+
+        impl From<JvmTIError> for $name {
+            fn from(error: JvmTIError) -> Self {
+                match error {
+                    $(JvmTIError::$variant => Self::$variant,)*
+                    other => Self::Other(other),
+                }
+            }
+        }
+
+        impl From<$name> for JvmTIError {
+            fn from(error: $name) -> Self {
+                match error {
+                    $($name::$variant => Self::$variant,)*
+                    $($name::$missing(_) => Self::MustPossessCapability,)?
+                    $name::Other(other) => other,
+                }
+            }
+        }
+    };
+}
+
+function_group_error! {
+    /// The errors returned by the thread and thread group functions.
+    ThreadError {
+        InvalidThread,
+        ThreadNotAlive,
+        ThreadSuspended,
+        ThreadNotSuspended,
+        InvalidThreadGroup,
+        InvalidPriority,
+        IllegalArgument,
+        UnsupportedOperation,
+        MustPossessCapability,
+    }
+}
+
+function_group_error! {
+    /// The errors returned by the stack frame and local variable functions.
+    StackError {
+        InvalidThread,
+        ThreadNotAlive,
+        ThreadNotSuspended,
+        NoMoreFrames,
+        OpaqueFrame,
+        IllegalArgument,
+        InvalidSlot,
+        AbsentInformation,
+        TypeMismatch,
+        InvalidMethodId,
+        NativeMethod,
+        Duplicate,
+        MustPossessCapability,
+    }
+}
+
+function_group_error! {
+    /// The errors returned by the heap and object functions.
+    HeapError {
+        InvalidObject,
+        InvalidClass,
+        IllegalArgument,
+        MustPossessCapability,
+    } + MissingCapabilities
+}
+
+function_group_error! {
+    /// The errors returned by the class functions.
+    ClassError {
+        InvalidClass,
+        ClassNotPrepared,
+        AbsentInformation,
+        MustPossessCapability,
+    }
+}
+
+function_group_error! {
+    /// The errors returned by the method and field functions.
+    MethodError {
+        InvalidMethodId,
+        InvalidFieldId,
+        InvalidClass,
+        NativeMethod,
+        AbsentInformation,
+        MustPossessCapability,
+    }
+}
+
+function_group_error! {
+    /// The errors returned by the breakpoint and watched field functions.
+    BreakpointError {
+        InvalidMethodId,
+        InvalidLocation,
+        InvalidClass,
+        InvalidFieldId,
+        Duplicate,
+        NotFound,
+        MustPossessCapability,
+    } + MissingCapabilities
+}
+
+function_group_error! {
+    /// The errors returned by the class redefinition and retransformation functions.
+    RedefineError {
+        InvalidClass,
+        UnmodifiableClass,
+        UnmodifiableModule,
+        UnsupportedVersion,
+        InvalidClassFormat,
+        CircularClassDefinition,
+        FailsVerification,
+        NamesDontMatch,
+        UnsupportedRedefinitionMethodAdded,
+        UnsupportedRedefinitionSchemaChanged,
+        UnsupportedRedefinitionHierarchyChanged,
+        UnsupportedRedefinitionMethodDeleted,
+        UnsupportedRedefinitionClassModifiersChanged,
+        UnsupportedRedefinitionMethodModifiersChanged,
+        UnsupportedRedefinitionClassAttributeChanged,
+        IllegalArgument,
+        MustPossessCapability,
+    }
+}
+
+function_group_error! {
+    /// The errors returned by the event management functions.
+    EventError {
+        InvalidEventType,
+        InvalidThread,
+        ThreadNotAlive,
+        IllegalArgument,
+        UnsupportedOperation,
+        MustPossessCapability,
+    } + MissingCapabilities
+}
+
+function_group_error! {
+    /// The errors returned by the capability functions.
+    CapabilityError {
+        NotAvailable,
+    }
+}
+
+function_group_error! {
+    /// The errors returned by the raw monitor functions.
+    MonitorError {
+        InvalidMonitor,
+        NotMonitorOwner,
+        Interrupt,
+        IllegalArgument,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_group_errors_and_wraps_the_others() {
+        assert_eq!(
+            ThreadError::from(JvmTIError::ThreadNotAlive),
+            ThreadError::ThreadNotAlive
+        );
+        assert_eq!(
+            ThreadError::from(JvmTIError::OutOfMemory),
+            ThreadError::Other(JvmTIError::OutOfMemory)
+        );
+        for error in [
+            JvmTIError::InvalidSlot,
+            JvmTIError::WrongPhase,
+            JvmTIError::Internal,
+        ] {
+            assert_eq!(JvmTIError::from(StackError::from(error)), error);
+        }
+    }
+
+    #[test]
+    fn displays_like_the_universal_error() {
+        assert_eq!(
+            ThreadError::ThreadNotAlive.to_string(),
+            JvmTIError::ThreadNotAlive.to_string()
+        );
+        assert_eq!(
+            ThreadError::Other(JvmTIError::WrongPhase).to_string(),
+            "JVMTI_ERROR_WRONG_PHASE"
+        );
+    }
+
+    #[test]
+    fn missing_capabilities_convert_to_must_possess_capability() {
+        let error = HeapError::MissingCapabilities(JvmtiCapabilities::CAN_TAG_OBJECTS);
+        assert_eq!(JvmTIError::from(error), JvmTIError::MustPossessCapability);
+        assert!(error
+            .to_string()
+            .starts_with("JVMTI_ERROR_MUST_POSSESS_CAPABILITY: cannot add"));
+    }
+}
@@ -1,20 +1,99 @@
 //! APIs for working with event callbacks.
+//!
+//! The trampolines of each event group are only compiled in when the corresponding cargo feature
+//! is enabled, so agents can leave out the event machinery they do not use:
+//! - `vm-events`: `VMInit`, `VMDeath`, `VMStart`, `DataDumpRequest`, and `ResourceExhausted`.
+//! - `thread-events`: `ThreadStart`, `ThreadEnd`, `VirtualThreadStart`, and `VirtualThreadEnd`.
+//! - `class-events`: `ClassFileLoadHook`, `ClassLoad`, and `ClassPrepare`.
+//! - `debug-events`: `SingleStep`, `Breakpoint`, `FieldAccess`, and `FieldModification`.
+//! - `method-events`: `MethodExit`, `FramePop`, and `NativeMethodBind`.
+//! - `monitor-events`: `MonitorWait`, `MonitorWaited`, `MonitorContendedEnter`, and
+//!   `MonitorContendedEntered`.
+//! - `alloc-events`: `VMObjectAlloc` and `SampledObjectAlloc`.
+//! - `gc-events`: `GarbageCollectionStart`, `GarbageCollectionFinish`, and `ObjectFree`.
+//!
+//! All the groups are enabled by default.
 
-use std::{
-    ffi::{c_char, c_uchar, CStr, OsStr, OsString},
-    mem::size_of,
-    os::unix::prelude::OsStrExt,
+#[cfg(feature = "class-events")]
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+#[cfg(any(
+    feature = "vm-events",
+    feature = "class-events",
+    feature = "debug-events"
+))]
+use std::ffi::c_char;
+#[cfg(feature = "class-events")]
+use std::ffi::c_uchar;
+#[cfg(any(feature = "vm-events", feature = "method-events"))]
+use std::ffi::c_void;
+#[cfg(feature = "debug-events")]
+use std::ffi::OsString;
+#[cfg(any(feature = "vm-events", feature = "class-events"))]
+use std::ffi::{CStr, OsStr};
+use std::mem::size_of;
+#[cfg(any(
+    feature = "vm-events",
+    feature = "class-events",
+    feature = "method-events"
+))]
+use std::os::unix::prelude::OsStrExt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex, PoisonError,
 };
+#[cfg(feature = "monitor-events")]
+use std::time::Duration;
+use std::time::Instant;
 
-use crate::{
-    jvm::{class::Class, objects::Object},
-    macros::call_jvmti,
-    sys,
-};
+use crate::{macros::call_jvmti, sys};
 
-use super::{errors::JvmTIError, jni::JNI, threads::Thread, Jvm};
+#[cfg(any(
+    feature = "class-events",
+    feature = "debug-events",
+    feature = "alloc-events"
+))]
+use super::class::Class;
+#[cfg(feature = "debug-events")]
+use super::errors::MethodError;
+#[cfg(feature = "debug-events")]
+use super::fields::Field;
+#[cfg(any(
+    feature = "vm-events",
+    feature = "thread-events",
+    feature = "class-events",
+    feature = "debug-events",
+    feature = "method-events",
+    feature = "monitor-events",
+    feature = "alloc-events"
+))]
+use super::jni::JNI;
+#[cfg(any(feature = "debug-events", feature = "method-events"))]
+use super::methods::Method;
+#[cfg(any(
+    feature = "class-events",
+    feature = "debug-events",
+    feature = "monitor-events",
+    feature = "alloc-events"
+))]
+use super::objects::Object;
+#[cfg(any(feature = "debug-events", feature = "method-events"))]
+use super::values::{JType, JValue};
+#[cfg(feature = "class-events")]
+use super::{
+    class_filter::ClassNameFilter,
+    scratch::ScratchArena,
+    strings::{ModifiedUtf8Error, ModifiedUtf8Ext, Utf8Policy},
+};
+use super::{
+    errors::{EventError, JvmTIError},
+    thread_filter::ThreadFilter,
+    threads::{Thread, ThreadInfo},
+    Jvm,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u32)]
 pub enum JvmTIEvent {
     VMInit = sys::JVMTI_EVENT_VM_INIT,
@@ -53,23 +132,103 @@ pub enum JvmTIEvent {
     VirtualThreadEnd = sys::JVMTI_EVENT_VIRTUAL_THREAD_END,
 }
 
+/// Whether an event is generated, see [`Jvm::set_event_notification_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum EventMode {
+    /// The event is generated.
+    Enable = sys::JVMTI_ENABLE,
+    /// The event is not generated.
+    Disable = sys::JVMTI_DISABLE,
+}
+
 impl Jvm {
     /// Enables the given event.
     /// See [`SetEventNotificationMode`](https://docs.oracle.com/javase/8/docs/platform/jvmti/jvmti.html#SetEventNotificationMode).
     /// # Errors
-    /// See [`JvmTIError`] for more information.
+    /// See [`EventError`] for more information.
     pub fn enable_event(
-        &mut self,
+        &self,
         event_type: JvmTIEvent,
         thread: Option<Thread<'_>>,
-    ) -> Result<(), JvmTIError> {
+    ) -> Result<(), EventError> {
+        self.set_event_notification_mode(EventMode::Enable, event_type, thread)
+    }
+
+    /// Disables the given event, e.g. to stop a high-volume event after a sampling window.
+    /// See [`SetEventNotificationMode`](https://docs.oracle.com/javase/8/docs/platform/jvmti/jvmti.html#SetEventNotificationMode).
+    /// # Errors
+    /// See [`EventError`] for more information.
+    pub fn disable_event(
+        &self,
+        event_type: JvmTIEvent,
+        thread: Option<Thread<'_>>,
+    ) -> Result<(), EventError> {
+        self.set_event_notification_mode(EventMode::Disable, event_type, thread)
+    }
+
+    /// Removes the callback subscribed with `subscription` from the handler of its event, and
+    /// returns whether it was subscribed. Unlike [`Jvm::update_callbacks`], this only needs a
+    /// shared [`Jvm`], so callbacks can be removed at any time, including from event callbacks.
+    /// The native callback of the event stays installed, so the event is still dispatched until
+    /// it is disabled, e.g. with [`Jvm::disable_event`].
+    /// See [`Handler::unsubscribe`].
+    pub fn unsubscribe(&self, subscription: Subscription) -> bool {
+        self.callbacks.unsubscribe(subscription)
+    }
+
+    /// Controls the generation of the given event, for all threads if `thread` is `None` or for
+    /// `thread` only otherwise. `SetEventNotificationMode` is thread-safe, so this can be called at
+    /// any time, including from event callbacks.
+    /// See [`SetEventNotificationMode`](https://docs.oracle.com/javase/8/docs/platform/jvmti/jvmti.html#SetEventNotificationMode).
+    /// # Errors
+    /// See [`EventError`] for more information.
+    pub fn set_event_notification_mode(
+        &self,
+        mode: EventMode,
+        event_type: JvmTIEvent,
+        thread: Option<Thread<'_>>,
+    ) -> Result<(), EventError> {
         let thread_ptr = thread.map_or(std::ptr::null_mut(), Thread::into_raw);
+        // SAFETY: `thread_ptr` is either null or a valid `jthread`.
+        unsafe { self.notification_mode(mode, event_type, thread_ptr) }
+    }
+
+    /// Controls the generation of the given event for `thread` only, like
+    /// [`Jvm::set_event_notification_mode`] but without taking the thread handle, e.g. to enable
+    /// `SingleStep` for the thread being debugged.
+    /// # Errors
+    /// See [`EventError`] for more information.
+    pub(crate) fn set_thread_event_mode(
+        &self,
+        mode: EventMode,
+        event_type: JvmTIEvent,
+        thread: &Thread<'_>,
+    ) -> Result<(), EventError> {
+        // SAFETY: `thread.jthread` is a valid `jthread`.
+        unsafe { self.notification_mode(mode, event_type, thread.jthread) }
+    }
+
+    /// Calls `SetEventNotificationMode`, acquiring the capabilities required by `event_type` when
+    /// enabling it.
+    /// # Safety
+    /// `thread_ptr` must be either null or a valid `jthread`.
+    unsafe fn notification_mode(
+        &self,
+        mode: EventMode,
+        event_type: JvmTIEvent,
+        thread_ptr: sys::jthread,
+    ) -> Result<(), EventError> {
+        if mode == EventMode::Enable {
+            self.acquire_capabilities(event_type.required_capabilities())
+                .map_err(EventError::MissingCapabilities)?;
+        }
         // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv` because of the API restrictions.
         unsafe {
             call_jvmti!(
                 self.jvmti_ptr,
                 SetEventNotificationMode,
-                sys::JVMTI_ENABLE,
+                mode as sys::jvmtiEventMode,
                 event_type as sys::jvmtiEvent,
                 thread_ptr
             )
@@ -77,14 +236,27 @@ impl Jvm {
         Ok(())
     }
 
-    pub(super) fn update_native_callback(&self) -> Result<(), JvmTIError> {
-        let callbacks = Box::new(self.callbacks.c_callbacks());
+    /// Sets whether [`Jvm::update_callbacks`] also enables the events that gain a callback and
+    /// disables the events that lose their callback, so that registering a callback is enough to
+    /// receive its events. It is off by default.
+    pub fn set_auto_enable_events(&mut self, enabled: bool) {
+        self.auto_enable_events = enabled;
+    }
+
+    /// Sets what happens when an event callback panics. It is [`PanicPolicy::Log`] by default.
+    pub fn set_panic_policy(&mut self, policy: PanicPolicy) {
+        self.panic_policy = policy;
+    }
+
+    /// Rewrites the table of native callbacks in place and installs it.
+    pub(super) fn update_native_callback(&mut self) -> Result<(), JvmTIError> {
+        self.native_callbacks = self.callbacks.c_callbacks();
         // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv` because of the API restrictions.
         unsafe {
             call_jvmti!(
                 self.jvmti_ptr,
                 SetEventCallbacks,
-                Box::into_raw(callbacks),
+                std::ptr::from_ref(&self.native_callbacks),
                 size_of::<sys::jvmtiEventCallbacks>() as sys::jint
             )
         }?;
@@ -93,6 +265,7 @@ impl Jvm {
 }
 
 impl EventCallbacks {
+    #[cfg(feature = "vm-events")]
     unsafe extern "C" fn vm_init_callback(
         jvmti_env: *mut sys::jvmtiEnv,
         jni_env: *mut sys::JNIEnv,
@@ -101,33 +274,69 @@ impl EventCallbacks {
         let jvm = Jvm::from_ptr(jvmti_env);
         let jni = JNI::from_ptr(jni_env);
         let thread = Thread::from_ptr(jvm, thread);
-        if let Some(ref callback) = jvm.callbacks.vm_init {
-            callback(jvm, &jni, &thread);
+        if let Some(ref handler) = jvm.callbacks.vm_init {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread);
+            });
         }
     }
 
+    #[cfg(feature = "vm-events")]
     unsafe extern "C" fn vm_death_callback(
         jvmti_env: *mut sys::jvmtiEnv,
         jni_env: *mut sys::JNIEnv,
     ) {
         let jvm = Jvm::from_ptr(jvmti_env);
         let jni = JNI::from_ptr(jni_env);
-        if let Some(ref callback) = jvm.callbacks.vm_death {
-            callback(jvm, &jni);
+        if let Some(ref handler) = jvm.callbacks.vm_death {
+            handler.call_each(jvm.panic_policy, |callback| callback(jvm, &jni));
         }
     }
 
+    #[cfg(feature = "vm-events")]
     unsafe extern "C" fn vm_start_callback(
         jvmti_env: *mut sys::jvmtiEnv,
         jni_env: *mut sys::JNIEnv,
     ) {
         let jvm = Jvm::from_ptr(jvmti_env);
         let jni = JNI::from_ptr(jni_env);
-        if let Some(ref callback) = jvm.callbacks.vm_start {
-            callback(jvm, &jni);
+        if let Some(ref handler) = jvm.callbacks.vm_start {
+            handler.call_each(jvm.panic_policy, |callback| callback(jvm, &jni));
+        }
+    }
+
+    #[cfg(feature = "vm-events")]
+    unsafe extern "C" fn data_dump_request_callback(jvmti_env: *mut sys::jvmtiEnv) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        if let Some(ref handler) = jvm.callbacks.data_dump_request {
+            handler.call_each(jvm.panic_policy, |callback| callback(jvm));
         }
     }
 
+    #[cfg(feature = "vm-events")]
+    unsafe extern "C" fn resource_exhausted_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        flags: sys::jint,
+        _reserved: *const c_void,
+        description: *const c_char,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let flags = ResourceExhaustedFlags::from_bits_retain(flags.cast_unsigned());
+        let description = if description.is_null() {
+            OsStr::new("")
+        } else {
+            OsStr::from_bytes(CStr::from_ptr(description).to_bytes())
+        };
+        if let Some(ref handler) = jvm.callbacks.resource_exhausted {
+            handler.call_each(jvm.panic_policy, |callback| {
+                callback(jvm, &jni, flags, description);
+            });
+        }
+    }
+
+    #[cfg(feature = "thread-events")]
     unsafe extern "C" fn thread_start_callback(
         jvmti_env: *mut sys::jvmtiEnv,
         jni_env: *mut sys::JNIEnv,
@@ -136,10 +345,13 @@ impl EventCallbacks {
         let jvm = Jvm::from_ptr(jvmti_env);
         let jni = JNI::from_ptr(jni_env);
         let thread = Thread::from_ptr(jvm, thread);
-        if let Some(ref callback) = jvm.callbacks.thread_start {
-            callback(jvm, &jni, &thread);
+        if let Some(ref handler) = jvm.callbacks.thread_start {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread);
+            });
         }
     }
+    #[cfg(feature = "thread-events")]
     unsafe extern "C" fn thread_end_callback(
         jvmti_env: *mut sys::jvmtiEnv,
         jni_env: *mut sys::JNIEnv,
@@ -148,11 +360,46 @@ impl EventCallbacks {
         let jvm = Jvm::from_ptr(jvmti_env);
         let jni = JNI::from_ptr(jni_env);
         let thread = Thread::from_ptr(jvm, thread);
-        if let Some(ref callback) = jvm.callbacks.thread_end {
-            callback(jvm, &jni, &thread);
+        if let Some(ref handler) = jvm.callbacks.thread_end {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread);
+            });
         }
     }
 
+    #[cfg(feature = "thread-events")]
+    unsafe extern "C" fn virtual_thread_start_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        virtual_thread: sys::jthread,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let virtual_thread = Thread::from_ptr(jvm, virtual_thread);
+        if let Some(ref handler) = jvm.callbacks.virtual_thread_start {
+            handler.call_each_on(jvm.panic_policy, &virtual_thread, |callback| {
+                callback(jvm, &jni, &virtual_thread);
+            });
+        }
+    }
+
+    #[cfg(feature = "thread-events")]
+    unsafe extern "C" fn virtual_thread_end_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        virtual_thread: sys::jthread,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let virtual_thread = Thread::from_ptr(jvm, virtual_thread);
+        if let Some(ref handler) = jvm.callbacks.virtual_thread_end {
+            handler.call_each_on(jvm.panic_policy, &virtual_thread, |callback| {
+                callback(jvm, &jni, &virtual_thread);
+            });
+        }
+    }
+
+    #[cfg(feature = "class-events")]
     unsafe extern "C" fn class_file_load_hook_callback(
         jvmti_env: *mut sys::jvmtiEnv,
         jni_env: *mut sys::JNIEnv,
@@ -167,37 +414,61 @@ impl EventCallbacks {
     ) {
         let jvm = Jvm::from_ptr(jvmti_env);
         let jni = JNI::from_ptr(jni_env);
-        let class_being_redefined = Class::from_ptr(jvm, class_being_redefined);
+        // The class is only being redefined for redefinitions and retransformations.
+        let class_being_redefined =
+            (!class_being_redefined.is_null()).then(|| Class::from_ptr(jvm, class_being_redefined));
         let name = if name.is_null() {
             None
         } else {
             Some(OsStr::from_bytes(CStr::from_ptr(name).to_bytes()))
         };
-        let class_loader = Object::from_ptr(jvm, loader);
-        let protection_domain = Object::from_ptr(jvm, protection_domain);
-        let class_data = Vec::from(std::slice::from_raw_parts(
-            class_data,
-            class_data_len as usize,
-        ));
-        if let Some(bytes) = jvm.callbacks.class_file_load_hook.as_ref().and_then(|it| {
-            it(
-                jvm,
-                &jni,
-                &class_being_redefined,
-                name,
-                &class_loader,
-                &protection_domain,
-                class_data,
-            )
-        }) {
-            *new_class_data_len = bytes.len() as sys::jint;
-            *new_class_data = bytes.leak().as_mut_ptr();
+        // The loader is null for the bootstrap class loader.
+        let class_loader = (!loader.is_null()).then(|| Object::from_ptr(jvm, loader));
+        let protection_domain =
+            (!protection_domain.is_null()).then(|| Object::from_ptr(jvm, protection_domain));
+        let handler = jvm
+            .callbacks
+            .class_file_load_hook
+            .as_ref()
+            .filter(|it| it.accepts_class(name));
+        let new_class = ScratchArena::with(|scratch| {
+            let handler = handler?;
+            // The class data is owned by the VM and only lent to the callbacks.
+            let class_data = scratch.bytes(class_data, class_data_len);
+            // Each callback transforms the output of the previous one.
+            let mut transformed: Option<Vec<u8>> = None;
+            handler.call_each(jvm.panic_policy, |callback| {
+                let event = ClassFileLoadEvent {
+                    class_being_redefined: class_being_redefined.as_ref(),
+                    name,
+                    loader: class_loader.as_ref(),
+                    protection_domain: protection_domain.as_ref(),
+                    class_data: transformed.as_deref().unwrap_or(class_data),
+                };
+                if let Some(output) = callback(jvm, &jni, &event) {
+                    transformed = Some(output);
+                }
+            });
+            transformed
+        });
+        // The new class data is released by the JVM with `Deallocate`, so it has to be allocated
+        // by the JVM TI environment.
+        let new_class = new_class.and_then(|bytes| {
+            let len = sys::jint::try_from(bytes.len()).ok()?;
+            let mem = jvm.allocate(bytes.len()).ok()?;
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), mem, bytes.len());
+            Some((len, mem))
+        });
+        if let Some((len, mem)) = new_class {
+            *new_class_data_len = len;
+            *new_class_data = mem;
         } else {
             *new_class_data_len = 0;
             *new_class_data = std::ptr::null_mut();
         }
     }
 
+    #[cfg(feature = "class-events")]
     unsafe extern "C" fn class_load_callback(
         jvmti_env: *mut sys::jvmtiEnv,
         jni_env: *mut sys::JNIEnv,
@@ -208,11 +479,14 @@ impl EventCallbacks {
         let jni = JNI::from_ptr(jni_env);
         let thread = Thread::from_ptr(jvm, thread);
         let class = Class::from_ptr(jvm, klass);
-        if let Some(ref callback) = jvm.callbacks.class_load {
-            callback(jvm, &jni, &thread, &class);
+        if let Some(ref handler) = jvm.callbacks.class_load {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &class);
+            });
         }
     }
 
+    #[cfg(feature = "class-events")]
     unsafe extern "C" fn class_prepare_callback(
         jvmti_env: *mut sys::jvmtiEnv,
         jni_env: *mut sys::JNIEnv,
@@ -223,80 +497,1615 @@ impl EventCallbacks {
         let jni = JNI::from_ptr(jni_env);
         let thread = Thread::from_ptr(jvm, thread);
         let class = Class::from_ptr(jvm, klass);
-        if let Some(ref callback) = jvm.callbacks.class_prepare {
-            callback(jvm, &jni, &thread, &class);
+        if let Some(ref handler) = jvm.callbacks.class_prepare {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &class);
+            });
+        }
+    }
+
+    #[cfg(feature = "debug-events")]
+    unsafe extern "C" fn single_step_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        method: sys::jmethodID,
+        location: sys::jlocation,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let method = Method::from_ptr(jvm, method);
+        if let Some(ref handler) = jvm.callbacks.single_step {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &method, location);
+            });
+        }
+    }
+
+    #[cfg(feature = "debug-events")]
+    unsafe extern "C" fn breakpoint_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        method: sys::jmethodID,
+        location: sys::jlocation,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let method = Method::from_ptr(jvm, method);
+        if let Some(ref handler) = jvm.callbacks.breakpoint {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &method, location);
+            });
+        }
+    }
+
+    #[cfg(feature = "debug-events")]
+    #[allow(clippy::too_many_arguments)]
+    unsafe extern "C" fn field_access_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        method: sys::jmethodID,
+        location: sys::jlocation,
+        field_klass: sys::jclass,
+        object: sys::jobject,
+        field: sys::jfieldID,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let method = Method::from_ptr(jvm, method);
+        let field_klass = Class::from_ptr(jvm, field_klass);
+        let object = (!object.is_null()).then(|| Object::from_ptr(jvm, object));
+        let event = FieldEvent {
+            method: &method,
+            location,
+            field_class: &field_klass,
+            object: object.as_ref(),
+            field: Field::from_ptr(&field_klass, field),
+            new_value: None,
+        };
+        if let Some(ref handler) = jvm.callbacks.field_access {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &event);
+            });
+        }
+    }
+
+    #[cfg(feature = "debug-events")]
+    #[allow(clippy::too_many_arguments)]
+    unsafe extern "C" fn field_modification_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        method: sys::jmethodID,
+        location: sys::jlocation,
+        field_klass: sys::jclass,
+        object: sys::jobject,
+        field: sys::jfieldID,
+        signature_type: c_char,
+        new_value: sys::jvalue,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let method = Method::from_ptr(jvm, method);
+        let field_klass = Class::from_ptr(jvm, field_klass);
+        let object = (!object.is_null()).then(|| Object::from_ptr(jvm, object));
+        // SAFETY: The member of `new_value` matching `signature_type` is initialized.
+        let new_value = JType::from_descriptor(&[signature_type.cast_unsigned()])
+            .and_then(|ty| JValue::from_raw(jvm, new_value, ty));
+        let event = FieldEvent {
+            method: &method,
+            location,
+            field_class: &field_klass,
+            object: object.as_ref(),
+            field: Field::from_ptr(&field_klass, field),
+            new_value: new_value.as_ref(),
+        };
+        if let Some(ref handler) = jvm.callbacks.field_modification {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &event);
+            });
+        }
+    }
+
+    #[cfg(feature = "method-events")]
+    unsafe extern "C" fn method_exit_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        method: sys::jmethodID,
+        was_popped_by_exception: sys::jboolean,
+        return_value: sys::jvalue,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let Some(ref handler) = jvm.callbacks.method_exit else {
+            return;
+        };
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        if !handler.accepts(jvm.panic_policy, Some(&thread)) {
+            return;
+        }
+        let method = Method::from_ptr(jvm, method);
+        let was_popped_by_exception = was_popped_by_exception != 0;
+        // The return value is undefined if the method was popped by an exception.
+        let return_type = if was_popped_by_exception {
+            None
+        } else {
+            method
+                .signature()
+                .ok()
+                .and_then(|it| JType::return_type_of(it.as_bytes()))
+        };
+        handler.call_each(jvm.panic_policy, |callback| {
+            // The value is decoded for each callback since references cannot be shared.
+            let return_value = return_type.and_then(|ty| JValue::from_raw(jvm, return_value, ty));
+            callback(
+                jvm,
+                &jni,
+                &thread,
+                &method,
+                was_popped_by_exception,
+                return_value,
+            );
+        });
+    }
+
+    #[cfg(feature = "method-events")]
+    unsafe extern "C" fn frame_pop_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        method: sys::jmethodID,
+        was_popped_by_exception: sys::jboolean,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let method = Method::from_ptr(jvm, method);
+        if let Some(ref handler) = jvm.callbacks.frame_pop {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &method, was_popped_by_exception != 0);
+            });
+        }
+    }
+
+    #[cfg(feature = "method-events")]
+    unsafe extern "C" fn native_method_bind_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        method: sys::jmethodID,
+        address: *mut c_void,
+        new_address_ptr: *mut *mut c_void,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let Some(ref handler) = jvm.callbacks.native_method_bind else {
+            return;
+        };
+        // The JNI environment and the thread are null when the event is sent during the
+        // primordial phase.
+        let jni = (!jni_env.is_null()).then(|| JNI::from_ptr(jni_env));
+        let thread = (!thread.is_null()).then(|| Thread::from_ptr(jvm, thread));
+        if !handler.accepts(jvm.panic_policy, thread.as_ref()) {
+            return;
+        }
+        let method = Method::from_ptr(jvm, method);
+        // Each callback sees the address chosen by the previous one.
+        let mut current_address = address;
+        handler.call_each(jvm.panic_policy, |callback| {
+            if let Some(new_address) =
+                callback(jvm, jni.as_ref(), thread.as_ref(), &method, current_address)
+            {
+                current_address = new_address;
+            }
+        });
+        if current_address != address {
+            *new_address_ptr = current_address;
+        }
+    }
+
+    #[cfg(feature = "monitor-events")]
+    unsafe extern "C" fn monitor_wait_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        object: sys::jobject,
+        timeout: sys::jlong,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let object = Object::from_ptr(jvm, object);
+        // A timeout of zero means waiting forever.
+        let timeout = u64::try_from(timeout)
+            .ok()
+            .filter(|&it| it > 0)
+            .map(Duration::from_millis);
+        if let Some(ref handler) = jvm.callbacks.monitor_wait {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &object, timeout);
+            });
+        }
+    }
+
+    #[cfg(feature = "monitor-events")]
+    unsafe extern "C" fn monitor_waited_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        object: sys::jobject,
+        timed_out: sys::jboolean,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let object = Object::from_ptr(jvm, object);
+        if let Some(ref handler) = jvm.callbacks.monitor_waited {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &object, timed_out != 0);
+            });
+        }
+    }
+
+    #[cfg(feature = "monitor-events")]
+    unsafe extern "C" fn monitor_contended_enter_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        object: sys::jobject,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let object = Object::from_ptr(jvm, object);
+        if let Some(ref handler) = jvm.callbacks.monitor_contended_enter {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &object);
+            });
+        }
+    }
+
+    #[cfg(feature = "monitor-events")]
+    unsafe extern "C" fn monitor_contended_entered_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        object: sys::jobject,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let object = Object::from_ptr(jvm, object);
+        if let Some(ref handler) = jvm.callbacks.monitor_contended_entered {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &object);
+            });
+        }
+    }
+
+    #[cfg(feature = "alloc-events")]
+    unsafe extern "C" fn vm_object_alloc_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        object: sys::jobject,
+        klass: sys::jclass,
+        size: sys::jlong,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let object = Object::from_ptr(jvm, object);
+        let class = Class::from_ptr(jvm, klass);
+        if let Some(ref handler) = jvm.callbacks.vm_object_alloc {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &object, &class, size.cast_unsigned());
+            });
+        }
+    }
+
+    #[cfg(feature = "alloc-events")]
+    unsafe extern "C" fn sampled_object_alloc_callback(
+        jvmti_env: *mut sys::jvmtiEnv,
+        jni_env: *mut sys::JNIEnv,
+        thread: sys::jthread,
+        object: sys::jobject,
+        klass: sys::jclass,
+        size: sys::jlong,
+    ) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        let jni = JNI::from_ptr(jni_env);
+        let thread = Thread::from_ptr(jvm, thread);
+        let object = Object::from_ptr(jvm, object);
+        let class = Class::from_ptr(jvm, klass);
+        if let Some(ref handler) = jvm.callbacks.sampled_object_alloc {
+            handler.call_each_on(jvm.panic_policy, &thread, |callback| {
+                callback(jvm, &jni, &thread, &object, &class, size.cast_unsigned());
+            });
+        }
+    }
+
+    #[cfg(feature = "gc-events")]
+    unsafe extern "C" fn garbage_collection_start_callback(jvmti_env: *mut sys::jvmtiEnv) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        if let Some(ref handler) = jvm.callbacks.garbage_collection_start {
+            handler.call_each(jvm.panic_policy, |callback| {
+                callback(&RestrictedJvm { jvm });
+            });
+        }
+    }
+
+    #[cfg(feature = "gc-events")]
+    unsafe extern "C" fn garbage_collection_finish_callback(jvmti_env: *mut sys::jvmtiEnv) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        if let Some(ref handler) = jvm.callbacks.garbage_collection_finish {
+            handler.call_each(jvm.panic_policy, |callback| {
+                callback(&RestrictedJvm { jvm });
+            });
+        }
+    }
+
+    #[cfg(feature = "gc-events")]
+    unsafe extern "C" fn object_free_callback(jvmti_env: *mut sys::jvmtiEnv, tag: sys::jlong) {
+        let jvm = Jvm::from_ptr(jvmti_env);
+        if let Some(ref handler) = jvm.callbacks.object_free {
+            handler.call_each(jvm.panic_policy, |callback| {
+                callback(&RestrictedJvm { jvm }, tag);
+            });
+        }
+    }
+}
+
+/// The callbacks registered for an event, which are called in registration order.
+///
+/// Events arrive on arbitrary threads, so the callbacks must be [`Send`], and they may be stateful
+/// since the calls to each of them are serialized. Different callbacks may run at the same time on
+/// different threads. A call that would reenter a callback on the same thread, e.g. because the
+/// callback itself triggers the event, is skipped.
+///
+/// Several independent components can subscribe to the same event, e.g.
+/// `it.thread_start.get_or_insert_with(Handler::default).subscribe(Box::new(...))`, and later
+/// remove their callback with the returned [`Subscription`].
+///
+/// For frequent events, a [`Sampler`] limits how often the callbacks are called, e.g.
+/// `Handler::new(Box::new(...)).sampled(Sampler::every_nth(1000))`, and a thread selection
+/// restricts them to the events of chosen threads, e.g.
+/// `Handler::new(Box::new(...)).with_thread_filter(ThreadFilter::new().exclude_name("C2 *"))`.
+pub struct Handler<F: ?Sized> {
+    /// The subscribed callbacks. The list is replaced rather than modified in place, so that the
+    /// events take a snapshot of it and call the callbacks without holding the lock.
+    callbacks: Mutex<Arc<Vec<Arc<Subscriber<F>>>>>,
+    sampler: Option<Sampler>,
+    threads: Option<ThreadSelection>,
+    /// The classes whose `ClassFileLoadHook` events reach the callbacks.
+    #[cfg(feature = "class-events")]
+    classes: Option<ClassNameFilter>,
+}
+
+/// A callback subscribed to a [`Handler`].
+struct Subscriber<F: ?Sized> {
+    subscription: Subscription,
+    /// The callback, or `None` once it has been unsubscribed. The lock serializes the calls.
+    callback: Mutex<Option<Box<F>>>,
+    /// The marker of the thread running the callback, or `0` if it is not running.
+    owner: AtomicUsize,
+}
+
+impl<F: ?Sized> Subscriber<F> {
+    /// Takes the callback out of the subscriber, waiting for it to return if it is running on
+    /// another thread. Returns `None` if it is running on the current thread, in which case it is
+    /// dropped once the events holding the subscriber are done with it.
+    fn take(&self) -> Option<Box<F>> {
+        let marker = THREAD_MARKER
+            .try_with(|it| std::ptr::from_ref(it) as usize)
+            .unwrap_or(0);
+        if marker != 0 && self.owner.load(Ordering::Relaxed) == marker {
+            return None;
+        }
+        // An event may still hold a snapshot with the subscriber, so the callback is taken out
+        // of it rather than unwrapped.
+        let callback = self
+            .callback
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        callback
+    }
+}
+
+/// A predicate selecting threads by their information, see [`Handler::for_threads`].
+pub type ThreadPredicate = dyn Fn(&ThreadInfo<'_, '_>) -> bool + Send + Sync;
+
+/// The threads whose events reach the callbacks of a [`Handler`].
+enum ThreadSelection {
+    Filter(ThreadFilter),
+    // Only the events with a thread, which may all be compiled out, read the predicate.
+    #[cfg_attr(
+        not(any(
+            feature = "vm-events",
+            feature = "thread-events",
+            feature = "class-events",
+            feature = "debug-events",
+            feature = "method-events",
+            feature = "monitor-events",
+            feature = "alloc-events"
+        )),
+        allow(dead_code)
+    )]
+    Predicate(Box<ThreadPredicate>),
+}
+
+impl ThreadSelection {
+    /// Returns whether `thread` is selected. Threads that cannot be inspected, e.g. because they
+    /// have ended, are selected.
+    #[cfg(any(
+        feature = "vm-events",
+        feature = "thread-events",
+        feature = "class-events",
+        feature = "debug-events",
+        feature = "method-events",
+        feature = "monitor-events",
+        feature = "alloc-events"
+    ))]
+    fn selects(&self, thread: &Thread<'_>) -> bool {
+        match self {
+            Self::Filter(filter) => !filter.excludes(thread),
+            Self::Predicate(predicate) => thread.info().map_or(true, |info| predicate(&info)),
+        }
+    }
+}
+
+impl std::fmt::Debug for ThreadSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Filter(filter) => f.debug_tuple("Filter").field(filter).finish(),
+            Self::Predicate(_) => f.debug_tuple("Predicate").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// A limit on how often the callbacks of a [`Handler`] are called, see [`Handler::sampled`].
+///
+/// The occurrences of the event that are not sampled skip all the callbacks of the handler.
+#[derive(Debug)]
+pub struct Sampler {
+    kind: SamplerKind,
+}
+
+#[derive(Debug)]
+enum SamplerKind {
+    EveryNth {
+        n: u64,
+        count: AtomicU64,
+    },
+    RateLimited {
+        per_second: f64,
+        burst: f64,
+        /// The tokens available and the time they were last refilled.
+        bucket: Mutex<(f64, Instant)>,
+    },
+}
+
+impl Sampler {
+    /// Creates a sampler that keeps the first occurrence and then every `n`th one.
+    /// An `n` of `0` is treated as `1`, which keeps every occurrence.
+    #[must_use]
+    pub fn every_nth(n: u64) -> Self {
+        Self {
+            kind: SamplerKind::EveryNth {
+                n: n.max(1),
+                count: AtomicU64::new(0),
+            },
+        }
+    }
+
+    /// Creates a token bucket sampler that keeps at most `per_second` occurrences per second on
+    /// average, and at most `burst` occurrences in a row.
+    #[must_use]
+    pub fn rate_limited(per_second: u32, burst: u32) -> Self {
+        let burst = f64::from(burst);
+        Self {
+            kind: SamplerKind::RateLimited {
+                per_second: f64::from(per_second),
+                burst,
+                bucket: Mutex::new((burst, Instant::now())),
+            },
+        }
+    }
+
+    /// Returns whether the current occurrence is sampled.
+    fn sample(&self) -> bool {
+        match &self.kind {
+            SamplerKind::EveryNth { n, count } => count.fetch_add(1, Ordering::Relaxed) % n == 0,
+            SamplerKind::RateLimited {
+                per_second,
+                burst,
+                bucket,
+            } => {
+                let mut bucket = bucket.lock().unwrap_or_else(PoisonError::into_inner);
+                let (tokens, refilled_at) = &mut *bucket;
+                let now = Instant::now();
+                let elapsed = now.duration_since(*refilled_at).as_secs_f64();
+                *tokens = (*tokens + elapsed * per_second).min(*burst);
+                *refilled_at = now;
+                if *tokens < 1.0 {
+                    return false;
+                }
+                *tokens -= 1.0;
+                true
+            }
+        }
+    }
+}
+
+/// What happens when an event callback panics, see [`Jvm::set_panic_policy`].
+///
+/// A panic must not unwind from a callback into the VM, so it is always caught before the
+/// trampoline returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum PanicPolicy {
+    /// Prints a message and keeps calling the callback on later events.
+    #[default]
+    Log,
+    /// Prints a message and unsubscribes the callback.
+    Disable,
+    /// Aborts the process.
+    Abort,
+}
+
+/// A token identifying a callback subscribed to a [`Handler`], which removes it when passed to
+/// [`Jvm::unsubscribe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Subscription(u64);
+
+/// The next identifier of a [`Subscription`], unique across all the handlers.
+static NEXT_SUBSCRIPTION: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// A per-thread value whose address identifies the current thread.
+    static THREAD_MARKER: u8 = const { 0 };
+    /// The number of [`Handler::call_each`] calls in progress on the current thread.
+    static DISPATCH_DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// The actions to run once the current thread leaves the callbacks, see [`after_dispatch`].
+    static DEFERRED: RefCell<Vec<Box<dyn FnOnce()>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Runs `action` on the current thread once the callbacks it is running have returned and their
+/// locks have been released, or right away if it is not running any callback.
+///
+/// This lets a callback block its thread, e.g. suspend it, without holding up the callbacks on
+/// other threads. A deferred action still runs before the VM regains control, so the references
+/// given to the callback are valid while it runs, and it panics like a callback would.
+#[cfg(feature = "dap")]
+pub(crate) fn after_dispatch(action: Box<dyn FnOnce()>) {
+    let mut action = Some(action);
+    if DISPATCH_DEPTH.try_with(Cell::get).unwrap_or(0) > 0 {
+        let _ = DEFERRED.try_with(|it| it.borrow_mut().extend(action.take()));
+    }
+    if let Some(action) = action {
+        action();
+    }
+}
+
+/// Runs the actions deferred with [`after_dispatch`], handling panics according to `policy`.
+fn run_deferred(policy: PanicPolicy) {
+    for action in DEFERRED.try_with(RefCell::take).unwrap_or_default() {
+        // A panic must not unwind into the VM, which would abort the process.
+        if catch_unwind(AssertUnwindSafe(action)).is_ok() {
+            continue;
+        }
+        // The panic message has already been printed by the panic hook.
+        if policy == PanicPolicy::Abort {
+            std::process::abort();
+        }
+        eprintln!("coffee-filter: a deferred event action panicked, ignoring the panic");
+    }
+}
+
+impl<F: ?Sized> Handler<F> {
+    /// Creates a handler with `callback` as its only callback.
+    #[must_use]
+    pub fn new(callback: Box<F>) -> Self {
+        let mut handler = Self::default();
+        handler.subscribe(callback);
+        handler
+    }
+
+    /// Adds `callback` after the callbacks already subscribed, and returns the token to
+    /// unsubscribe it.
+    pub fn subscribe(&mut self, callback: Box<F>) -> Subscription {
+        let subscription = Subscription(NEXT_SUBSCRIPTION.fetch_add(1, Ordering::Relaxed));
+        let subscriber = Arc::new(Subscriber {
+            subscription,
+            callback: Mutex::new(Some(callback)),
+            owner: AtomicUsize::new(0),
+        });
+        let callbacks = self
+            .callbacks
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        Arc::make_mut(callbacks).push(subscriber);
+        subscription
+    }
+
+    /// Removes the callback subscribed with `subscription`, and returns it if it was subscribed
+    /// to this handler. If the callback is running on another thread, this waits for it to return.
+    /// A callback unsubscribing itself is not called again, but `None` is returned since it is
+    /// still running.
+    pub fn unsubscribe(&self, subscription: Subscription) -> Option<Box<F>> {
+        self.detach(subscription)?.take()
+    }
+
+    /// Removes the subscriber with `subscription` from the list, so that later events do not
+    /// call it.
+    fn detach(&self, subscription: Subscription) -> Option<Arc<Subscriber<F>>> {
+        let mut callbacks = self
+            .callbacks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let index = callbacks
+            .iter()
+            .position(|it| it.subscription == subscription)?;
+        Some(Arc::make_mut(&mut callbacks).remove(index))
+    }
+
+    /// Limits how often the callbacks are called with `sampler`.
+    #[must_use]
+    pub fn sampled(mut self, sampler: Sampler) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Replaces the sampler of the handler, or removes it if `sampler` is `None`.
+    pub fn set_sampler(&mut self, sampler: Option<Sampler>) {
+        self.sampler = sampler;
+    }
+
+    /// Restricts the callbacks to the events of the threads not excluded by `filter`. Events
+    /// without a thread, e.g. `GarbageCollectionStart`, are not restricted.
+    #[must_use]
+    pub fn with_thread_filter(mut self, filter: ThreadFilter) -> Self {
+        self.threads = Some(ThreadSelection::Filter(filter));
+        self
+    }
+
+    /// Restricts the callbacks to the events of the threads whose information satisfies
+    /// `predicate`. Events without a thread, e.g. `GarbageCollectionStart`, are not restricted.
+    #[must_use]
+    pub fn for_threads(
+        mut self,
+        predicate: impl Fn(&ThreadInfo<'_, '_>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.threads = Some(ThreadSelection::Predicate(Box::new(predicate)));
+        self
+    }
+
+    /// Removes the thread restriction of the handler.
+    pub fn for_all_threads(&mut self) {
+        self.threads = None;
+    }
+
+    /// Returns whether no callback is subscribed.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.callbacks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+
+    /// Returns whether the events of `thread` reach the callbacks. Events without a thread always
+    /// do. A panicking thread predicate is handled according to `policy`, and skips the event
+    /// unless the process is aborted.
+    #[cfg(any(
+        feature = "vm-events",
+        feature = "thread-events",
+        feature = "class-events",
+        feature = "debug-events",
+        feature = "method-events",
+        feature = "monitor-events",
+        feature = "alloc-events"
+    ))]
+    pub(crate) fn accepts(&self, policy: PanicPolicy, thread: Option<&Thread<'_>>) -> bool {
+        let (Some(threads), Some(thread)) = (&self.threads, thread) else {
+            return true;
+        };
+        // A panic must not unwind into the VM, which would abort the process.
+        if let Ok(selected) = catch_unwind(AssertUnwindSafe(|| threads.selects(thread))) {
+            return selected;
+        }
+        // The panic message has already been printed by the panic hook.
+        if policy == PanicPolicy::Abort {
+            std::process::abort();
+        }
+        eprintln!("coffee-filter: a thread selection panicked, skipping the event");
+        false
+    }
+
+    /// Calls each callback with `call` like [`Handler::call_each`] if the events of `thread`
+    /// reach the callbacks.
+    #[cfg(any(
+        feature = "vm-events",
+        feature = "thread-events",
+        feature = "class-events",
+        feature = "debug-events",
+        feature = "method-events",
+        feature = "monitor-events",
+        feature = "alloc-events"
+    ))]
+    pub(crate) fn call_each_on(
+        &self,
+        policy: PanicPolicy,
+        thread: &Thread<'_>,
+        call: impl FnMut(&mut F),
+    ) {
+        if self.accepts(policy, Some(thread)) {
+            self.call_each(policy, call);
+        }
+    }
+
+    /// Calls each callback in registration order with `call`, unless the current thread is
+    /// already running it or the occurrence is not sampled. A panicking callback is handled
+    /// according to `policy`.
+    pub(crate) fn call_each(&self, policy: PanicPolicy, mut call: impl FnMut(&mut F)) {
+        /// Marks the callback as not running when dropped.
+        struct Release<'a>(&'a AtomicUsize);
+
+        impl Drop for Release<'_> {
+            fn drop(&mut self) {
+                self.0.store(0, Ordering::Relaxed);
+            }
+        }
+
+        if self.sampler.as_ref().is_some_and(|it| !it.sample()) {
+            return;
+        }
+        // The callbacks are called without holding the lock of the list, so that a callback that
+        // blocks does not hold up the other callbacks on other threads.
+        let subscribers = Arc::clone(
+            &self
+                .callbacks
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        // The marker is not available while the thread-local values of the thread are destroyed,
+        // in which case reentrant calls cannot be detected.
+        let marker = THREAD_MARKER
+            .try_with(|it| std::ptr::from_ref(it) as usize)
+            .unwrap_or(0);
+        let mut disabled = Vec::new();
+        let _ = DISPATCH_DEPTH.try_with(|it| it.set(it.get() + 1));
+        for subscriber in subscribers.iter() {
+            // Only the current thread can have stored its own marker, so a relaxed load suffices.
+            if marker != 0 && subscriber.owner.load(Ordering::Relaxed) == marker {
+                continue;
+            }
+            let mut callback = subscriber
+                .callback
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let Some(callback) = callback.as_mut() else {
+                continue;
+            };
+            subscriber.owner.store(marker, Ordering::Relaxed);
+            let _release = Release(&subscriber.owner);
+            // A panic must not unwind into the VM, which would abort the process.
+            if catch_unwind(AssertUnwindSafe(|| call(callback))).is_ok() {
+                continue;
+            }
+            // The panic message has already been printed by the panic hook.
+            match policy {
+                PanicPolicy::Log => {
+                    eprintln!("coffee-filter: an event callback panicked, ignoring the panic");
+                }
+                PanicPolicy::Disable => {
+                    eprintln!("coffee-filter: an event callback panicked, disabling it");
+                    disabled.push(subscriber.subscription);
+                }
+                PanicPolicy::Abort => std::process::abort(),
+            }
+        }
+        if !disabled.is_empty() {
+            let mut callbacks = self
+                .callbacks
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            Arc::make_mut(&mut callbacks).retain(|it| !disabled.contains(&it.subscription));
+        }
+        let outermost = DISPATCH_DEPTH
+            .try_with(|it| {
+                it.set(it.get() - 1);
+                it.get() == 0
+            })
+            .unwrap_or(false);
+        if outermost {
+            run_deferred(policy);
+        }
+    }
+}
+
+#[cfg(feature = "class-events")]
+impl Handler<ClassFileLoadHookCallback> {
+    /// Restricts the callbacks to the classes selected by `filter`. The other classes are loaded
+    /// without calling into the callbacks.
+    #[must_use]
+    pub fn for_classes(mut self, filter: ClassNameFilter) -> Self {
+        self.classes = Some(filter);
+        self
+    }
+
+    /// Removes the class restriction of the handler.
+    pub fn for_all_classes(&mut self) {
+        self.classes = None;
+    }
+
+    /// Returns whether the `ClassFileLoadHook` events of the class named `name` reach the
+    /// callbacks.
+    pub(crate) fn accepts_class(&self, name: Option<&OsStr>) -> bool {
+        self.classes
+            .as_ref()
+            .is_none_or(|it| it.selects(name.map(OsStr::as_bytes)))
+    }
+}
+
+impl<F: ?Sized> Default for Handler<F> {
+    /// Creates a handler without callbacks.
+    fn default() -> Self {
+        Self {
+            callbacks: Mutex::new(Arc::new(Vec::new())),
+            sampler: None,
+            threads: None,
+            #[cfg(feature = "class-events")]
+            classes: None,
         }
     }
 }
 
+impl<F: ?Sized> std::fmt::Debug for Handler<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let callbacks = self
+            .callbacks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        f.debug_struct("Handler")
+            .field("callbacks", &callbacks.len())
+            .field("sampler", &self.sampler)
+            .field("threads", &self.threads)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A handle to the JVM TI environment given to the callbacks that run while the VM is in a
+/// restricted state, e.g. during a garbage collection.
+///
+/// In such a state only the raw monitor, memory management, and environment-local storage
+/// functions may be called, so this handle does not give access to the [`Jvm`]. Synchronization
+/// with other threads is still possible through the [`Mutex`](crate::jvmti_sync::Mutex) and
+/// [`Condvar`](crate::jvmti_sync::Condvar) created beforehand, which are backed by raw monitors.
+#[cfg(feature = "gc-events")]
+pub struct RestrictedJvm<'j> {
+    jvm: &'j Jvm,
+}
+
+#[cfg(feature = "gc-events")]
+impl std::fmt::Debug for RestrictedJvm<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("RestrictedJvm@{:p}", self.jvm.jvmti_ptr))
+    }
+}
+
+/// The callback of the `GarbageCollectionStart` and `GarbageCollectionFinish` events.
+#[cfg(feature = "gc-events")]
+pub type GarbageCollectionCallback = dyn FnMut(&RestrictedJvm<'_>) + Send;
+
+/// The callback of the `ObjectFree` event.
+#[cfg(feature = "gc-events")]
+pub type ObjectFreeCallback = dyn FnMut(&RestrictedJvm<'_>, sys::jlong) + Send;
+
+/// The callback of the `VMObjectAlloc` and `SampledObjectAlloc` events, with the allocated object,
+/// its class, and its size in bytes.
+#[cfg(feature = "alloc-events")]
+pub type ObjectAllocCallback =
+    dyn FnMut(&Jvm, &JNI, &Thread<'_>, &Object<'_>, &Class<'_>, u64) + Send;
+
+/// The callback of the `VMInit` event.
+#[cfg(feature = "vm-events")]
+pub type VMInitCallback = dyn FnMut(&Jvm, &JNI, &Thread<'_>) + Send;
+
+/// The callback of the `VMStart` and `VMDeath` events.
+#[cfg(feature = "vm-events")]
+pub type VMPhaseCallback = dyn FnMut(&Jvm, &JNI) + Send;
+
+/// The callback of the `ThreadStart` and `ThreadEnd` events.
+#[cfg(feature = "thread-events")]
+pub type ThreadCallback = dyn FnMut(&Jvm, &JNI, &Thread<'_>) + Send;
+
+/// The callback of the `VirtualThreadStart` and `VirtualThreadEnd` events.
+#[cfg(feature = "thread-events")]
+pub type VirtualThreadCallback = dyn FnMut(&Jvm, &JNI, &Thread<'_>) + Send;
+
+/// The callback of the `DataDumpRequest` event.
+#[cfg(feature = "vm-events")]
+pub type DataDumpRequestCallback = dyn FnMut(&Jvm) + Send;
+
+/// The arguments of a `ClassFileLoadHook` event.
+#[cfg(feature = "class-events")]
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct ClassFileLoadEvent<'a> {
+    /// The class being redefined or retransformed, or `None` if the class is being loaded.
+    pub class_being_redefined: Option<&'a Class<'a>>,
+    /// The internal name of the class, e.g. `java/lang/String`, or `None` if it is unknown.
+    pub name: Option<&'a OsStr>,
+    /// The defining loader, or `None` for the bootstrap class loader.
+    pub loader: Option<&'a Object<'a>>,
+    /// The protection domain of the class, if any.
+    pub protection_domain: Option<&'a Object<'a>>,
+    /// The class file, which is the output of the previous callback if it replaced the class data.
+    pub class_data: &'a [u8],
+}
+
+#[cfg(feature = "class-events")]
+impl ClassFileLoadEvent<'_> {
+    /// Gets the internal name of the class decoded according to `policy`, or `None` if it is
+    /// unknown.
+    /// # Errors
+    /// Returns a [`ModifiedUtf8Error`] if the name is not valid modified UTF-8 and `policy` is
+    /// [`Utf8Policy::Strict`].
+    pub fn class_name_utf8(
+        &self,
+        policy: Utf8Policy,
+    ) -> Result<Option<Cow<'_, str>>, ModifiedUtf8Error> {
+        self.name.map(|it| it.to_utf8(policy)).transpose()
+    }
+
+    /// Returns whether the class is being redefined or retransformed rather than loaded.
+    #[must_use]
+    pub fn is_redefinition(&self) -> bool {
+        self.class_being_redefined.is_some()
+    }
+}
+
+/// The callback of the `ClassFileLoadHook` event. Returning `Some` replaces the class data.
+#[cfg(feature = "class-events")]
+pub type ClassFileLoadHookCallback =
+    dyn FnMut(&Jvm, &JNI, &ClassFileLoadEvent<'_>) -> Option<Vec<u8>> + Send;
+
+/// The callback of the `ClassLoad` and `ClassPrepare` events.
+#[cfg(feature = "class-events")]
+pub type ClassCallback = dyn FnMut(&Jvm, &JNI, &Thread<'_>, &Class<'_>) + Send;
+
+/// The callback of the `SingleStep` event.
+#[cfg(feature = "debug-events")]
+pub type SingleStepCallback =
+    dyn FnMut(&Jvm, &JNI, &Thread<'_>, &Method<'_>, sys::jlocation) + Send;
+
+/// The callback of the `Breakpoint` event.
+#[cfg(feature = "debug-events")]
+pub type BreakpointCallback =
+    dyn FnMut(&Jvm, &JNI, &Thread<'_>, &Method<'_>, sys::jlocation) + Send;
+
+/// The arguments of a `FieldAccess` or `FieldModification` event.
+#[cfg(feature = "debug-events")]
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct FieldEvent<'a> {
+    /// The method accessing the field.
+    pub method: &'a Method<'a>,
+    /// The location of the instruction accessing the field.
+    pub location: sys::jlocation,
+    /// The class declaring the field.
+    pub field_class: &'a Class<'a>,
+    /// The object whose field is accessed, or `None` for a static field.
+    pub object: Option<&'a Object<'a>>,
+    /// The accessed field.
+    pub field: Field<'a>,
+    /// The value being written for a `FieldModification` event, or `None` for a `FieldAccess`
+    /// event.
+    pub new_value: Option<&'a JValue<'a>>,
+}
+
+#[cfg(feature = "debug-events")]
+impl FieldEvent<'_> {
+    /// Gets the name of the field.
+    /// See [`GetFieldName`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetFieldName).
+    /// # Errors
+    /// See [`MethodError`] for more information.
+    pub fn field_name(&self) -> Result<OsString, MethodError> {
+        self.field.name()
+    }
+}
+
+/// The callback of the `FieldAccess` and `FieldModification` events.
+#[cfg(feature = "debug-events")]
+pub type FieldCallback = dyn FnMut(&Jvm, &JNI, &Thread<'_>, &FieldEvent<'_>) + Send;
+
+/// The callback of the `MethodExit` event.
+#[cfg(feature = "method-events")]
+pub type MethodExitCallback =
+    dyn FnMut(&Jvm, &JNI, &Thread<'_>, &Method<'_>, bool, Option<JValue<'_>>) + Send;
+
+/// The callback of the `FramePop` event.
+#[cfg(feature = "method-events")]
+pub type FramePopCallback = dyn FnMut(&Jvm, &JNI, &Thread<'_>, &Method<'_>, bool) + Send;
+
+/// The callback of the `NativeMethodBind` event.
+#[cfg(feature = "method-events")]
+pub type NativeMethodBindCallback = dyn FnMut(&Jvm, Option<&JNI>, Option<&Thread<'_>>, &Method<'_>, *mut c_void) -> Option<*mut c_void>
+    + Send;
+
+#[cfg(feature = "vm-events")]
+bitflags::bitflags! {
+    /// The resources reported as exhausted by the `ResourceExhausted` event.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ResourceExhaustedFlags: u32 {
+        /// An `OutOfMemoryError` will be thrown after the event returns.
+        const OOM_ERROR = sys::JVMTI_RESOURCE_EXHAUSTED_OOM_ERROR;
+        /// The VM was unable to allocate memory from the Java heap.
+        const JAVA_HEAP = sys::JVMTI_RESOURCE_EXHAUSTED_JAVA_HEAP;
+        /// The VM was unable to create a thread.
+        const THREADS = sys::JVMTI_RESOURCE_EXHAUSTED_THREADS;
+    }
+}
+
+/// The callback of the `ResourceExhausted` event.
+#[cfg(feature = "vm-events")]
+pub type ResourceExhaustedCallback = dyn FnMut(&Jvm, &JNI, ResourceExhaustedFlags, &OsStr) + Send;
+
+/// The callback of the `MonitorWait` event.
+#[cfg(feature = "monitor-events")]
+pub type MonitorWaitCallback =
+    dyn FnMut(&Jvm, &JNI, &Thread<'_>, &Object<'_>, Option<Duration>) + Send;
+
+/// The callback of the `MonitorWaited` event.
+#[cfg(feature = "monitor-events")]
+pub type MonitorWaitedCallback = dyn FnMut(&Jvm, &JNI, &Thread<'_>, &Object<'_>, bool) + Send;
+
+/// The callback of the `MonitorContendedEnter` and `MonitorContendedEntered` events.
+#[cfg(feature = "monitor-events")]
+pub type MonitorContendedCallback = dyn FnMut(&Jvm, &JNI, &Thread<'_>, &Object<'_>) + Send;
+
+/// The callbacks of the events, registered with [`Jvm::update_callbacks`], e.g.
+/// `it.thread_start = Some(Handler::new(Box::new(|jvm, jni, thread| ...)))`.
 #[derive(Default)]
 #[non_exhaustive]
 pub struct EventCallbacks {
-    // pub vm_init: Option<Box<dyn Fn(&Jvm, &JNI, &Thread<'_>) + Send + Sync>>,
-    pub vm_init: Option<Box<dyn Fn(&Jvm, &JNI, &Thread<'_>)>>,
-    pub vm_death: Option<Box<dyn Fn(&Jvm, &JNI)>>,
-    pub vm_start: Option<Box<dyn Fn(&Jvm, &JNI)>>,
-    pub thread_start: Option<Box<dyn Fn(&Jvm, &JNI, &Thread<'_>)>>,
-    pub thread_end: Option<Box<dyn Fn(&Jvm, &JNI, &Thread<'_>)>>,
-    pub class_file_load_hook: Option<
-        Box<
-            dyn Fn(
-                &Jvm,
-                &JNI,
-                &Class<'_>,
-                Option<&OsStr>,
-                &Object<'_>,
-                &Object<'_>,
-                Vec<u8>,
-            ) -> Option<Vec<u8>>,
-        >,
-    >,
-    pub class_load: Option<Box<dyn Fn(&Jvm, &JNI, &Thread<'_>, &Class<'_>)>>,
-    pub class_prepare: Option<Box<dyn Fn(&Jvm, &JNI, &Thread<'_>, &Class<'_>)>>,
+    #[cfg(feature = "vm-events")]
+    pub vm_init: Option<Handler<VMInitCallback>>,
+    #[cfg(feature = "vm-events")]
+    pub vm_death: Option<Handler<VMPhaseCallback>>,
+    #[cfg(feature = "vm-events")]
+    pub vm_start: Option<Handler<VMPhaseCallback>>,
+    /// Called when the user requests the agent to dump its data, e.g. by sending `SIGQUIT` or
+    /// pressing `Ctrl-\` in the terminal of the VM.
+    #[cfg(feature = "vm-events")]
+    pub data_dump_request: Option<Handler<DataDumpRequestCallback>>,
+    /// Called when the VM runs out of a resource, with the exhausted resources and a description
+    /// of the failure, e.g. right before an `OutOfMemoryError` is thrown.
+    /// Requires the `can_generate_resource_exhaustion_heap_events` or
+    /// `can_generate_resource_exhaustion_threads_events` capability to be reported for the heap
+    /// or for threads respectively.
+    #[cfg(feature = "vm-events")]
+    pub resource_exhausted: Option<Handler<ResourceExhaustedCallback>>,
+    #[cfg(feature = "thread-events")]
+    pub thread_start: Option<Handler<ThreadCallback>>,
+    #[cfg(feature = "thread-events")]
+    pub thread_end: Option<Handler<ThreadCallback>>,
+    /// Called on the virtual thread right after it starts.
+    /// Requires the `can_support_virtual_threads` capability.
+    #[cfg(feature = "thread-events")]
+    pub virtual_thread_start: Option<Handler<VirtualThreadCallback>>,
+    /// Called on the virtual thread right before it ends.
+    /// Requires the `can_support_virtual_threads` capability.
+    #[cfg(feature = "thread-events")]
+    pub virtual_thread_end: Option<Handler<VirtualThreadCallback>>,
+    #[cfg(feature = "class-events")]
+    pub class_file_load_hook: Option<Handler<ClassFileLoadHookCallback>>,
+    #[cfg(feature = "class-events")]
+    pub class_load: Option<Handler<ClassCallback>>,
+    #[cfg(feature = "class-events")]
+    pub class_prepare: Option<Handler<ClassCallback>>,
+    /// Called when a thread is about to execute a new instruction, with the method and the
+    /// location of the instruction. The event is usually only enabled for a single thread, see
+    /// [`StepSession`](crate::diagnostics::stepping::StepSession).
+    /// Requires the `can_generate_single_step_events` capability.
+    #[cfg(feature = "debug-events")]
+    pub single_step: Option<Handler<SingleStepCallback>>,
+    /// Called when a thread hits a breakpoint, with the method and the location of the breakpoint.
+    /// Requires the `can_generate_breakpoint_events` capability.
+    #[cfg(feature = "debug-events")]
+    pub breakpoint: Option<Handler<BreakpointCallback>>,
+    /// Called when a thread reads a field watched for [`WatchKind::Access`].
+    /// Requires the `can_generate_field_access_events` capability.
+    ///
+    /// [`WatchKind::Access`]: super::fields::WatchKind::Access
+    #[cfg(feature = "debug-events")]
+    pub field_access: Option<Handler<FieldCallback>>,
+    /// Called when a thread writes a field watched for [`WatchKind::Modification`], with the
+    /// value being written.
+    /// Requires the `can_generate_field_modification_events` capability.
+    ///
+    /// [`WatchKind::Modification`]: super::fields::WatchKind::Modification
+    #[cfg(feature = "debug-events")]
+    pub field_modification: Option<Handler<FieldCallback>>,
+    /// Called when a method returns, with whether it was popped by an exception and its return
+    /// value decoded according to its descriptor. The return value is `None` for `void` methods,
+    /// methods popped by an exception, and methods whose descriptor cannot be retrieved.
+    /// Requires the `can_generate_method_exit_events` capability.
+    #[cfg(feature = "method-events")]
+    pub method_exit: Option<Handler<MethodExitCallback>>,
+    /// Called when a frame requested with [`Thread::notify_frame_pop`] is popped, with the
+    /// method of the frame and whether it was popped by an exception.
+    /// Requires the `can_generate_frame_pop_events` capability.
+    #[cfg(feature = "method-events")]
+    pub frame_pop: Option<Handler<FramePopCallback>>,
+    /// Called when the VM binds a native method to its implementation at the given address.
+    /// Returning `Some` redirects the binding to another function, which must have the same
+    /// signature. The JNI environment and the thread are `None` during the primordial phase.
+    /// Requires the `can_generate_native_method_bind_events` capability.
+    #[cfg(feature = "method-events")]
+    pub native_method_bind: Option<Handler<NativeMethodBindCallback>>,
+    /// Called when a thread is about to wait on an object with `Object.wait()`, with the timeout
+    /// of the wait, or `None` if it waits without a timeout.
+    /// Requires the `can_generate_monitor_events` capability.
+    #[cfg(feature = "monitor-events")]
+    pub monitor_wait: Option<Handler<MonitorWaitCallback>>,
+    /// Called when a thread finishes waiting on an object, with whether the wait timed out.
+    /// Requires the `can_generate_monitor_events` capability.
+    #[cfg(feature = "monitor-events")]
+    pub monitor_waited: Option<Handler<MonitorWaitedCallback>>,
+    /// Called when a thread is about to block entering a monitor already held by another thread.
+    /// Requires the `can_generate_monitor_events` capability.
+    #[cfg(feature = "monitor-events")]
+    pub monitor_contended_enter: Option<Handler<MonitorContendedCallback>>,
+    /// Called when a thread enters a monitor after having blocked on it.
+    /// Requires the `can_generate_monitor_events` capability.
+    #[cfg(feature = "monitor-events")]
+    pub monitor_contended_entered: Option<Handler<MonitorContendedCallback>>,
+    /// Called when the VM allocates an object that bytecode instrumentation cannot observe, e.g.
+    /// through JNI or reflection.
+    /// Requires the `can_generate_vm_object_alloc_events` capability.
+    #[cfg(feature = "alloc-events")]
+    pub vm_object_alloc: Option<Handler<ObjectAllocCallback>>,
+    /// Called for a sample of the allocated objects, about once per heap sampling interval, see
+    /// [`Jvm::set_heap_sampling_interval`].
+    /// Requires the `can_generate_sampled_object_alloc_events` capability.
+    #[cfg(feature = "alloc-events")]
+    pub sampled_object_alloc: Option<Handler<ObjectAllocCallback>>,
+    /// Called when a full garbage collection starts. The callback runs while the VM is in a
+    /// restricted state, see [`RestrictedJvm`].
+    /// Requires the `can_generate_garbage_collection_events` capability.
+    #[cfg(feature = "gc-events")]
+    pub garbage_collection_start: Option<Handler<GarbageCollectionCallback>>,
+    /// Called when a full garbage collection finishes. The callback runs while the VM is in a
+    /// restricted state, see [`RestrictedJvm`].
+    /// Requires the `can_generate_garbage_collection_events` capability.
+    #[cfg(feature = "gc-events")]
+    pub garbage_collection_finish: Option<Handler<GarbageCollectionCallback>>,
+    /// Called when the garbage collector frees a tagged object, with the tag of the object. The
+    /// callback runs while the VM is in a restricted state, see [`RestrictedJvm`].
+    /// Requires the `can_generate_object_free_events` capability.
+    #[cfg(feature = "gc-events")]
+    pub object_free: Option<Handler<ObjectFreeCallback>>,
+}
+
+/// Returns whether `handler` has at least one callback.
+#[cfg(any(
+    feature = "vm-events",
+    feature = "thread-events",
+    feature = "class-events",
+    feature = "debug-events",
+    feature = "method-events",
+    feature = "monitor-events",
+    feature = "alloc-events",
+    feature = "gc-events"
+))]
+fn is_registered<F: ?Sized>(handler: Option<&Handler<F>>) -> bool {
+    handler.is_some_and(|it| !it.is_empty())
+}
+
+/// The operations on a [`Handler`] that do not depend on the type of its callbacks.
+trait AnyHandler {
+    fn is_empty(&self) -> bool;
+    fn remove(&self, subscription: Subscription) -> bool;
+}
+
+impl<F: ?Sized> AnyHandler for Handler<F> {
+    fn is_empty(&self) -> bool {
+        Handler::is_empty(self)
+    }
+
+    fn remove(&self, subscription: Subscription) -> bool {
+        self.detach(subscription).map(|it| it.take()).is_some()
+    }
+}
+
+impl std::fmt::Debug for EventCallbacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventCallbacks")
+            .field("registered_events", &self.registered_events())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Erases the type of the callbacks of `handler`.
+#[cfg(any(
+    feature = "vm-events",
+    feature = "thread-events",
+    feature = "class-events",
+    feature = "debug-events",
+    feature = "method-events",
+    feature = "monitor-events",
+    feature = "alloc-events",
+    feature = "gc-events"
+))]
+fn erase<F: ?Sized>(handler: Option<&Handler<F>>) -> Option<&dyn AnyHandler> {
+    handler.map(|it| it as &dyn AnyHandler)
 }
 
 impl EventCallbacks {
+    /// Gets the handler of each event, with the types of their callbacks erased.
+    #[allow(clippy::too_many_lines)]
+    fn handlers(&self) -> Vec<(Option<&dyn AnyHandler>, JvmTIEvent)> {
+        #[allow(unused_mut)]
+        let mut events: Vec<(Option<&dyn AnyHandler>, JvmTIEvent)> = Vec::new();
+        #[cfg(feature = "vm-events")]
+        events.extend([
+            (erase(self.vm_init.as_ref()), JvmTIEvent::VMInit),
+            (erase(self.vm_death.as_ref()), JvmTIEvent::VMDeath),
+            (erase(self.vm_start.as_ref()), JvmTIEvent::VMStart),
+            (
+                erase(self.data_dump_request.as_ref()),
+                JvmTIEvent::DataDumpRequest,
+            ),
+            (
+                erase(self.resource_exhausted.as_ref()),
+                JvmTIEvent::ResourceExhausted,
+            ),
+        ]);
+        #[cfg(feature = "thread-events")]
+        events.extend([
+            (erase(self.thread_start.as_ref()), JvmTIEvent::ThreadStart),
+            (erase(self.thread_end.as_ref()), JvmTIEvent::ThreadEnd),
+            (
+                erase(self.virtual_thread_start.as_ref()),
+                JvmTIEvent::VirtualThreadStart,
+            ),
+            (
+                erase(self.virtual_thread_end.as_ref()),
+                JvmTIEvent::VirtualThreadEnd,
+            ),
+        ]);
+        #[cfg(feature = "class-events")]
+        events.extend([
+            (
+                erase(self.class_file_load_hook.as_ref()),
+                JvmTIEvent::ClassFileLoadHook,
+            ),
+            (erase(self.class_load.as_ref()), JvmTIEvent::ClassLoad),
+            (erase(self.class_prepare.as_ref()), JvmTIEvent::ClassPrepare),
+        ]);
+        #[cfg(feature = "debug-events")]
+        events.extend([
+            (erase(self.single_step.as_ref()), JvmTIEvent::SingleStep),
+            (erase(self.breakpoint.as_ref()), JvmTIEvent::Breakpoint),
+            (erase(self.field_access.as_ref()), JvmTIEvent::FieldAccess),
+            (
+                erase(self.field_modification.as_ref()),
+                JvmTIEvent::FieldModification,
+            ),
+        ]);
+        #[cfg(feature = "method-events")]
+        events.extend([
+            (erase(self.method_exit.as_ref()), JvmTIEvent::MethodExit),
+            (erase(self.frame_pop.as_ref()), JvmTIEvent::FramePop),
+            (
+                erase(self.native_method_bind.as_ref()),
+                JvmTIEvent::NativeMethodBind,
+            ),
+        ]);
+        #[cfg(feature = "monitor-events")]
+        events.extend([
+            (erase(self.monitor_wait.as_ref()), JvmTIEvent::MonitorWait),
+            (
+                erase(self.monitor_waited.as_ref()),
+                JvmTIEvent::MonitorWaited,
+            ),
+            (
+                erase(self.monitor_contended_enter.as_ref()),
+                JvmTIEvent::MonitorContendedEnter,
+            ),
+            (
+                erase(self.monitor_contended_entered.as_ref()),
+                JvmTIEvent::MonitorContendedEntered,
+            ),
+        ]);
+        #[cfg(feature = "alloc-events")]
+        events.extend([
+            (
+                erase(self.vm_object_alloc.as_ref()),
+                JvmTIEvent::VMObjectAlloc,
+            ),
+            (
+                erase(self.sampled_object_alloc.as_ref()),
+                JvmTIEvent::SampledObjectAlloc,
+            ),
+        ]);
+        #[cfg(feature = "gc-events")]
+        events.extend([
+            (
+                erase(self.garbage_collection_start.as_ref()),
+                JvmTIEvent::GarbageCollectionStart,
+            ),
+            (
+                erase(self.garbage_collection_finish.as_ref()),
+                JvmTIEvent::GarbageCollectionFinish,
+            ),
+            (erase(self.object_free.as_ref()), JvmTIEvent::ObjectFree),
+        ]);
+        events
+    }
+
+    /// Gets the events with a registered callback.
+    pub(crate) fn registered_events(&self) -> Vec<JvmTIEvent> {
+        self.handlers()
+            .into_iter()
+            .filter(|(handler, _)| handler.is_some_and(|it| !AnyHandler::is_empty(it)))
+            .map(|(_, event)| event)
+            .collect()
+    }
+
+    /// Removes the callback subscribed with `subscription` from the handler of any event, and
+    /// returns whether it was subscribed.
+    pub(crate) fn unsubscribe(&self, subscription: Subscription) -> bool {
+        self.handlers()
+            .into_iter()
+            .filter_map(|(handler, _)| handler)
+            .any(|it| it.remove(subscription))
+    }
+
+    /// Builds the table of native callbacks, in which only the trampolines of the events with a
+    /// registered callback are installed so that the VM does not dispatch the other events.
     pub(crate) fn c_callbacks(&self) -> sys::jvmtiEventCallbacks {
-        sys::jvmtiEventCallbacks {
-            VMInit: Some(Self::vm_init_callback),
-            VMDeath: Some(Self::vm_death_callback),
-            VMStart: Some(Self::vm_start_callback),
-            ThreadStart: Some(Self::thread_start_callback),
-            ThreadEnd: Some(Self::thread_end_callback),
-            ClassFileLoadHook: Some(Self::class_file_load_hook_callback),
-            ClassLoad: Some(Self::class_load_callback),
-            ClassPrepare: Some(Self::class_prepare_callback),
-            Exception: None,
-            ExceptionCatch: None,
-            SingleStep: None,
-            FramePop: None,
-            Breakpoint: None,
-            FieldAccess: None,
-            FieldModification: None,
-            MethodEntry: None,
-            MethodExit: None,
-            NativeMethodBind: None,
-            CompiledMethodLoad: None,
-            CompiledMethodUnload: None,
-            DynamicCodeGenerated: None,
-            DataDumpRequest: None,
-            reserved72: None,
-            MonitorWait: None,
-            MonitorWaited: None,
-            MonitorContendedEnter: None,
-            MonitorContendedEntered: None,
-            reserved77: None,
-            reserved78: None,
-            reserved79: None,
-            ResourceExhausted: None,
-            GarbageCollectionStart: None,
-            GarbageCollectionFinish: None,
-            ObjectFree: None,
-            VMObjectAlloc: None,
-            reserved85: None,
-            SampledObjectAlloc: None,
-            VirtualThreadStart: None,
-            VirtualThreadEnd: None,
+        // SAFETY: All the fields are optional function pointers, for which all zeros is `None`.
+        #[allow(unused_mut)]
+        let mut callbacks: sys::jvmtiEventCallbacks = unsafe { std::mem::zeroed() };
+        #[cfg(feature = "vm-events")]
+        {
+            callbacks.VMInit =
+                is_registered(self.vm_init.as_ref()).then_some(Self::vm_init_callback as _);
+            callbacks.VMDeath =
+                is_registered(self.vm_death.as_ref()).then_some(Self::vm_death_callback as _);
+            callbacks.VMStart =
+                is_registered(self.vm_start.as_ref()).then_some(Self::vm_start_callback as _);
+            callbacks.DataDumpRequest = is_registered(self.data_dump_request.as_ref())
+                .then_some(Self::data_dump_request_callback as _);
+            callbacks.ResourceExhausted = is_registered(self.resource_exhausted.as_ref())
+                .then_some(Self::resource_exhausted_callback as _);
         }
+        #[cfg(feature = "thread-events")]
+        {
+            callbacks.ThreadStart = is_registered(self.thread_start.as_ref())
+                .then_some(Self::thread_start_callback as _);
+            callbacks.ThreadEnd =
+                is_registered(self.thread_end.as_ref()).then_some(Self::thread_end_callback as _);
+            callbacks.VirtualThreadStart = is_registered(self.virtual_thread_start.as_ref())
+                .then_some(Self::virtual_thread_start_callback as _);
+            callbacks.VirtualThreadEnd = is_registered(self.virtual_thread_end.as_ref())
+                .then_some(Self::virtual_thread_end_callback as _);
+        }
+        #[cfg(feature = "class-events")]
+        {
+            callbacks.ClassFileLoadHook = is_registered(self.class_file_load_hook.as_ref())
+                .then_some(Self::class_file_load_hook_callback as _);
+            callbacks.ClassLoad =
+                is_registered(self.class_load.as_ref()).then_some(Self::class_load_callback as _);
+            callbacks.ClassPrepare = is_registered(self.class_prepare.as_ref())
+                .then_some(Self::class_prepare_callback as _);
+        }
+        #[cfg(feature = "debug-events")]
+        {
+            callbacks.SingleStep =
+                is_registered(self.single_step.as_ref()).then_some(Self::single_step_callback as _);
+            callbacks.Breakpoint =
+                is_registered(self.breakpoint.as_ref()).then_some(Self::breakpoint_callback as _);
+            callbacks.FieldAccess = is_registered(self.field_access.as_ref())
+                .then_some(Self::field_access_callback as _);
+            callbacks.FieldModification = is_registered(self.field_modification.as_ref())
+                .then_some(Self::field_modification_callback as _);
+        }
+        #[cfg(feature = "method-events")]
+        {
+            callbacks.MethodExit =
+                is_registered(self.method_exit.as_ref()).then_some(Self::method_exit_callback as _);
+            callbacks.FramePop =
+                is_registered(self.frame_pop.as_ref()).then_some(Self::frame_pop_callback as _);
+            callbacks.NativeMethodBind = is_registered(self.native_method_bind.as_ref())
+                .then_some(Self::native_method_bind_callback as _);
+        }
+        #[cfg(feature = "monitor-events")]
+        {
+            callbacks.MonitorWait = is_registered(self.monitor_wait.as_ref())
+                .then_some(Self::monitor_wait_callback as _);
+            callbacks.MonitorWaited = is_registered(self.monitor_waited.as_ref())
+                .then_some(Self::monitor_waited_callback as _);
+            callbacks.MonitorContendedEnter = is_registered(self.monitor_contended_enter.as_ref())
+                .then_some(Self::monitor_contended_enter_callback as _);
+            callbacks.MonitorContendedEntered =
+                is_registered(self.monitor_contended_entered.as_ref())
+                    .then_some(Self::monitor_contended_entered_callback as _);
+        }
+        #[cfg(feature = "alloc-events")]
+        {
+            callbacks.VMObjectAlloc = is_registered(self.vm_object_alloc.as_ref())
+                .then_some(Self::vm_object_alloc_callback as _);
+            callbacks.SampledObjectAlloc = is_registered(self.sampled_object_alloc.as_ref())
+                .then_some(Self::sampled_object_alloc_callback as _);
+        }
+        #[cfg(feature = "gc-events")]
+        {
+            callbacks.GarbageCollectionStart =
+                is_registered(self.garbage_collection_start.as_ref())
+                    .then_some(Self::garbage_collection_start_callback as _);
+            callbacks.GarbageCollectionFinish =
+                is_registered(self.garbage_collection_finish.as_ref())
+                    .then_some(Self::garbage_collection_finish_callback as _);
+            callbacks.ObjectFree =
+                is_registered(self.object_free.as_ref()).then_some(Self::object_free_callback as _);
+        }
+        callbacks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn samples(sampler: &Sampler, occurrences: usize) -> Vec<bool> {
+        (0..occurrences).map(|_| sampler.sample()).collect()
+    }
+
+    #[test]
+    fn every_nth_keeps_the_first_and_every_nth_occurrence() {
+        assert_eq!(
+            samples(&Sampler::every_nth(3), 7),
+            [true, false, false, true, false, false, true]
+        );
+        assert_eq!(samples(&Sampler::every_nth(1), 3), [true; 3]);
+        assert_eq!(samples(&Sampler::every_nth(0), 3), [true; 3]);
+    }
+
+    #[test]
+    fn rate_limited_keeps_a_burst_and_then_refills() {
+        let sampler = Sampler::rate_limited(2, 3);
+        assert_eq!(samples(&sampler, 4), [true, true, true, false]);
+        let SamplerKind::RateLimited { bucket, .. } = &sampler.kind else {
+            unreachable!("the sampler is rate limited");
+        };
+        // One second later, two tokens have been refilled.
+        bucket.lock().unwrap().1 -= Duration::from_secs(1);
+        assert_eq!(samples(&sampler, 3), [true, true, false]);
+        // The refill is capped at the burst.
+        bucket.lock().unwrap().1 -= Duration::from_secs(10);
+        assert_eq!(samples(&sampler, 4), [true, true, true, false]);
+    }
+
+    #[test]
+    fn rate_limited_without_burst_keeps_nothing() {
+        assert_eq!(samples(&Sampler::rate_limited(1_000, 0), 3), [false; 3]);
+    }
+
+    type Callback = dyn FnMut(&mut Vec<u32>) + Send;
+
+    fn panicking_handler() -> Handler<Callback> {
+        let mut handler = Handler::<Callback>::new(Box::new(|calls| calls.push(1)));
+        handler.subscribe(Box::new(|_| panic!("the callback fails")));
+        handler.subscribe(Box::new(|calls| calls.push(3)));
+        handler
+    }
+
+    #[test]
+    fn log_policy_keeps_panicking_callbacks() {
+        let handler = panicking_handler();
+        let mut calls = Vec::new();
+        handler.call_each(PanicPolicy::Log, |callback| callback(&mut calls));
+        handler.call_each(PanicPolicy::Log, |callback| callback(&mut calls));
+        assert_eq!(calls, [1, 3, 1, 3]);
+        assert_eq!(handler.callbacks.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn disable_policy_unsubscribes_panicking_callbacks() {
+        let handler = panicking_handler();
+        let mut calls = Vec::new();
+        handler.call_each(PanicPolicy::Disable, |callback| callback(&mut calls));
+        assert_eq!(handler.callbacks.lock().unwrap().len(), 2);
+        handler.call_each(PanicPolicy::Disable, |callback| callback(&mut calls));
+        assert_eq!(calls, [1, 3, 1, 3]);
+    }
+
+    #[cfg(feature = "vm-events")]
+    #[test]
+    fn reads_resource_exhausted_flags() {
+        // The flags the VM reports when the Java heap is full.
+        let flags = ResourceExhaustedFlags::from_bits_retain(0x0003);
+        assert_eq!(
+            flags,
+            ResourceExhaustedFlags::OOM_ERROR | ResourceExhaustedFlags::JAVA_HEAP
+        );
+        assert!(!flags.contains(ResourceExhaustedFlags::THREADS));
+        // Bits added by later versions of the specification are kept.
+        assert_eq!(
+            ResourceExhaustedFlags::from_bits_retain(0x0010).bits(),
+            0x0010
+        );
+    }
+
+    #[cfg(all(feature = "vm-events", feature = "gc-events"))]
+    #[test]
+    fn installs_only_registered_callbacks() {
+        let callbacks = EventCallbacks {
+            vm_death: Some(Handler::new(Box::new(|_, _| {}))),
+            garbage_collection_start: Some(Handler::default()),
+            ..EventCallbacks::default()
+        };
+        let table = callbacks.c_callbacks();
+        assert!(table.VMDeath.is_some());
+        assert!(table.VMInit.is_none());
+        // A handler without callbacks does not make the VM dispatch the event.
+        assert!(table.GarbageCollectionStart.is_none());
+    }
+
+    #[cfg(all(feature = "vm-events", feature = "thread-events"))]
+    #[test]
+    fn lists_events_with_callbacks() {
+        let callbacks = EventCallbacks {
+            vm_init: Some(Handler::new(Box::new(|_, _, _| {}))),
+            vm_death: Some(Handler::new(Box::new(|_, _| {}))),
+            thread_start: Some(Handler::default()),
+            ..EventCallbacks::default()
+        };
+        assert_eq!(
+            callbacks.registered_events(),
+            [JvmTIEvent::VMInit, JvmTIEvent::VMDeath]
+        );
+        assert!(EventCallbacks::default().registered_events().is_empty());
+    }
+
+    #[test]
+    fn calls_subscribers_in_order_until_unsubscribed() {
+        let mut handler = Handler::<Callback>::new(Box::new(|calls| calls.push(1)));
+        let second = handler.subscribe(Box::new(|calls| calls.push(2)));
+        handler.subscribe(Box::new(|calls| calls.push(3)));
+        let mut calls = Vec::new();
+        handler.call_each(PanicPolicy::Log, |callback| callback(&mut calls));
+        assert!(handler.unsubscribe(second).is_some());
+        assert!(handler.unsubscribe(second).is_none());
+        handler.call_each(PanicPolicy::Log, |callback| callback(&mut calls));
+        assert_eq!(calls, [1, 2, 3, 1, 3]);
+    }
+
+    #[cfg(feature = "vm-events")]
+    #[test]
+    fn unsubscribes_from_any_event() {
+        let mut vm_death = Handler::<VMPhaseCallback>::default();
+        let subscription = vm_death.subscribe(Box::new(|_, _| {}));
+        let callbacks = EventCallbacks {
+            vm_init: Some(Handler::new(Box::new(|_, _, _| {}))),
+            vm_death: Some(vm_death),
+            ..EventCallbacks::default()
+        };
+        assert!(callbacks.unsubscribe(subscription));
+        assert!(!callbacks.unsubscribe(subscription));
+        assert_eq!(callbacks.registered_events(), [JvmTIEvent::VMInit]);
     }
 }
@@ -0,0 +1,473 @@
+//! Extension events, which a VM may offer in addition to the events of the specification.
+//!
+//! For example, recent Java versions report virtual thread mounts and unmounts only through the
+//! `com.sun.hotspot.events.VirtualThreadMount` and `com.sun.hotspot.events.VirtualThreadUnmount`
+//! extension events. The extension events are listed with [`Jvm::get_extension_events`] and
+//! handled with [`Jvm::set_extension_event_callback`].
+//!
+//! Extension event callbacks are variadic C functions. The trampolines receive the arguments as
+//! fixed ones, which works for integral and pointer arguments on the platforms whose calling
+//! convention passes variadic arguments like fixed ones, e.g. x86-64 and 64-bit ARM except on
+//! Apple platforms.
+use std::{
+    ffi::{c_void, CStr, OsStr, OsString},
+    mem::MaybeUninit,
+    os::unix::prelude::OsStrExt,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{macros::call_jvmti, sys};
+
+use super::{
+    class::Class, errors::JvmTIError, events::Handler, jni::JNI, methods::Method, objects::Object,
+    threads::Thread, Jvm,
+};
+
+/// How a parameter of an extension event is passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ParamKind {
+    /// An argument passed by value.
+    In = sys::JVMTI_KIND_IN,
+    /// A pointer to an argument.
+    InPtr = sys::JVMTI_KIND_IN_PTR,
+    /// A pointer to an array of arguments.
+    InBuf = sys::JVMTI_KIND_IN_BUF,
+    /// A pointer to a pointer to an array allocated by the callee.
+    AllocBuf = sys::JVMTI_KIND_ALLOC_BUF,
+    /// A pointer to a pointer to an array of arrays allocated by the callee.
+    AllocAllocBuf = sys::JVMTI_KIND_ALLOC_ALLOC_BUF,
+    /// A pointer to a result.
+    Out = sys::JVMTI_KIND_OUT,
+    /// A pointer to an array of results.
+    OutBuf = sys::JVMTI_KIND_OUT_BUF,
+    /// A kind not known to this crate.
+    Unknown(sys::jvmtiParamKind),
+}
+
+/// The base type of a parameter of an extension event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ParamType {
+    /// `jbyte`.
+    Byte = sys::JVMTI_TYPE_JBYTE,
+    /// `jchar`.
+    Char = sys::JVMTI_TYPE_JCHAR,
+    /// `jshort`.
+    Short = sys::JVMTI_TYPE_JSHORT,
+    /// `jint`.
+    Int = sys::JVMTI_TYPE_JINT,
+    /// `jlong`.
+    Long = sys::JVMTI_TYPE_JLONG,
+    /// `jfloat`.
+    Float = sys::JVMTI_TYPE_JFLOAT,
+    /// `jdouble`.
+    Double = sys::JVMTI_TYPE_JDOUBLE,
+    /// `jboolean`.
+    Boolean = sys::JVMTI_TYPE_JBOOLEAN,
+    /// `jobject`.
+    Object = sys::JVMTI_TYPE_JOBJECT,
+    /// `jthread`.
+    Thread = sys::JVMTI_TYPE_JTHREAD,
+    /// `jclass`.
+    Class = sys::JVMTI_TYPE_JCLASS,
+    /// `jvalue`.
+    Value = sys::JVMTI_TYPE_JVALUE,
+    /// `jfieldID`.
+    FieldId = sys::JVMTI_TYPE_JFIELDID,
+    /// `jmethodID`.
+    MethodId = sys::JVMTI_TYPE_JMETHODID,
+    /// `char`.
+    CChar = sys::JVMTI_TYPE_CCHAR,
+    /// `void`.
+    CVoid = sys::JVMTI_TYPE_CVOID,
+    /// `JNIEnv`.
+    JniEnv = sys::JVMTI_TYPE_JNIENV,
+    /// A type not known to this crate.
+    Unknown(sys::jvmtiParamTypes),
+}
+
+/// The description of a parameter of an extension event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParamInfo {
+    /// The name of the parameter.
+    pub name: OsString,
+    /// How the parameter is passed.
+    pub kind: ParamKind,
+    /// The base type of the parameter.
+    pub base_type: ParamType,
+    /// Whether a null pointer may be passed.
+    pub null_ok: bool,
+}
+
+impl ParamInfo {
+    /// Returns whether the argument is passed in a floating point register, which the
+    /// trampolines cannot read.
+    fn is_floating_point(&self) -> bool {
+        self.kind == ParamKind::In && matches!(self.base_type, ParamType::Float | ParamType::Double)
+    }
+}
+
+/// The description of an extension event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionEventInfo {
+    /// The index identifying the event.
+    pub index: sys::jint,
+    /// The identifier of the event, e.g. `com.sun.hotspot.events.VirtualThreadMount`.
+    pub id: OsString,
+    /// A description of the event.
+    pub short_description: OsString,
+    /// The parameters of the callback, excluding the leading JVM TI environment.
+    pub params: Vec<ParamInfo>,
+}
+
+/// An argument of an extension event, decoded according to its [`ParamInfo`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ExtensionArg<'a> {
+    /// A `JNIEnv` pointer.
+    Jni(JNI),
+    /// A `jthread`.
+    Thread(Thread<'a>),
+    /// A `jclass`.
+    Class(Class<'a>),
+    /// A `jobject`.
+    Object(Object<'a>),
+    /// A `jmethodID`.
+    Method(Method<'a>),
+    /// A null-terminated string.
+    String(&'a OsStr),
+    /// A `jbyte`, `jchar`, `jshort`, `jint`, or `jlong` passed by value.
+    Int(i64),
+    /// A `jboolean` passed by value.
+    Boolean(bool),
+    /// A null pointer.
+    Null,
+    /// Any other argument, as the raw machine word it was passed in.
+    Raw(usize),
+}
+
+impl<'a> ExtensionArg<'a> {
+    /// Decodes `raw` as an argument described by `param`.
+    /// # Safety
+    /// `raw` must be the argument passed by the VM for `param`.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    unsafe fn decode(jvm: &'a Jvm, param: &ParamInfo, raw: usize) -> Self {
+        let ptr = raw as *mut c_void;
+        match (param.kind, param.base_type) {
+            // Only the low bits of the machine word belong to narrower integers.
+            (ParamKind::In, ParamType::Byte) => Self::Int((raw as i8).into()),
+            (ParamKind::In, ParamType::Char) => Self::Int((raw as u16).into()),
+            (ParamKind::In, ParamType::Short) => Self::Int((raw as i16).into()),
+            (ParamKind::In, ParamType::Int) => Self::Int((raw as i32).into()),
+            (ParamKind::In, ParamType::Long) => Self::Int(raw as i64),
+            (ParamKind::In, ParamType::Boolean) => Self::Boolean(raw as u8 != 0),
+            _ if ptr.is_null() => Self::Null,
+            (_, ParamType::JniEnv) => Self::Jni(JNI::from_ptr(ptr.cast())),
+            (ParamKind::In, ParamType::Thread) => Self::Thread(Thread::from_ptr(jvm, ptr.cast())),
+            (ParamKind::In, ParamType::Class) => Self::Class(Class::from_ptr(jvm, ptr.cast())),
+            (ParamKind::In, ParamType::Object) => Self::Object(Object::from_ptr(jvm, ptr.cast())),
+            (ParamKind::In, ParamType::MethodId) => Self::Method(Method::from_ptr(jvm, ptr.cast())),
+            (ParamKind::InPtr | ParamKind::InBuf, ParamType::CChar) => {
+                Self::String(OsStr::from_bytes(CStr::from_ptr(ptr.cast()).to_bytes()))
+            }
+            _ => Self::Raw(raw),
+        }
+    }
+}
+
+/// The callback of an extension event, see [`Jvm::set_extension_event_callback`].
+pub type ExtensionEventCallback = dyn FnMut(&Jvm, &[ExtensionArg<'_>]) + Send;
+
+/// The maximum number of arguments, besides the JVM TI environment, the trampolines can receive.
+const MAX_ARGS: usize = 5;
+
+/// The maximum number of extension events that can have a callback at the same time.
+const SLOTS: usize = 8;
+
+/// A trampoline receiving the variadic arguments of an extension event as fixed ones.
+type Trampoline = unsafe extern "C" fn(*mut sys::jvmtiEnv, usize, usize, usize, usize, usize);
+
+/// The trampolines, one per slot, since the VM does not tell which event a callback is called for.
+const TRAMPOLINES: [Trampoline; SLOTS] = [
+    trampoline::<0>,
+    trampoline::<1>,
+    trampoline::<2>,
+    trampoline::<3>,
+    trampoline::<4>,
+    trampoline::<5>,
+    trampoline::<6>,
+    trampoline::<7>,
+];
+
+/// An extension event with a callback.
+struct ExtensionSlot {
+    index: sys::jint,
+    params: Vec<ParamInfo>,
+    handler: Handler<ExtensionEventCallback>,
+}
+
+/// The extension events with a callback, indexed by the slot of their trampoline.
+///
+/// The slots are replaced while the trampolines may be running on other threads, so a trampoline
+/// takes its own reference to the slot and dispatches the event after releasing the lock.
+#[derive(Default)]
+pub(crate) struct ExtensionCallbacks {
+    slots: Mutex<[Option<Arc<ExtensionSlot>>; SLOTS]>,
+}
+
+impl ExtensionCallbacks {
+    fn slots(&self) -> MutexGuard<'_, [Option<Arc<ExtensionSlot>>; SLOTS]> {
+        self.slots.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+unsafe extern "C" fn trampoline<const SLOT: usize>(
+    jvmti_env: *mut sys::jvmtiEnv,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+) {
+    let jvm = Jvm::from_ptr(jvmti_env);
+    let Some(slot) = jvm.extension_callbacks.slots()[SLOT].clone() else {
+        return;
+    };
+    let arguments: Vec<_> = slot
+        .params
+        .iter()
+        .zip([arg0, arg1, arg2, arg3, arg4])
+        .map(|(param, raw)| ExtensionArg::decode(jvm, param, raw))
+        .collect();
+    slot.handler
+        .call_each(jvm.panic_policy, |callback| callback(jvm, &arguments));
+}
+
+impl Jvm {
+    /// Gets the extension events offered by the VM.
+    /// See [`GetExtensionEvents`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetExtensionEvents).
+    /// # Errors
+    /// See [`JvmTIError`] for more information.
+    pub fn get_extension_events(&self) -> Result<Vec<ExtensionEventInfo>, JvmTIError> {
+        let mut count = MaybeUninit::uninit();
+        let mut infos_ptr = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv` because of the API restrictions.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetExtensionEvents,
+                count.as_mut_ptr(),
+                infos_ptr.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `count` and `infos_ptr` have been initialized.
+        let (count, infos_ptr) = unsafe { (count.assume_init(), infos_ptr.assume_init()) };
+        let infos: &[sys::jvmtiExtensionEventInfo] = if infos_ptr.is_null() {
+            &[]
+        } else {
+            // SAFETY: The VM returns an array of `count` descriptions.
+            unsafe { std::slice::from_raw_parts(infos_ptr, usize::try_from(count).unwrap_or(0)) }
+        };
+        // All the strings and arrays are released even if one of them fails to be released.
+        let events = infos
+            .iter()
+            // SAFETY: The descriptions are allocated by the VM and released exactly once here.
+            .map(|info| unsafe { self.take_extension_event_info(info) })
+            .collect();
+        // SAFETY: `infos_ptr` is allocated by the VM.
+        unsafe { self.deallocate(infos_ptr) }?;
+        events
+    }
+
+    /// Copies `info` and releases the strings and arrays it refers to.
+    /// # Safety
+    /// `info` must have been returned by `GetExtensionEvents` and not released yet.
+    unsafe fn take_extension_event_info(
+        &self,
+        info: &sys::jvmtiExtensionEventInfo,
+    ) -> Result<ExtensionEventInfo, JvmTIError> {
+        let id = self.take_string(info.id);
+        let short_description = self.take_string(info.short_description);
+        let raw_params: &[sys::jvmtiParamInfo] = if info.params.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(info.params, usize::try_from(info.param_count).unwrap_or(0))
+        };
+        let params: Result<Vec<_>, _> = raw_params
+            .iter()
+            .map(|param| {
+                Ok::<_, JvmTIError>(ParamInfo {
+                    name: self.take_string(param.name)?,
+                    kind: ParamKind::from_raw(param.kind),
+                    base_type: ParamType::from_raw(param.base_type),
+                    null_ok: param.null_ok != 0,
+                })
+            })
+            .collect();
+        self.deallocate(info.params)?;
+        Ok(ExtensionEventInfo {
+            index: info.extension_event_index,
+            id: id?,
+            short_description: short_description?,
+            params: params?,
+        })
+    }
+
+    /// Sets `callback` as the callback of the extension event `index`, which also enables the
+    /// event, or removes the callback and disables the event if `callback` is `None`.
+    /// See [`SetExtensionEventCallback`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SetExtensionEventCallback).
+    /// # Errors
+    /// Returns [`JvmTIError::IllegalArgument`] if there is no extension event `index`, or if its
+    /// arguments cannot be received because there are more than five of them or some of them are
+    /// floating point values passed by value. Returns [`JvmTIError::NotAvailable`] if eight other
+    /// extension events already have a callback.
+    /// See [`JvmTIError`] for other possible errors.
+    pub fn set_extension_event_callback(
+        &mut self,
+        index: sys::jint,
+        callback: Option<Box<ExtensionEventCallback>>,
+    ) -> Result<(), JvmTIError> {
+        let current = self
+            .extension_callbacks
+            .slots()
+            .iter()
+            .position(|it| it.as_ref().is_some_and(|it| it.index == index));
+        let Some(callback) = callback else {
+            // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv` because of the API restrictions.
+            unsafe { call_jvmti!(self.jvmti_ptr, SetExtensionEventCallback, index, None) }?;
+            if let Some(current) = current {
+                self.extension_callbacks.slots()[current] = None;
+            }
+            return Ok(());
+        };
+        if let Some(current) = current {
+            let mut slots = self.extension_callbacks.slots();
+            if let Some(slot) = slots[current].as_mut() {
+                *slot = Arc::new(ExtensionSlot {
+                    index,
+                    params: slot.params.clone(),
+                    handler: Handler::new(callback),
+                });
+                return Ok(());
+            }
+        }
+        let slot = self
+            .extension_callbacks
+            .slots()
+            .iter()
+            .position(Option::is_none)
+            .ok_or(JvmTIError::NotAvailable)?;
+        let params = self
+            .get_extension_events()?
+            .into_iter()
+            .find(|it| it.index == index)
+            .map(|it| it.params)
+            .ok_or(JvmTIError::IllegalArgument)?;
+        if params.len() > MAX_ARGS || params.iter().any(ParamInfo::is_floating_point) {
+            return Err(JvmTIError::IllegalArgument);
+        }
+        // The slot is filled first since the event may be sent as soon as the callback is set.
+        self.extension_callbacks.slots()[slot] = Some(Arc::new(ExtensionSlot {
+            index,
+            params,
+            handler: Handler::new(callback),
+        }));
+        // SAFETY: The trampolines only read the arguments declared by the event, see the module
+        // documentation.
+        let trampoline = unsafe {
+            std::mem::transmute::<Trampoline, unsafe extern "C" fn(*mut sys::jvmtiEnv, ...)>(
+                TRAMPOLINES[slot],
+            )
+        };
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv` because of the API restrictions.
+        let result = unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                SetExtensionEventCallback,
+                index,
+                Some(trampoline)
+            )
+        };
+        if result.is_err() {
+            self.extension_callbacks.slots()[slot] = None;
+        }
+        result
+    }
+}
+
+impl ParamKind {
+    fn from_raw(kind: sys::jvmtiParamKind) -> Self {
+        match kind {
+            sys::JVMTI_KIND_IN => Self::In,
+            sys::JVMTI_KIND_IN_PTR => Self::InPtr,
+            sys::JVMTI_KIND_IN_BUF => Self::InBuf,
+            sys::JVMTI_KIND_ALLOC_BUF => Self::AllocBuf,
+            sys::JVMTI_KIND_ALLOC_ALLOC_BUF => Self::AllocAllocBuf,
+            sys::JVMTI_KIND_OUT => Self::Out,
+            sys::JVMTI_KIND_OUT_BUF => Self::OutBuf,
+            kind => Self::Unknown(kind),
+        }
+    }
+}
+
+impl ParamType {
+    fn from_raw(base_type: sys::jvmtiParamTypes) -> Self {
+        match base_type {
+            sys::JVMTI_TYPE_JBYTE => Self::Byte,
+            sys::JVMTI_TYPE_JCHAR => Self::Char,
+            sys::JVMTI_TYPE_JSHORT => Self::Short,
+            sys::JVMTI_TYPE_JINT => Self::Int,
+            sys::JVMTI_TYPE_JLONG => Self::Long,
+            sys::JVMTI_TYPE_JFLOAT => Self::Float,
+            sys::JVMTI_TYPE_JDOUBLE => Self::Double,
+            sys::JVMTI_TYPE_JBOOLEAN => Self::Boolean,
+            sys::JVMTI_TYPE_JOBJECT => Self::Object,
+            sys::JVMTI_TYPE_JTHREAD => Self::Thread,
+            sys::JVMTI_TYPE_JCLASS => Self::Class,
+            sys::JVMTI_TYPE_JVALUE => Self::Value,
+            sys::JVMTI_TYPE_JFIELDID => Self::FieldId,
+            sys::JVMTI_TYPE_JMETHODID => Self::MethodId,
+            sys::JVMTI_TYPE_CCHAR => Self::CChar,
+            sys::JVMTI_TYPE_CVOID => Self::CVoid,
+            sys::JVMTI_TYPE_JNIENV => Self::JniEnv,
+            base_type => Self::Unknown(base_type),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param(kind: ParamKind, base_type: ParamType) -> ParamInfo {
+        ParamInfo {
+            name: OsString::from("value"),
+            kind,
+            base_type,
+            null_ok: false,
+        }
+    }
+
+    #[test]
+    fn decodes_param_descriptions() {
+        assert_eq!(
+            ParamKind::from_raw(sys::JVMTI_KIND_IN_PTR),
+            ParamKind::InPtr
+        );
+        assert_eq!(ParamKind::from_raw(99), ParamKind::Unknown(99));
+        assert_eq!(
+            ParamType::from_raw(sys::JVMTI_TYPE_JTHREAD),
+            ParamType::Thread
+        );
+        assert_eq!(ParamType::from_raw(1), ParamType::Unknown(1));
+    }
+
+    #[test]
+    fn finds_floating_point_arguments() {
+        assert!(param(ParamKind::In, ParamType::Float).is_floating_point());
+        assert!(param(ParamKind::In, ParamType::Double).is_floating_point());
+        assert!(!param(ParamKind::InPtr, ParamType::Double).is_floating_point());
+        assert!(!param(ParamKind::In, ParamType::Long).is_floating_point());
+    }
+}
@@ -0,0 +1,213 @@
+//! APIs for working with Java fields.
+
+use std::{ffi::OsString, mem::MaybeUninit, os::unix::prelude::OsStrExt, ptr::null_mut};
+
+use crate::{macros::call_jvmti, sys};
+
+use super::{
+    capabilities::JvmtiCapabilities,
+    class::Class,
+    errors::{BreakpointError, ClassError, JvmTIError, MethodError},
+};
+
+/// The kind of accesses to a field that trigger a watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WatchKind {
+    /// Reads of the field, reported through the `FieldAccess` event.
+    Access,
+    /// Writes to the field, reported through the `FieldModification` event.
+    Modification,
+}
+
+impl WatchKind {
+    /// Gets the capability required to watch fields for this kind of access.
+    #[must_use]
+    pub fn required_capabilities(self) -> JvmtiCapabilities {
+        match self {
+            Self::Access => JvmtiCapabilities::CAN_GENERATE_FIELD_ACCESS_EVENTS,
+            Self::Modification => JvmtiCapabilities::CAN_GENERATE_FIELD_MODIFICATION_EVENTS,
+        }
+    }
+}
+
+/// A field of a Java class, identified by its declaring class and its `jfieldID`.
+#[derive(Debug, Clone, Copy)]
+pub struct Field<'a> {
+    class: &'a Class<'a>,
+    jfield_id: sys::jfieldID,
+}
+
+impl<'a> Field<'a> {
+    pub(crate) unsafe fn from_ptr(class: &'a Class<'a>, jfield_id: sys::jfieldID) -> Field<'a> {
+        assert!(!jfield_id.is_null(), "The field pointer must not be null");
+        Field { class, jfield_id }
+    }
+
+    /// Creates a [`Field`] from a raw `jfieldID` of a field declared by `class`, e.g. one
+    /// obtained from other JNI code.
+    /// # Safety
+    /// `jfield_id` must be a valid `jfieldID` of a field declared by `class`.
+    /// # Panics
+    /// Panics if `jfield_id` is null.
+    pub unsafe fn from_raw(class: &'a Class<'a>, jfield_id: sys::jfieldID) -> Field<'a> {
+        Self::from_ptr(class, jfield_id)
+    }
+
+    /// Gets the class declaring the field.
+    #[must_use]
+    pub fn class(&self) -> &'a Class<'a> {
+        self.class
+    }
+
+    /// Gets the raw `jfieldID`, which identifies the field as long as its class is loaded.
+    #[must_use]
+    pub fn as_raw(&self) -> sys::jfieldID {
+        self.jfield_id
+    }
+
+    /// Gets the name of the field.
+    /// See [`GetFieldName`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetFieldName).
+    /// # Errors
+    /// See [`MethodError`] for more information.
+    pub fn name(&self) -> Result<OsString, MethodError> {
+        let mut name_ptr = MaybeUninit::uninit();
+        // SAFETY: `self.class.jclass` and `self.jfield_id` are valid and the signatures are not
+        // requested.
+        unsafe {
+            call_jvmti!(
+                self.class.jvm.jvmti_ptr,
+                GetFieldName,
+                self.class.jclass,
+                self.jfield_id,
+                name_ptr.as_mut_ptr(),
+                null_mut(),
+                null_mut()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `name_ptr` points to a JVM TI allocated string.
+        let name = unsafe { self.class.jvm.take_string(name_ptr.assume_init()) }?;
+        Ok(name)
+    }
+
+    /// Gets the type descriptor of the field, e.g. `I` or `Ljava/lang/String;`.
+    /// See [`GetFieldName`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetFieldName).
+    /// # Errors
+    /// See [`MethodError`] for more information.
+    pub fn signature(&self) -> Result<OsString, MethodError> {
+        let mut signature_ptr = MaybeUninit::uninit();
+        // SAFETY: `self.class.jclass` and `self.jfield_id` are valid and only the signature is
+        // requested.
+        unsafe {
+            call_jvmti!(
+                self.class.jvm.jvmti_ptr,
+                GetFieldName,
+                self.class.jclass,
+                self.jfield_id,
+                null_mut(),
+                signature_ptr.as_mut_ptr(),
+                null_mut()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `signature_ptr` points to a JVM TI allocated
+        // string.
+        let signature = unsafe { self.class.jvm.take_string(signature_ptr.assume_init()) }?;
+        Ok(signature)
+    }
+
+    /// Starts reporting the accesses of `kind` to the field through the `FieldAccess` or
+    /// `FieldModification` event.
+    /// See [`SetFieldAccessWatch`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SetFieldAccessWatch).
+    /// # Errors
+    /// Returns [`BreakpointError::Duplicate`] if the field is already watched for `kind`.
+    /// See [`BreakpointError`] for more information.
+    pub fn watch(&self, kind: WatchKind) -> Result<(), BreakpointError> {
+        self.class
+            .jvm
+            .acquire_capabilities(kind.required_capabilities())
+            .map_err(BreakpointError::MissingCapabilities)?;
+        let (jvmti_ptr, jclass) = (self.class.jvm.jvmti_ptr, self.class.jclass);
+        // SAFETY: `jclass` and `self.jfield_id` are valid.
+        unsafe {
+            match kind {
+                WatchKind::Access => {
+                    call_jvmti!(jvmti_ptr, SetFieldAccessWatch, jclass, self.jfield_id)
+                }
+                WatchKind::Modification => {
+                    call_jvmti!(jvmti_ptr, SetFieldModificationWatch, jclass, self.jfield_id)
+                }
+            }
+        }?;
+        Ok(())
+    }
+
+    /// Stops reporting the accesses of `kind` to the field.
+    /// See [`ClearFieldAccessWatch`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#ClearFieldAccessWatch).
+    /// # Errors
+    /// Returns [`BreakpointError::NotFound`] if the field is not watched for `kind`.
+    /// See [`BreakpointError`] for more information.
+    pub fn unwatch(&self, kind: WatchKind) -> Result<(), BreakpointError> {
+        let (jvmti_ptr, jclass) = (self.class.jvm.jvmti_ptr, self.class.jclass);
+        // SAFETY: `jclass` and `self.jfield_id` are valid.
+        unsafe {
+            match kind {
+                WatchKind::Access => {
+                    call_jvmti!(jvmti_ptr, ClearFieldAccessWatch, jclass, self.jfield_id)
+                }
+                WatchKind::Modification => {
+                    call_jvmti!(
+                        jvmti_ptr,
+                        ClearFieldModificationWatch,
+                        jclass,
+                        self.jfield_id
+                    )
+                }
+            }
+        }?;
+        Ok(())
+    }
+}
+
+impl Class<'_> {
+    /// Gets the fields declared by the class, but not inherited fields.
+    /// See [`GetClassFields`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetClassFields).
+    /// # Errors
+    /// Returns [`ClassError::ClassNotPrepared`] if the class is not prepared yet.
+    /// See [`ClassError`] for more information.
+    pub fn fields(&self) -> Result<Vec<Field<'_>>, ClassError> {
+        let mut count = MaybeUninit::uninit();
+        let mut fields = MaybeUninit::uninit();
+        // SAFETY: `self.jclass` is a valid `jclass`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetClassFields,
+                self.jclass,
+                count.as_mut_ptr(),
+                fields.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `fields` points to `count` field IDs.
+        let fields = unsafe {
+            self.jvm
+                .take_array(fields.assume_init(), count.assume_init())
+        }?;
+        Ok(fields
+            .into_iter()
+            // SAFETY: The field IDs are fields declared by the class.
+            .map(|it| unsafe { Field::from_ptr(self, it) })
+            .collect())
+    }
+
+    /// Finds the field declared by the class whose name is `name` in modified UTF-8.
+    /// # Errors
+    /// Returns [`JvmTIError::ClassNotPrepared`] if the class is not prepared yet.
+    /// See [`JvmTIError`] for more information.
+    pub(crate) fn find_field(&self, name: &[u8]) -> Result<Option<Field<'_>>, JvmTIError> {
+        for field in self.fields()? {
+            if field.name()?.as_bytes() == name {
+                return Ok(Some(field));
+            }
+        }
+        Ok(None)
+    }
+}
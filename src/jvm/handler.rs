@@ -0,0 +1,653 @@
+//! Trait-based registration of event callbacks.
+//!
+//! Instead of registering a closure per event with [`Jvm::update_callbacks`], an agent can
+//! implement [`JvmtiEventHandler`] on a single type holding its shared state and install it with
+//! [`Jvm::install_handler`]. Only the events listed by [`JvmtiEventHandler::events`] are
+//! dispatched to the handler.
+
+#[cfg(feature = "method-events")]
+use std::ffi::c_void;
+#[cfg(feature = "vm-events")]
+use std::ffi::OsStr;
+use std::sync::Arc;
+#[cfg(feature = "monitor-events")]
+use std::time::Duration;
+
+#[cfg(any(feature = "debug-events", feature = "gc-events"))]
+use crate::sys;
+
+#[cfg(any(feature = "class-events", feature = "alloc-events"))]
+use super::class::Class;
+#[cfg(feature = "class-events")]
+use super::events::ClassFileLoadEvent;
+#[cfg(feature = "debug-events")]
+use super::events::FieldEvent;
+#[cfg(any(
+    feature = "vm-events",
+    feature = "thread-events",
+    feature = "class-events",
+    feature = "debug-events",
+    feature = "method-events",
+    feature = "monitor-events",
+    feature = "alloc-events",
+    feature = "gc-events"
+))]
+use super::events::Handler;
+#[cfg(feature = "vm-events")]
+use super::events::ResourceExhaustedFlags;
+#[cfg(feature = "gc-events")]
+use super::events::RestrictedJvm;
+#[cfg(any(
+    feature = "vm-events",
+    feature = "thread-events",
+    feature = "class-events",
+    feature = "debug-events",
+    feature = "method-events",
+    feature = "monitor-events",
+    feature = "alloc-events"
+))]
+use super::jni::JNI;
+#[cfg(any(feature = "debug-events", feature = "method-events"))]
+use super::methods::Method;
+#[cfg(any(feature = "monitor-events", feature = "alloc-events"))]
+use super::objects::Object;
+#[cfg(any(
+    feature = "vm-events",
+    feature = "thread-events",
+    feature = "class-events",
+    feature = "debug-events",
+    feature = "method-events",
+    feature = "monitor-events",
+    feature = "alloc-events"
+))]
+use super::threads::Thread;
+#[cfg(feature = "method-events")]
+use super::values::JValue;
+use super::{
+    errors::JvmTIError,
+    events::{EventCallbacks, JvmTIEvent},
+    Jvm,
+};
+
+/// A handler of JVM TI events, with a method per event that does nothing by default.
+///
+/// The methods take `&self` and may be called concurrently from different threads, so shared
+/// state has to be synchronized, e.g. with [`Mutex`](std::sync::Mutex) or atomics. The arguments
+/// and requirements of each method are the same as those of the corresponding field of
+/// [`EventCallbacks`].
+pub trait JvmtiEventHandler: Send + Sync {
+    /// The events dispatched to the handler. The methods of the other events are never called.
+    fn events(&self) -> Vec<JvmTIEvent>;
+
+    /// Handles the `VMInit` event.
+    #[cfg(feature = "vm-events")]
+    fn on_vm_init(&self, jvm: &Jvm, jni: &JNI, thread: &Thread<'_>) {
+        let _ = (jvm, jni, thread);
+    }
+
+    /// Handles the `VMDeath` event.
+    #[cfg(feature = "vm-events")]
+    fn on_vm_death(&self, jvm: &Jvm, jni: &JNI) {
+        let _ = (jvm, jni);
+    }
+
+    /// Handles the `VMStart` event.
+    #[cfg(feature = "vm-events")]
+    fn on_vm_start(&self, jvm: &Jvm, jni: &JNI) {
+        let _ = (jvm, jni);
+    }
+
+    /// Handles the `DataDumpRequest` event.
+    #[cfg(feature = "vm-events")]
+    fn on_data_dump_request(&self, jvm: &Jvm) {
+        let _ = jvm;
+    }
+
+    /// Handles the `ResourceExhausted` event.
+    #[cfg(feature = "vm-events")]
+    fn on_resource_exhausted(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        flags: ResourceExhaustedFlags,
+        description: &OsStr,
+    ) {
+        let _ = (jvm, jni, flags, description);
+    }
+
+    /// Handles the `ThreadStart` event.
+    #[cfg(feature = "thread-events")]
+    fn on_thread_start(&self, jvm: &Jvm, jni: &JNI, thread: &Thread<'_>) {
+        let _ = (jvm, jni, thread);
+    }
+
+    /// Handles the `ThreadEnd` event.
+    #[cfg(feature = "thread-events")]
+    fn on_thread_end(&self, jvm: &Jvm, jni: &JNI, thread: &Thread<'_>) {
+        let _ = (jvm, jni, thread);
+    }
+
+    /// Handles the `VirtualThreadStart` event.
+    #[cfg(feature = "thread-events")]
+    fn on_virtual_thread_start(&self, jvm: &Jvm, jni: &JNI, virtual_thread: &Thread<'_>) {
+        let _ = (jvm, jni, virtual_thread);
+    }
+
+    /// Handles the `VirtualThreadEnd` event.
+    #[cfg(feature = "thread-events")]
+    fn on_virtual_thread_end(&self, jvm: &Jvm, jni: &JNI, virtual_thread: &Thread<'_>) {
+        let _ = (jvm, jni, virtual_thread);
+    }
+
+    /// Handles the `ClassFileLoadHook` event. Returning `Some` replaces the class data.
+    #[cfg(feature = "class-events")]
+    fn on_class_file_load_hook(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        event: &ClassFileLoadEvent<'_>,
+    ) -> Option<Vec<u8>> {
+        let _ = (jvm, jni, event);
+        None
+    }
+
+    /// Handles the `ClassLoad` event.
+    #[cfg(feature = "class-events")]
+    fn on_class_load(&self, jvm: &Jvm, jni: &JNI, thread: &Thread<'_>, class: &Class<'_>) {
+        let _ = (jvm, jni, thread, class);
+    }
+
+    /// Handles the `ClassPrepare` event.
+    #[cfg(feature = "class-events")]
+    fn on_class_prepare(&self, jvm: &Jvm, jni: &JNI, thread: &Thread<'_>, class: &Class<'_>) {
+        let _ = (jvm, jni, thread, class);
+    }
+
+    /// Handles the `SingleStep` event.
+    #[cfg(feature = "debug-events")]
+    fn on_single_step(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        method: &Method<'_>,
+        location: sys::jlocation,
+    ) {
+        let _ = (jvm, jni, thread, method, location);
+    }
+
+    /// Handles the `Breakpoint` event.
+    #[cfg(feature = "debug-events")]
+    fn on_breakpoint(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        method: &Method<'_>,
+        location: sys::jlocation,
+    ) {
+        let _ = (jvm, jni, thread, method, location);
+    }
+
+    /// Handles the `FieldAccess` event.
+    #[cfg(feature = "debug-events")]
+    fn on_field_access(&self, jvm: &Jvm, jni: &JNI, thread: &Thread<'_>, event: &FieldEvent<'_>) {
+        let _ = (jvm, jni, thread, event);
+    }
+
+    /// Handles the `FieldModification` event.
+    #[cfg(feature = "debug-events")]
+    fn on_field_modification(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        event: &FieldEvent<'_>,
+    ) {
+        let _ = (jvm, jni, thread, event);
+    }
+
+    /// Handles the `MethodExit` event.
+    #[cfg(feature = "method-events")]
+    fn on_method_exit(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        method: &Method<'_>,
+        was_popped_by_exception: bool,
+        return_value: Option<JValue<'_>>,
+    ) {
+        let _ = (
+            jvm,
+            jni,
+            thread,
+            method,
+            was_popped_by_exception,
+            return_value,
+        );
+    }
+
+    /// Handles the `FramePop` event.
+    #[cfg(feature = "method-events")]
+    fn on_frame_pop(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        method: &Method<'_>,
+        was_popped_by_exception: bool,
+    ) {
+        let _ = (jvm, jni, thread, method, was_popped_by_exception);
+    }
+
+    /// Handles the `NativeMethodBind` event. Returning `Some` redirects the binding.
+    #[cfg(feature = "method-events")]
+    fn on_native_method_bind(
+        &self,
+        jvm: &Jvm,
+        jni: Option<&JNI>,
+        thread: Option<&Thread<'_>>,
+        method: &Method<'_>,
+        address: *mut c_void,
+    ) -> Option<*mut c_void> {
+        let _ = (jvm, jni, thread, method, address);
+        None
+    }
+
+    /// Handles the `MonitorWait` event.
+    #[cfg(feature = "monitor-events")]
+    fn on_monitor_wait(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        object: &Object<'_>,
+        timeout: Option<Duration>,
+    ) {
+        let _ = (jvm, jni, thread, object, timeout);
+    }
+
+    /// Handles the `MonitorWaited` event.
+    #[cfg(feature = "monitor-events")]
+    fn on_monitor_waited(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        object: &Object<'_>,
+        timed_out: bool,
+    ) {
+        let _ = (jvm, jni, thread, object, timed_out);
+    }
+
+    /// Handles the `MonitorContendedEnter` event.
+    #[cfg(feature = "monitor-events")]
+    fn on_monitor_contended_enter(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        object: &Object<'_>,
+    ) {
+        let _ = (jvm, jni, thread, object);
+    }
+
+    /// Handles the `MonitorContendedEntered` event.
+    #[cfg(feature = "monitor-events")]
+    fn on_monitor_contended_entered(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        object: &Object<'_>,
+    ) {
+        let _ = (jvm, jni, thread, object);
+    }
+
+    /// Handles the `VMObjectAlloc` event.
+    #[cfg(feature = "alloc-events")]
+    fn on_vm_object_alloc(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        object: &Object<'_>,
+        class: &Class<'_>,
+        size: u64,
+    ) {
+        let _ = (jvm, jni, thread, object, class, size);
+    }
+
+    /// Handles the `SampledObjectAlloc` event.
+    #[cfg(feature = "alloc-events")]
+    fn on_sampled_object_alloc(
+        &self,
+        jvm: &Jvm,
+        jni: &JNI,
+        thread: &Thread<'_>,
+        object: &Object<'_>,
+        class: &Class<'_>,
+        size: u64,
+    ) {
+        let _ = (jvm, jni, thread, object, class, size);
+    }
+
+    /// Handles the `GarbageCollectionStart` event.
+    #[cfg(feature = "gc-events")]
+    fn on_garbage_collection_start(&self, jvm: &RestrictedJvm<'_>) {
+        let _ = jvm;
+    }
+
+    /// Handles the `GarbageCollectionFinish` event.
+    #[cfg(feature = "gc-events")]
+    fn on_garbage_collection_finish(&self, jvm: &RestrictedJvm<'_>) {
+        let _ = jvm;
+    }
+
+    /// Handles the `ObjectFree` event.
+    #[cfg(feature = "gc-events")]
+    fn on_object_free(&self, jvm: &RestrictedJvm<'_>, tag: sys::jlong) {
+        let _ = (jvm, tag);
+    }
+}
+
+impl Jvm {
+    /// Installs `handler` as the callback of each event listed by [`JvmtiEventHandler::events`],
+    /// replacing the callbacks previously registered for these events.
+    /// # Errors
+    /// Returns [`JvmTIError::InvalidEventType`] if one of the events has no callback, e.g.
+    /// because its event group is not enabled, in which case nothing is installed.
+    /// See [`Jvm::update_callbacks`] for other possible errors.
+    pub fn install_handler(
+        &mut self,
+        handler: Box<dyn JvmtiEventHandler>,
+    ) -> Result<(), JvmTIError> {
+        let handler: Arc<dyn JvmtiEventHandler> = Arc::from(handler);
+        let events = handler.events();
+        self.install_handler_for(&handler, &events)
+    }
+
+    /// Installs `handler` as the callback of each of `events`, or of none of them if one has no
+    /// callback.
+    pub(super) fn install_handler_for(
+        &mut self,
+        handler: &Arc<dyn JvmtiEventHandler>,
+        events: &[JvmTIEvent],
+    ) -> Result<(), JvmTIError> {
+        if !events
+            .iter()
+            .all(|&event| register(&mut EventCallbacks::default(), event, handler))
+        {
+            return Err(JvmTIError::InvalidEventType);
+        }
+        self.update_callbacks(|callbacks| {
+            for &event in events {
+                register(callbacks, event, handler);
+            }
+        })
+    }
+}
+
+/// Registers `handler` as the callback of `event`, and returns whether `event` has a callback.
+#[allow(clippy::too_many_lines)]
+#[cfg_attr(
+    not(any(
+        feature = "vm-events",
+        feature = "thread-events",
+        feature = "class-events",
+        feature = "debug-events",
+        feature = "method-events",
+        feature = "monitor-events",
+        feature = "alloc-events",
+        feature = "gc-events"
+    )),
+    allow(unused_variables, unreachable_code)
+)]
+fn register(
+    callbacks: &mut EventCallbacks,
+    event: JvmTIEvent,
+    handler: &Arc<dyn JvmtiEventHandler>,
+) -> bool {
+    let handler = Arc::clone(handler);
+    match event {
+        #[cfg(feature = "vm-events")]
+        JvmTIEvent::VMInit => {
+            callbacks.vm_init = Some(Handler::new(Box::new(move |jvm, jni, thread| {
+                handler.on_vm_init(jvm, jni, thread);
+            })));
+        }
+        #[cfg(feature = "vm-events")]
+        JvmTIEvent::VMDeath => {
+            callbacks.vm_death = Some(Handler::new(Box::new(move |jvm, jni| {
+                handler.on_vm_death(jvm, jni);
+            })));
+        }
+        #[cfg(feature = "vm-events")]
+        JvmTIEvent::VMStart => {
+            callbacks.vm_start = Some(Handler::new(Box::new(move |jvm, jni| {
+                handler.on_vm_start(jvm, jni);
+            })));
+        }
+        #[cfg(feature = "vm-events")]
+        JvmTIEvent::DataDumpRequest => {
+            callbacks.data_dump_request = Some(Handler::new(Box::new(move |jvm| {
+                handler.on_data_dump_request(jvm);
+            })));
+        }
+        #[cfg(feature = "vm-events")]
+        JvmTIEvent::ResourceExhausted => {
+            callbacks.resource_exhausted = Some(Handler::new(Box::new(
+                move |jvm, jni, flags, description| {
+                    handler.on_resource_exhausted(jvm, jni, flags, description);
+                },
+            )));
+        }
+        #[cfg(feature = "thread-events")]
+        JvmTIEvent::ThreadStart => {
+            callbacks.thread_start = Some(Handler::new(Box::new(move |jvm, jni, thread| {
+                handler.on_thread_start(jvm, jni, thread);
+            })));
+        }
+        #[cfg(feature = "thread-events")]
+        JvmTIEvent::ThreadEnd => {
+            callbacks.thread_end = Some(Handler::new(Box::new(move |jvm, jni, thread| {
+                handler.on_thread_end(jvm, jni, thread);
+            })));
+        }
+        #[cfg(feature = "thread-events")]
+        JvmTIEvent::VirtualThreadStart => {
+            callbacks.virtual_thread_start =
+                Some(Handler::new(Box::new(move |jvm, jni, thread| {
+                    handler.on_virtual_thread_start(jvm, jni, thread);
+                })));
+        }
+        #[cfg(feature = "thread-events")]
+        JvmTIEvent::VirtualThreadEnd => {
+            callbacks.virtual_thread_end = Some(Handler::new(Box::new(move |jvm, jni, thread| {
+                handler.on_virtual_thread_end(jvm, jni, thread);
+            })));
+        }
+        #[cfg(feature = "class-events")]
+        JvmTIEvent::ClassFileLoadHook => {
+            callbacks.class_file_load_hook =
+                Some(Handler::new(Box::new(move |jvm, jni, event| {
+                    handler.on_class_file_load_hook(jvm, jni, event)
+                })));
+        }
+        #[cfg(feature = "class-events")]
+        JvmTIEvent::ClassLoad => {
+            callbacks.class_load = Some(Handler::new(Box::new(move |jvm, jni, thread, class| {
+                handler.on_class_load(jvm, jni, thread, class);
+            })));
+        }
+        #[cfg(feature = "class-events")]
+        JvmTIEvent::ClassPrepare => {
+            callbacks.class_prepare =
+                Some(Handler::new(Box::new(move |jvm, jni, thread, class| {
+                    handler.on_class_prepare(jvm, jni, thread, class);
+                })));
+        }
+        #[cfg(feature = "debug-events")]
+        JvmTIEvent::SingleStep => {
+            callbacks.single_step = Some(Handler::new(Box::new(
+                move |jvm, jni, thread, method, location| {
+                    handler.on_single_step(jvm, jni, thread, method, location);
+                },
+            )));
+        }
+        #[cfg(feature = "debug-events")]
+        JvmTIEvent::Breakpoint => {
+            callbacks.breakpoint = Some(Handler::new(Box::new(
+                move |jvm, jni, thread, method, location| {
+                    handler.on_breakpoint(jvm, jni, thread, method, location);
+                },
+            )));
+        }
+        #[cfg(feature = "debug-events")]
+        JvmTIEvent::FieldAccess => {
+            callbacks.field_access =
+                Some(Handler::new(Box::new(move |jvm, jni, thread, event| {
+                    handler.on_field_access(jvm, jni, thread, event);
+                })));
+        }
+        #[cfg(feature = "debug-events")]
+        JvmTIEvent::FieldModification => {
+            callbacks.field_modification =
+                Some(Handler::new(Box::new(move |jvm, jni, thread, event| {
+                    handler.on_field_modification(jvm, jni, thread, event);
+                })));
+        }
+        #[cfg(feature = "method-events")]
+        JvmTIEvent::MethodExit => {
+            callbacks.method_exit = Some(Handler::new(Box::new(
+                move |jvm, jni, thread, method, was_popped_by_exception, return_value| {
+                    handler.on_method_exit(
+                        jvm,
+                        jni,
+                        thread,
+                        method,
+                        was_popped_by_exception,
+                        return_value,
+                    );
+                },
+            )));
+        }
+        #[cfg(feature = "method-events")]
+        JvmTIEvent::FramePop => {
+            callbacks.frame_pop = Some(Handler::new(Box::new(
+                move |jvm, jni, thread, method, was_popped_by_exception| {
+                    handler.on_frame_pop(jvm, jni, thread, method, was_popped_by_exception);
+                },
+            )));
+        }
+        #[cfg(feature = "method-events")]
+        JvmTIEvent::NativeMethodBind => {
+            callbacks.native_method_bind = Some(Handler::new(Box::new(
+                move |jvm, jni, thread, method, address| {
+                    handler.on_native_method_bind(jvm, jni, thread, method, address)
+                },
+            )));
+        }
+        #[cfg(feature = "monitor-events")]
+        JvmTIEvent::MonitorWait => {
+            callbacks.monitor_wait = Some(Handler::new(Box::new(
+                move |jvm, jni, thread, object, timeout| {
+                    handler.on_monitor_wait(jvm, jni, thread, object, timeout);
+                },
+            )));
+        }
+        #[cfg(feature = "monitor-events")]
+        JvmTIEvent::MonitorWaited => {
+            callbacks.monitor_waited = Some(Handler::new(Box::new(
+                move |jvm, jni, thread, object, timed_out| {
+                    handler.on_monitor_waited(jvm, jni, thread, object, timed_out);
+                },
+            )));
+        }
+        #[cfg(feature = "monitor-events")]
+        JvmTIEvent::MonitorContendedEnter => {
+            callbacks.monitor_contended_enter =
+                Some(Handler::new(Box::new(move |jvm, jni, thread, object| {
+                    handler.on_monitor_contended_enter(jvm, jni, thread, object);
+                })));
+        }
+        #[cfg(feature = "monitor-events")]
+        JvmTIEvent::MonitorContendedEntered => {
+            callbacks.monitor_contended_entered =
+                Some(Handler::new(Box::new(move |jvm, jni, thread, object| {
+                    handler.on_monitor_contended_entered(jvm, jni, thread, object);
+                })));
+        }
+        #[cfg(feature = "alloc-events")]
+        JvmTIEvent::VMObjectAlloc => {
+            callbacks.vm_object_alloc = Some(Handler::new(Box::new(
+                move |jvm, jni, thread, object, class, size| {
+                    handler.on_vm_object_alloc(jvm, jni, thread, object, class, size);
+                },
+            )));
+        }
+        #[cfg(feature = "alloc-events")]
+        JvmTIEvent::SampledObjectAlloc => {
+            callbacks.sampled_object_alloc = Some(Handler::new(Box::new(
+                move |jvm, jni, thread, object, class, size| {
+                    handler.on_sampled_object_alloc(jvm, jni, thread, object, class, size);
+                },
+            )));
+        }
+        #[cfg(feature = "gc-events")]
+        JvmTIEvent::GarbageCollectionStart => {
+            callbacks.garbage_collection_start = Some(Handler::new(Box::new(move |jvm| {
+                handler.on_garbage_collection_start(jvm);
+            })));
+        }
+        #[cfg(feature = "gc-events")]
+        JvmTIEvent::GarbageCollectionFinish => {
+            callbacks.garbage_collection_finish = Some(Handler::new(Box::new(move |jvm| {
+                handler.on_garbage_collection_finish(jvm);
+            })));
+        }
+        #[cfg(feature = "gc-events")]
+        JvmTIEvent::ObjectFree => {
+            callbacks.object_free = Some(Handler::new(Box::new(move |jvm, tag| {
+                handler.on_object_free(jvm, tag);
+            })));
+        }
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(all(test, feature = "vm-events", feature = "thread-events"))]
+mod tests {
+    use super::*;
+
+    struct Lifecycle;
+
+    impl JvmtiEventHandler for Lifecycle {
+        fn events(&self) -> Vec<JvmTIEvent> {
+            vec![JvmTIEvent::VMDeath, JvmTIEvent::ThreadStart]
+        }
+    }
+
+    #[test]
+    fn registers_the_events_of_the_handler() {
+        let handler: Arc<dyn JvmtiEventHandler> = Arc::new(Lifecycle);
+        let mut callbacks = EventCallbacks::default();
+        for event in handler.events() {
+            assert!(register(&mut callbacks, event, &handler));
+        }
+        assert_eq!(
+            callbacks.registered_events(),
+            [JvmTIEvent::VMDeath, JvmTIEvent::ThreadStart]
+        );
+    }
+
+    #[test]
+    fn rejects_events_without_a_method() {
+        let handler: Arc<dyn JvmtiEventHandler> = Arc::new(Lifecycle);
+        let mut callbacks = EventCallbacks::default();
+        assert!(!register(&mut callbacks, JvmTIEvent::MethodEntry, &handler));
+        assert!(callbacks.registered_events().is_empty());
+    }
+}
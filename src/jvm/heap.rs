@@ -0,0 +1,490 @@
+//! Heap iteration and traversal based on object tags.
+//!
+//! The JVM TI reports the objects of the heap to callbacks by the tag of their class, their size,
+//! and a mutable reference to their own tag, which the callback can read, set, or clear. Objects
+//! themselves are never handed out, as no JNI function may be called during the iteration; tag
+//! the objects of interest and retrieve them afterwards with [`Jvm::objects_with_tags`].
+//!
+//! Heap iteration and traversal require the `can_tag_objects` capability.
+
+use std::{
+    any::Any,
+    ffi::c_void,
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    ptr::null_mut,
+};
+
+use bitflags::bitflags;
+
+use crate::{macros::call_jvmti, sys};
+
+use super::{
+    capabilities::JvmtiCapabilities, class::Class, errors::HeapError, objects::Object, Jvm,
+};
+
+bitflags! {
+    /// The objects excluded from a heap iteration. The empty filter reports all the objects.
+    /// See [Heap Filter Flags](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#jvmtiHeapFilter).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct HeapFilter: u32 {
+        /// Skips tagged objects.
+        const TAGGED = sys::JVMTI_HEAP_FILTER_TAGGED;
+        /// Skips untagged objects.
+        const UNTAGGED = sys::JVMTI_HEAP_FILTER_UNTAGGED;
+        /// Skips objects whose class is tagged.
+        const CLASS_TAGGED = sys::JVMTI_HEAP_FILTER_CLASS_TAGGED;
+        /// Skips objects whose class is not tagged.
+        const CLASS_UNTAGGED = sys::JVMTI_HEAP_FILTER_CLASS_UNTAGGED;
+    }
+}
+
+bitflags! {
+    /// What a heap callback asks the iteration to do next. The empty value continues with the
+    /// next object without following the references of the current one.
+    /// See [Heap Visit Control Flags](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#jvmtiHeapVisitControl).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct VisitControl: u32 {
+        /// Follows the references of the visited object. Only meaningful when following
+        /// references; a heap iteration visits every object anyway.
+        const VISIT_OBJECTS = sys::JVMTI_VISIT_OBJECTS;
+        /// Stops the iteration.
+        const ABORT = sys::JVMTI_VISIT_ABORT;
+    }
+}
+
+/// The state of a heap iteration passed to the native callbacks.
+struct Visitor<F> {
+    callback: F,
+    /// The payload of a panic in `callback`, which aborts the iteration and is resumed once the
+    /// iteration has returned.
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<F> Visitor<F> {
+    fn new(callback: F) -> Self {
+        Self {
+            callback,
+            panic: None,
+        }
+    }
+
+    /// Calls `visit` on the callback and converts its result for the VM, turning a panic into an
+    /// aborted iteration.
+    fn visit(&mut self, visit: impl FnOnce(&mut F) -> VisitControl) -> sys::jint {
+        if self.panic.is_some() {
+            return VisitControl::ABORT.bits().cast_signed();
+        }
+        match catch_unwind(AssertUnwindSafe(|| visit(&mut self.callback))) {
+            Ok(control) => control.bits().cast_signed(),
+            Err(payload) => {
+                self.panic = Some(payload);
+                VisitControl::ABORT.bits().cast_signed()
+            }
+        }
+    }
+
+    /// Propagates a panic caught during the iteration.
+    fn finish(self) {
+        if let Some(payload) = self.panic {
+            resume_unwind(payload);
+        }
+    }
+}
+
+impl Jvm {
+    /// Calls `callback` for each object in the heap that passes `filter` and, if `class` is
+    /// `Some`, is an instance of that class. The callback receives the tag of the class of the
+    /// object, the size of the object in bytes, a mutable reference to the tag of the object, and
+    /// the length of the object if it is an array. Setting the tag tags or untags the object.
+    ///
+    /// The VM is stopped during the iteration, so the callback must return quickly and must not
+    /// call JNI or JVM TI functions. A panic in the callback aborts the iteration and is resumed
+    /// once the iteration has returned.
+    /// See [`IterateThroughHeap`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#IterateThroughHeap).
+    /// # Errors
+    /// Returns [`HeapError::MissingCapabilities`] if the `can_tag_objects` capability cannot be
+    /// added. See [`HeapError`] for more information.
+    pub fn iterate_heap<F>(
+        &self,
+        filter: HeapFilter,
+        class: Option<&Class<'_>>,
+        callback: F,
+    ) -> Result<(), HeapError>
+    where
+        F: FnMut(sys::jlong, u64, &mut sys::jlong, Option<usize>) -> VisitControl,
+    {
+        unsafe extern "C" fn visit_object<F>(
+            class_tag: sys::jlong,
+            size: sys::jlong,
+            tag_ptr: *mut sys::jlong,
+            length: sys::jint,
+            user_data: *mut c_void,
+        ) -> sys::jint
+        where
+            F: FnMut(sys::jlong, u64, &mut sys::jlong, Option<usize>) -> VisitControl,
+        {
+            // SAFETY: `user_data` is the `visitor` passed to `IterateThroughHeap` below.
+            let visitor = unsafe { &mut *user_data.cast::<Visitor<F>>() };
+            // SAFETY: `tag_ptr` points to the tag of the object for the duration of the call.
+            let tag = unsafe { &mut *tag_ptr };
+            let length = usize::try_from(length).ok();
+            visitor.visit(|callback| callback(class_tag, size.cast_unsigned(), tag, length))
+        }
+
+        self.acquire_capabilities(JvmtiCapabilities::CAN_TAG_OBJECTS)
+            .map_err(HeapError::MissingCapabilities)?;
+        // SAFETY: All the fields are optional function pointers, for which all zeros is `None`.
+        let mut callbacks: sys::jvmtiHeapCallbacks = unsafe { std::mem::zeroed() };
+        callbacks.heap_iteration_callback = Some(visit_object::<F>);
+        let mut visitor = Visitor::new(callback);
+        // SAFETY: `callbacks` and `visitor` outlive the iteration, and `class` is either null or a
+        // valid `jclass`.
+        let result = unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                IterateThroughHeap,
+                filter.bits().cast_signed(),
+                class.map_or(null_mut(), |it| it.jclass),
+                std::ptr::from_ref(&callbacks),
+                std::ptr::addr_of_mut!(visitor).cast()
+            )
+        };
+        visitor.finish();
+        result?;
+        Ok(())
+    }
+}
+
+/// A reference reported by [`Jvm::follow_references`], with the details of its kind.
+/// See [Heap Reference Enumeration](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#jvmtiHeapReferenceKind).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HeapReference {
+    /// From an object to its class.
+    Class,
+    /// From an object to the value of one of its instance fields.
+    Field {
+        /// The index of the field among the fields of the class and its superclasses, as
+        /// specified by the JVM TI.
+        index: usize,
+    },
+    /// From an array to one of its elements.
+    ArrayElement {
+        /// The index of the element.
+        index: usize,
+    },
+    /// From a class to its class loader.
+    ClassLoader,
+    /// From a class to its signers array.
+    Signers,
+    /// From a class to its protection domain.
+    ProtectionDomain,
+    /// From a class to one of its interfaces.
+    Interface,
+    /// From a class to the value of one of its static fields.
+    StaticField {
+        /// The index of the field, as for [`HeapReference::Field`].
+        index: usize,
+    },
+    /// From a class to a resolved entry in its constant pool.
+    ConstantPool {
+        /// The index of the entry.
+        index: usize,
+    },
+    /// From a class to its superclass.
+    Superclass,
+    /// A root held by a JNI global reference.
+    JniGlobal,
+    /// A root held by the system class loader.
+    SystemClass,
+    /// A root used as a monitor.
+    Monitor,
+    /// A root held by a local variable of a stack frame.
+    StackLocal {
+        /// The tag of the thread, or `0` if it is not tagged.
+        thread_tag: sys::jlong,
+        /// The unique ID of the thread.
+        thread_id: sys::jlong,
+        /// The depth of the frame.
+        depth: usize,
+        /// The method of the frame. It cannot be used during the traversal.
+        method: sys::jmethodID,
+        /// The location executing in the frame.
+        location: sys::jlocation,
+        /// The slot of the local variable.
+        slot: usize,
+    },
+    /// A root held by a JNI local reference.
+    JniLocal {
+        /// The tag of the thread, or `0` if it is not tagged.
+        thread_tag: sys::jlong,
+        /// The unique ID of the thread.
+        thread_id: sys::jlong,
+        /// The depth of the frame.
+        depth: usize,
+        /// The method of the frame. It cannot be used during the traversal.
+        method: sys::jmethodID,
+    },
+    /// A root held by a thread.
+    Thread,
+    /// Another kind of root.
+    Other,
+}
+
+impl HeapReference {
+    /// Decodes the kind of a reference and its details.
+    /// # Safety
+    /// `info` must point to the details of a reference of `kind`, or be null for the kinds without
+    /// details.
+    unsafe fn from_raw(
+        kind: sys::jvmtiHeapReferenceKind,
+        info: *const sys::jvmtiHeapReferenceInfo,
+    ) -> Self {
+        let index = |index: sys::jint| usize::try_from(index).unwrap_or_default();
+        match kind {
+            sys::JVMTI_HEAP_REFERENCE_CLASS => Self::Class,
+            sys::JVMTI_HEAP_REFERENCE_FIELD => Self::Field {
+                index: index(unsafe { (*info).field.index }),
+            },
+            sys::JVMTI_HEAP_REFERENCE_ARRAY_ELEMENT => Self::ArrayElement {
+                index: index(unsafe { (*info).array.index }),
+            },
+            sys::JVMTI_HEAP_REFERENCE_CLASS_LOADER => Self::ClassLoader,
+            sys::JVMTI_HEAP_REFERENCE_SIGNERS => Self::Signers,
+            sys::JVMTI_HEAP_REFERENCE_PROTECTION_DOMAIN => Self::ProtectionDomain,
+            sys::JVMTI_HEAP_REFERENCE_INTERFACE => Self::Interface,
+            sys::JVMTI_HEAP_REFERENCE_STATIC_FIELD => Self::StaticField {
+                index: index(unsafe { (*info).field.index }),
+            },
+            sys::JVMTI_HEAP_REFERENCE_CONSTANT_POOL => Self::ConstantPool {
+                index: index(unsafe { (*info).constant_pool.index }),
+            },
+            sys::JVMTI_HEAP_REFERENCE_SUPERCLASS => Self::Superclass,
+            sys::JVMTI_HEAP_REFERENCE_JNI_GLOBAL => Self::JniGlobal,
+            sys::JVMTI_HEAP_REFERENCE_SYSTEM_CLASS => Self::SystemClass,
+            sys::JVMTI_HEAP_REFERENCE_MONITOR => Self::Monitor,
+            sys::JVMTI_HEAP_REFERENCE_STACK_LOCAL => {
+                let local = unsafe { (*info).stack_local };
+                Self::StackLocal {
+                    thread_tag: local.thread_tag,
+                    thread_id: local.thread_id,
+                    depth: index(local.depth),
+                    method: local.method,
+                    location: local.location,
+                    slot: index(local.slot),
+                }
+            }
+            sys::JVMTI_HEAP_REFERENCE_JNI_LOCAL => {
+                let local = unsafe { (*info).jni_local };
+                Self::JniLocal {
+                    thread_tag: local.thread_tag,
+                    thread_id: local.thread_id,
+                    depth: index(local.depth),
+                    method: local.method,
+                }
+            }
+            sys::JVMTI_HEAP_REFERENCE_THREAD => Self::Thread,
+            _ => Self::Other,
+        }
+    }
+
+    /// Returns whether the reference is a root of the heap, which has no referrer.
+    #[must_use]
+    pub fn is_root(&self) -> bool {
+        matches!(
+            self,
+            Self::JniGlobal
+                | Self::SystemClass
+                | Self::Monitor
+                | Self::StackLocal { .. }
+                | Self::JniLocal { .. }
+                | Self::Thread
+                | Self::Other
+        )
+    }
+}
+
+/// An object reported by a heap traversal.
+#[derive(Debug)]
+pub struct HeapObject<'a> {
+    /// The tag of the class of the object, or `0` if it is not tagged.
+    pub class_tag: sys::jlong,
+    /// The size of the object in bytes.
+    pub size: u64,
+    /// The tag of the object, or `0` if it is not tagged. Setting it tags or untags the object.
+    pub tag: &'a mut sys::jlong,
+    /// The length of the object if it is an array.
+    pub length: Option<usize>,
+}
+
+/// The object holding a reference reported by a heap traversal.
+#[derive(Debug)]
+pub struct Referrer<'a> {
+    /// The tag of the class of the referrer, or `0` if it is not tagged.
+    pub class_tag: sys::jlong,
+    /// The tag of the referrer, which can be set like [`HeapObject::tag`]. It is `None` if the
+    /// object refers to itself, in which case the tag of the referee is the tag of the referrer.
+    pub tag: Option<&'a mut sys::jlong>,
+}
+
+/// Visits the references found by [`Jvm::follow_references`].
+pub trait HeapVisitor {
+    /// Visits the reference of `kind` from `referrer`, or from a root if `referrer` is `None`, to
+    /// `referee`. Returning [`VisitControl::VISIT_OBJECTS`] follows the references of `referee`,
+    /// while leaving it out prunes the subtree rooted at `referee` unless it is reached through
+    /// another reference; [`VisitControl::ABORT`] stops the traversal.
+    ///
+    /// The VM is stopped during the traversal, so this must return quickly and must not call JNI
+    /// or JVM TI functions.
+    fn visit_reference(
+        &mut self,
+        kind: HeapReference,
+        referrer: Option<Referrer<'_>>,
+        referee: HeapObject<'_>,
+    ) -> VisitControl;
+}
+
+impl<F> HeapVisitor for F
+where
+    F: FnMut(HeapReference, Option<Referrer<'_>>, HeapObject<'_>) -> VisitControl,
+{
+    fn visit_reference(
+        &mut self,
+        kind: HeapReference,
+        referrer: Option<Referrer<'_>>,
+        referee: HeapObject<'_>,
+    ) -> VisitControl {
+        self(kind, referrer, referee)
+    }
+}
+
+impl Jvm {
+    /// Traverses the objects reachable from `initial`, or from the heap roots if `initial` is
+    /// `None`, reporting each reference to `visitor`. Only the referees that pass `filter` and,
+    /// if `class` is `Some`, are instances of that class are reported, although the others are
+    /// still traversed. A panic in the visitor aborts the traversal and is resumed once the
+    /// traversal has returned.
+    /// See [`FollowReferences`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#FollowReferences).
+    /// # Errors
+    /// Returns [`HeapError::MissingCapabilities`] if the `can_tag_objects` capability cannot be
+    /// added. See [`HeapError`] for more information.
+    pub fn follow_references<V>(
+        &self,
+        filter: HeapFilter,
+        class: Option<&Class<'_>>,
+        initial: Option<&Object<'_>>,
+        visitor: &mut V,
+    ) -> Result<(), HeapError>
+    where
+        V: HeapVisitor + ?Sized,
+    {
+        #[allow(clippy::too_many_arguments)]
+        unsafe extern "C" fn visit_reference<V: HeapVisitor + ?Sized>(
+            reference_kind: sys::jvmtiHeapReferenceKind,
+            reference_info: *const sys::jvmtiHeapReferenceInfo,
+            class_tag: sys::jlong,
+            referrer_class_tag: sys::jlong,
+            size: sys::jlong,
+            tag_ptr: *mut sys::jlong,
+            referrer_tag_ptr: *mut sys::jlong,
+            length: sys::jint,
+            user_data: *mut c_void,
+        ) -> sys::jint {
+            // SAFETY: `user_data` is the `visitor` passed to `FollowReferences` below.
+            let visitor = unsafe { &mut *user_data.cast::<Visitor<&mut V>>() };
+            // SAFETY: The VM passes details matching the kind of the reference.
+            let kind = unsafe { HeapReference::from_raw(reference_kind, reference_info) };
+            // SAFETY: The tag pointers are valid for the duration of the call. The referrer tag
+            // pointer is null for roots and equal to `tag_ptr` for self references, which are not
+            // handed out twice.
+            let referrer = (!referrer_tag_ptr.is_null()).then(|| Referrer {
+                class_tag: referrer_class_tag,
+                tag: (referrer_tag_ptr != tag_ptr).then(|| unsafe { &mut *referrer_tag_ptr }),
+            });
+            let referee = HeapObject {
+                class_tag,
+                size: size.cast_unsigned(),
+                tag: unsafe { &mut *tag_ptr },
+                length: usize::try_from(length).ok(),
+            };
+            visitor.visit(|it| it.visit_reference(kind, referrer, referee))
+        }
+
+        self.acquire_capabilities(JvmtiCapabilities::CAN_TAG_OBJECTS)
+            .map_err(HeapError::MissingCapabilities)?;
+        // SAFETY: All the fields are optional function pointers, for which all zeros is `None`.
+        let mut callbacks: sys::jvmtiHeapCallbacks = unsafe { std::mem::zeroed() };
+        callbacks.heap_reference_callback = Some(visit_reference::<V>);
+        let mut visitor = Visitor::new(visitor);
+        // SAFETY: `callbacks` and `visitor` outlive the traversal, and `class` and `initial` are
+        // either null or valid references.
+        let result = unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                FollowReferences,
+                filter.bits().cast_signed(),
+                class.map_or(null_mut(), |it| it.jclass),
+                initial.map_or(null_mut(), |it| it.jobject),
+                std::ptr::from_ref(&callbacks),
+                std::ptr::addr_of_mut!(visitor).cast()
+            )
+        };
+        visitor.finish();
+        result?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::{from_ref, null, null_mut};
+
+    use super::*;
+
+    #[test]
+    fn decodes_references_with_details() {
+        // SAFETY: The details are plain integers and pointers, for which all zeros is valid.
+        let mut info: sys::jvmtiHeapReferenceInfo = unsafe { std::mem::zeroed() };
+        info.array.index = 7;
+        // SAFETY: `info` holds the details of an array element reference.
+        let reference = unsafe {
+            HeapReference::from_raw(sys::JVMTI_HEAP_REFERENCE_ARRAY_ELEMENT, from_ref(&info))
+        };
+        assert_eq!(reference, HeapReference::ArrayElement { index: 7 });
+
+        info.stack_local.thread_tag = 0;
+        info.stack_local.thread_id = 3;
+        info.stack_local.depth = 2;
+        info.stack_local.method = null_mut();
+        info.stack_local.location = 14;
+        info.stack_local.slot = -1;
+        // SAFETY: `info` holds the details of a stack local reference.
+        let reference = unsafe {
+            HeapReference::from_raw(sys::JVMTI_HEAP_REFERENCE_STACK_LOCAL, from_ref(&info))
+        };
+        assert_eq!(
+            reference,
+            HeapReference::StackLocal {
+                thread_tag: 0,
+                thread_id: 3,
+                depth: 2,
+                method: null_mut(),
+                location: 14,
+                // A negative index is read as zero rather than wrapped.
+                slot: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_references_without_details() {
+        // SAFETY: These kinds have no details.
+        unsafe {
+            assert_eq!(
+                HeapReference::from_raw(sys::JVMTI_HEAP_REFERENCE_SUPERCLASS, null()),
+                HeapReference::Superclass
+            );
+            assert_eq!(HeapReference::from_raw(99, null()), HeapReference::Other);
+        }
+    }
+}
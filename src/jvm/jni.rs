@@ -1,4 +1,21 @@
-use crate::sys;
+#[cfg(feature = "event-stream")]
+use std::cell::RefCell;
+use std::{ffi::c_void, marker::PhantomData, mem::MaybeUninit};
+
+use crate::{macros::call_jni, sys};
+
+use super::{class::Class, objects::Object, strings::encode_modified_utf8, threads::Thread, Jvm};
+
+/// An error returned by a JNI function.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JNIError {
+    /// The JVM ran out of memory. A `java.lang.OutOfMemoryError` is pending.
+    #[error("The JVM is out of memory")]
+    OutOfMemory,
+    /// The function failed with a Java exception, which is pending.
+    #[error("A Java exception is pending")]
+    PendingException,
+}
 
 #[derive(Debug)]
 pub struct JNI {
@@ -10,4 +27,455 @@ impl JNI {
         assert!(!jni_ptr.is_null(), "The JNI pointer is null");
         Self { jni_ptr }
     }
+
+    /// Gets the raw JNI environment pointer.
+    #[cfg(feature = "dap")]
+    pub(crate) fn jni_ptr(&self) -> *mut sys::JNIEnv {
+        self.jni_ptr
+    }
+
+    /// Creates a new local reference frame that can hold at least `capacity` local references.
+    /// See [`PushLocalFrame`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#pushlocalframe).
+    pub(crate) fn push_local_frame(&self, capacity: sys::jint) -> Result<(), JNIError> {
+        // SAFETY: `self.jni_ptr` is a valid `JNIEnv` of the current thread.
+        let result = unsafe { call_jni!(self.jni_ptr, PushLocalFrame, capacity) };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(JNIError::OutOfMemory)
+        }
+    }
+
+    /// Pops the current local reference frame, freeing all the local references created in it.
+    /// See [`PopLocalFrame`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#poplocalframe).
+    /// # Safety
+    /// There must be a frame pushed by [`JNI::push_local_frame`] that has not been popped yet.
+    pub(crate) unsafe fn pop_local_frame(&self) {
+        call_jni!(self.jni_ptr, PopLocalFrame, std::ptr::null_mut());
+    }
+
+    /// Calls `f` inside a new local reference frame that can hold at least `capacity` local
+    /// references, and frees all the local references created in it when `f` returns or panics,
+    /// e.g. to process thousands of loaded classes without overflowing the local reference table.
+    ///
+    /// `f` receives a reborrow of `jvm` whose lifetime is limited to the frame. Handles created
+    /// through it are branded with that lifetime, so they cannot be returned from `f`, as their
+    /// references are freed with the frame. Return plain data or a [`GlobalRef`] instead. Handles
+    /// must therefore not be created through `jvm` captured from outside of `f`.
+    /// See [`PushLocalFrame`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#pushlocalframe)
+    /// and [`PopLocalFrame`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#poplocalframe).
+    /// # Errors
+    /// Returns [`JNIError::OutOfMemory`] if the frame cannot be created, in which case `f` is not
+    /// called.
+    pub fn with_local_frame<R>(
+        &self,
+        jvm: &Jvm,
+        capacity: usize,
+        f: impl for<'f> FnOnce(&'f Jvm, &'f JNI) -> R,
+    ) -> Result<R, JNIError> {
+        /// Pops the frame even if `f` panics.
+        struct Frame<'a>(&'a JNI);
+
+        impl Drop for Frame<'_> {
+            fn drop(&mut self) {
+                // SAFETY: The frame was pushed by `with_local_frame` and has not been popped yet.
+                unsafe { self.0.pop_local_frame() };
+            }
+        }
+
+        self.push_local_frame(sys::jint::try_from(capacity).unwrap_or(sys::jint::MAX))?;
+        let frame = Frame(self);
+        Ok(f(jvm, frame.0))
+    }
+
+    /// Creates a new local reference to the object referred to by `reference`.
+    /// See [`NewLocalRef`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#newlocalref).
+    /// # Safety
+    /// `reference` must be a valid reference.
+    pub(crate) unsafe fn new_local_ref(&self, reference: sys::jobject) -> sys::jobject {
+        call_jni!(self.jni_ptr, NewLocalRef, reference)
+    }
+
+    /// Checks whether `a` and `b` refer to the same Java object.
+    /// See [`IsSameObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#issameobject).
+    /// # Safety
+    /// `a` and `b` must be valid references or null.
+    pub(crate) unsafe fn is_same_object(&self, a: sys::jobject, b: sys::jobject) -> bool {
+        call_jni!(self.jni_ptr, IsSameObject, a, b) != 0
+    }
+
+    /// Gets the class of the object referred to by `reference` as a new local reference.
+    /// See [`GetObjectClass`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#getobjectclass).
+    /// # Safety
+    /// `reference` must be a valid, non-null reference.
+    pub(crate) unsafe fn get_object_class(&self, reference: sys::jobject) -> sys::jclass {
+        call_jni!(self.jni_ptr, GetObjectClass, reference)
+    }
+
+    /// Gets the superclass of `class` as a new local reference, or null if `class` is an interface,
+    /// a primitive class, or `java.lang.Object`.
+    /// See [`GetSuperclass`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#getsuperclass).
+    /// # Safety
+    /// `class` must be a valid, non-null reference to a class.
+    pub(crate) unsafe fn get_superclass(&self, class: sys::jclass) -> sys::jclass {
+        call_jni!(self.jni_ptr, GetSuperclass, class)
+    }
+
+    /// Creates an unstarted `java.lang.Thread` named `name`, e.g. to run an agent thread.
+    /// See [`NewObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#newobject).
+    pub(crate) fn new_thread(&self, name: &str) -> Result<sys::jthread, JNIError> {
+        let name = encode_modified_utf8(name);
+        // SAFETY: `self.jni_ptr` is a valid `JNIEnv` of the current thread, and all the strings are
+        // null-terminated.
+        unsafe {
+            let class = call_jni!(self.jni_ptr, FindClass, c"java/lang/Thread".as_ptr());
+            if class.is_null() {
+                return Err(JNIError::PendingException);
+            }
+            let constructor = call_jni!(
+                self.jni_ptr,
+                GetMethodID,
+                class,
+                c"<init>".as_ptr(),
+                c"(Ljava/lang/String;)V".as_ptr()
+            );
+            let name = call_jni!(self.jni_ptr, NewStringUTF, name.as_ptr());
+            let thread = if constructor.is_null() || name.is_null() {
+                std::ptr::null_mut()
+            } else {
+                let arguments = [sys::jvalue { l: name }];
+                call_jni!(
+                    self.jni_ptr,
+                    NewObjectA,
+                    class,
+                    constructor,
+                    arguments.as_ptr()
+                )
+            };
+            self.delete_local_ref(name);
+            self.delete_local_ref(class);
+            if thread.is_null() {
+                Err(JNIError::PendingException)
+            } else {
+                Ok(thread)
+            }
+        }
+    }
+
+    /// Deletes the local reference `reference`.
+    /// See [`DeleteLocalRef`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#deletelocalref).
+    /// # Safety
+    /// `reference` must be a valid local reference that is not used afterwards.
+    pub(crate) unsafe fn delete_local_ref(&self, reference: sys::jobject) {
+        call_jni!(self.jni_ptr, DeleteLocalRef, reference);
+    }
+}
+
+/// A kind of Java object that a [`GlobalRef`] or [`WeakGlobalRef`] can refer to, implemented by
+/// [`Object`], [`Class`], and [`Thread`] with the `'static` lifetime, e.g. `GlobalRef<Class<'static>>`.
+pub trait Reference: private::Sealed {
+    /// The handle of the object borrowing the VM for `'j`.
+    type Handle<'j>;
+
+    /// Gets the raw reference of `handle`.
+    fn raw(handle: &Self::Handle<'_>) -> sys::jobject;
+
+    /// Creates a handle from a raw reference.
+    /// # Safety
+    /// `reference` must be a valid, non-null reference to an object of this kind.
+    unsafe fn handle(jvm: &Jvm, reference: sys::jobject) -> Self::Handle<'_>;
+}
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for super::Object<'static> {}
+    impl Sealed for super::Class<'static> {}
+    impl Sealed for super::Thread<'static> {}
+}
+
+impl Reference for Object<'static> {
+    type Handle<'j> = Object<'j>;
+
+    fn raw(handle: &Object<'_>) -> sys::jobject {
+        handle.jobject
+    }
+
+    unsafe fn handle(jvm: &Jvm, reference: sys::jobject) -> Object<'_> {
+        Object::from_ptr(jvm, reference)
+    }
+}
+
+impl Reference for Class<'static> {
+    type Handle<'j> = Class<'j>;
+
+    fn raw(handle: &Class<'_>) -> sys::jobject {
+        handle.jclass
+    }
+
+    unsafe fn handle(jvm: &Jvm, reference: sys::jobject) -> Class<'_> {
+        Class::from_ptr(jvm, reference)
+    }
+}
+
+impl Reference for Thread<'static> {
+    type Handle<'j> = Thread<'j>;
+
+    fn raw(handle: &Thread<'_>) -> sys::jobject {
+        handle.jthread
+    }
+
+    unsafe fn handle(jvm: &Jvm, reference: sys::jobject) -> Thread<'_> {
+        Thread::from_ptr(jvm, reference)
+    }
+}
+
+/// A JNI global reference, which stays valid across callbacks and threads until it is dropped,
+/// unlike the handles given to callbacks, which are only valid during the callback.
+/// See [`NewGlobalRef`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#newglobalref).
+pub struct GlobalRef<T: Reference = Object<'static>> {
+    vm: *mut sys::JavaVM,
+    reference: sys::jobject,
+    kind: PhantomData<fn() -> T>,
+}
+
+// SAFETY: A global reference can be used and deleted on any thread attached to the VM, and the
+// VM pointer is valid for the whole life of the VM.
+unsafe impl<T: Reference> Send for GlobalRef<T> {}
+// SAFETY: See above.
+unsafe impl<T: Reference> Sync for GlobalRef<T> {}
+
+impl<T: Reference> std::fmt::Debug for GlobalRef<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GlobalRef")
+            .field("reference", &self.reference)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Reference> GlobalRef<T> {
+    /// Creates a global reference to the object of `handle`, e.g.
+    /// `GlobalRef::<Class<'static>>::new(jni, &class)`.
+    /// # Errors
+    /// Returns [`JNIError::OutOfMemory`] if the VM runs out of memory.
+    pub fn new(jni: &JNI, handle: &T::Handle<'_>) -> Result<Self, JNIError> {
+        // SAFETY: The handle holds a valid, non-null reference of the kind of `T`.
+        unsafe { Self::from_raw(jni, T::raw(handle)) }.ok_or(JNIError::OutOfMemory)
+    }
+
+    /// Creates a global reference to the object referred to by `reference`, or returns `None` if
+    /// the VM runs out of memory.
+    /// # Safety
+    /// `reference` must be a valid, non-null reference to an object of the kind of `T`.
+    pub(crate) unsafe fn from_raw(jni: &JNI, reference: sys::jobject) -> Option<Self> {
+        let vm = java_vm(jni)?;
+        let reference = call_jni!(jni.jni_ptr, NewGlobalRef, reference);
+        (!reference.is_null()).then_some(Self {
+            vm,
+            reference,
+            kind: PhantomData,
+        })
+    }
+
+    /// Gets a handle to the referred object, which can be used while `self` is alive.
+    #[must_use]
+    pub fn get<'a>(&'a self, jvm: &'a Jvm) -> T::Handle<'a> {
+        // SAFETY: The reference is valid as long as `self` is alive.
+        unsafe { T::handle(jvm, self.reference) }
+    }
+
+    /// Gets the referred object as an [`Object`].
+    #[must_use]
+    pub fn object<'a>(&'a self, jvm: &'a Jvm) -> Object<'a> {
+        // SAFETY: The reference is valid as long as `self` is alive.
+        unsafe { Object::from_ptr(jvm, self.reference) }
+    }
+
+    /// Creates a weak global reference to the referred object.
+    /// # Errors
+    /// Returns [`JNIError::OutOfMemory`] if the VM runs out of memory.
+    pub fn downgrade(&self, jni: &JNI) -> Result<WeakGlobalRef<T>, JNIError> {
+        // SAFETY: The reference is valid as long as `self` is alive.
+        unsafe { WeakGlobalRef::from_raw(jni, self.reference) }.ok_or(JNIError::OutOfMemory)
+    }
+}
+
+impl<T: Reference> Drop for GlobalRef<T> {
+    fn drop(&mut self) {
+        // SAFETY: `self.reference` is a global reference that is not used afterwards.
+        with_env(self.vm, |env| unsafe {
+            call_jni!(env, DeleteGlobalRef, self.reference);
+        });
+    }
+}
+
+/// A JNI weak global reference, which does not keep the referred object alive. It has to be
+/// upgraded to a [`GlobalRef`] to use the object, which fails once the object has been collected.
+/// See [`NewWeakGlobalRef`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#newweakglobalref).
+pub struct WeakGlobalRef<T: Reference = Object<'static>> {
+    vm: *mut sys::JavaVM,
+    reference: sys::jweak,
+    kind: PhantomData<fn() -> T>,
+}
+
+// SAFETY: A weak global reference can be used and deleted on any thread attached to the VM, and
+// the VM pointer is valid for the whole life of the VM.
+unsafe impl<T: Reference> Send for WeakGlobalRef<T> {}
+// SAFETY: See above.
+unsafe impl<T: Reference> Sync for WeakGlobalRef<T> {}
+
+impl<T: Reference> std::fmt::Debug for WeakGlobalRef<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WeakGlobalRef")
+            .field("reference", &self.reference)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Reference> WeakGlobalRef<T> {
+    /// Creates a weak global reference to the object of `handle`.
+    /// # Errors
+    /// Returns [`JNIError::OutOfMemory`] if the VM runs out of memory.
+    pub fn new(jni: &JNI, handle: &T::Handle<'_>) -> Result<Self, JNIError> {
+        // SAFETY: The handle holds a valid, non-null reference of the kind of `T`.
+        unsafe { Self::from_raw(jni, T::raw(handle)) }.ok_or(JNIError::OutOfMemory)
+    }
+
+    /// Creates a weak global reference to the object referred to by `reference`, or returns
+    /// `None` if the VM runs out of memory.
+    /// # Safety
+    /// `reference` must be a valid, non-null reference to an object of the kind of `T`.
+    unsafe fn from_raw(jni: &JNI, reference: sys::jobject) -> Option<Self> {
+        let vm = java_vm(jni)?;
+        let reference = call_jni!(jni.jni_ptr, NewWeakGlobalRef, reference);
+        (!reference.is_null()).then_some(Self {
+            vm,
+            reference,
+            kind: PhantomData,
+        })
+    }
+
+    /// Creates a global reference to the referred object, or returns `None` if the object has
+    /// been garbage collected.
+    /// See [`NewGlobalRef`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#newglobalref).
+    #[must_use]
+    pub fn upgrade(&self, jni: &JNI) -> Option<GlobalRef<T>> {
+        // SAFETY: A weak global reference is a valid argument of `NewGlobalRef`, which returns
+        // null if the object has been collected.
+        unsafe { GlobalRef::from_raw(jni, self.reference) }
+    }
+
+    /// Returns whether `handle` refers to the referred object, which is `false` once the object
+    /// has been garbage collected.
+    /// See [`IsSameObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#issameobject).
+    #[must_use]
+    pub fn refers_to(&self, jni: &JNI, handle: &T::Handle<'_>) -> bool {
+        // SAFETY: `self.reference` is a valid weak global reference and the handle holds a valid,
+        // non-null reference.
+        unsafe { jni.is_same_object(self.reference, T::raw(handle)) }
+    }
+
+    /// Returns whether the referred object has been garbage collected.
+    /// See [`IsSameObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#issameobject).
+    #[must_use]
+    pub fn is_collected(&self, jni: &JNI) -> bool {
+        // SAFETY: `self.reference` is a valid weak global reference.
+        unsafe { jni.is_same_object(self.reference, std::ptr::null_mut()) }
+    }
+}
+
+impl<T: Reference> Drop for WeakGlobalRef<T> {
+    fn drop(&mut self) {
+        // SAFETY: `self.reference` is a weak global reference that is not used afterwards.
+        with_env(self.vm, |env| unsafe {
+            call_jni!(env, DeleteWeakGlobalRef, self.reference);
+        });
+    }
+}
+
+/// Gets the VM of `jni`.
+fn java_vm(jni: &JNI) -> Option<*mut sys::JavaVM> {
+    let mut vm = MaybeUninit::uninit();
+    // SAFETY: `jni.jni_ptr` is a valid `JNIEnv` of the current thread.
+    let result = unsafe { call_jni!(jni.jni_ptr, GetJavaVM, vm.as_mut_ptr()) };
+    // SAFETY: A successful result indicates that `vm` has been initialized.
+    (result == sys::JNI_OK.cast_signed()).then(|| unsafe { vm.assume_init() })
+}
+
+#[cfg(feature = "event-stream")]
+thread_local! {
+    /// The VM that [`keep_attached`] has attached the current thread to.
+    static KEPT_ATTACHED: RefCell<Option<KeptAttached>> = const { RefCell::new(None) };
+}
+
+#[cfg(feature = "event-stream")]
+/// Detaches the current thread from the VM when dropped, i.e. when the thread exits.
+struct KeptAttached(*mut sys::JavaVM);
+
+#[cfg(feature = "event-stream")]
+impl Drop for KeptAttached {
+    fn drop(&mut self) {
+        // SAFETY: The current thread has been attached to the VM by `keep_attached`.
+        unsafe { call_jni!(self.0, DetachCurrentThread) };
+    }
+}
+
+#[cfg(feature = "event-stream")]
+/// Attaches the current thread to `vm` as a daemon thread until the thread exits, unless it is
+/// attached already. References dropped on the thread afterwards are deleted without attaching
+/// and detaching it each time, see [`with_env`]. Returns whether the thread is attached.
+pub(crate) fn keep_attached(vm: *mut sys::JavaVM) -> bool {
+    let mut env: MaybeUninit<*mut sys::JNIEnv> = MaybeUninit::uninit();
+    // SAFETY: `vm` is valid for the whole life of the VM.
+    unsafe {
+        let env_ptr = env.as_mut_ptr().cast::<*mut c_void>();
+        match call_jni!(vm, GetEnv, env_ptr, sys::JNI_VERSION_1_2.cast_signed()) {
+            it if it == sys::JNI_OK.cast_signed() => true,
+            sys::JNI_EDETACHED => {
+                if call_jni!(
+                    vm,
+                    AttachCurrentThreadAsDaemon,
+                    env_ptr,
+                    std::ptr::null_mut()
+                ) != sys::JNI_OK.cast_signed()
+                {
+                    return false;
+                }
+                let attached = KeptAttached(vm);
+                // The thread is being torn down if its storage is gone, so it is detached right
+                // away by dropping `attached`.
+                KEPT_ATTACHED
+                    .try_with(|it| *it.borrow_mut() = Some(attached))
+                    .is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Calls `f` with the JNI environment of the current thread, e.g. to delete a global reference
+/// when it is dropped. A thread that is not attached, e.g. a consumer thread of the agent, is
+/// attached as a daemon thread for the call and detached again afterwards, so that dropping a
+/// reference does not leave the thread attached. Nothing is called if the VM is gone.
+fn with_env(vm: *mut sys::JavaVM, f: impl FnOnce(*mut sys::JNIEnv)) {
+    let mut env: MaybeUninit<*mut sys::JNIEnv> = MaybeUninit::uninit();
+    // SAFETY: `vm` is valid for the whole life of the VM.
+    unsafe {
+        let env_ptr = env.as_mut_ptr().cast::<*mut c_void>();
+        match call_jni!(vm, GetEnv, env_ptr, sys::JNI_VERSION_1_2.cast_signed()) {
+            it if it == sys::JNI_OK.cast_signed() => f(env.assume_init()),
+            sys::JNI_EDETACHED => {
+                let attached = call_jni!(
+                    vm,
+                    AttachCurrentThreadAsDaemon,
+                    env_ptr,
+                    std::ptr::null_mut()
+                ) == sys::JNI_OK.cast_signed();
+                if attached {
+                    f(env.assume_init());
+                    call_jni!(vm, DetachCurrentThread);
+                }
+            }
+            _ => {}
+        }
+    }
 }
@@ -0,0 +1,350 @@
+//! APIs for working with Java methods.
+
+use std::{ffi::OsString, mem::MaybeUninit, os::unix::prelude::OsStrExt, ptr::null_mut};
+
+use crate::{macros::call_jvmti, sys};
+
+use super::{
+    capabilities::JvmtiCapabilities,
+    class::Class,
+    errors::{BreakpointError, MethodError},
+    strings::{ModifiedUtf8Ext, StringError, Utf8Policy},
+    values::JType,
+    Jvm,
+};
+
+/// An entry of the local variable table of a method.
+/// See [`Method::local_variable_table`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalVariable {
+    /// The first location at which the variable is live.
+    pub start_location: sys::jlocation,
+    /// The number of locations, starting at `start_location`, at which the variable is live.
+    pub length: usize,
+    /// The name of the variable.
+    pub name: OsString,
+    /// The type descriptor of the variable, e.g. `I` or `Ljava/lang/String;`.
+    pub signature: OsString,
+    /// The generic signature of the variable, if any.
+    pub generic_signature: Option<OsString>,
+    /// The slot of the variable in the frame.
+    pub slot: usize,
+}
+
+impl LocalVariable {
+    /// Returns whether the variable is live at `location`.
+    #[must_use]
+    pub fn is_live_at(&self, location: sys::jlocation) -> bool {
+        let length = sys::jlocation::try_from(self.length).unwrap_or(sys::jlocation::MAX);
+        location >= self.start_location && location - self.start_location < length
+    }
+
+    /// Gets the type of the variable.
+    #[must_use]
+    pub fn ty(&self) -> Option<JType> {
+        JType::from_descriptor(self.signature.as_bytes())
+    }
+}
+
+/// An entry of the line number table of a method.
+/// See [`Method::line_number_table`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineNumberEntry {
+    /// The first location of the line.
+    pub start_location: sys::jlocation,
+    /// The line number in the source file.
+    pub line_number: u32,
+}
+
+/// A Java method.
+#[derive(Debug)]
+pub struct Method<'j> {
+    jvm: &'j Jvm,
+    jmethod_id: sys::jmethodID,
+}
+
+impl<'j> Method<'j> {
+    pub(crate) unsafe fn from_ptr(jvm: &'j Jvm, jmethod_id: sys::jmethodID) -> Method<'j> {
+        assert!(!jmethod_id.is_null(), "The method pointer must not be null");
+        Method { jvm, jmethod_id }
+    }
+
+    /// Creates a [`Method`] from a raw `jmethodID`, e.g. one obtained from other JNI code.
+    /// # Safety
+    /// `jmethod_id` must be a valid `jmethodID` whose class stays loaded while the returned handle
+    /// is used.
+    /// # Panics
+    /// Panics if `jmethod_id` is null.
+    pub unsafe fn from_raw(jvm: &'j Jvm, jmethod_id: sys::jmethodID) -> Method<'j> {
+        Self::from_ptr(jvm, jmethod_id)
+    }
+
+    /// Gets the raw `jmethodID`, which identifies the method as long as its class is loaded.
+    #[must_use]
+    pub fn as_raw(&self) -> sys::jmethodID {
+        self.jmethod_id
+    }
+
+    /// Gives up the handle and returns the raw `jmethodID`.
+    #[must_use]
+    pub fn into_raw(self) -> sys::jmethodID {
+        self.jmethod_id
+    }
+
+    /// Gets the name of the method.
+    /// See [`GetMethodName`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetMethodName).
+    /// # Errors
+    /// See [`MethodError`] for more information.
+    pub fn name(&self) -> Result<OsString, MethodError> {
+        let mut name_ptr = MaybeUninit::uninit();
+        // SAFETY: `self.jmethod_id` is a valid `jmethodID` and the signatures are not requested.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetMethodName,
+                self.jmethod_id,
+                name_ptr.as_mut_ptr(),
+                null_mut(),
+                null_mut()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `name_ptr` points to a JVM TI allocated string.
+        let name = unsafe { self.jvm.take_string(name_ptr.assume_init()) }?;
+        Ok(name)
+    }
+
+    /// Gets the JNI descriptor of the method, e.g. `(Ljava/lang/String;)V`.
+    /// See [`GetMethodName`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetMethodName).
+    /// # Errors
+    /// See [`MethodError`] for more information.
+    pub fn signature(&self) -> Result<OsString, MethodError> {
+        let mut signature_ptr = MaybeUninit::uninit();
+        // SAFETY: `self.jmethod_id` is a valid `jmethodID` and the name is not requested.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetMethodName,
+                self.jmethod_id,
+                null_mut(),
+                signature_ptr.as_mut_ptr(),
+                null_mut()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `signature_ptr` points to a JVM TI allocated string.
+        let signature = unsafe { self.jvm.take_string(signature_ptr.assume_init()) }?;
+        Ok(signature)
+    }
+
+    /// Gets the name of the method decoded according to `policy`.
+    /// See [`Method::name`] for the byte-level form.
+    /// # Errors
+    /// See [`StringError`] for more information.
+    pub fn name_utf8(&self, policy: Utf8Policy) -> Result<String, StringError<MethodError>> {
+        let name = self.name()?;
+        name.to_utf8(policy)
+            .map(Into::into)
+            .map_err(StringError::Utf8)
+    }
+
+    /// Gets the class that declares the method.
+    /// See [`GetMethodDeclaringClass`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetMethodDeclaringClass).
+    /// # Errors
+    /// See [`MethodError`] for more information.
+    pub fn declaring_class(&self) -> Result<Class<'j>, MethodError> {
+        let mut class_ptr = MaybeUninit::uninit();
+        // SAFETY: `self.jmethod_id` is a valid `jmethodID`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetMethodDeclaringClass,
+                self.jmethod_id,
+                class_ptr.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `class_ptr` has been initialized.
+        Ok(unsafe { Class::from_local(self.jvm, class_ptr.assume_init()) })
+    }
+
+    /// Gets the local variables of the method from its local variable table.
+    /// See [`GetLocalVariableTable`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetLocalVariableTable).
+    /// # Errors
+    /// Returns [`MethodError::AbsentInformation`] if the class was compiled without debug
+    /// information. See [`MethodError`] for more information. The `can_access_local_variables`
+    /// capability is required.
+    pub fn local_variable_table(&self) -> Result<Vec<LocalVariable>, MethodError> {
+        let mut count = MaybeUninit::uninit();
+        let mut entries = MaybeUninit::uninit();
+        // SAFETY: `self.jmethod_id` is a valid `jmethodID`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetLocalVariableTable,
+                self.jmethod_id,
+                count.as_mut_ptr(),
+                entries.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `entries` points to `count` entries whose
+        // strings are allocated separately.
+        unsafe {
+            let entries = self
+                .jvm
+                .take_array(entries.assume_init(), count.assume_init())?;
+            let mut variables = Vec::with_capacity(entries.len());
+            for entry in entries {
+                variables.push(LocalVariable {
+                    start_location: entry.start_location,
+                    length: usize::try_from(entry.length).unwrap_or_default(),
+                    name: self.jvm.take_string(entry.name)?,
+                    signature: self.jvm.take_string(entry.signature)?,
+                    generic_signature: if entry.generic_signature.is_null() {
+                        None
+                    } else {
+                        Some(self.jvm.take_string(entry.generic_signature)?)
+                    },
+                    slot: usize::try_from(entry.slot).unwrap_or_default(),
+                });
+            }
+            Ok(variables)
+        }
+    }
+
+    /// Gets the line number table of the method, which maps locations to source lines.
+    /// See [`GetLineNumberTable`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetLineNumberTable).
+    /// # Errors
+    /// Returns [`MethodError::AbsentInformation`] if the class was compiled without line numbers,
+    /// and [`MethodError::NativeMethod`] for native methods. See [`MethodError`] for more
+    /// information. The `can_get_line_numbers` capability is required.
+    pub fn line_number_table(&self) -> Result<Vec<LineNumberEntry>, MethodError> {
+        let mut count = MaybeUninit::uninit();
+        let mut entries = MaybeUninit::uninit();
+        // SAFETY: `self.jmethod_id` is a valid `jmethodID`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetLineNumberTable,
+                self.jmethod_id,
+                count.as_mut_ptr(),
+                entries.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `entries` points to `count` entries.
+        let entries = unsafe {
+            self.jvm
+                .take_array(entries.assume_init(), count.assume_init())
+        }?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| LineNumberEntry {
+                start_location: entry.start_location,
+                line_number: entry.line_number.cast_unsigned(),
+            })
+            .collect())
+    }
+
+    /// Gets the source line containing `location`, i.e. the last line of the line number table
+    /// starting at or before it, or `None` if the table has no such line.
+    /// See [`Method::line_number_table`].
+    /// # Errors
+    /// See [`Method::line_number_table`] for more information.
+    pub fn line_number_at(&self, location: sys::jlocation) -> Result<Option<u32>, MethodError> {
+        Ok(self
+            .line_number_table()?
+            .into_iter()
+            .filter(|it| it.start_location <= location)
+            .max_by_key(|it| it.start_location)
+            .map(|it| it.line_number))
+    }
+
+    /// Sets a breakpoint at `location` in the method and returns a handle that clears it when
+    /// dropped. Hits are reported through the `Breakpoint` event.
+    /// See [`SetBreakpoint`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SetBreakpoint).
+    /// # Errors
+    /// Returns [`BreakpointError::InvalidLocation`] if `location` is not a valid location in the
+    /// method and [`BreakpointError::Duplicate`] if there already is a breakpoint at `location`.
+    /// See [`BreakpointError`] for more information.
+    pub fn breakpoint(&self, location: sys::jlocation) -> Result<Breakpoint<'j>, BreakpointError> {
+        self.set_breakpoint(location)?;
+        Ok(Breakpoint {
+            jvm: self.jvm,
+            jmethod_id: self.jmethod_id,
+            location,
+        })
+    }
+
+    /// Sets a breakpoint at `location` in the method. The breakpoint stays set until it is cleared
+    /// with [`Method::clear_breakpoint`]; see [`Method::breakpoint`] for a handle that clears it
+    /// automatically.
+    /// See [`SetBreakpoint`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SetBreakpoint).
+    /// # Errors
+    /// See [`BreakpointError`] for more information.
+    pub fn set_breakpoint(&self, location: sys::jlocation) -> Result<(), BreakpointError> {
+        self.jvm
+            .acquire_capabilities(JvmtiCapabilities::CAN_GENERATE_BREAKPOINT_EVENTS)
+            .map_err(BreakpointError::MissingCapabilities)?;
+        // SAFETY: `self.jmethod_id` is a valid `jmethodID`.
+        unsafe { call_jvmti!(self.jvm.jvmti_ptr, SetBreakpoint, self.jmethod_id, location) }?;
+        Ok(())
+    }
+
+    /// Clears the breakpoint at `location` in the method.
+    /// See [`ClearBreakpoint`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#ClearBreakpoint).
+    /// # Errors
+    /// See [`BreakpointError`] for more information.
+    pub fn clear_breakpoint(&self, location: sys::jlocation) -> Result<(), BreakpointError> {
+        // SAFETY: `self.jmethod_id` is a valid `jmethodID`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                ClearBreakpoint,
+                self.jmethod_id,
+                location
+            )
+        }?;
+        Ok(())
+    }
+}
+
+/// A breakpoint set with [`Method::breakpoint`], which is cleared when the handle is dropped.
+#[derive(Debug)]
+#[must_use = "the breakpoint is cleared when the handle is dropped"]
+pub struct Breakpoint<'j> {
+    jvm: &'j Jvm,
+    jmethod_id: sys::jmethodID,
+    location: sys::jlocation,
+}
+
+impl<'j> Breakpoint<'j> {
+    /// Gets the method containing the breakpoint.
+    #[must_use]
+    pub fn method(&self) -> Method<'j> {
+        Method {
+            jvm: self.jvm,
+            jmethod_id: self.jmethod_id,
+        }
+    }
+
+    /// Gets the location of the breakpoint in the method.
+    #[must_use]
+    pub fn location(&self) -> sys::jlocation {
+        self.location
+    }
+
+    /// Clears the breakpoint, reporting the errors that dropping the handle would ignore.
+    /// # Errors
+    /// Returns [`BreakpointError::NotFound`] if the breakpoint was already cleared by other means.
+    /// See [`BreakpointError`] for more information.
+    pub fn clear(self) -> Result<(), BreakpointError> {
+        let result = self.method().clear_breakpoint(self.location);
+        std::mem::forget(self);
+        result
+    }
+}
+
+impl Drop for Breakpoint<'_> {
+    fn drop(&mut self) {
+        // The class may have been unloaded or the breakpoint cleared by other means, in which case
+        // there is nothing left to clear.
+        let _ = self.method().clear_breakpoint(self.location);
+    }
+}
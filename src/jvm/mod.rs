@@ -1,30 +1,94 @@
 //! APIs for interacting with the JVM Tool Interface (JVM TI).
 use std::{
+    ffi::{c_char, CStr, OsStr, OsString},
     fmt::Debug,
-    mem::{size_of, MaybeUninit},
+    mem::MaybeUninit,
+    os::unix::prelude::OsStrExt,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicPtr, AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
 };
 
+pub mod capabilities;
+pub mod chunks;
 pub mod class;
+#[cfg(feature = "class-events")]
+pub mod class_filter;
 pub mod errors;
 pub mod events;
+pub mod extensions;
+pub mod fields;
 pub mod general;
+pub mod handler;
+pub mod heap;
 pub mod jni;
+pub mod methods;
 pub mod objects;
+pub mod presets;
+#[cfg(feature = "class-events")]
+mod scratch;
+pub mod stack;
+#[cfg(feature = "event-stream")]
+pub mod stream;
+pub mod strings;
+pub mod tags;
+pub mod thread_filter;
 pub mod threads;
+pub mod values;
 
-use crate::{macros::call_jvmti, sys};
+use crate::{
+    macros::{call_jni, call_jvmti},
+    sys,
+};
 
-use self::{errors::JvmTIError, general::JvmTIVersion};
+use self::{errors::JvmTIError, general::JvmTIVersion, jni::JNI};
 
 /// A raw JVM pointer.
 pub type JvmPointer = *mut sys::JavaVM;
 
+/// The addresses of the VMs for which a [`Jvm`] has been created by [`Jvm::from_jvm_ptr`], each
+/// with the [`Jvm`] once it has been published with [`Jvm::publish`].
+static ENVIRONMENTS: Mutex<Vec<(usize, Option<&'static Jvm>)>> = Mutex::new(Vec::new());
+
+/// The first [`Jvm`] published with [`Jvm::publish`], see [`Jvm::current`].
+static CURRENT: AtomicPtr<Jvm> = AtomicPtr::new(null_mut());
+
 /// An JVM Tool Interface (JVM TI) environment.
 pub struct Jvm {
     jvmti_ptr: *mut sys::jvmtiEnv,
+    /// The VM of the environment, which gives the JNI environment of the current thread.
+    vm_ptr: JvmPointer,
     callbacks: events::EventCallbacks,
+    /// The table of native callbacks last installed with `SetEventCallbacks`.
+    native_callbacks: sys::jvmtiEventCallbacks,
+    auto_enable_events: bool,
+    auto_capabilities: bool,
+    /// The bits of the capabilities known to be possessed since they were acquired, which spares
+    /// looking them up again, see [`Jvm::acquire_capabilities`].
+    acquired_capabilities: AtomicU64,
+    panic_policy: events::PanicPolicy,
+    extension_callbacks: extensions::ExtensionCallbacks,
+    /// The running threads started with [`Jvm::spawn_agent_thread`].
+    agent_threads: Mutex<Vec<jni::GlobalRef<threads::Thread<'static>>>>,
 }
 
+// SAFETY: A JVM TI environment and the `JavaVM` may be used from any thread, unlike a JNI
+// environment, which is looked up for the current thread whenever it is needed. All the other
+// state is only modified through `&mut Jvm` or is behind locks, as the assertion below checks for
+// the fields that are not raw pointers.
+unsafe impl Sync for Jvm {}
+
+const _: () = {
+    const fn assert_sync<T: Sync>() {}
+    assert_sync::<events::EventCallbacks>();
+    assert_sync::<sys::jvmtiEventCallbacks>();
+    assert_sync::<events::PanicPolicy>();
+    assert_sync::<extensions::ExtensionCallbacks>();
+    assert_sync::<Mutex<Vec<jni::GlobalRef<threads::Thread<'static>>>>>();
+};
+
 impl Debug for Jvm {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("Jvm@{:p}", self.jvmti_ptr))
@@ -43,20 +107,54 @@ pub enum JvmTICreationError {
     /// When the JVM is not attached.
     #[error("The VM is not attached")]
     Detached,
+    /// When a [`Jvm`] has already been created for the VM but not published yet.
+    #[error("The JVM TI environment is being initialized")]
+    AlreadyInitialized,
     /// When the JVM TI environment fails to initialize.
     #[error("Fail to initialize the JVM TI environment: {0}")]
     JvmTIInitialization(#[from] JvmTIError),
 }
 
+/// The [`Jvm`] of a VM returned by [`Jvm::from_jvm_ptr`].
+#[derive(Debug)]
+pub enum JvmEnvironment {
+    /// A new environment, exclusively owned by the caller until it calls [`Jvm::publish`].
+    Created(&'static mut Jvm),
+    /// The environment created for the VM by an earlier call, which has been published.
+    Existing(&'static Jvm),
+}
+
 impl Jvm {
     /// Creates a new [`Jvm`] from a raw JVM pointer.
-    pub fn from_jvm_ptr<'j>(
+    ///
+    /// Initialization is idempotent per VM: if a [`Jvm`] has already been created for `vm_ptr`,
+    /// e.g. because both `Agent_OnLoad` and `Agent_OnAttach` are invoked, no second environment
+    /// is created and the existing [`Jvm`] is returned as [`JvmEnvironment::Existing`]. Since the
+    /// first caller holds an exclusive reference to it until it calls [`Jvm::publish`],
+    /// [`JvmTICreationError::AlreadyInitialized`] is returned if it has not been published yet.
+    ///
+    /// The registry of environments is per copy of this crate. Each agent library built on it
+    /// therefore gets its own JVM TI environment, with its own capabilities and event callbacks,
+    /// even when several such agents are loaded into the same process.
+    /// # Errors
+    /// See [`JvmTICreationError`] for more information.
+    /// # Panics
+    /// Panics if the VM has no `GetEnv` function or returns an unexpected error code from it.
+    pub fn from_jvm_ptr(
         vm_ptr: JvmPointer,
         version: JvmTIVersion,
-    ) -> Result<&'j mut Self, JvmTICreationError> {
+    ) -> Result<JvmEnvironment, JvmTICreationError> {
         if vm_ptr.is_null() {
             return Err(JvmTICreationError::NullJVMPointer);
         }
+        // The lock is held during the creation so that concurrent calls do not create two
+        // environments for the same VM.
+        let mut environments = ENVIRONMENTS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&(_, published)) = environments.iter().find(|(vm, _)| *vm == vm_ptr as usize) {
+            return published
+                .map(JvmEnvironment::Existing)
+                .ok_or(JvmTICreationError::AlreadyInitialized);
+        }
         let mut jvmti_ptr: MaybeUninit<*mut sys::jvmtiEnv> = MaybeUninit::uninit();
 
         // SAFETY: GetEnv will return an error code if it fails instead of panicking.
@@ -70,7 +168,17 @@ impl Jvm {
                 let jvmti_ptr = unsafe { jvmti_ptr.assume_init() };
                 let result = Self {
                     jvmti_ptr,
+                    vm_ptr,
                     callbacks: Default::default(),
+                    // SAFETY: All the fields are optional function pointers, for which all zeros
+                    // is `None`.
+                    native_callbacks: unsafe { std::mem::zeroed() },
+                    auto_enable_events: false,
+                    auto_capabilities: false,
+                    acquired_capabilities: AtomicU64::new(0),
+                    panic_policy: events::PanicPolicy::default(),
+                    extension_callbacks: extensions::ExtensionCallbacks::default(),
+                    agent_threads: Mutex::new(Vec::new()),
                 };
                 let result = Box::leak(Box::new(result));
                 unsafe {
@@ -80,7 +188,8 @@ impl Jvm {
                         result as *mut _ as *const _
                     )
                 }?;
-                Ok(result)
+                environments.push((vm_ptr as usize, None));
+                Ok(JvmEnvironment::Created(result))
             }
             sys::JNI_EDETACHED => Err(JvmTICreationError::Detached),
             sys::JNI_EVERSION => Err(JvmTICreationError::WrongVersion),
@@ -88,6 +197,37 @@ impl Jvm {
         }
     }
 
+    /// Gives up the exclusive reference to the [`Jvm`] and makes it available through
+    /// [`Jvm::current`], unless another [`Jvm`] has been published before, and to later calls of
+    /// [`Jvm::from_jvm_ptr`] for the same VM.
+    ///
+    /// The agent startup functions of [`agent_on_load!`](crate::agent_on_load) and
+    /// [`agent_on_attach!`](crate::agent_on_attach) publish the [`Jvm`] after they return.
+    pub fn publish(&'static mut self) -> &'static Self {
+        let _ = CURRENT.compare_exchange(null_mut(), self, Ordering::AcqRel, Ordering::Acquire);
+        let jvm: &'static Self = self;
+        let mut environments = ENVIRONMENTS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, published)) = environments
+            .iter_mut()
+            .find(|(vm, _)| *vm == jvm.vm_ptr as usize)
+        {
+            published.get_or_insert(jvm);
+        }
+        jvm
+    }
+
+    /// Gets the [`Jvm`] published at agent startup, or `None` if the agent startup function has
+    /// not returned yet.
+    ///
+    /// This lets helper threads and registered native methods reach the JVM TI environment without
+    /// passing it through every function. The [`Jvm`] is shared by all the threads from then on,
+    /// since the exclusive reference handed to the startup function has been given up.
+    #[must_use]
+    pub fn current() -> Option<&'static Self> {
+        // SAFETY: The pointer is either null or comes from a leaked `Box` that is never released.
+        unsafe { CURRENT.load(Ordering::Acquire).as_ref() }
+    }
+
     /// Gets a reference to the [`Jvm`] from the JVM TI environment pointer.
     /// # Safety
     /// It is safe to call this function if there is a [`Jvm`] created with [`from_jvm_ptr`].
@@ -113,12 +253,130 @@ impl Jvm {
         .expect("Fail to get the jvm pointer from local storage.")
     }
 
+    /// Extends the lifetime of the reference to the [`Jvm`], e.g. for state that outlives an
+    /// event callback. Every [`Jvm`] is created by [`Jvm::from_jvm_ptr`], which leaks it, so it is
+    /// never released.
+    #[cfg(feature = "dap")]
+    pub(crate) fn to_static(&self) -> &'static Self {
+        // SAFETY: The `Jvm` comes from a leaked `Box` that is never released, see above.
+        unsafe { &*std::ptr::from_ref(self) }
+    }
+
+    /// Gets the raw JVM TI environment pointer.
+    pub(crate) fn jvmti_ptr(&self) -> *mut sys::jvmtiEnv {
+        self.jvmti_ptr
+    }
+
+    /// Deletes a local reference of the current thread. Nothing is done if the current thread is
+    /// not attached to the VM, in which case it cannot hold local references either.
+    /// See [`DeleteLocalRef`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#deletelocalref).
+    /// # Safety
+    /// `reference` must be a local reference of the current thread that is not used afterwards.
+    pub(crate) unsafe fn delete_local_ref(&self, reference: sys::jobject) {
+        if let Some(jni) = self.current_jni() {
+            jni.delete_local_ref(reference);
+        }
+    }
+
+    /// Gets the JNI environment of the current thread, or `None` if the current thread is not
+    /// attached to the VM.
+    /// See [`GetEnv`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/invocation.html#getenv).
+    pub(crate) fn current_jni(&self) -> Option<JNI> {
+        let mut env: MaybeUninit<*mut sys::JNIEnv> = MaybeUninit::uninit();
+        // SAFETY: `self.vm_ptr` is valid for the whole life of the VM.
+        let result = unsafe {
+            call_jni!(
+                self.vm_ptr,
+                GetEnv,
+                env.as_mut_ptr().cast(),
+                sys::JNI_VERSION_1_2.cast_signed()
+            )
+        };
+        // SAFETY: A successful result indicates that `env` has been initialized with the JNI
+        // environment of the current thread.
+        (result == sys::JNI_OK.cast_signed()).then(|| unsafe { JNI::from_ptr(env.assume_init()) })
+    }
+
+    /// Allocates `size` bytes of memory that can be released by the JVM or by [`Jvm::deallocate`].
+    /// See [`Allocate`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#Allocate).
+    #[cfg(feature = "class-events")]
+    pub(crate) fn allocate(&self, size: usize) -> Result<*mut u8, JvmTIError> {
+        let size = sys::jlong::try_from(size).map_err(|_| JvmTIError::IllegalArgument)?;
+        let mut mem = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv` because of the API restrictions.
+        unsafe { call_jvmti!(self.jvmti_ptr, Allocate, size, mem.as_mut_ptr()) }?;
+        // SAFETY: A successful result indicates that `mem` has been initialized.
+        Ok(unsafe { mem.assume_init() })
+    }
+
+    /// Releases memory allocated by the JVM TI environment.
+    /// See [`Deallocate`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#Deallocate).
+    /// # Safety
+    /// `mem` must be null or a pointer returned by a JVM TI function that has not been deallocated yet.
+    pub(crate) unsafe fn deallocate<T>(&self, mem: *mut T) -> Result<(), JvmTIError> {
+        if mem.is_null() {
+            return Ok(());
+        }
+        call_jvmti!(self.jvmti_ptr, Deallocate, mem.cast())
+    }
+
+    /// Copies a modified UTF-8 string allocated by the JVM TI environment and deallocates it.
+    /// # Safety
+    /// `ptr` must be a null-terminated string allocated by a JVM TI function.
+    pub(crate) unsafe fn take_string(&self, ptr: *mut c_char) -> Result<OsString, JvmTIError> {
+        let string = OsStr::from_bytes(CStr::from_ptr(ptr).to_bytes()).to_owned();
+        self.deallocate(ptr)?;
+        Ok(string)
+    }
+
+    /// Copies an array of `count` elements allocated by the JVM TI environment and deallocates it.
+    /// # Safety
+    /// `ptr` must be null or point to an array of `count` elements allocated by a JVM TI function.
+    pub(crate) unsafe fn take_array<T: Copy>(
+        &self,
+        ptr: *mut T,
+        count: sys::jint,
+    ) -> Result<Vec<T>, JvmTIError> {
+        let count = usize::try_from(count).unwrap_or_default();
+        let elements = if ptr.is_null() || count == 0 {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(ptr, count).to_vec()
+        };
+        self.deallocate(ptr)?;
+        Ok(elements)
+    }
+
+    /// Modifies the event callbacks with `modifier` and reinstalls the native callbacks, so that
+    /// the VM only dispatches the events that have a callback afterwards.
+    /// If [`Jvm::set_auto_enable_events`] is on, the events that gain a callback are also enabled
+    /// globally and the events that lose their callback are disabled globally.
+    /// See [`SetEventCallbacks`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SetEventCallbacks).
+    /// # Errors
+    /// See [`JvmTIError`] for more information. Enabling an event fails with
+    /// [`JvmTIError::MustPossessCapability`] if the capability it requires has not been added.
     pub fn update_callbacks<U>(&mut self, modifier: U) -> Result<(), JvmTIError>
     where
         U: FnOnce(&mut events::EventCallbacks),
     {
+        let registered_before = self.callbacks.registered_events();
         modifier(&mut self.callbacks);
         self.update_native_callback()?;
+        if self.auto_enable_events {
+            let registered_after = self.callbacks.registered_events();
+            for &event in registered_before
+                .iter()
+                .filter(|it| !registered_after.contains(it))
+            {
+                self.disable_event(event, None)?;
+            }
+            for &event in registered_after
+                .iter()
+                .filter(|it| !registered_before.contains(it))
+            {
+                self.enable_event(event, None)?;
+            }
+        }
         Ok(())
     }
 }
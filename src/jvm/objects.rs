@@ -1,16 +1,342 @@
-use crate::sys;
+//! APIs for working with Java objects.
 
-use super::Jvm;
+use std::{
+    ffi::c_void,
+    mem::{ManuallyDrop, MaybeUninit},
+    ptr::null_mut,
+};
 
+use crate::{macros::call_jvmti, sys};
+
+use super::{
+    capabilities::JvmtiCapabilities,
+    class::Class,
+    errors::{HeapError, JvmTIError},
+    general::JvmTiPhase,
+    jni::{GlobalRef, JNIError, JNI},
+    Jvm,
+};
+
+/// A Java object.
+///
+/// A handle wraps a JNI local reference of the thread it was obtained on. Handles given to event
+/// callbacks, or created with [`Object::from_raw`], borrow a reference owned by someone else and are
+/// only valid while it is, e.g. until the callback returns. Handles returned by the functions of
+/// this crate own the local reference they were given and delete it when dropped, so that long
+/// loops do not exhaust the local reference table. Either way, a handle must not be used after its
+/// thread leaves the native method or callback it was obtained in; use [`Object::to_global`] to keep
+/// the object longer or to use it on another thread.
 #[derive(Debug)]
 pub struct Object<'j> {
-    jvm: &'j Jvm,
-    jobject: sys::jobject,
+    pub(crate) jvm: &'j Jvm,
+    pub(crate) jobject: sys::jobject,
+    /// Whether the handle owns its local reference and deletes it when dropped.
+    owned: bool,
 }
 
 impl Object<'_> {
     pub(crate) unsafe fn from_ptr<'j>(jvm: &'j Jvm, jobject: sys::jobject) -> Object<'j> {
         assert!(!jobject.is_null(), "The object pointer must not be null");
-        Object { jvm, jobject }
+        Object {
+            jvm,
+            jobject,
+            owned: false,
+        }
+    }
+
+    /// Creates a handle that owns `jobject`, a local reference created by this crate, and deletes it
+    /// when dropped.
+    /// # Safety
+    /// `jobject` must be a valid local reference of the current thread that is not used elsewhere.
+    pub(crate) unsafe fn from_local(jvm: &Jvm, jobject: sys::jobject) -> Object<'_> {
+        Object {
+            owned: true,
+            ..Self::from_ptr(jvm, jobject)
+        }
+    }
+
+    /// Creates a [`Object`] from a raw JNI reference to the object, e.g. one obtained from other JNI code.
+    /// # Safety
+    /// `jobject` must be a valid reference to an object that stays valid while the returned handle is used.
+    /// # Panics
+    /// Panics if `jobject` is null.
+    pub unsafe fn from_raw(jvm: &Jvm, jobject: sys::jobject) -> Object<'_> {
+        Self::from_ptr(jvm, jobject)
+    }
+
+    /// Gets the raw JNI reference to the object without giving up the handle.
+    #[must_use]
+    pub fn as_raw(&self) -> sys::jobject {
+        self.jobject
+    }
+
+    /// Gives up the handle and returns the raw JNI reference to the object. The reference is not
+    /// deleted, so the caller becomes responsible for a local reference owned by the handle.
+    #[must_use]
+    pub fn into_raw(self) -> sys::jobject {
+        ManuallyDrop::new(self).jobject
+    }
+
+    /// Creates a global reference to the object, which can outlive the handle and be sent to other
+    /// threads.
+    /// See [`NewGlobalRef`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#newglobalref).
+    /// # Errors
+    /// Returns [`JNIError::OutOfMemory`] if the VM runs out of memory.
+    pub fn to_global(&self, jni: &JNI) -> Result<GlobalRef<Object<'static>>, JNIError> {
+        GlobalRef::new(jni, self)
+    }
+
+    /// Returns whether `self` and `other` refer to the same object. Handles are local references,
+    /// so two handles for the same object are usually different pointers.
+    /// See [`IsSameObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#issameobject).
+    #[must_use]
+    pub fn is_same(&self, jni: &JNI, other: &Object<'_>) -> bool {
+        // SAFETY: Both are valid object references.
+        unsafe { jni.is_same_object(self.jobject, other.jobject) }
+    }
+
+    /// Gets the tag of the object, or `0` if the object is not tagged.
+    /// See [`GetTag`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetTag).
+    /// # Errors
+    /// Returns [`HeapError::MissingCapabilities`] if the `can_tag_objects` capability cannot be
+    /// added. See [`HeapError`] for more information.
+    pub fn tag(&self) -> Result<sys::jlong, HeapError> {
+        self.jvm
+            .acquire_capabilities(JvmtiCapabilities::CAN_TAG_OBJECTS)
+            .map_err(HeapError::MissingCapabilities)?;
+        let mut tag = MaybeUninit::uninit();
+        // SAFETY: `self.jobject` is a valid `jobject`.
+        unsafe { call_jvmti!(self.jvm.jvmti_ptr, GetTag, self.jobject, tag.as_mut_ptr()) }?;
+        // SAFETY: A successful result indicates that `tag` has been initialized.
+        Ok(unsafe { tag.assume_init() })
+    }
+
+    /// Gets the identity hash code of the object, as returned by `System.identityHashCode`. It
+    /// stays the same for the life of the object, so unlike the address of a local reference it
+    /// can be used to recognize an object across handles. Distinct objects may share a hash code.
+    /// See [`GetObjectHashCode`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetObjectHashCode).
+    /// # Errors
+    /// See [`HeapError`] for more information.
+    pub fn identity_hash(&self) -> Result<sys::jint, HeapError> {
+        let mut hash_code = MaybeUninit::uninit();
+        // SAFETY: `self.jobject` is a valid `jobject`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetObjectHashCode,
+                self.jobject,
+                hash_code.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `hash_code` has been initialized.
+        Ok(unsafe { hash_code.assume_init() })
+    }
+
+    /// Gets an implementation-specific approximation of the amount of storage consumed by the
+    /// object in bytes, which may include some or all of its overhead.
+    /// See [`GetObjectSize`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetObjectSize).
+    /// # Errors
+    /// See [`HeapError`] for more information.
+    pub fn size(&self) -> Result<u64, HeapError> {
+        let mut size = MaybeUninit::uninit();
+        // SAFETY: `self.jobject` is a valid `jobject`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetObjectSize,
+                self.jobject,
+                size.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `size` has been initialized.
+        let size = unsafe { size.assume_init() };
+        Ok(size.cast_unsigned())
+    }
+
+    /// Sets the tag of the object. A tag of `0` untags the object.
+    /// See [`SetTag`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SetTag).
+    /// # Errors
+    /// Returns [`HeapError::MissingCapabilities`] if the `can_tag_objects` capability cannot be
+    /// added. See [`HeapError`] for more information.
+    pub fn set_tag(&self, tag: sys::jlong) -> Result<(), HeapError> {
+        self.jvm
+            .acquire_capabilities(JvmtiCapabilities::CAN_TAG_OBJECTS)
+            .map_err(HeapError::MissingCapabilities)?;
+        // SAFETY: `self.jobject` is a valid `jobject`.
+        unsafe { call_jvmti!(self.jvm.jvmti_ptr, SetTag, self.jobject, tag) }?;
+        Ok(())
+    }
+}
+
+impl<'j> Object<'j> {
+    /// Gets the class of the object.
+    /// See [`GetObjectClass`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#getobjectclass).
+    #[must_use]
+    pub fn class(&self, jni: &JNI) -> Class<'j> {
+        // SAFETY: `self.jobject` is a valid, non-null `jobject`, whose class is never null.
+        unsafe { Class::from_local(self.jvm, jni.get_object_class(self.jobject)) }
+    }
+}
+
+impl Jvm {
+    /// Forces the VM to perform a garbage collection, e.g. before measuring the heap. The
+    /// collection is complete when this returns, although the `ObjectFree` events it causes may be
+    /// reported later. Finalizers are not run.
+    /// See [`ForceGarbageCollection`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#ForceGarbageCollection).
+    /// # Errors
+    /// See [`JvmTIError`] for more information.
+    pub fn force_gc(&self) -> Result<(), JvmTIError> {
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe { call_jvmti!(self.jvmti_ptr, ForceGarbageCollection) }?;
+        Ok(())
+    }
+
+    /// Sets the average number of bytes allocated between two `SampledObjectAlloc` events, which
+    /// is 512 KiB by default. An interval of `0` samples every allocation. The new interval takes
+    /// effect once the current one has elapsed on each thread.
+    /// This can only be called in the `OnLoad` or live phase.
+    /// See [`SetHeapSamplingInterval`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SetHeapSamplingInterval).
+    /// # Errors
+    /// Returns [`HeapError::MissingCapabilities`] if the `can_generate_sampled_object_alloc_events`
+    /// capability cannot be added, [`HeapError::IllegalArgument`] if `bytes` does not fit in a
+    /// `jint`, or [`JvmTIError::WrongPhase`] wrapped in [`HeapError::Other`] outside of the
+    /// `OnLoad` and live phases. See [`HeapError`] for more information.
+    pub fn set_heap_sampling_interval(&self, bytes: u32) -> Result<(), HeapError> {
+        if !matches!(self.get_phase()?, JvmTiPhase::OnLoad | JvmTiPhase::Live) {
+            return Err(HeapError::Other(JvmTIError::WrongPhase));
+        }
+        let interval = sys::jint::try_from(bytes).map_err(|_| HeapError::IllegalArgument)?;
+        self.acquire_capabilities(JvmtiCapabilities::CAN_GENERATE_SAMPLED_OBJECT_ALLOC_EVENTS)
+            .map_err(HeapError::MissingCapabilities)?;
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe { call_jvmti!(self.jvmti_ptr, SetHeapSamplingInterval, interval) }?;
+        Ok(())
+    }
+
+    /// Counts the objects in the heap and their total size in bytes.
+    /// See [`IterateThroughHeap`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#IterateThroughHeap).
+    pub(crate) fn heap_totals(&self) -> Result<(u64, u64), HeapError> {
+        unsafe extern "C" fn count_object(
+            _class_tag: sys::jlong,
+            size: sys::jlong,
+            _tag_ptr: *mut sys::jlong,
+            _length: sys::jint,
+            user_data: *mut c_void,
+        ) -> sys::jint {
+            // SAFETY: `user_data` is the `totals` passed to `IterateThroughHeap` below.
+            let totals = unsafe { &mut *user_data.cast::<(u64, u64)>() };
+            totals.0 += 1;
+            totals.1 += u64::try_from(size).unwrap_or_default();
+            0
+        }
+
+        // SAFETY: All the fields are optional function pointers, for which all zeros is `None`.
+        let mut callbacks: sys::jvmtiHeapCallbacks = unsafe { std::mem::zeroed() };
+        callbacks.heap_iteration_callback = Some(count_object);
+        let mut totals = (0_u64, 0_u64);
+        // SAFETY: `callbacks` and `totals` outlive the iteration.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                IterateThroughHeap,
+                0,
+                null_mut(),
+                std::ptr::from_ref(&callbacks),
+                std::ptr::addr_of_mut!(totals).cast()
+            )
+        }?;
+        Ok(totals)
+    }
+
+    /// Returns the subset of `tags` that are still attached to live objects in the heap.
+    /// See [`GetObjectsWithTags`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetObjectsWithTags).
+    pub(crate) fn live_tags(&self, tags: &[sys::jlong]) -> Result<Vec<sys::jlong>, HeapError> {
+        if tags.is_empty() {
+            return Ok(Vec::new());
+        }
+        let tag_count = sys::jint::try_from(tags.len()).map_err(|_| HeapError::IllegalArgument)?;
+        let mut count = MaybeUninit::uninit();
+        let mut tag_result = MaybeUninit::uninit();
+        // SAFETY: `tags` holds `tag_count` tags and the objects are not requested.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetObjectsWithTags,
+                tag_count,
+                tags.as_ptr(),
+                count.as_mut_ptr(),
+                null_mut(),
+                tag_result.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `count` and `tag_result` have been initialized
+        // and that `tag_result` points to an array of `count` tags.
+        unsafe {
+            let count = usize::try_from(count.assume_init()).unwrap_or_default();
+            let tag_result = tag_result.assume_init();
+            let live = if count == 0 {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(tag_result, count).to_vec()
+            };
+            self.deallocate(tag_result)?;
+            Ok(live)
+        }
+    }
+
+    /// Gets the live objects tagged with any of `tags` together with their tags, e.g. to retrieve
+    /// the instances marked during a heap iteration. The objects are returned as local references;
+    /// see [`Jvm::objects_with_tags_chunked`] to process many objects without exhausting the local
+    /// reference table.
+    /// See [`GetObjectsWithTags`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetObjectsWithTags).
+    /// # Errors
+    /// Returns [`HeapError::IllegalArgument`] if `tags` contains `0` and
+    /// [`HeapError::MissingCapabilities`] if the `can_tag_objects` capability cannot be added.
+    /// See [`HeapError`] for more information.
+    pub fn objects_with_tags(
+        &self,
+        tags: &[sys::jlong],
+    ) -> Result<Vec<(Object<'_>, sys::jlong)>, HeapError> {
+        self.acquire_capabilities(JvmtiCapabilities::CAN_TAG_OBJECTS)
+            .map_err(HeapError::MissingCapabilities)?;
+        let tag_count = sys::jint::try_from(tags.len()).map_err(|_| HeapError::IllegalArgument)?;
+        let mut count = MaybeUninit::uninit();
+        let mut objects = MaybeUninit::uninit();
+        let mut tag_result = MaybeUninit::uninit();
+        // SAFETY: `tags` holds `tag_count` tags.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetObjectsWithTags,
+                tag_count,
+                tags.as_ptr(),
+                count.as_mut_ptr(),
+                objects.as_mut_ptr(),
+                tag_result.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `objects` and `tag_result` point to arrays of
+        // `count` local references and tags respectively.
+        let (objects, found_tags) = unsafe {
+            let count = count.assume_init();
+            let objects = self.take_array(objects.assume_init(), count);
+            let found_tags = self.take_array(tag_result.assume_init(), count);
+            (objects?, found_tags?)
+        };
+        Ok(objects
+            .into_iter()
+            .zip(found_tags)
+            // SAFETY: The references returned by `GetObjectsWithTags` are valid and non-null.
+            .map(|(jobject, tag)| (unsafe { Object::from_local(self, jobject) }, tag))
+            .collect())
+    }
+}
+
+impl Drop for Object<'_> {
+    fn drop(&mut self) {
+        if self.owned {
+            // SAFETY: An owned handle is the only user of its local reference.
+            unsafe { self.jvm.delete_local_ref(self.jobject) };
+        }
     }
 }
@@ -0,0 +1,169 @@
+//! Capability and event presets for common kinds of agents.
+//!
+//! A [`Preset`] bundles the capabilities and events a typical agent of its kind needs, so a new
+//! agent gets a working baseline with a single [`Jvm::apply_preset`] call in `Agent_OnLoad`.
+//! Handlers for the enabled events are registered with [`Jvm::update_callbacks`] as usual, or
+//! installed together with the preset by [`Jvm::apply_preset_with_handler`].
+
+use std::sync::Arc;
+
+use crate::sys;
+
+use super::{errors::JvmTIError, events::JvmTIEvent, handler::JvmtiEventHandler, Jvm};
+
+/// A bundle of capabilities and events for a common kind of agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Preset {
+    /// A sampling profiler that periodically inspects thread stacks.
+    SamplingProfiler,
+    /// A debugger that sets breakpoints and inspects frames and local variables.
+    Debugger,
+    /// An instrumentation agent that transforms class files, like a `java.lang.instrument` agent.
+    Instrumentation,
+}
+
+impl Preset {
+    /// The events enabled by the preset.
+    #[must_use]
+    pub const fn events(self) -> &'static [JvmTIEvent] {
+        match self {
+            Preset::SamplingProfiler => &[
+                JvmTIEvent::ThreadStart,
+                JvmTIEvent::ThreadEnd,
+                JvmTIEvent::ClassPrepare,
+                JvmTIEvent::VMDeath,
+            ],
+            Preset::Debugger => &[
+                JvmTIEvent::VMInit,
+                JvmTIEvent::VMDeath,
+                JvmTIEvent::ThreadStart,
+                JvmTIEvent::ThreadEnd,
+                JvmTIEvent::ClassPrepare,
+                JvmTIEvent::Breakpoint,
+            ],
+            Preset::Instrumentation => &[JvmTIEvent::ClassFileLoadHook],
+        }
+    }
+
+    fn capabilities(self) -> sys::jvmtiCapabilities {
+        // SAFETY: `jvmtiCapabilities` is a plain bit field for which all zeros means no capability.
+        let mut capabilities: sys::jvmtiCapabilities = unsafe { std::mem::zeroed() };
+        match self {
+            Preset::SamplingProfiler => {
+                capabilities.set_can_get_source_file_name(1);
+                capabilities.set_can_get_line_numbers(1);
+                capabilities.set_can_suspend(1);
+                capabilities.set_can_get_thread_cpu_time(1);
+                capabilities.set_can_get_current_thread_cpu_time(1);
+            }
+            Preset::Debugger => {
+                capabilities.set_can_get_source_file_name(1);
+                capabilities.set_can_get_line_numbers(1);
+                capabilities.set_can_suspend(1);
+                capabilities.set_can_access_local_variables(1);
+                capabilities.set_can_generate_breakpoint_events(1);
+                capabilities.set_can_generate_single_step_events(1);
+                capabilities.set_can_generate_frame_pop_events(1);
+                capabilities.set_can_get_owned_monitor_info(1);
+                capabilities.set_can_get_current_contended_monitor(1);
+                capabilities.set_can_pop_frame(1);
+                capabilities.set_can_force_early_return(1);
+            }
+            Preset::Instrumentation => {
+                capabilities.set_can_generate_all_class_hook_events(1);
+                capabilities.set_can_redefine_classes(1);
+                capabilities.set_can_redefine_any_class(1);
+                capabilities.set_can_retransform_classes(1);
+                capabilities.set_can_retransform_any_class(1);
+                capabilities.set_can_set_native_method_prefix(1);
+            }
+        }
+        capabilities
+    }
+}
+
+impl Jvm {
+    /// Requests the capabilities of `preset` and enables its events globally.
+    /// Some of the capabilities can only be added in the `OnLoad` phase, so presets should be
+    /// applied from `Agent_OnLoad`.
+    /// # Errors
+    /// Returns [`JvmTIError::NotAvailable`] if the VM cannot provide the capabilities of the preset.
+    /// See [`JvmTIError`] for other possible errors.
+    pub fn apply_preset(&mut self, preset: Preset) -> Result<(), JvmTIError> {
+        self.add_raw_capabilities(&preset.capabilities())?;
+        for &event in preset.events() {
+            self.enable_event(event, None)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `preset` like [`Jvm::apply_preset`], and installs `handler` as the callback of the
+    /// events of the preset and of those listed by [`JvmtiEventHandler::events`], enabling all of
+    /// them. The methods of `handler` for the events it does not override do nothing.
+    /// Like [`Jvm::install_handler`], this replaces the callbacks previously registered for these
+    /// events.
+    /// # Errors
+    /// Returns [`JvmTIError::NotAvailable`] if the VM cannot provide the capabilities of the preset.
+    /// See [`Jvm::install_handler`] and [`JvmTIError`] for other possible errors.
+    pub fn apply_preset_with_handler(
+        &mut self,
+        preset: Preset,
+        handler: Box<dyn JvmtiEventHandler>,
+    ) -> Result<(), JvmTIError> {
+        let handler: Arc<dyn JvmtiEventHandler> = Arc::from(handler);
+        let mut events = preset.events().to_vec();
+        for event in handler.events() {
+            if !events.contains(&event) {
+                events.push(event);
+            }
+        }
+        self.add_raw_capabilities(&preset.capabilities())?;
+        self.install_handler_for(&handler, &events)?;
+        for &event in &events {
+            self.enable_event(event, None)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jvm::capabilities::JvmtiCapabilities;
+
+    const PRESETS: [Preset; 3] = [
+        Preset::SamplingProfiler,
+        Preset::Debugger,
+        Preset::Instrumentation,
+    ];
+
+    #[test]
+    fn presets_request_the_capabilities_of_their_events() {
+        for preset in PRESETS {
+            let capabilities = JvmtiCapabilities::from(preset.capabilities());
+            for &event in preset.events() {
+                assert!(
+                    capabilities.contains(event.required_capabilities()),
+                    "{preset:?} cannot enable {event:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn presets_request_their_defining_capabilities() {
+        let capabilities = |preset: Preset| JvmtiCapabilities::from(preset.capabilities());
+        assert!(capabilities(Preset::SamplingProfiler).contains(
+            JvmtiCapabilities::CAN_GET_LINE_NUMBERS | JvmtiCapabilities::CAN_GET_THREAD_CPU_TIME
+        ));
+        assert!(capabilities(Preset::Debugger).contains(
+            JvmtiCapabilities::CAN_GENERATE_BREAKPOINT_EVENTS
+                | JvmtiCapabilities::CAN_ACCESS_LOCAL_VARIABLES
+        ));
+        assert!(capabilities(Preset::Instrumentation)
+            .contains(JvmtiCapabilities::CAN_RETRANSFORM_CLASSES));
+        assert!(!capabilities(Preset::Instrumentation)
+            .contains(JvmtiCapabilities::CAN_GENERATE_BREAKPOINT_EVENTS));
+    }
+}
@@ -0,0 +1,59 @@
+//! A per-callback scratch scope for the temporary conversions done by the event trampolines.
+//!
+//! Hot events are dispatched many times per second, so the trampolines lend the buffers passed by
+//! the VM to the callbacks instead of copying them into fresh allocations for every dispatch. The
+//! buffers are only valid until the event returns, which the lifetime of the borrowed
+//! [`ScratchArena`] enforces.
+
+use std::marker::PhantomData;
+
+use crate::sys;
+
+/// The scope of the event arguments lent to one callback.
+#[derive(Debug)]
+pub(crate) struct ScratchArena {
+    /// The arguments are only valid on the thread the event is dispatched on.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ScratchArena {
+    /// Runs `f` with a scratch arena that is dropped when `f` returns, so nothing borrowed from it
+    /// outlives the call.
+    pub(crate) fn with<R>(f: impl FnOnce(&ScratchArena) -> R) -> R {
+        f(&ScratchArena {
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Borrows the `len` bytes at `data` for as long as the arena, without copying them. A null
+    /// `data` is read as an empty buffer.
+    /// # Safety
+    /// Unless null, `data` must point to `len` bytes that stay valid and unchanged until the
+    /// event that passed them returns.
+    #[allow(clippy::unused_self)] // `self` only bounds the lifetime of the result.
+    pub(crate) unsafe fn bytes(&self, data: *const u8, len: sys::jint) -> &[u8] {
+        if data.is_null() {
+            &[]
+        } else {
+            std::slice::from_raw_parts(data, usize::try_from(len).unwrap_or(0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lends_bytes_without_copying() {
+        let data = [1_u8, 2, 3];
+        ScratchArena::with(|scratch| {
+            // SAFETY: `data` outlives the arena.
+            let lent = unsafe { scratch.bytes(data.as_ptr(), 3) };
+            assert_eq!(lent.as_ptr(), data.as_ptr());
+            assert_eq!(lent, &data);
+            // SAFETY: A null pointer is read as an empty buffer.
+            assert!(unsafe { scratch.bytes(std::ptr::null(), 3) }.is_empty());
+        });
+    }
+}
@@ -0,0 +1,556 @@
+//! APIs for inspecting the call stacks of Java threads.
+
+use std::{mem::MaybeUninit, os::unix::prelude::OsStrExt};
+
+use crate::{macros::call_jvmti, sys};
+
+use super::{
+    errors::{JvmTIError, StackError},
+    methods::{LocalVariable, Method},
+    objects::Object,
+    strings::encode_modified_utf8,
+    threads::{Thread, ThreadState},
+    values::{JType, JValue},
+    Jvm,
+};
+
+/// A frame on the call stack of a Java thread.
+#[derive(Debug)]
+pub struct Frame<'j> {
+    /// The method executing in this frame.
+    pub method: Method<'j>,
+    /// The location of the currently executing instruction, or `-1` for native methods.
+    pub location: sys::jlocation,
+}
+
+/// The call stack of a thread, as returned by [`Jvm::all_stack_traces`] and
+/// [`Jvm::thread_list_stack_traces`].
+#[derive(Debug)]
+pub struct StackInfo<'j> {
+    /// The thread.
+    pub thread: Thread<'j>,
+    /// The state of the thread.
+    pub state: ThreadState,
+    /// The frames on the call stack of the thread, innermost first.
+    pub frames: Vec<Frame<'j>>,
+}
+
+/// A frame on the call stack of a thread, identified by its depth, with `0` being the current
+/// frame. The thread must be the current thread or suspended while the frame is inspected, and
+/// the frame is only meaningful as long as the stack does not change.
+/// See [`Thread::frame`].
+#[derive(Debug, Clone, Copy)]
+pub struct StackFrame<'a, 'j> {
+    thread: &'a Thread<'j>,
+    depth: sys::jint,
+}
+
+impl<'j> StackFrame<'_, 'j> {
+    /// Gets the thread on whose call stack the frame is.
+    #[must_use]
+    pub fn thread(&self) -> &Thread<'j> {
+        self.thread
+    }
+
+    /// Gets the depth of the frame, with `0` being the current frame.
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        usize::try_from(self.depth).unwrap_or_default()
+    }
+
+    /// Gets the method executing in the frame and the location of the currently executing
+    /// instruction.
+    /// See [`GetFrameLocation`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetFrameLocation).
+    /// # Errors
+    /// Returns [`StackError::NoMoreFrames`] if there is no frame at this depth.
+    /// See [`StackError`] for more information.
+    pub fn location(&self) -> Result<Frame<'j>, StackError> {
+        let mut method = MaybeUninit::uninit();
+        let mut location = MaybeUninit::uninit();
+        // SAFETY: `self.thread.jthread` is a valid `jthread`.
+        unsafe {
+            call_jvmti!(
+                self.thread.jvm.jvmti_ptr,
+                GetFrameLocation,
+                self.thread.jthread,
+                self.depth,
+                method.as_mut_ptr(),
+                location.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `method` and `location` have been
+        // initialized.
+        unsafe {
+            Ok(Frame {
+                method: Method::from_ptr(self.thread.jvm, method.assume_init()),
+                location: location.assume_init(),
+            })
+        }
+    }
+
+    /// Gets the value of the local variable in `slot`, reading it as a value of type `ty`. Values
+    /// of type `long` and `double` take two slots, of which `slot` is the first one.
+    /// See [`GetLocalObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetLocalObject).
+    /// # Errors
+    /// Returns [`StackError::InvalidSlot`] if the slot is out of range, and
+    /// [`StackError::TypeMismatch`] if `ty` does not match the type of the variable or is
+    /// [`JType::Void`]. See [`StackError`] for more information. The `can_access_local_variables`
+    /// capability is required.
+    pub fn get_local_as(&self, slot: usize, ty: JType) -> Result<JValue<'j>, StackError> {
+        let slot = sys::jint::try_from(slot).map_err(|_| StackError::InvalidSlot)?;
+        let jvm = self.thread.jvm;
+        let thread = self.thread.jthread;
+        let depth = self.depth;
+        // SAFETY: `thread` is a valid `jthread`, and each function initializes its output on
+        // success.
+        unsafe {
+            match ty {
+                JType::Void => Err(StackError::TypeMismatch),
+                JType::Long => {
+                    let mut value = MaybeUninit::uninit();
+                    call_jvmti!(
+                        jvm.jvmti_ptr,
+                        GetLocalLong,
+                        thread,
+                        depth,
+                        slot,
+                        value.as_mut_ptr()
+                    )?;
+                    Ok(JValue::Long(value.assume_init()))
+                }
+                JType::Float => {
+                    let mut value = MaybeUninit::uninit();
+                    call_jvmti!(
+                        jvm.jvmti_ptr,
+                        GetLocalFloat,
+                        thread,
+                        depth,
+                        slot,
+                        value.as_mut_ptr()
+                    )?;
+                    Ok(JValue::Float(value.assume_init()))
+                }
+                JType::Double => {
+                    let mut value = MaybeUninit::uninit();
+                    call_jvmti!(
+                        jvm.jvmti_ptr,
+                        GetLocalDouble,
+                        thread,
+                        depth,
+                        slot,
+                        value.as_mut_ptr()
+                    )?;
+                    Ok(JValue::Double(value.assume_init()))
+                }
+                JType::Object => {
+                    let mut value = MaybeUninit::uninit();
+                    call_jvmti!(
+                        jvm.jvmti_ptr,
+                        GetLocalObject,
+                        thread,
+                        depth,
+                        slot,
+                        value.as_mut_ptr()
+                    )?;
+                    let object = value.assume_init();
+                    Ok(JValue::Object(
+                        (!object.is_null()).then(|| Object::from_local(jvm, object)),
+                    ))
+                }
+                JType::Boolean | JType::Byte | JType::Char | JType::Short | JType::Int => {
+                    let mut value = MaybeUninit::uninit();
+                    call_jvmti!(
+                        jvm.jvmti_ptr,
+                        GetLocalInt,
+                        thread,
+                        depth,
+                        slot,
+                        value.as_mut_ptr()
+                    )?;
+                    JValue::from_int(value.assume_init(), ty).ok_or(StackError::TypeMismatch)
+                }
+            }
+        }
+    }
+
+    /// Gets the value of the local variable in `slot`, inferring its type from the local variable
+    /// table of the method at the current location of the frame.
+    /// See [`StackFrame::get_local_as`] and [`Method::local_variable_table`].
+    /// # Errors
+    /// Returns [`StackError::AbsentInformation`] if the method has no local variable table, and
+    /// [`StackError::InvalidSlot`] if no variable in `slot` is live at the current location.
+    /// See [`StackError`] for more information. The `can_access_local_variables` capability is
+    /// required.
+    pub fn get_local(&self, slot: usize) -> Result<JValue<'j>, StackError> {
+        let variable = self.live_variable(|it| it.slot == slot)?;
+        let ty = variable.ty().ok_or(StackError::TypeMismatch)?;
+        self.get_local_as(slot, ty)
+    }
+
+    /// Gets the value of the local variable named `name`, looking up its slot and type in the
+    /// local variable table of the method at the current location of the frame.
+    /// See [`StackFrame::get_local_as`] and [`Method::local_variable_table`].
+    /// # Errors
+    /// Returns [`StackError::AbsentInformation`] if the method has no local variable table, and
+    /// [`StackError::InvalidSlot`] if no variable named `name` is live at the current location.
+    /// See [`StackError`] for more information. The `can_access_local_variables` capability is
+    /// required.
+    pub fn local(&self, name: &str) -> Result<JValue<'j>, StackError> {
+        let name = encode_modified_utf8(name);
+        let variable = self.live_variable(|it| it.name.as_bytes() == name.as_bytes())?;
+        let ty = variable.ty().ok_or(StackError::TypeMismatch)?;
+        self.get_local_as(variable.slot, ty)
+    }
+
+    /// Finds the local variable matching `predicate` that is live at the current location of the
+    /// frame.
+    fn live_variable(
+        &self,
+        predicate: impl Fn(&LocalVariable) -> bool,
+    ) -> Result<LocalVariable, StackError> {
+        let frame = self.location()?;
+        frame
+            .method
+            .local_variable_table()
+            .map_err(JvmTIError::from)?
+            .into_iter()
+            .find(|it| predicate(it) && it.is_live_at(frame.location))
+            .ok_or(StackError::InvalidSlot)
+    }
+
+    /// Gets the receiver of the method executing in the frame, i.e. `this`, or `None` if the
+    /// method is static. Unlike reading slot `0`, this does not depend on the local variable table.
+    /// See [`GetLocalInstance`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetLocalInstance).
+    /// # Errors
+    /// Returns [`StackError::OpaqueFrame`] if the frame is a native method.
+    /// See [`StackError`] for more information. The `can_access_local_variables` capability is
+    /// required.
+    pub fn this_object(&self) -> Result<Option<Object<'j>>, StackError> {
+        let mut object = MaybeUninit::uninit();
+        // SAFETY: `self.thread.jthread` is a valid `jthread`.
+        let result = unsafe {
+            call_jvmti!(
+                self.thread.jvm.jvmti_ptr,
+                GetLocalInstance,
+                self.thread.jthread,
+                self.depth,
+                object.as_mut_ptr()
+            )
+        };
+        match result {
+            Ok(()) => {
+                // SAFETY: A successful result indicates that `object` is a valid local reference
+                // or null.
+                let object = unsafe { object.assume_init() };
+                // SAFETY: `object` is a new local reference.
+                Ok((!object.is_null())
+                    .then(|| unsafe { Object::from_local(self.thread.jvm, object) }))
+            }
+            Err(JvmTIError::InvalidSlot) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Sets the local variable in `slot` to `value`, writing it as a value of the type of
+    /// `value`. Values of type `long` and `double` take two slots, of which `slot` is the first
+    /// one. `boolean`, `byte`, `char`, and `short` values are written as `int`s, as the JVM stores
+    /// them.
+    /// See [`SetLocalObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SetLocalObject).
+    /// # Errors
+    /// Returns [`StackError::InvalidSlot`] if the slot is out of range,
+    /// [`StackError::TypeMismatch`] if the type of `value` does not match the type of the
+    /// variable, and [`StackError::OpaqueFrame`] if the frame is a native method.
+    /// See [`StackError`] for more information. The `can_access_local_variables` capability is
+    /// required.
+    pub fn set_local(&self, slot: usize, value: &JValue<'_>) -> Result<(), StackError> {
+        let slot = sys::jint::try_from(slot).map_err(|_| StackError::InvalidSlot)?;
+        let jvmti_ptr = self.thread.jvm.jvmti_ptr;
+        let thread = self.thread.jthread;
+        let depth = self.depth;
+        // SAFETY: `thread` is a valid `jthread` and references are valid or null.
+        unsafe {
+            match value {
+                JValue::Object(object) => call_jvmti!(
+                    jvmti_ptr,
+                    SetLocalObject,
+                    thread,
+                    depth,
+                    slot,
+                    object
+                        .as_ref()
+                        .map_or(std::ptr::null_mut(), |it| it.jobject)
+                ),
+                &JValue::Long(value) => {
+                    call_jvmti!(jvmti_ptr, SetLocalLong, thread, depth, slot, value)
+                }
+                &JValue::Float(value) => {
+                    call_jvmti!(jvmti_ptr, SetLocalFloat, thread, depth, slot, value)
+                }
+                &JValue::Double(value) => {
+                    call_jvmti!(jvmti_ptr, SetLocalDouble, thread, depth, slot, value)
+                }
+                value => {
+                    let value = value.as_int().ok_or(StackError::TypeMismatch)?;
+                    call_jvmti!(jvmti_ptr, SetLocalInt, thread, depth, slot, value)
+                }
+            }
+        }?;
+        Ok(())
+    }
+}
+
+impl<'j> Thread<'j> {
+    /// Gets the frame at `depth` on the call stack of the thread, with `0` being the current
+    /// frame. The frame is not checked to exist until it is inspected.
+    /// # Errors
+    /// Returns [`StackError::IllegalArgument`] if `depth` is out of range.
+    pub fn frame(&self, depth: usize) -> Result<StackFrame<'_, 'j>, StackError> {
+        let depth = sys::jint::try_from(depth).map_err(|_| StackError::IllegalArgument)?;
+        Ok(StackFrame {
+            thread: self,
+            depth,
+        })
+    }
+
+    /// Gets up to `max_frames` frames from the top of the call stack of the thread.
+    /// See [`GetStackTrace`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetStackTrace).
+    /// # Errors
+    /// See [`StackError`] for more information.
+    pub fn stack_trace(&self, max_frames: usize) -> Result<Vec<Frame<'j>>, StackError> {
+        self.stack_trace_from(0, max_frames)
+    }
+
+    /// Gets up to `max_frames` frames of the call stack of the thread, starting at `start_depth`.
+    /// A non-negative `start_depth` skips that many frames from the top of the stack, e.g. `1`
+    /// starts at the caller of the current method. A negative `start_depth` starts that many
+    /// frames above the bottom of the stack, e.g. `-1` only returns the outermost frame. The
+    /// frames are always ordered from the innermost to the outermost.
+    ///
+    /// The buffer for the frames is sized from the current depth of the stack, so a large
+    /// `max_frames` such as [`usize::MAX`] does not allocate more than needed. If the thread is
+    /// running, frames pushed after the depth is measured are not included.
+    /// See [`GetStackTrace`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetStackTrace).
+    /// # Errors
+    /// Returns [`StackError::IllegalArgument`] if `start_depth` is beyond the depth of the stack.
+    /// See [`StackError`] for more information.
+    pub fn stack_trace_from(
+        &self,
+        start_depth: isize,
+        max_frames: usize,
+    ) -> Result<Vec<Frame<'j>>, StackError> {
+        let start_depth =
+            sys::jint::try_from(start_depth).map_err(|_| StackError::IllegalArgument)?;
+        let max_frames = max_frames.min(self.frame_count()?);
+        let max_frames = sys::jint::try_from(max_frames).unwrap_or(sys::jint::MAX);
+        let mut frame_buffer: Vec<sys::jvmtiFrameInfo> =
+            Vec::with_capacity(usize::try_from(max_frames).unwrap_or_default());
+        let mut count = MaybeUninit::uninit();
+        // SAFETY: `frame_buffer` has room for `max_frames` frames.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetStackTrace,
+                self.jthread,
+                start_depth,
+                max_frames,
+                frame_buffer.as_mut_ptr(),
+                count.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `count` has been initialized.
+        let count = usize::try_from(unsafe { count.assume_init() }).unwrap_or_default();
+        // SAFETY: A successful result indicates that the first `count` frames have been written.
+        unsafe { frame_buffer.set_len(count) };
+        Ok(frame_buffer
+            .into_iter()
+            .map(|frame| Frame {
+                // SAFETY: The frame buffer holds valid method IDs written by the JVM.
+                method: unsafe { Method::from_ptr(self.jvm, frame.method) },
+                location: frame.location,
+            })
+            .collect())
+    }
+
+    /// Requests a `FramePop` event when the frame at `depth` returns or is popped by an exception,
+    /// with `0` being the current frame. The thread must be the current thread or suspended.
+    /// See [`NotifyFramePop`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#NotifyFramePop).
+    /// # Errors
+    /// See [`StackError`] for more information. The `can_generate_frame_pop_events` capability
+    /// is required.
+    pub fn notify_frame_pop(&self, depth: usize) -> Result<(), StackError> {
+        let depth = sys::jint::try_from(depth).map_err(|_| StackError::IllegalArgument)?;
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        unsafe { call_jvmti!(self.jvm.jvmti_ptr, NotifyFramePop, self.jthread, depth) }?;
+        Ok(())
+    }
+
+    /// Makes the current method of the thread return immediately with `value`, or without a value
+    /// if `value` is `None`, without running the rest of the method or releasing locks held by
+    /// it. The type of `value` must match the return type of the method, with `boolean`, `byte`,
+    /// `char`, and `short` methods taking any of these or an `int`. The thread must be the
+    /// current thread or suspended, and the return happens when it is resumed.
+    /// See [`ForceEarlyReturnObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#ForceEarlyReturnObject).
+    /// # Errors
+    /// Returns [`StackError::TypeMismatch`] if the value does not match the return type, and
+    /// [`StackError::OpaqueFrame`] if the current frame is a native method. See [`StackError`] for
+    /// more information. The `can_force_early_return` capability is required.
+    pub fn force_early_return(&self, value: Option<&JValue<'_>>) -> Result<(), StackError> {
+        let jvmti_ptr = self.jvm.jvmti_ptr;
+        let thread = self.jthread;
+        // SAFETY: `self.jthread` is a valid `jthread` and references are valid or null.
+        unsafe {
+            match value {
+                None => call_jvmti!(jvmti_ptr, ForceEarlyReturnVoid, thread),
+                Some(JValue::Object(object)) => call_jvmti!(
+                    jvmti_ptr,
+                    ForceEarlyReturnObject,
+                    thread,
+                    object
+                        .as_ref()
+                        .map_or(std::ptr::null_mut(), |it| it.jobject)
+                ),
+                Some(&JValue::Long(value)) => {
+                    call_jvmti!(jvmti_ptr, ForceEarlyReturnLong, thread, value)
+                }
+                Some(&JValue::Float(value)) => {
+                    call_jvmti!(jvmti_ptr, ForceEarlyReturnFloat, thread, value)
+                }
+                Some(&JValue::Double(value)) => {
+                    call_jvmti!(jvmti_ptr, ForceEarlyReturnDouble, thread, value)
+                }
+                Some(value) => {
+                    let value = value.as_int().ok_or(StackError::TypeMismatch)?;
+                    call_jvmti!(jvmti_ptr, ForceEarlyReturnInt, thread, value)
+                }
+            }
+        }?;
+        Ok(())
+    }
+
+    /// Gets the number of frames on the call stack of the thread.
+    /// See [`GetFrameCount`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetFrameCount).
+    /// # Errors
+    /// See [`StackError`] for more information.
+    pub fn frame_count(&self) -> Result<usize, StackError> {
+        let mut count = MaybeUninit::uninit();
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetFrameCount,
+                self.jthread,
+                count.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `count` has been initialized.
+        Ok(usize::try_from(unsafe { count.assume_init() }).unwrap_or_default())
+    }
+}
+
+impl Jvm {
+    /// Gets up to `max_frames` frames from the top of the call stacks of all the live platform
+    /// threads. Unlike calling [`Thread::stack_trace`] for each thread, the stacks are collected
+    /// at the same time, so they are consistent with each other.
+    /// See [`GetAllStackTraces`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetAllStackTraces).
+    /// # Errors
+    /// See [`StackError`] for more information.
+    pub fn all_stack_traces(&self, max_frames: usize) -> Result<Vec<StackInfo<'_>>, StackError> {
+        let max_frames = sys::jint::try_from(max_frames).unwrap_or(sys::jint::MAX);
+        let mut stack_infos = MaybeUninit::uninit();
+        let mut count = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetAllStackTraces,
+                max_frames,
+                stack_infos.as_mut_ptr(),
+                count.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `stack_infos` points to `count` entries,
+        // whose threads are new local references.
+        unsafe { self.take_stack_infos(stack_infos.assume_init(), count.assume_init(), true) }
+    }
+
+    /// Gets up to `max_frames` frames from the top of the call stacks of `threads`, collected at
+    /// the same time. The stacks are returned in the same order as `threads`, and their threads
+    /// borrow the references of the handles in `threads`.
+    /// See [`GetThreadListStackTraces`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetThreadListStackTraces).
+    /// # Errors
+    /// See [`StackError`] for more information.
+    pub fn thread_list_stack_traces<'a>(
+        &'a self,
+        threads: &[&'a Thread<'_>],
+        max_frames: usize,
+    ) -> Result<Vec<StackInfo<'a>>, StackError> {
+        if threads.is_empty() {
+            return Ok(Vec::new());
+        }
+        let jthreads: Vec<_> = threads.iter().map(|it| it.jthread).collect();
+        let count = sys::jint::try_from(jthreads.len()).map_err(|_| StackError::IllegalArgument)?;
+        let max_frames = sys::jint::try_from(max_frames).unwrap_or(sys::jint::MAX);
+        let mut stack_infos = MaybeUninit::uninit();
+        // SAFETY: `jthreads` holds `count` elements.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetThreadListStackTraces,
+                count,
+                jthreads.as_ptr(),
+                max_frames,
+                stack_infos.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `stack_infos` points to `count` entries,
+        // whose threads are the references in `jthreads`.
+        unsafe { self.take_stack_infos(stack_infos.assume_init(), count, false) }
+    }
+
+    /// Copies the stack information returned by a JVM TI function and deallocates it. The frame
+    /// buffers are part of the same allocation, so a single deallocation releases everything.
+    /// The returned threads own their references if `owned_threads` is set.
+    /// # Safety
+    /// `stack_infos` must point to `count` entries allocated by a JVM TI function, and the
+    /// thread references must be new local references if `owned_threads` is set.
+    unsafe fn take_stack_infos(
+        &self,
+        stack_infos: *mut sys::jvmtiStackInfo,
+        count: sys::jint,
+        owned_threads: bool,
+    ) -> Result<Vec<StackInfo<'_>>, StackError> {
+        let count = usize::try_from(count).unwrap_or_default();
+        let infos = if stack_infos.is_null() || count == 0 {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(stack_infos, count)
+                .iter()
+                .map(|info| {
+                    let frame_count = usize::try_from(info.frame_count).unwrap_or_default();
+                    let frames = if frame_count == 0 {
+                        &[][..]
+                    } else {
+                        std::slice::from_raw_parts(info.frame_buffer, frame_count)
+                    };
+                    StackInfo {
+                        thread: if owned_threads {
+                            Thread::from_local(self, info.thread)
+                        } else {
+                            Thread::from_ptr(self, info.thread)
+                        },
+                        state: ThreadState::from_bits_retain(info.state.cast_unsigned()),
+                        frames: frames
+                            .iter()
+                            .map(|frame| Frame {
+                                method: Method::from_ptr(self, frame.method),
+                                location: frame.location,
+                            })
+                            .collect(),
+                    }
+                })
+                .collect()
+        };
+        self.deallocate(stack_infos)?;
+        Ok(infos)
+    }
+}
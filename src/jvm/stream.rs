@@ -0,0 +1,492 @@
+//! A bridge delivering events to a channel, so that they can be processed on another thread.
+//!
+//! [`Jvm::event_stream`] subscribes to an event and sends an owned [`EventRecord`] for each
+//! occurrence to a bounded channel. References to Java objects are kept as [`GlobalRef`]s, which
+//! remain valid after the callback returns. The callback never blocks the VM: when the channel is
+//! full, the record is dropped and counted in [`EventStream::dropped`].
+//!
+//! Deleting the global references of a record requires the dropping thread to be attached to the
+//! VM. Receiving from an [`EventStream`] therefore attaches the receiving thread as a daemon thread
+//! until it exits, instead of attaching and detaching it for every dropped reference.
+
+use std::{
+    ffi::{OsStr, OsString},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::sys;
+
+use super::{
+    class::Class,
+    errors::JvmTIError,
+    events::{Handler, JvmTIEvent, ResourceExhaustedFlags, Subscription},
+    jni::{keep_attached, GlobalRef},
+    threads::Thread,
+    Jvm,
+};
+
+/// An owned record of an event, see [`Jvm::event_stream`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EventRecord {
+    /// A `VMInit` event.
+    VMInit {
+        /// The initial thread.
+        thread: GlobalRef<Thread<'static>>,
+    },
+    /// A `VMStart` event.
+    VMStart,
+    /// A `VMDeath` event.
+    VMDeath,
+    /// A `DataDumpRequest` event.
+    DataDumpRequest,
+    /// A `ResourceExhausted` event.
+    ResourceExhausted {
+        /// The exhausted resources.
+        flags: ResourceExhaustedFlags,
+        /// The description of the failure.
+        description: OsString,
+    },
+    /// A `ThreadStart` event.
+    ThreadStart {
+        /// The started thread.
+        thread: GlobalRef<Thread<'static>>,
+    },
+    /// A `ThreadEnd` event.
+    ThreadEnd {
+        /// The ending thread.
+        thread: GlobalRef<Thread<'static>>,
+    },
+    /// A `VirtualThreadStart` event.
+    VirtualThreadStart {
+        /// The started virtual thread.
+        virtual_thread: GlobalRef<Thread<'static>>,
+    },
+    /// A `VirtualThreadEnd` event.
+    VirtualThreadEnd {
+        /// The ending virtual thread.
+        virtual_thread: GlobalRef<Thread<'static>>,
+    },
+    /// A `ClassFileLoadHook` event. The class data cannot be replaced through a stream.
+    ClassFileLoadHook {
+        /// The internal name of the class, e.g. `java/lang/String`, if known.
+        name: Option<OsString>,
+        /// The defining loader, or `None` for the bootstrap class loader.
+        loader: Option<GlobalRef>,
+        /// A copy of the class file bytes.
+        class_data: Vec<u8>,
+    },
+    /// A `ClassLoad` event.
+    ClassLoad {
+        /// The thread loading the class.
+        thread: GlobalRef<Thread<'static>>,
+        /// The loaded class.
+        class: GlobalRef<Class<'static>>,
+    },
+    /// A `ClassPrepare` event.
+    ClassPrepare {
+        /// The thread preparing the class.
+        thread: GlobalRef<Thread<'static>>,
+        /// The prepared class.
+        class: GlobalRef<Class<'static>>,
+    },
+    /// A `MonitorWait` event.
+    MonitorWait {
+        /// The waiting thread.
+        thread: GlobalRef<Thread<'static>>,
+        /// The monitor waited on.
+        object: GlobalRef,
+        /// The timeout of the wait, or `None` if it waits without a timeout.
+        timeout: Option<Duration>,
+    },
+    /// A `MonitorWaited` event.
+    MonitorWaited {
+        /// The thread that finished waiting.
+        thread: GlobalRef<Thread<'static>>,
+        /// The monitor waited on.
+        object: GlobalRef,
+        /// Whether the wait timed out.
+        timed_out: bool,
+    },
+    /// A `MonitorContendedEnter` event.
+    MonitorContendedEnter {
+        /// The blocking thread.
+        thread: GlobalRef<Thread<'static>>,
+        /// The contended monitor.
+        object: GlobalRef,
+    },
+    /// A `MonitorContendedEntered` event.
+    MonitorContendedEntered {
+        /// The thread that entered the monitor.
+        thread: GlobalRef<Thread<'static>>,
+        /// The contended monitor.
+        object: GlobalRef,
+    },
+    /// A `GarbageCollectionStart` event.
+    GarbageCollectionStart,
+    /// A `GarbageCollectionFinish` event.
+    GarbageCollectionFinish,
+    /// An `ObjectFree` event.
+    ObjectFree {
+        /// The tag of the freed object.
+        tag: sys::jlong,
+    },
+}
+
+/// The receiving end of an event stream, see [`Jvm::event_stream`].
+///
+/// The callback feeding the stream stays subscribed after the stream is dropped, but then
+/// discards the events. It can be removed with [`Handler::unsubscribe`] and
+/// [`EventStream::subscription`].
+#[derive(Debug)]
+pub struct EventStream {
+    vm: *mut sys::JavaVM,
+    receiver: Receiver<EventRecord>,
+    dropped: Arc<AtomicU64>,
+    subscription: Subscription,
+}
+
+// SAFETY: The VM pointer is valid for the whole life of the VM and is only used to attach the
+// receiving thread.
+unsafe impl Send for EventStream {}
+
+impl EventStream {
+    /// Waits for the next record, or returns `None` if the callback feeding the stream is gone.
+    #[must_use]
+    pub fn recv(&self) -> Option<EventRecord> {
+        keep_attached(self.vm);
+        self.receiver.recv().ok()
+    }
+
+    /// Waits for the next record for at most `timeout`.
+    /// # Errors
+    /// See [`RecvTimeoutError`] for more information.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<EventRecord, RecvTimeoutError> {
+        keep_attached(self.vm);
+        self.receiver.recv_timeout(timeout)
+    }
+
+    /// Gets the next record if one is available.
+    /// # Errors
+    /// See [`TryRecvError`] for more information.
+    pub fn try_recv(&self) -> Result<EventRecord, TryRecvError> {
+        keep_attached(self.vm);
+        self.receiver.try_recv()
+    }
+
+    /// Gets the number of records dropped so far because the stream was full.
+    #[must_use]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Gets the subscription of the callback feeding the stream.
+    #[must_use]
+    pub fn subscription(&self) -> Subscription {
+        self.subscription
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = EventRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.recv()
+    }
+}
+
+/// The sending end of an event stream, owned by the callback.
+struct Sink {
+    sender: SyncSender<EventRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Sink {
+    /// Sends the record built by `record` without blocking. A record that cannot be built, e.g.
+    /// because a global reference cannot be created, counts as dropped.
+    fn send(&self, record: impl FnOnce() -> Option<EventRecord>) {
+        match record().map(|it| self.sender.try_send(it)) {
+            Some(Ok(()) | Err(TrySendError::Disconnected(_))) => {}
+            None | Some(Err(TrySendError::Full(_))) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Subscribes `callback` to `handler`, creating the handler if needed.
+fn subscribe<F: ?Sized>(handler: &mut Option<Handler<F>>, callback: Box<F>) -> Subscription {
+    handler
+        .get_or_insert_with(Handler::default)
+        .subscribe(callback)
+}
+
+impl Jvm {
+    /// Subscribes to `event` and returns a stream receiving a record of each occurrence, holding
+    /// at most `capacity` records that have not been received yet.
+    ///
+    /// The event still has to be enabled, e.g. with [`Jvm::enable_event`], and records arrive
+    /// only after the stream is created.
+    /// # Errors
+    /// Returns [`JvmTIError::IllegalArgument`] if `capacity` is `0`, and
+    /// [`JvmTIError::InvalidEventType`] if the event cannot be streamed.
+    /// See [`Jvm::update_callbacks`] for other possible errors.
+    #[allow(clippy::too_many_lines)]
+    pub fn event_stream(
+        &mut self,
+        event: JvmTIEvent,
+        capacity: usize,
+    ) -> Result<EventStream, JvmTIError> {
+        // A zero-capacity channel only hands over records to a receiver that is already waiting,
+        // so nearly every event would be dropped.
+        if capacity == 0 {
+            return Err(JvmTIError::IllegalArgument);
+        }
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let sink = Sink {
+            sender,
+            dropped: Arc::clone(&dropped),
+        };
+        let mut subscription = None;
+        // SAFETY (all closures below): The references given to the callbacks are valid, non-null
+        // references for the duration of the callback.
+        self.update_callbacks(|it| {
+            subscription = Some(match event {
+                JvmTIEvent::VMInit => subscribe(
+                    &mut it.vm_init,
+                    Box::new(move |_, jni, thread| {
+                        sink.send(|| {
+                            Some(EventRecord::VMInit {
+                                thread: unsafe { GlobalRef::from_raw(jni, thread.jthread) }?,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::VMStart => subscribe(
+                    &mut it.vm_start,
+                    Box::new(move |_, _| sink.send(|| Some(EventRecord::VMStart))),
+                ),
+                JvmTIEvent::VMDeath => subscribe(
+                    &mut it.vm_death,
+                    Box::new(move |_, _| sink.send(|| Some(EventRecord::VMDeath))),
+                ),
+                JvmTIEvent::DataDumpRequest => subscribe(
+                    &mut it.data_dump_request,
+                    Box::new(move |_| sink.send(|| Some(EventRecord::DataDumpRequest))),
+                ),
+                JvmTIEvent::ResourceExhausted => subscribe(
+                    &mut it.resource_exhausted,
+                    Box::new(move |_, _, flags, description| {
+                        sink.send(|| {
+                            Some(EventRecord::ResourceExhausted {
+                                flags,
+                                description: description.to_owned(),
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::ThreadStart => subscribe(
+                    &mut it.thread_start,
+                    Box::new(move |_, jni, thread| {
+                        sink.send(|| {
+                            Some(EventRecord::ThreadStart {
+                                thread: unsafe { GlobalRef::from_raw(jni, thread.jthread) }?,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::ThreadEnd => subscribe(
+                    &mut it.thread_end,
+                    Box::new(move |_, jni, thread| {
+                        sink.send(|| {
+                            Some(EventRecord::ThreadEnd {
+                                thread: unsafe { GlobalRef::from_raw(jni, thread.jthread) }?,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::VirtualThreadStart => subscribe(
+                    &mut it.virtual_thread_start,
+                    Box::new(move |_, jni, thread| {
+                        sink.send(|| {
+                            Some(EventRecord::VirtualThreadStart {
+                                virtual_thread: unsafe {
+                                    GlobalRef::from_raw(jni, thread.jthread)
+                                }?,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::VirtualThreadEnd => subscribe(
+                    &mut it.virtual_thread_end,
+                    Box::new(move |_, jni, thread| {
+                        sink.send(|| {
+                            Some(EventRecord::VirtualThreadEnd {
+                                virtual_thread: unsafe {
+                                    GlobalRef::from_raw(jni, thread.jthread)
+                                }?,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::ClassFileLoadHook => subscribe(
+                    &mut it.class_file_load_hook,
+                    Box::new(move |_, jni, event| {
+                        sink.send(|| {
+                            let loader = match event.loader {
+                                Some(loader) => {
+                                    Some(unsafe { GlobalRef::from_raw(jni, loader.jobject) }?)
+                                }
+                                None => None,
+                            };
+                            Some(EventRecord::ClassFileLoadHook {
+                                name: event.name.map(OsStr::to_owned),
+                                loader,
+                                class_data: event.class_data.to_vec(),
+                            })
+                        });
+                        None
+                    }),
+                ),
+                JvmTIEvent::ClassLoad => subscribe(
+                    &mut it.class_load,
+                    Box::new(move |_, jni, thread, class| {
+                        sink.send(|| {
+                            Some(EventRecord::ClassLoad {
+                                thread: unsafe { GlobalRef::from_raw(jni, thread.jthread) }?,
+                                class: unsafe { GlobalRef::from_raw(jni, class.as_raw()) }?,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::ClassPrepare => subscribe(
+                    &mut it.class_prepare,
+                    Box::new(move |_, jni, thread, class| {
+                        sink.send(|| {
+                            Some(EventRecord::ClassPrepare {
+                                thread: unsafe { GlobalRef::from_raw(jni, thread.jthread) }?,
+                                class: unsafe { GlobalRef::from_raw(jni, class.as_raw()) }?,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::MonitorWait => subscribe(
+                    &mut it.monitor_wait,
+                    Box::new(move |_, jni, thread, object, timeout| {
+                        sink.send(|| {
+                            Some(EventRecord::MonitorWait {
+                                thread: unsafe { GlobalRef::from_raw(jni, thread.jthread) }?,
+                                object: unsafe { GlobalRef::from_raw(jni, object.jobject) }?,
+                                timeout,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::MonitorWaited => subscribe(
+                    &mut it.monitor_waited,
+                    Box::new(move |_, jni, thread, object, timed_out| {
+                        sink.send(|| {
+                            Some(EventRecord::MonitorWaited {
+                                thread: unsafe { GlobalRef::from_raw(jni, thread.jthread) }?,
+                                object: unsafe { GlobalRef::from_raw(jni, object.jobject) }?,
+                                timed_out,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::MonitorContendedEnter => subscribe(
+                    &mut it.monitor_contended_enter,
+                    Box::new(move |_, jni, thread, object| {
+                        sink.send(|| {
+                            Some(EventRecord::MonitorContendedEnter {
+                                thread: unsafe { GlobalRef::from_raw(jni, thread.jthread) }?,
+                                object: unsafe { GlobalRef::from_raw(jni, object.jobject) }?,
+                            })
+                        });
+                    }),
+                ),
+                JvmTIEvent::MonitorContendedEntered => subscribe(
+                    &mut it.monitor_contended_entered,
+                    Box::new(move |_, jni, thread, object| {
+                        sink.send(|| {
+                            Some(EventRecord::MonitorContendedEntered {
+                                thread: unsafe { GlobalRef::from_raw(jni, thread.jthread) }?,
+                                object: unsafe { GlobalRef::from_raw(jni, object.jobject) }?,
+                            })
+                        });
+                    }),
+                ),
+                // The garbage collection events run in a restricted state, in which sending to
+                // the channel only takes a lock of the standard library.
+                JvmTIEvent::GarbageCollectionStart => subscribe(
+                    &mut it.garbage_collection_start,
+                    Box::new(move |_| sink.send(|| Some(EventRecord::GarbageCollectionStart))),
+                ),
+                JvmTIEvent::GarbageCollectionFinish => subscribe(
+                    &mut it.garbage_collection_finish,
+                    Box::new(move |_| sink.send(|| Some(EventRecord::GarbageCollectionFinish))),
+                ),
+                JvmTIEvent::ObjectFree => subscribe(
+                    &mut it.object_free,
+                    Box::new(move |_, tag| sink.send(|| Some(EventRecord::ObjectFree { tag }))),
+                ),
+                _ => return,
+            });
+        })?;
+        let subscription = subscription.ok_or(JvmTIError::InvalidEventType)?;
+        Ok(EventStream {
+            vm: self.vm_ptr,
+            receiver,
+            dropped,
+            subscription,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sink(capacity: usize) -> (Sink, Receiver<EventRecord>) {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let sink = Sink {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (sink, receiver)
+    }
+
+    #[test]
+    fn counts_records_that_do_not_fit() {
+        let (sink, receiver) = sink(1);
+        sink.send(|| Some(EventRecord::ObjectFree { tag: 1 }));
+        sink.send(|| Some(EventRecord::ObjectFree { tag: 2 }));
+        assert_eq!(sink.dropped.load(Ordering::Relaxed), 1);
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(EventRecord::ObjectFree { tag: 1 })
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn counts_records_that_cannot_be_built() {
+        let (sink, _receiver) = sink(1);
+        sink.send(|| None);
+        assert_eq!(sink.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn discards_records_after_the_stream_is_dropped() {
+        let (sink, receiver) = sink(1);
+        drop(receiver);
+        sink.send(|| Some(EventRecord::GarbageCollectionStart));
+        assert_eq!(sink.dropped.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,258 @@
+//! Conversions of the modified UTF-8 strings used by the JVM into Rust strings.
+//!
+//! Names and signatures returned by the JVM TI are encoded in
+//! [modified UTF-8](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/types.html#modified-utf-8-strings),
+//! which differs from standard UTF-8 in the encoding of the null character and of supplementary
+//! characters. The byte-level `OsStr`/`OsString` forms are kept for exotic names; the `_utf8`
+//! variants decode them according to an explicit [`Utf8Policy`].
+
+use std::{
+    borrow::Cow,
+    ffi::{CString, OsStr},
+    os::unix::prelude::OsStrExt,
+};
+
+/// The UTF-16 code unit of `U+FFFD REPLACEMENT CHARACTER`.
+const REPLACEMENT_UNIT: u16 = 0xFFFD;
+
+/// How to handle byte sequences that are not valid modified UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Utf8Policy {
+    /// Fail with a [`ModifiedUtf8Error`].
+    Strict,
+    /// Replace invalid sequences with `U+FFFD REPLACEMENT CHARACTER`.
+    #[default]
+    Lossy,
+}
+
+/// The error returned when a string is not valid modified UTF-8 under [`Utf8Policy::Strict`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("invalid modified UTF-8 sequence at byte {position}")]
+pub struct ModifiedUtf8Error {
+    /// The position of the first invalid byte.
+    pub position: usize,
+}
+
+/// The error returned by the `_utf8` variants of functions returning names or signatures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum StringError<E> {
+    /// The underlying JVM TI function failed.
+    #[error(transparent)]
+    Jvm(#[from] E),
+    /// The returned string is not valid modified UTF-8.
+    #[error(transparent)]
+    Utf8(ModifiedUtf8Error),
+}
+
+/// Decodes a modified UTF-8 string.
+/// Strings that decode identically under standard UTF-8 are borrowed without copying.
+/// # Errors
+/// Returns a [`ModifiedUtf8Error`] if `bytes` is not valid modified UTF-8 and `policy` is
+/// [`Utf8Policy::Strict`].
+pub fn decode_modified_utf8(
+    bytes: &[u8],
+    policy: Utf8Policy,
+) -> Result<Cow<'_, str>, ModifiedUtf8Error> {
+    // Standard UTF-8 rejects the encoded surrogates and the overlong null used by modified UTF-8,
+    // so any string it accepts decodes identically under both encodings, except for zero bytes
+    // and four-byte sequences, which are not valid modified UTF-8.
+    if !bytes.iter().any(|&byte| byte == 0 || byte >= 0xF0) {
+        if let Ok(string) = std::str::from_utf8(bytes) {
+            return Ok(Cow::Borrowed(string));
+        }
+    }
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut unit_positions = Vec::with_capacity(bytes.len());
+    let mut first_invalid = None;
+    let mut position = 0;
+    while position < bytes.len() {
+        let (unit, length) = decode_unit(&bytes[position..]);
+        units.push(unit.unwrap_or_else(|| {
+            first_invalid.get_or_insert(position);
+            REPLACEMENT_UNIT
+        }));
+        unit_positions.push(position);
+        position += length;
+    }
+    let mut string = String::with_capacity(bytes.len());
+    let mut unit_index = 0;
+    for result in char::decode_utf16(units.iter().copied()) {
+        if let Ok(c) = result {
+            string.push(c);
+            unit_index += c.len_utf16();
+        } else {
+            let position = unit_positions[unit_index];
+            if first_invalid.is_none_or(|first| position < first) {
+                first_invalid = Some(position);
+            }
+            string.push(char::REPLACEMENT_CHARACTER);
+            unit_index += 1;
+        }
+    }
+    match first_invalid {
+        Some(position) if policy == Utf8Policy::Strict => Err(ModifiedUtf8Error { position }),
+        _ => Ok(Cow::Owned(string)),
+    }
+}
+
+/// Encodes `string` in modified UTF-8 as a null-terminated string that can be passed to the JVM.
+#[allow(clippy::cast_possible_truncation)] // The bytes are masked before the casts.
+pub(crate) fn encode_modified_utf8(string: &str) -> CString {
+    let mut bytes = Vec::with_capacity(string.len() + 1);
+    for unit in string.encode_utf16() {
+        match unit {
+            0x0001..=0x007F => bytes.push(unit as u8),
+            // The null character and the characters up to U+07FF take two bytes.
+            0x0000 | 0x0080..=0x07FF => {
+                bytes.extend([0xC0 | (unit >> 6) as u8, 0x80 | (unit & 0x3F) as u8]);
+            }
+            // Supplementary characters are encoded as surrogate pairs, three bytes each.
+            _ => bytes.extend([
+                0xE0 | (unit >> 12) as u8,
+                0x80 | (unit >> 6 & 0x3F) as u8,
+                0x80 | (unit & 0x3F) as u8,
+            ]),
+        }
+    }
+    // Modified UTF-8 never contains a zero byte.
+    CString::new(bytes).expect("modified UTF-8 contains no zero byte")
+}
+
+/// Decodes one UTF-16 code unit from the start of `bytes`, returning the unit (or `None` if the
+/// sequence is invalid) and the number of bytes consumed.
+fn decode_unit(bytes: &[u8]) -> (Option<u16>, usize) {
+    let is_continuation = |byte: Option<&u8>| byte.is_some_and(|&byte| byte & 0xC0 == 0x80);
+    match bytes[0] {
+        byte @ 0x01..=0x7F => (Some(u16::from(byte)), 1),
+        byte @ 0xC0..=0xDF if is_continuation(bytes.get(1)) => {
+            let unit = u16::from(byte & 0x1F) << 6 | u16::from(bytes[1] & 0x3F);
+            (Some(unit), 2)
+        }
+        byte @ 0xE0..=0xEF if is_continuation(bytes.get(1)) && is_continuation(bytes.get(2)) => {
+            let unit = u16::from(byte & 0x0F) << 12
+                | u16::from(bytes[1] & 0x3F) << 6
+                | u16::from(bytes[2] & 0x3F);
+            (Some(unit), 3)
+        }
+        _ => (None, 1),
+    }
+}
+
+/// Extension methods for decoding the modified UTF-8 strings returned by the JVM.
+pub trait ModifiedUtf8Ext {
+    /// Decodes the string according to `policy`.
+    /// # Errors
+    /// Returns a [`ModifiedUtf8Error`] if the string is not valid modified UTF-8 and `policy` is
+    /// [`Utf8Policy::Strict`].
+    fn to_utf8(&self, policy: Utf8Policy) -> Result<Cow<'_, str>, ModifiedUtf8Error>;
+
+    /// Decodes the string, replacing invalid sequences with `U+FFFD REPLACEMENT CHARACTER`.
+    fn to_utf8_lossy(&self) -> Cow<'_, str> {
+        match self.to_utf8(Utf8Policy::Lossy) {
+            Ok(string) => string,
+            Err(_) => unreachable!("Lossy decoding never fails"),
+        }
+    }
+}
+
+impl ModifiedUtf8Ext for OsStr {
+    fn to_utf8(&self, policy: Utf8Policy) -> Result<Cow<'_, str>, ModifiedUtf8Error> {
+        decode_modified_utf8(self.as_bytes(), policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8], policy: Utf8Policy) -> Result<String, ModifiedUtf8Error> {
+        decode_modified_utf8(bytes, policy).map(Cow::into_owned)
+    }
+
+    #[test]
+    fn borrows_ascii_and_bmp_characters() {
+        let bytes = "java/lang/Object\u{e9}\u{4e2d}".as_bytes();
+        let decoded = decode_modified_utf8(bytes, Utf8Policy::Strict).unwrap();
+        assert!(matches!(
+            decoded,
+            Cow::Borrowed("java/lang/Object\u{e9}\u{4e2d}")
+        ));
+    }
+
+    #[test]
+    fn decodes_overlong_null() {
+        assert_eq!(decode(b"a\xC0\x80b", Utf8Policy::Strict).unwrap(), "a\0b");
+    }
+
+    #[test]
+    fn decodes_surrogate_pairs() {
+        let bytes = b"\xED\xA0\xBD\xED\xB8\x80";
+        assert_eq!(decode(bytes, Utf8Policy::Strict).unwrap(), "\u{1F600}");
+    }
+
+    #[test]
+    fn strict_rejects_four_byte_sequences() {
+        let bytes = "x\u{1F600}".as_bytes();
+        assert_eq!(
+            decode(bytes, Utf8Policy::Strict),
+            Err(ModifiedUtf8Error { position: 1 })
+        );
+        assert_eq!(
+            decode(bytes, Utf8Policy::Lossy).unwrap(),
+            "x\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}"
+        );
+    }
+
+    #[test]
+    fn strict_rejects_zero_bytes() {
+        assert_eq!(
+            decode(b"a\0", Utf8Policy::Strict),
+            Err(ModifiedUtf8Error { position: 1 })
+        );
+    }
+
+    #[test]
+    fn strict_rejects_unpaired_surrogates() {
+        let bytes = b"ab\xED\xA0\xBD";
+        assert_eq!(
+            decode(bytes, Utf8Policy::Strict),
+            Err(ModifiedUtf8Error { position: 2 })
+        );
+        assert_eq!(decode(bytes, Utf8Policy::Lossy).unwrap(), "ab\u{FFFD}");
+    }
+
+    #[test]
+    fn strict_rejects_truncated_sequences() {
+        assert_eq!(
+            decode(b"a\xE4\xB8", Utf8Policy::Strict),
+            Err(ModifiedUtf8Error { position: 1 })
+        );
+    }
+
+    #[test]
+    fn encodes_null_and_supplementary_characters() {
+        let encoded = encode_modified_utf8("a\0\u{e9}\u{1F600}");
+        assert_eq!(
+            encoded.as_bytes(),
+            b"a\xC0\x80\xC3\xA9\xED\xA0\xBD\xED\xB8\x80"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        for string in [
+            "",
+            "plain",
+            "\0",
+            "caf\u{e9}",
+            "\u{4e2d}\u{6587}",
+            "\u{1F600}\u{10FFFF}",
+        ] {
+            let encoded = encode_modified_utf8(string);
+            assert_eq!(
+                decode(encoded.as_bytes(), Utf8Policy::Strict).unwrap(),
+                string
+            );
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Association of Rust values with Java objects through object tags.
+//!
+//! The JVM TI lets an agent attach a single `jlong` tag to each object. A [`TagMap`] assigns the
+//! tags itself and keeps a value per tagged object on the Rust side, so that heap tools do not
+//! have to multiplex tags into their own side tables. The tags of all the maps are drawn from a
+//! single counter, so several maps can share the `ObjectFree` event without mixing up their
+//! entries, but an object can only be in one map at a time.
+//!
+//! Tagging requires the `can_tag_objects` capability.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+
+#[cfg(feature = "gc-events")]
+use std::sync::Arc;
+
+use crate::sys;
+
+#[cfg(feature = "gc-events")]
+use super::events::{Handler, JvmTIEvent, Subscription};
+use super::{errors::HeapError, objects::Object, Jvm};
+
+#[cfg(feature = "gc-events")]
+use super::errors::JvmTIError;
+
+/// The next tag assigned by a [`TagMap`], unique across all the maps.
+static NEXT_TAG: AtomicI64 = AtomicI64::new(1);
+
+/// Reserves `count` consecutive tags that no [`TagMap`] will assign and returns the first one.
+pub(crate) fn reserve_tags(count: usize) -> sys::jlong {
+    let count = sys::jlong::try_from(count).unwrap_or(sys::jlong::MAX);
+    NEXT_TAG.fetch_add(count, Ordering::Relaxed)
+}
+
+/// A map from Java objects to values of type `T`, keyed by object tags.
+///
+/// Entries of objects that have been garbage collected are removed by
+/// [`TagMap::on_object_free`], which should be called from the `ObjectFree` event, e.g. by
+/// subscribing with [`TagMap::subscribe_object_free`]. No JNI functions may be called in that
+/// event, so the values of collected objects are only dropped by the next call to
+/// [`TagMap::insert`], [`TagMap::remove`], [`TagMap::retain_live`] or
+/// [`TagMap::drop_freed`], or together with the map.
+///
+/// Objects tagged by another map or another user of object tags cannot be inserted.
+#[derive(Debug)]
+pub struct TagMap<T> {
+    values: Mutex<HashMap<sys::jlong, T>>,
+    freed: Mutex<Vec<T>>,
+}
+
+impl<T> Default for TagMap<T> {
+    fn default() -> Self {
+        Self {
+            values: Mutex::new(HashMap::new()),
+            freed: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<T> TagMap<T> {
+    /// Creates an empty map.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Associates `value` with `object`, tagging the object if it is not in the map yet, and
+    /// returns the value previously associated with it.
+    /// # Errors
+    /// Returns [`HeapError::IllegalArgument`] if the object is tagged by another map or another
+    /// user of object tags. See [`HeapError`] for other possible errors.
+    pub fn insert(&self, object: &Object<'_>, value: T) -> Result<Option<T>, HeapError> {
+        self.drop_freed();
+        let mut values = self.lock();
+        let tag = object.tag()?;
+        if let Some(previous) = values.get_mut(&tag) {
+            return Ok(Some(std::mem::replace(previous, value)));
+        }
+        if tag != 0 {
+            // Re-tagging the object would leave its entry in the other map behind forever.
+            return Err(HeapError::IllegalArgument);
+        }
+        let tag = reserve_tags(1);
+        object.set_tag(tag)?;
+        values.insert(tag, value);
+        Ok(None)
+    }
+
+    /// Gets a copy of the value associated with `object`.
+    /// # Errors
+    /// See [`HeapError`] for more information.
+    pub fn get(&self, object: &Object<'_>) -> Result<Option<T>, HeapError>
+    where
+        T: Clone,
+    {
+        self.with(object, |it| it.clone())
+    }
+
+    /// Calls `f` with the value associated with `object` and returns its result, or `None` if the
+    /// object is not in the map.
+    /// # Errors
+    /// See [`HeapError`] for more information.
+    pub fn with<R>(
+        &self,
+        object: &Object<'_>,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<Option<R>, HeapError> {
+        let mut values = self.lock();
+        let tag = object.tag()?;
+        Ok(values.get_mut(&tag).map(f))
+    }
+
+    /// Returns whether `object` is in the map.
+    /// # Errors
+    /// See [`HeapError`] for more information.
+    pub fn contains(&self, object: &Object<'_>) -> Result<bool, HeapError> {
+        let values = self.lock();
+        Ok(values.contains_key(&object.tag()?))
+    }
+
+    /// Removes `object` from the map, untagging it, and returns the value associated with it.
+    /// # Errors
+    /// See [`HeapError`] for more information.
+    pub fn remove(&self, object: &Object<'_>) -> Result<Option<T>, HeapError> {
+        self.drop_freed();
+        let mut values = self.lock();
+        let tag = object.tag()?;
+        let Some(value) = values.remove(&tag) else {
+            return Ok(None);
+        };
+        object.set_tag(0)?;
+        Ok(Some(value))
+    }
+
+    /// Removes the entry of the object with the given tag, which has been garbage collected. Tags
+    /// of other maps are ignored.
+    /// This only locks the map and is safe to call from the `ObjectFree` event, where almost no
+    /// JVM TI functions and no JNI functions may be used. The value is kept until
+    /// [`TagMap::drop_freed`] drops it outside the event.
+    pub fn on_object_free(&self, tag: sys::jlong) {
+        let Some(value) = self.lock().remove(&tag) else {
+            return;
+        };
+        self.freed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(value);
+    }
+
+    /// Drops the values of the collected objects removed by [`TagMap::on_object_free`]. Dropping
+    /// a value may call JNI functions, e.g. to delete a global reference, so this must not be
+    /// called from the `ObjectFree` event.
+    pub fn drop_freed(&self) {
+        let freed = std::mem::take(&mut *self.freed.lock().unwrap_or_else(PoisonError::into_inner));
+        drop(freed);
+    }
+
+    /// Gets the live objects in the map together with a copy of their values.
+    /// See [`Jvm::objects_with_tags`].
+    /// # Errors
+    /// See [`HeapError`] for more information.
+    pub fn entries<'j>(&self, jvm: &'j Jvm) -> Result<Vec<(Object<'j>, T)>, HeapError>
+    where
+        T: Clone,
+    {
+        let values = self.lock();
+        let tags: Vec<_> = values.keys().copied().collect();
+        if tags.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(jvm
+            .objects_with_tags(&tags)?
+            .into_iter()
+            .filter_map(|(object, tag)| Some((object, values.get(&tag)?.clone())))
+            .collect())
+    }
+
+    /// Forgets the entries of the objects that have been garbage collected, and calls `f` with each
+    /// remaining value. Unlike [`TagMap::entries`], no references to the objects are created.
+    /// See [`Jvm::objects_with_tags`].
+    /// # Errors
+    /// See [`HeapError`] for more information.
+    pub fn retain_live(&self, jvm: &Jvm, f: impl FnMut(&T)) -> Result<(), HeapError> {
+        self.drop_freed();
+        let mut values = self.lock();
+        let tags: Vec<_> = values.keys().copied().collect();
+        let live: HashSet<_> = jvm.live_tags(&tags)?.into_iter().collect();
+        values.retain(|tag, _| live.contains(tag));
+        values.values().for_each(f);
+        Ok(())
+    }
+
+    /// Gets the number of entries, including the entries of collected objects whose `ObjectFree`
+    /// event has not been handled yet.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns whether the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<sys::jlong, T>> {
+        self.values.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "gc-events")]
+impl<T: Send + 'static> TagMap<T> {
+    /// Subscribes the map to the `ObjectFree` event and enables the event, so that the entries of
+    /// collected objects are removed automatically. The values are dropped later outside the
+    /// event, see [`TagMap::drop_freed`].
+    /// # Errors
+    /// Returns [`JvmTIError::MustPossessCapability`] if the
+    /// `can_generate_object_free_events` capability is missing. See [`Jvm::update_callbacks`] for
+    /// other possible errors.
+    pub fn subscribe_object_free(
+        self: &Arc<Self>,
+        jvm: &mut Jvm,
+    ) -> Result<Subscription, JvmTIError> {
+        let map = Arc::clone(self);
+        let mut subscription = None;
+        jvm.update_callbacks(|it| {
+            subscription = Some(
+                it.object_free
+                    .get_or_insert_with(Handler::default)
+                    .subscribe(Box::new(move |_, tag| {
+                        map.on_object_free(tag);
+                    })),
+            );
+        })?;
+        jvm.enable_event(JvmTIEvent::ObjectFree, None)?;
+        subscription.ok_or(JvmTIError::Internal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+
+    struct CountsDrops(Rc<Cell<usize>>);
+
+    impl Drop for CountsDrops {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn object_free_defers_drops() {
+        let drops = Rc::new(Cell::new(0));
+        let map = TagMap::new();
+        map.lock().insert(1, CountsDrops(Rc::clone(&drops)));
+        map.lock().insert(2, CountsDrops(Rc::clone(&drops)));
+
+        map.on_object_free(1);
+        map.on_object_free(3);
+        assert_eq!(map.len(), 1);
+        assert_eq!(drops.get(), 0);
+
+        map.drop_freed();
+        assert_eq!(drops.get(), 1);
+        drop(map);
+        assert_eq!(drops.get(), 2);
+    }
+}
@@ -0,0 +1,174 @@
+//! Exclusion of threads from event callbacks and from the data collected by the diagnostic
+//! subsystems.
+//!
+//! A [`ThreadFilter`] excludes threads by name pattern, by thread group, or because they belong to
+//! the agent itself, so that neither the agent's own threads nor chosen framework threads pollute
+//! the collected data. The same filter can restrict event callbacks with
+//! [`Handler::with_thread_filter`](super::events::Handler::with_thread_filter) and be shared by
+//! several diagnostic subsystems, e.g.
+//! [`FlightRecorder::with_thread_filter`](crate::diagnostics::flight_recorder::FlightRecorder::with_thread_filter)
+//! and [`ClassLoadGraph::with_thread_filter`](crate::diagnostics::class_graph::ClassLoadGraph::with_thread_filter).
+
+use super::{strings::ModifiedUtf8Ext, threads::Thread};
+
+/// The name prefix marking threads as agent-internal. Agents should name the threads they attach
+/// to the VM with this prefix so that [`ThreadFilter::exclude_agent_threads`] recognizes them.
+pub const AGENT_THREAD_PREFIX: &str = "coffee-filter-";
+
+/// The maximum depth of thread group nesting inspected by a [`ThreadFilter`].
+const MAX_GROUP_DEPTH: usize = 32;
+
+/// Decides which threads are excluded from the collected data.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadFilter {
+    name_patterns: Vec<String>,
+    groups: Vec<String>,
+    agent_threads: bool,
+}
+
+impl ThreadFilter {
+    /// Creates a filter that excludes no threads.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Excludes the threads whose names match `pattern`, in which `*` matches any sequence of
+    /// characters and `?` matches any single character, e.g. `ForkJoinPool-*-worker-?`.
+    #[must_use]
+    pub fn exclude_name(mut self, pattern: impl Into<String>) -> Self {
+        self.name_patterns.push(pattern.into());
+        self
+    }
+
+    /// Excludes the threads in the thread group named `group` or in any of its subgroups.
+    #[must_use]
+    pub fn exclude_group(mut self, group: impl Into<String>) -> Self {
+        self.groups.push(group.into());
+        self
+    }
+
+    /// Excludes the threads whose names start with [`AGENT_THREAD_PREFIX`].
+    #[must_use]
+    pub fn exclude_agent_threads(mut self) -> Self {
+        self.agent_threads = true;
+        self
+    }
+
+    /// Returns whether the filter excludes no threads at all.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.name_patterns.is_empty() && self.groups.is_empty() && !self.agent_threads
+    }
+
+    /// Returns whether a thread named `name` is excluded by its name.
+    #[must_use]
+    pub fn excludes_name(&self, name: &str) -> bool {
+        (self.agent_threads && name.starts_with(AGENT_THREAD_PREFIX))
+            || self
+                .name_patterns
+                .iter()
+                .any(|pattern| glob_matches(pattern, name))
+    }
+
+    /// Returns whether `thread` is excluded. Threads that cannot be inspected, e.g. because they
+    /// have ended, are not excluded.
+    #[must_use]
+    pub fn excludes(&self, thread: &Thread<'_>) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let Ok(info) = thread.info() else {
+            return false;
+        };
+        if self.excludes_name(&info.name.to_utf8_lossy()) {
+            return true;
+        }
+        if self.groups.is_empty() {
+            return false;
+        }
+        let mut group = info.group;
+        for _ in 0..MAX_GROUP_DEPTH {
+            let Some(Ok(info)) = group.map(|it| it.info()) else {
+                break;
+            };
+            let name = info.name.to_utf8_lossy();
+            if self.groups.iter().any(|it| *it == name) {
+                return true;
+            }
+            group = info.parent;
+        }
+        false
+    }
+}
+
+/// Matches `name` against `pattern`, in which `*` matches any sequence of characters and `?`
+/// matches any single character.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let name: Vec<_> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // The position of the last `*` in the pattern and the position in the name it resumes from.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, resume)) => {
+                    p = star + 1;
+                    n = resume + 1;
+                    backtrack = Some((star, resume + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_matches;
+
+    #[test]
+    fn matches_literal_names() {
+        assert!(glob_matches("main", "main"));
+        assert!(!glob_matches("main", "mains"));
+        assert!(!glob_matches("mains", "main"));
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "main"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(glob_matches("worker-?", "worker-1"));
+        assert!(glob_matches("worker-?", "worker-\u{e9}"));
+        assert!(!glob_matches("worker-?", "worker-"));
+        assert!(!glob_matches("worker-?", "worker-12"));
+    }
+
+    #[test]
+    fn star_matches_any_sequence() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*", "anything"));
+        assert!(glob_matches("pool-*-thread-*", "pool-1-thread-12"));
+        assert!(glob_matches("*-thread", "pool-thread-thread"));
+        assert!(glob_matches("a**b", "ab"));
+        assert!(!glob_matches("pool-*-thread-*", "pool-1-worker-12"));
+        assert!(!glob_matches("*x", "abc"));
+    }
+
+    #[test]
+    fn star_backtracks() {
+        assert!(glob_matches("*ab*ab", "abxabyab"));
+        assert!(glob_matches("a*?c", "abbc"));
+        assert!(!glob_matches("a*?c", "ac"));
+    }
+}
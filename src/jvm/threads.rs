@@ -1,14 +1,23 @@
 //! APIs for working with JVM threads.
 
 use std::{
-    ffi::{c_uint, CStr, OsStr, OsString},
-    mem::MaybeUninit,
-    os::unix::prelude::OsStrExt,
+    borrow::Cow,
+    ffi::{c_void, OsString},
+    mem::{ManuallyDrop, MaybeUninit},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::PoisonError,
 };
 
-use crate::{macros::call_jvmti, sys};
+use crate::{macros::call_jvmti, prelude::native_call_result, sys};
 
-use super::{errors::JvmTIError, objects::Object, Jvm};
+use super::{
+    errors::{JvmTIError, ThreadError},
+    events::PanicPolicy,
+    jni::{GlobalRef, JNIError, JNI},
+    objects::Object,
+    strings::{ModifiedUtf8Error, ModifiedUtf8Ext, Utf8Policy},
+    Jvm,
+};
 
 #[derive(Debug)]
 pub struct ThreadGroup<'j> {
@@ -27,6 +36,169 @@ impl ThreadGroup<'_> {
         );
         ThreadGroup { jvm, jthread_group }
     }
+
+    /// Creates a [`ThreadGroup`] from a raw JNI reference to the thread group, e.g. one obtained from other JNI code.
+    /// # Safety
+    /// `jthread_group` must be a valid reference to a `java.lang.ThreadGroup` that stays valid while the returned handle is used.
+    /// # Panics
+    /// Panics if `jthread_group` is null.
+    pub unsafe fn from_raw(jvm: &Jvm, jthread_group: sys::jthreadGroup) -> ThreadGroup<'_> {
+        Self::from_ptr(jvm, jthread_group)
+    }
+
+    /// Gets the raw JNI reference to the thread group without giving up the handle.
+    #[must_use]
+    pub fn as_raw(&self) -> sys::jthreadGroup {
+        self.jthread_group
+    }
+
+    /// Gives up the handle and returns the raw JNI reference to the thread group.
+    #[must_use]
+    pub fn into_raw(self) -> sys::jthreadGroup {
+        self.jthread_group
+    }
+}
+
+impl<'j> ThreadGroup<'j> {
+    /// Gets the information about the thread group.
+    /// See [`GetThreadGroupInfo`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetThreadGroupInfo).
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn info(&self) -> Result<ThreadGroupInfo<'j>, ThreadError> {
+        let mut info = MaybeUninit::<sys::jvmtiThreadGroupInfo>::uninit();
+        // SAFETY: `self.jthread_group` is a valid `jthreadGroup`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetThreadGroupInfo,
+                self.jthread_group,
+                info.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `info` has been initialized.
+        let info = unsafe { info.assume_init() };
+        // SAFETY: `info.name` is a JVM TI allocated string.
+        let name = unsafe { self.jvm.take_string(info.name) }?;
+        let parent = (!info.parent.is_null()).then(|| {
+            // SAFETY: A non-null `info.parent` is a valid `jthreadGroup`.
+            unsafe { ThreadGroup::from_ptr(self.jvm, info.parent) }
+        });
+        Ok(ThreadGroupInfo {
+            name,
+            parent,
+            max_priority: info.max_priority,
+            is_daemon: info.is_daemon != 0,
+        })
+    }
+
+    /// Gets the live threads and the subgroups of the thread group.
+    /// See [`GetThreadGroupChildren`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetThreadGroupChildren).
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn children(&self) -> Result<ThreadGroupChildren<'j>, ThreadError> {
+        let mut thread_count = MaybeUninit::uninit();
+        let mut threads = MaybeUninit::uninit();
+        let mut group_count = MaybeUninit::uninit();
+        let mut groups = MaybeUninit::uninit();
+        // SAFETY: `self.jthread_group` is a valid `jthreadGroup`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetThreadGroupChildren,
+                self.jthread_group,
+                thread_count.as_mut_ptr(),
+                threads.as_mut_ptr(),
+                group_count.as_mut_ptr(),
+                groups.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that both arrays have been allocated and hold
+        // valid local references.
+        unsafe {
+            let threads = self
+                .jvm
+                .take_array(threads.assume_init(), thread_count.assume_init())?;
+            let groups = self
+                .jvm
+                .take_array(groups.assume_init(), group_count.assume_init())?;
+            Ok(ThreadGroupChildren {
+                threads: threads
+                    .into_iter()
+                    .map(|it| Thread::from_local(self.jvm, it))
+                    .collect(),
+                groups: groups
+                    .into_iter()
+                    .map(|it| ThreadGroup::from_ptr(self.jvm, it))
+                    .collect(),
+            })
+        }
+    }
+
+    /// Walks the tree of thread groups rooted at this group depth-first, starting with this group.
+    #[must_use]
+    pub fn walk(self) -> ThreadGroupWalk<'j> {
+        ThreadGroupWalk {
+            pending: vec![(0, self)],
+        }
+    }
+}
+
+/// The information about a thread group.
+/// See [`ThreadGroup::info`].
+#[derive(Debug)]
+pub struct ThreadGroupInfo<'j> {
+    /// The name of the thread group.
+    pub name: OsString,
+    /// The parent group, or `None` for a top-level group.
+    pub parent: Option<ThreadGroup<'j>>,
+    /// The maximum priority of the threads in the group.
+    pub max_priority: i32,
+    /// Whether the group is a daemon group.
+    pub is_daemon: bool,
+}
+
+impl ThreadGroupInfo<'_> {
+    /// Gets the name of the thread group decoded according to `policy`.
+    /// # Errors
+    /// Returns a [`ModifiedUtf8Error`] if the name is not valid modified UTF-8 and `policy` is
+    /// [`Utf8Policy::Strict`].
+    pub fn name_utf8(&self, policy: Utf8Policy) -> Result<Cow<'_, str>, ModifiedUtf8Error> {
+        self.name.to_utf8(policy)
+    }
+}
+
+/// The live threads and the subgroups of a thread group.
+/// See [`ThreadGroup::children`].
+#[derive(Debug)]
+pub struct ThreadGroupChildren<'j> {
+    /// The live threads in the group.
+    pub threads: Vec<Thread<'j>>,
+    /// The subgroups of the group.
+    pub groups: Vec<ThreadGroup<'j>>,
+}
+
+/// A depth-first walk of a tree of thread groups, yielding each group with its depth below the
+/// root. A group whose children cannot be listed is yielded as an error and its subtree is skipped.
+/// See [`ThreadGroup::walk`] and [`Jvm::walk_thread_groups`].
+#[derive(Debug)]
+pub struct ThreadGroupWalk<'j> {
+    pending: Vec<(usize, ThreadGroup<'j>)>,
+}
+
+impl<'j> Iterator for ThreadGroupWalk<'j> {
+    type Item = Result<(usize, ThreadGroup<'j>), ThreadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (depth, group) = self.pending.pop()?;
+        match group.children() {
+            Ok(children) => {
+                self.pending
+                    .extend(children.groups.into_iter().rev().map(|it| (depth + 1, it)));
+                Some(Ok((depth, group)))
+            }
+            Err(error) => Some(Err(error)),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -34,31 +206,218 @@ pub struct ThreadInfo<'g, 'l> {
     pub name: OsString,
     pub priority: i32,
     pub is_daemon: bool,
-    pub group: ThreadGroup<'g>,
-    pub context_class_loader: Object<'l>,
+    /// The thread group, or `None` if the thread has terminated.
+    pub group: Option<ThreadGroup<'g>>,
+    /// The context class loader, or `None` if the thread has none, e.g. a system thread.
+    pub context_class_loader: Option<Object<'l>>,
+}
+
+impl ThreadInfo<'_, '_> {
+    /// Gets the name of the thread decoded according to `policy`.
+    /// # Errors
+    /// Returns a [`ModifiedUtf8Error`] if the name is not valid modified UTF-8 and `policy` is
+    /// [`Utf8Policy::Strict`].
+    pub fn name_utf8(&self, policy: Utf8Policy) -> Result<Cow<'_, str>, ModifiedUtf8Error> {
+        self.name.to_utf8(policy)
+    }
 }
 
+bitflags::bitflags! {
+    /// The state of a thread, as returned by [`Thread::state`].
+    /// See [`GetThreadState`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetThreadState).
+    ///
+    /// A thread that has neither [`ThreadState::ALIVE`] nor [`ThreadState::TERMINATED`] has not
+    /// been started yet. The vendor specific bits are kept as they are.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ThreadState: u32 {
+        /// The thread is alive, i.e. it has been started and has not terminated.
+        const ALIVE = sys::JVMTI_THREAD_STATE_ALIVE;
+        /// The thread has completed execution.
+        const TERMINATED = sys::JVMTI_THREAD_STATE_TERMINATED;
+        /// The thread is runnable.
+        const RUNNABLE = sys::JVMTI_THREAD_STATE_RUNNABLE;
+        /// The thread is waiting to enter a synchronized block or method, or to reenter one after
+        /// `Object.wait()`.
+        const BLOCKED_ON_MONITOR_ENTER = sys::JVMTI_THREAD_STATE_BLOCKED_ON_MONITOR_ENTER;
+        /// The thread is waiting.
+        const WAITING = sys::JVMTI_THREAD_STATE_WAITING;
+        /// The thread is waiting without a timeout.
+        const WAITING_INDEFINITELY = sys::JVMTI_THREAD_STATE_WAITING_INDEFINITELY;
+        /// The thread is waiting with a timeout.
+        const WAITING_WITH_TIMEOUT = sys::JVMTI_THREAD_STATE_WAITING_WITH_TIMEOUT;
+        /// The thread is sleeping in `Thread.sleep()`.
+        const SLEEPING = sys::JVMTI_THREAD_STATE_SLEEPING;
+        /// The thread is waiting in `Object.wait()`.
+        const IN_OBJECT_WAIT = sys::JVMTI_THREAD_STATE_IN_OBJECT_WAIT;
+        /// The thread is parked, e.g. in `LockSupport.park()`.
+        const PARKED = sys::JVMTI_THREAD_STATE_PARKED;
+        /// The thread has been suspended.
+        const SUSPENDED = sys::JVMTI_THREAD_STATE_SUSPENDED;
+        /// The thread has been interrupted.
+        const INTERRUPTED = sys::JVMTI_THREAD_STATE_INTERRUPTED;
+        /// The thread is executing native code.
+        const IN_NATIVE = sys::JVMTI_THREAD_STATE_IN_NATIVE;
+        /// Defined by the VM vendor.
+        const VENDOR_1 = sys::JVMTI_THREAD_STATE_VENDOR_1;
+        /// Defined by the VM vendor.
+        const VENDOR_2 = sys::JVMTI_THREAD_STATE_VENDOR_2;
+        /// Defined by the VM vendor.
+        const VENDOR_3 = sys::JVMTI_THREAD_STATE_VENDOR_3;
+    }
+}
+
+impl ThreadState {
+    /// Returns whether the thread has been started and has not terminated.
+    #[must_use]
+    pub fn is_alive(self) -> bool {
+        self.contains(Self::ALIVE)
+    }
+
+    /// Returns whether the thread has not been started yet.
+    #[must_use]
+    pub fn is_new(self) -> bool {
+        !self.intersects(Self::ALIVE | Self::TERMINATED)
+    }
+
+    /// Returns whether the thread has completed execution.
+    #[must_use]
+    pub fn is_terminated(self) -> bool {
+        self.contains(Self::TERMINATED)
+    }
+
+    /// Returns whether the thread is runnable.
+    #[must_use]
+    pub fn is_runnable(self) -> bool {
+        self.contains(Self::ALIVE | Self::RUNNABLE)
+    }
+
+    /// Returns whether the thread is blocked waiting for a monitor.
+    #[must_use]
+    pub fn is_blocked_on_monitor(self) -> bool {
+        self.contains(Self::ALIVE | Self::BLOCKED_ON_MONITOR_ENTER)
+    }
+
+    /// Returns whether the thread is waiting, e.g. sleeping, parked, or in `Object.wait()`.
+    #[must_use]
+    pub fn is_waiting(self) -> bool {
+        self.contains(Self::ALIVE | Self::WAITING)
+    }
+
+    /// Returns whether the thread is waiting with a timeout.
+    #[must_use]
+    pub fn is_timed_waiting(self) -> bool {
+        self.contains(Self::ALIVE | Self::WAITING | Self::WAITING_WITH_TIMEOUT)
+    }
+
+    /// Returns whether the thread is sleeping in `Thread.sleep()`.
+    #[must_use]
+    pub fn is_sleeping(self) -> bool {
+        self.contains(Self::ALIVE | Self::SLEEPING)
+    }
+
+    /// Returns whether the thread is parked.
+    #[must_use]
+    pub fn is_parked(self) -> bool {
+        self.contains(Self::ALIVE | Self::PARKED)
+    }
+
+    /// Returns whether the thread has been suspended.
+    #[must_use]
+    pub fn is_suspended(self) -> bool {
+        self.contains(Self::SUSPENDED)
+    }
+
+    /// Returns whether the thread has been interrupted.
+    #[must_use]
+    pub fn is_interrupted(self) -> bool {
+        self.contains(Self::INTERRUPTED)
+    }
+
+    /// Returns whether the thread is executing native code.
+    #[must_use]
+    pub fn is_in_native(self) -> bool {
+        self.contains(Self::IN_NATIVE)
+    }
+}
+
+/// A Java thread.
+///
+/// A handle wraps a JNI local reference of the thread it was obtained on. Handles given to event
+/// callbacks, or created with [`Thread::from_raw`], borrow a reference owned by someone else and are
+/// only valid while it is, e.g. until the callback returns. Handles returned by the functions of
+/// this crate own the local reference they were given and delete it when dropped, so that long
+/// loops do not exhaust the local reference table. Either way, a handle must not be used after its
+/// thread leaves the native method or callback it was obtained in; use [`Thread::to_global`] to keep
+/// the thread longer or to use it on another thread.
 #[derive(Debug)]
 pub struct Thread<'j> {
-    jvm: &'j Jvm,
-    jthread: sys::jthread,
+    pub(crate) jvm: &'j Jvm,
+    pub(crate) jthread: sys::jthread,
+    /// Whether the handle owns its local reference and deletes it when dropped.
+    owned: bool,
 }
 
 impl Thread<'_> {
-    pub fn into_raw(self) -> sys::jthread {
+    /// Creates a [`Thread`] from a raw JNI reference to the thread, e.g. one obtained from other JNI code.
+    /// # Safety
+    /// `jthread` must be a valid reference to a `java.lang.Thread` that stays valid while the returned handle is used.
+    /// # Panics
+    /// Panics if `jthread` is null.
+    pub unsafe fn from_raw(jvm: &Jvm, jthread: sys::jthread) -> Thread<'_> {
+        Self::from_ptr(jvm, jthread)
+    }
+
+    /// Gets the raw JNI reference to the thread without giving up the handle.
+    #[must_use]
+    pub fn as_raw(&self) -> sys::jthread {
         self.jthread
     }
 
-    pub fn info(&self) -> Result<ThreadInfo<'_, '_>, JvmTIError> {
+    /// Gives up the handle and returns the raw JNI reference to the thread. The reference is not
+    /// deleted, so the caller becomes responsible for a local reference owned by the handle.
+    #[must_use]
+    pub fn into_raw(self) -> sys::jthread {
+        ManuallyDrop::new(self).jthread
+    }
+
+    /// Creates a global reference to the thread, which can outlive the handle and be sent to other
+    /// threads.
+    /// See [`NewGlobalRef`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#newglobalref).
+    /// # Errors
+    /// Returns [`JNIError::OutOfMemory`] if the VM runs out of memory.
+    pub fn to_global(&self, jni: &JNI) -> Result<GlobalRef<Thread<'static>>, JNIError> {
+        GlobalRef::new(jni, self)
+    }
+
+    /// Returns whether `self` and `other` refer to the same thread. Handles are local references,
+    /// so two handles for the same thread are usually different pointers.
+    /// See [`IsSameObject`](https://docs.oracle.com/en/java/javase/21/docs/specs/jni/functions.html#issameobject).
+    #[must_use]
+    pub fn is_same(&self, jni: &JNI, other: &Thread<'_>) -> bool {
+        // SAFETY: Both are valid thread references.
+        unsafe { jni.is_same_object(self.jthread, other.jthread) }
+    }
+
+    /// Gets the information about the thread.
+    /// See [`GetThreadInfo`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetThreadInfo).
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn info(&self) -> Result<ThreadInfo<'_, '_>, ThreadError> {
         let native_thread_info = unsafe { self.jvm.get_thread_info(self.jthread) }?;
-        let name_c = unsafe { CStr::from_ptr(native_thread_info.name) };
-        let name = OsStr::from_bytes(name_c.to_bytes()).to_owned();
+        // SAFETY: `native_thread_info.name` is a JVM TI allocated string.
+        let name = unsafe { self.jvm.take_string(native_thread_info.name) }?;
         let priority = native_thread_info.priority;
         let is_daemon = native_thread_info.is_daemon != 0;
-        // SAFETY: `native_thread_info.thread_group` is a valid `sys::jthreadGroup` as garanteed by the JVM TI API.
-        let group = unsafe { ThreadGroup::from_ptr(self.jvm, native_thread_info.thread_group) };
+        let group = (!native_thread_info.thread_group.is_null()).then(|| {
+            // SAFETY: A non-null `native_thread_info.thread_group` is a valid `sys::jthreadGroup`.
+            unsafe { ThreadGroup::from_ptr(self.jvm, native_thread_info.thread_group) }
+        });
         let context_class_loader =
-            unsafe { Object::from_ptr(self.jvm, native_thread_info.context_class_loader) };
+            (!native_thread_info.context_class_loader.is_null()).then(|| {
+                // SAFETY: A non-null `native_thread_info.context_class_loader` is a local reference
+                // created for the caller.
+                unsafe { Object::from_local(self.jvm, native_thread_info.context_class_loader) }
+            });
         Ok(ThreadInfo {
             name,
             priority,
@@ -70,11 +429,545 @@ impl Thread<'_> {
 
     pub(crate) unsafe fn from_ptr<'j>(jvm: &'j Jvm, jthread: sys::jthread) -> Thread<'j> {
         assert!(!jthread.is_null(), "The thread pointer must not be null");
-        Thread { jvm, jthread }
+        Thread {
+            jvm,
+            jthread,
+            owned: false,
+        }
+    }
+
+    /// Creates a handle that owns `jthread`, a local reference created by this crate, and deletes it
+    /// when dropped.
+    /// # Safety
+    /// `jthread` must be a valid local reference of the current thread that is not used elsewhere.
+    pub(crate) unsafe fn from_local(jvm: &Jvm, jthread: sys::jthread) -> Thread<'_> {
+        Thread {
+            owned: true,
+            ..Self::from_ptr(jvm, jthread)
+        }
+    }
+
+    /// Gets the state of the thread.
+    /// See [`GetThreadState`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetThreadState).
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn state(&self) -> Result<ThreadState, ThreadError> {
+        let mut state = MaybeUninit::uninit();
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetThreadState,
+                self.jthread,
+                state.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `state` has been initialized.
+        Ok(ThreadState::from_bits_retain(
+            unsafe { state.assume_init() }.cast_unsigned(),
+        ))
+    }
+}
+
+impl Thread<'_> {
+    /// The minimum priority of a thread.
+    pub const MIN_PRIORITY: i32 = sys::JVMTI_THREAD_MIN_PRIORITY.cast_signed();
+    /// The default priority of a thread.
+    pub const NORM_PRIORITY: i32 = sys::JVMTI_THREAD_NORM_PRIORITY.cast_signed();
+    /// The maximum priority of a thread.
+    pub const MAX_PRIORITY: i32 = sys::JVMTI_THREAD_MAX_PRIORITY.cast_signed();
+}
+
+impl<'j> Thread<'j> {
+    /// Suspends the thread. Suspending the current thread blocks until another thread resumes it.
+    /// [`Thread::suspend_guard`] resumes the thread automatically.
+    /// See [`SuspendThread`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SuspendThread).
+    /// # Errors
+    /// See [`ThreadError`] for more information. The `can_suspend` capability is required.
+    pub fn suspend(&self) -> Result<(), ThreadError> {
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        unsafe { call_jvmti!(self.jvm.jvmti_ptr, SuspendThread, self.jthread) }?;
+        Ok(())
+    }
+
+    /// Resumes the thread suspended with [`Thread::suspend`].
+    /// See [`ResumeThread`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#ResumeThread).
+    /// # Errors
+    /// See [`ThreadError`] for more information. The `can_suspend` capability is required.
+    pub fn resume(&self) -> Result<(), ThreadError> {
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        unsafe { call_jvmti!(self.jvm.jvmti_ptr, ResumeThread, self.jthread) }?;
+        Ok(())
+    }
+
+    /// Suspends the thread until the returned guard is dropped, e.g. to inspect its stack
+    /// consistently. It must not be used on the current thread, which would never be resumed.
+    /// See [`SuspendThread`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SuspendThread).
+    /// # Errors
+    /// See [`ThreadError`] for more information. The `can_suspend` capability is required.
+    pub fn suspend_guard(&self) -> Result<SuspensionGuard<'_, 'j>, ThreadError> {
+        self.suspend()?;
+        Ok(SuspensionGuard { thread: self })
+    }
+
+    /// Gets the monitors owned by the thread.
+    /// See [`GetOwnedMonitorInfo`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetOwnedMonitorInfo).
+    /// # Errors
+    /// See [`ThreadError`] for more information. The `can_get_owned_monitor_info` capability is
+    /// required.
+    pub fn owned_monitors(&self) -> Result<Vec<Object<'j>>, ThreadError> {
+        let mut count = MaybeUninit::uninit();
+        let mut monitors = MaybeUninit::uninit();
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetOwnedMonitorInfo,
+                self.jthread,
+                count.as_mut_ptr(),
+                monitors.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `monitors` points to an array of `count`
+        // local references.
+        unsafe {
+            let count = usize::try_from(count.assume_init()).unwrap_or_default();
+            let monitors = monitors.assume_init();
+            let owned = if count == 0 {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(monitors, count)
+                    .iter()
+                    .map(|&it| Object::from_local(self.jvm, it))
+                    .collect()
+            };
+            self.jvm.deallocate(monitors)?;
+            Ok(owned)
+        }
+    }
+
+    /// Gets the monitors owned by the thread together with the depth of the stack frame that
+    /// locked each of them.
+    /// See [`GetOwnedMonitorStackDepthInfo`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetOwnedMonitorStackDepthInfo).
+    /// # Errors
+    /// See [`ThreadError`] for more information. The `can_get_owned_monitor_stack_depth_info`
+    /// capability is required.
+    pub fn owned_monitor_stack_depths(&self) -> Result<Vec<OwnedMonitor<'j>>, ThreadError> {
+        let mut count = MaybeUninit::uninit();
+        let mut monitors = MaybeUninit::uninit();
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetOwnedMonitorStackDepthInfo,
+                self.jthread,
+                count.as_mut_ptr(),
+                monitors.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `monitors` points to an array of `count`
+        // entries holding local references.
+        unsafe {
+            let count = usize::try_from(count.assume_init()).unwrap_or_default();
+            let monitors = monitors.assume_init();
+            let owned = if count == 0 {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(monitors, count)
+                    .iter()
+                    .map(|it| OwnedMonitor {
+                        monitor: Object::from_local(self.jvm, it.monitor),
+                        stack_depth: usize::try_from(it.stack_depth).ok(),
+                    })
+                    .collect()
+            };
+            self.jvm.deallocate(monitors)?;
+            Ok(owned)
+        }
+    }
+
+    /// Gets the monitor the thread is waiting to enter or waiting on, if any.
+    /// See [`GetCurrentContendedMonitor`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetCurrentContendedMonitor).
+    /// # Errors
+    /// See [`ThreadError`] for more information. The `can_get_current_contended_monitor`
+    /// capability is required.
+    pub fn contended_monitor(&self) -> Result<Option<Object<'j>>, ThreadError> {
+        let mut monitor = MaybeUninit::uninit();
+        // SAFETY: `self.jthread` is a valid `jthread`.
+        unsafe {
+            call_jvmti!(
+                self.jvm.jvmti_ptr,
+                GetCurrentContendedMonitor,
+                self.jthread,
+                monitor.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `monitor` is null or a valid local reference.
+        let monitor = unsafe { monitor.assume_init() };
+        // SAFETY: `monitor` is not null.
+        Ok((!monitor.is_null()).then(|| unsafe { Object::from_local(self.jvm, monitor) }))
+    }
+}
+
+/// A monitor owned by a thread, see [`Thread::owned_monitor_stack_depths`].
+#[derive(Debug)]
+pub struct OwnedMonitor<'j> {
+    /// The monitor.
+    pub monitor: Object<'j>,
+    /// The depth of the stack frame that locked the monitor, with `0` being the current frame,
+    /// or `None` if it is unknown, e.g. for monitors locked by JNI `MonitorEnter`.
+    pub stack_depth: Option<usize>,
+}
+
+/// An error returned by [`Jvm::spawn_agent_thread`].
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The `java.lang.Thread` object could not be created.
+    #[error(transparent)]
+    Jni(#[from] JNIError),
+    /// The thread could not be started.
+    #[error(transparent)]
+    Thread(#[from] ThreadError),
+}
+
+/// A suspended thread, resumed when the guard is dropped.
+/// See [`Thread::suspend_guard`].
+#[derive(Debug)]
+#[must_use = "the thread is resumed as soon as the guard is dropped"]
+pub struct SuspensionGuard<'a, 'j> {
+    thread: &'a Thread<'j>,
+}
+
+impl<'a, 'j> SuspensionGuard<'a, 'j> {
+    /// Gets the suspended thread.
+    #[must_use]
+    pub fn thread(&self) -> &'a Thread<'j> {
+        self.thread
+    }
+
+    /// Resumes the thread, reporting the error that dropping the guard would ignore.
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn resume(self) -> Result<(), ThreadError> {
+        let thread = self.thread;
+        std::mem::forget(self);
+        thread.resume()
+    }
+}
+
+impl<'j> std::ops::Deref for SuspensionGuard<'_, 'j> {
+    type Target = Thread<'j>;
+
+    fn deref(&self) -> &Self::Target {
+        self.thread
+    }
+}
+
+impl Drop for SuspensionGuard<'_, '_> {
+    fn drop(&mut self) {
+        // Nothing sensible can be done if resuming fails in a destructor.
+        let _ = self.thread.resume();
     }
 }
 
 impl Jvm {
+    /// Gets all the live platform threads. For a large number of threads,
+    /// [`Jvm::all_threads_chunked`] bounds the number of local references held at once.
+    /// See [`GetAllThreads`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetAllThreads).
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn get_all_threads(&self) -> Result<Vec<Thread<'_>>, ThreadError> {
+        let mut count = MaybeUninit::uninit();
+        let mut threads = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetAllThreads,
+                count.as_mut_ptr(),
+                threads.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `threads` points to an array of `count`
+        // local references.
+        unsafe {
+            let count = usize::try_from(count.assume_init()).unwrap_or_default();
+            let threads = threads.assume_init();
+            let all = if count == 0 {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(threads, count)
+                    .iter()
+                    .map(|&it| Thread::from_local(self, it))
+                    .collect()
+            };
+            self.deallocate(threads)?;
+            Ok(all)
+        }
+    }
+
+    /// Gets the top-level thread groups.
+    /// See [`GetTopThreadGroups`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetTopThreadGroups).
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn top_thread_groups(&self) -> Result<Vec<ThreadGroup<'_>>, ThreadError> {
+        let mut count = MaybeUninit::uninit();
+        let mut groups = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                GetTopThreadGroups,
+                count.as_mut_ptr(),
+                groups.as_mut_ptr()
+            )
+        }?;
+        // SAFETY: A successful result indicates that `groups` points to an array of `count` local
+        // references.
+        let groups = unsafe { self.take_array(groups.assume_init(), count.assume_init()) }?;
+        Ok(groups
+            .into_iter()
+            // SAFETY: Each element is a valid `jthreadGroup`.
+            .map(|it| unsafe { ThreadGroup::from_ptr(self, it) })
+            .collect())
+    }
+
+    /// Walks all the thread groups depth-first, starting with the top-level groups.
+    /// See [`ThreadGroupWalk`].
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn walk_thread_groups(&self) -> Result<ThreadGroupWalk<'_>, ThreadError> {
+        let mut pending: Vec<_> = self
+            .top_thread_groups()?
+            .into_iter()
+            .map(|it| (0, it))
+            .collect();
+        pending.reverse();
+        Ok(ThreadGroupWalk { pending })
+    }
+
+    /// Gets the current thread, or `None` if the function is not called from a Java thread, e.g.
+    /// during the `OnLoad` phase.
+    /// See [`GetCurrentThread`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetCurrentThread).
+    /// # Errors
+    /// See [`ThreadError`] for more information.
+    pub fn current_thread(&self) -> Result<Option<Thread<'_>>, ThreadError> {
+        let mut thread = MaybeUninit::uninit();
+        // SAFETY: `self.jvmti_ptr` is a valid `sys::jvmtiEnv`.
+        unsafe { call_jvmti!(self.jvmti_ptr, GetCurrentThread, thread.as_mut_ptr()) }?;
+        // SAFETY: A successful result indicates that `thread` has been initialized.
+        let thread = unsafe { thread.assume_init() };
+        // SAFETY: `thread` is not null.
+        Ok((!thread.is_null()).then(|| unsafe { Thread::from_local(self, thread) }))
+    }
+
+    /// Starts a daemon thread named `name` running `body` with a JVM TI and a JNI environment
+    /// attached to it. The thread is known to the VM, e.g. it shows up in thread dumps, and keeps
+    /// running until `body` returns. A panic in `body` is handled according to the
+    /// [`PanicPolicy`] instead of unwinding into the VM.
+    ///
+    /// This can only be called in the live phase, e.g. from the `VMInit` event. `priority` ranges
+    /// from [`Thread::MIN_PRIORITY`] to [`Thread::MAX_PRIORITY`].
+    /// See [`RunAgentThread`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#RunAgentThread).
+    /// # Errors
+    /// Returns [`SpawnError::Jni`] if the `java.lang.Thread` object cannot be created, and
+    /// [`SpawnError::Thread`] if the thread cannot be started. See [`ThreadError`] for more
+    /// information.
+    pub fn spawn_agent_thread<F>(
+        &self,
+        jni: &JNI,
+        name: &str,
+        priority: i32,
+        body: F,
+    ) -> Result<Thread<'_>, SpawnError>
+    where
+        F: FnOnce(&Jvm, &JNI) + Send + 'static,
+    {
+        unsafe extern "C" fn start<F: FnOnce(&Jvm, &JNI)>(
+            jvmti_env: *mut sys::jvmtiEnv,
+            jni_env: *mut sys::JNIEnv,
+            arg: *mut c_void,
+        ) {
+            // SAFETY: `arg` is the leaked box passed to `RunAgentThread` below, which is only
+            // started once.
+            let body = unsafe { Box::from_raw(arg.cast::<F>()) };
+            // SAFETY: The environments are valid for the agent thread.
+            let (jvm, jni) = unsafe { (Jvm::from_ptr(jvmti_env), JNI::from_ptr(jni_env)) };
+            // A panic must not unwind into the VM, which would abort the process.
+            if catch_unwind(AssertUnwindSafe(|| body(jvm, &jni))).is_err() {
+                // The panic message has already been printed by the panic hook.
+                match jvm.panic_policy {
+                    PanicPolicy::Abort => std::process::abort(),
+                    _ => eprintln!("coffee-filter: an agent thread panicked"),
+                }
+            }
+            if let Ok(Some(current)) = jvm.current_thread() {
+                jvm.unregister_agent_thread(&jni, &current);
+            }
+        }
+
+        let jthread = jni.new_thread(name)?;
+        // SAFETY: `jthread` is a valid local reference returned by `JNI::new_thread`.
+        let thread = unsafe { Thread::from_local(self, jthread) };
+        // The thread is registered before it starts so that it cannot end before being registered.
+        // SAFETY: `jthread` is a valid, non-null reference.
+        let registration =
+            unsafe { GlobalRef::from_raw(jni, jthread) }.ok_or(JNIError::OutOfMemory)?;
+        self.agent_threads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(registration);
+        let arg = Box::into_raw(Box::new(body));
+        // SAFETY: `start::<F>` takes ownership of `arg` when the thread starts.
+        let result = unsafe {
+            call_jvmti!(
+                self.jvmti_ptr,
+                RunAgentThread,
+                jthread,
+                Some(start::<F>),
+                arg.cast_const().cast(),
+                priority
+            )
+        };
+        if let Err(error) = result {
+            // SAFETY: The thread has not been started, so `arg` is still owned here.
+            drop(unsafe { Box::from_raw(arg) });
+            self.unregister_agent_thread(jni, &thread);
+            return Err(SpawnError::Thread(error.into()));
+        }
+        Ok(thread)
+    }
+
+    fn unregister_agent_thread(&self, jni: &JNI, thread: &Thread<'_>) {
+        self.agent_threads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|it| !it.get(self).is_same(jni, thread));
+    }
+
+    /// Returns whether `thread` was started with [`Jvm::spawn_agent_thread`] and is still running.
+    pub(crate) fn is_agent_thread(&self, jni: &JNI, thread: &Thread<'_>) -> bool {
+        self.agent_threads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|it| it.get(self).is_same(jni, thread))
+    }
+
+    /// Suspends the given threads in a single call, returning the result for each of them in the
+    /// same order. Threads that are already suspended or no longer alive fail individually with
+    /// [`ThreadError::ThreadSuspended`] or [`ThreadError::ThreadNotAlive`] without affecting the
+    /// others. The current thread, if listed, is suspended only after the others.
+    /// See [`SuspendThreadList`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SuspendThreadList).
+    /// # Errors
+    /// Returns an error if the request as a whole fails, e.g. because the `can_suspend` capability
+    /// is missing. See [`ThreadError`] for more information.
+    pub fn suspend_thread_list(
+        &self,
+        threads: &[&Thread<'_>],
+    ) -> Result<Vec<Result<(), ThreadError>>, ThreadError> {
+        self.thread_list_operation(threads, true)
+    }
+
+    /// Resumes the given threads in a single call, returning the result for each of them in the
+    /// same order. Threads that are not suspended fail individually with
+    /// [`ThreadError::ThreadNotSuspended`] without affecting the others.
+    /// See [`ResumeThreadList`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#ResumeThreadList).
+    /// # Errors
+    /// Returns an error if the request as a whole fails, e.g. because the `can_suspend` capability
+    /// is missing. See [`ThreadError`] for more information.
+    pub fn resume_thread_list(
+        &self,
+        threads: &[&Thread<'_>],
+    ) -> Result<Vec<Result<(), ThreadError>>, ThreadError> {
+        self.thread_list_operation(threads, false)
+    }
+
+    /// Suspends all the virtual threads except those in `except`.
+    /// See [`SuspendAllVirtualThreads`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SuspendAllVirtualThreads).
+    /// # Errors
+    /// See [`ThreadError`] for more information. The `can_suspend` and
+    /// `can_support_virtual_threads` capabilities are required.
+    pub fn suspend_all_virtual_threads(&self, except: &[&Thread<'_>]) -> Result<(), ThreadError> {
+        self.all_virtual_threads_operation(except, true)
+    }
+
+    /// Resumes all the virtual threads except those in `except`.
+    /// See [`ResumeAllVirtualThreads`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#ResumeAllVirtualThreads).
+    /// # Errors
+    /// See [`ThreadError`] for more information. The `can_suspend` and
+    /// `can_support_virtual_threads` capabilities are required.
+    pub fn resume_all_virtual_threads(&self, except: &[&Thread<'_>]) -> Result<(), ThreadError> {
+        self.all_virtual_threads_operation(except, false)
+    }
+
+    fn all_virtual_threads_operation(
+        &self,
+        except: &[&Thread<'_>],
+        suspend: bool,
+    ) -> Result<(), ThreadError> {
+        let jthreads: Vec<_> = except.iter().map(|it| it.jthread).collect();
+        let count =
+            sys::jint::try_from(jthreads.len()).map_err(|_| ThreadError::IllegalArgument)?;
+        // SAFETY: `jthreads` holds `count` elements.
+        unsafe {
+            if suspend {
+                call_jvmti!(
+                    self.jvmti_ptr,
+                    SuspendAllVirtualThreads,
+                    count,
+                    jthreads.as_ptr()
+                )
+            } else {
+                call_jvmti!(
+                    self.jvmti_ptr,
+                    ResumeAllVirtualThreads,
+                    count,
+                    jthreads.as_ptr()
+                )
+            }
+        }?;
+        Ok(())
+    }
+
+    fn thread_list_operation(
+        &self,
+        threads: &[&Thread<'_>],
+        suspend: bool,
+    ) -> Result<Vec<Result<(), ThreadError>>, ThreadError> {
+        if threads.is_empty() {
+            return Ok(Vec::new());
+        }
+        let jthreads: Vec<_> = threads.iter().map(|it| it.jthread).collect();
+        let count =
+            sys::jint::try_from(jthreads.len()).map_err(|_| ThreadError::IllegalArgument)?;
+        let mut results = vec![sys::JVMTI_ERROR_NONE; jthreads.len()];
+        // SAFETY: `jthreads` and `results` both hold `count` elements.
+        unsafe {
+            if suspend {
+                call_jvmti!(
+                    self.jvmti_ptr,
+                    SuspendThreadList,
+                    count,
+                    jthreads.as_ptr(),
+                    results.as_mut_ptr()
+                )
+            } else {
+                call_jvmti!(
+                    self.jvmti_ptr,
+                    ResumeThreadList,
+                    count,
+                    jthreads.as_ptr(),
+                    results.as_mut_ptr()
+                )
+            }
+        }?;
+        Ok(results
+            .into_iter()
+            .map(|it| native_call_result(it).map_err(ThreadError::from))
+            .collect())
+    }
+
     unsafe fn get_thread_info(
         &self,
         jthread: sys::jthread,
@@ -88,4 +981,91 @@ impl Jvm {
         )
         .map(|_| thread_info.assume_init())
     }
+
+    /// Gets the value of the JVM TI thread-local storage of `jthread`, or of the current thread
+    /// if `jthread` is null.
+    /// See [`GetThreadLocalStorage`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#GetThreadLocalStorage).
+    pub(crate) unsafe fn get_thread_local_storage(
+        &self,
+        jthread: sys::jthread,
+    ) -> Result<*mut c_void, JvmTIError> {
+        let mut data = MaybeUninit::uninit();
+        call_jvmti!(
+            self.jvmti_ptr,
+            GetThreadLocalStorage,
+            jthread,
+            data.as_mut_ptr()
+        )
+        .map(|()| data.assume_init())
+    }
+
+    /// Sets the value of the JVM TI thread-local storage of `jthread`, or of the current thread
+    /// if `jthread` is null.
+    /// See [`SetThreadLocalStorage`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#SetThreadLocalStorage).
+    pub(crate) unsafe fn set_thread_local_storage(
+        &self,
+        jthread: sys::jthread,
+        data: *const c_void,
+    ) -> Result<(), JvmTIError> {
+        call_jvmti!(self.jvmti_ptr, SetThreadLocalStorage, jthread, data)
+    }
+}
+
+impl Drop for Thread<'_> {
+    fn drop(&mut self) {
+        if self.owned {
+            // SAFETY: An owned handle is the only user of its local reference.
+            unsafe { self.jvm.delete_local_ref(self.jthread) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_lifecycle_states() {
+        let new = ThreadState::empty();
+        assert!(new.is_new() && !new.is_alive() && !new.is_terminated());
+        let terminated = ThreadState::TERMINATED;
+        assert!(terminated.is_terminated() && !terminated.is_new() && !terminated.is_runnable());
+        let runnable = ThreadState::ALIVE | ThreadState::RUNNABLE | ThreadState::IN_NATIVE;
+        assert!(runnable.is_alive() && runnable.is_runnable() && runnable.is_in_native());
+        assert!(!runnable.is_waiting() && !runnable.is_blocked_on_monitor());
+    }
+
+    #[test]
+    fn reads_waiting_states() {
+        // The value `GetThreadState` reports for a thread in `Thread.sleep(long)`.
+        let sleeping = ThreadState::from_bits_retain(
+            sys::JVMTI_THREAD_STATE_ALIVE
+                | sys::JVMTI_THREAD_STATE_WAITING
+                | sys::JVMTI_THREAD_STATE_WAITING_WITH_TIMEOUT
+                | sys::JVMTI_THREAD_STATE_SLEEPING,
+        );
+        assert!(sleeping.is_waiting() && sleeping.is_timed_waiting() && sleeping.is_sleeping());
+        assert!(!sleeping.is_parked() && !sleeping.is_runnable());
+        let parked = ThreadState::ALIVE
+            | ThreadState::WAITING
+            | ThreadState::WAITING_INDEFINITELY
+            | ThreadState::PARKED;
+        assert!(parked.is_waiting() && parked.is_parked() && !parked.is_timed_waiting());
+        let blocked = ThreadState::ALIVE | ThreadState::BLOCKED_ON_MONITOR_ENTER;
+        assert!(blocked.is_blocked_on_monitor() && !blocked.is_waiting());
+        // A thread that is not alive is not waiting, whatever the other bits say.
+        assert!(!(ThreadState::WAITING | ThreadState::PARKED).is_parked());
+    }
+
+    #[test]
+    fn keeps_vendor_and_flag_bits() {
+        let state = ThreadState::from_bits_retain(
+            sys::JVMTI_THREAD_STATE_ALIVE
+                | sys::JVMTI_THREAD_STATE_SUSPENDED
+                | sys::JVMTI_THREAD_STATE_INTERRUPTED
+                | sys::JVMTI_THREAD_STATE_VENDOR_2,
+        );
+        assert!(state.is_suspended() && state.is_interrupted());
+        assert!(state.contains(ThreadState::VENDOR_2));
+    }
 }
@@ -0,0 +1,225 @@
+//! Java values exchanged with the JVM TI.
+
+use crate::sys;
+
+use super::objects::Object;
+#[cfg(any(feature = "debug-events", feature = "method-events"))]
+use super::Jvm;
+
+/// The type of a Java value, as denoted by a JNI type descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum JType {
+    /// `void`, only valid as a return type.
+    Void,
+    /// `boolean`.
+    Boolean,
+    /// `byte`.
+    Byte,
+    /// `char`.
+    Char,
+    /// `short`.
+    Short,
+    /// `int`.
+    Int,
+    /// `long`.
+    Long,
+    /// `float`.
+    Float,
+    /// `double`.
+    Double,
+    /// A class, interface, or array type.
+    Object,
+}
+
+impl JType {
+    /// Gets the type denoted by the type descriptor `descriptor`, e.g. `I` or `Ljava/lang/String;`.
+    #[must_use]
+    pub fn from_descriptor(descriptor: &[u8]) -> Option<Self> {
+        match descriptor.first()? {
+            b'V' => Some(Self::Void),
+            b'Z' => Some(Self::Boolean),
+            b'B' => Some(Self::Byte),
+            b'C' => Some(Self::Char),
+            b'S' => Some(Self::Short),
+            b'I' => Some(Self::Int),
+            b'J' => Some(Self::Long),
+            b'F' => Some(Self::Float),
+            b'D' => Some(Self::Double),
+            b'L' | b'[' => Some(Self::Object),
+            _ => None,
+        }
+    }
+
+    /// Gets the return type of the method descriptor `descriptor`, e.g. `(I)V`.
+    #[must_use]
+    pub fn return_type_of(descriptor: &[u8]) -> Option<Self> {
+        let parameters_end = descriptor.iter().position(|&it| it == b')')?;
+        Self::from_descriptor(&descriptor[parameters_end + 1..])
+    }
+}
+
+/// A Java value of a primitive or reference type.
+#[derive(Debug)]
+pub enum JValue<'j> {
+    /// A `boolean`.
+    Boolean(bool),
+    /// A `byte`.
+    Byte(i8),
+    /// A `char`, which is a UTF-16 code unit.
+    Char(u16),
+    /// A `short`.
+    Short(i16),
+    /// An `int`.
+    Int(i32),
+    /// A `long`.
+    Long(i64),
+    /// A `float`.
+    Float(f32),
+    /// A `double`.
+    Double(f64),
+    /// A reference, or `None` for `null`.
+    Object(Option<Object<'j>>),
+}
+
+impl<'j> JValue<'j> {
+    /// Decodes `raw` as a value of type `ty`. Returns `None` for [`JType::Void`].
+    /// # Safety
+    /// The member of `raw` corresponding to `ty` must be initialized, and a reference must be
+    /// either null or a valid local reference.
+    #[cfg(any(feature = "debug-events", feature = "method-events"))]
+    pub(crate) unsafe fn from_raw(jvm: &'j Jvm, raw: sys::jvalue, ty: JType) -> Option<Self> {
+        let value = match ty {
+            JType::Void => return None,
+            JType::Boolean => Self::Boolean(raw.z != 0),
+            JType::Byte => Self::Byte(raw.b),
+            JType::Char => Self::Char(raw.c),
+            JType::Short => Self::Short(raw.s),
+            JType::Int => Self::Int(raw.i),
+            JType::Long => Self::Long(raw.j),
+            JType::Float => Self::Float(raw.f),
+            JType::Double => Self::Double(raw.d),
+            JType::Object => Self::Object((!raw.l.is_null()).then(|| Object::from_ptr(jvm, raw.l))),
+        };
+        Some(value)
+    }
+
+    /// Decodes an `int` passed by the JVM for a value of type `ty`, which must be `boolean`, `byte`,
+    /// `char`, `short`, or `int`.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub(crate) fn from_int(value: sys::jint, ty: JType) -> Option<Self> {
+        match ty {
+            JType::Boolean => Some(Self::Boolean(value != 0)),
+            JType::Byte => Some(Self::Byte(value as i8)),
+            JType::Char => Some(Self::Char(value as u16)),
+            JType::Short => Some(Self::Short(value as i16)),
+            JType::Int => Some(Self::Int(value)),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of a `boolean`, `byte`, `char`, `short`, or `int` widened to an `int`, as the
+    /// JVM passes these types, or `None` for the other types.
+    pub(crate) fn as_int(&self) -> Option<sys::jint> {
+        match *self {
+            Self::Boolean(value) => Some(sys::jint::from(value)),
+            Self::Byte(value) => Some(sys::jint::from(value)),
+            Self::Char(value) => Some(sys::jint::from(value)),
+            Self::Short(value) => Some(sys::jint::from(value)),
+            Self::Int(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Gets the type of the value.
+    #[must_use]
+    pub fn ty(&self) -> JType {
+        match self {
+            Self::Boolean(_) => JType::Boolean,
+            Self::Byte(_) => JType::Byte,
+            Self::Char(_) => JType::Char,
+            Self::Short(_) => JType::Short,
+            Self::Int(_) => JType::Int,
+            Self::Long(_) => JType::Long,
+            Self::Float(_) => JType::Float,
+            Self::Double(_) => JType::Double,
+            Self::Object(_) => JType::Object,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_type_descriptors() {
+        assert_eq!(JType::from_descriptor(b"V"), Some(JType::Void));
+        assert_eq!(JType::from_descriptor(b"Z"), Some(JType::Boolean));
+        assert_eq!(JType::from_descriptor(b"B"), Some(JType::Byte));
+        assert_eq!(JType::from_descriptor(b"C"), Some(JType::Char));
+        assert_eq!(JType::from_descriptor(b"S"), Some(JType::Short));
+        assert_eq!(JType::from_descriptor(b"I"), Some(JType::Int));
+        assert_eq!(JType::from_descriptor(b"J"), Some(JType::Long));
+        assert_eq!(JType::from_descriptor(b"F"), Some(JType::Float));
+        assert_eq!(JType::from_descriptor(b"D"), Some(JType::Double));
+        assert_eq!(
+            JType::from_descriptor(b"Ljava/lang/String;"),
+            Some(JType::Object)
+        );
+        assert_eq!(JType::from_descriptor(b"[I"), Some(JType::Object));
+        assert_eq!(JType::from_descriptor(b"Q"), None);
+        assert_eq!(JType::from_descriptor(b""), None);
+    }
+
+    #[test]
+    fn parses_return_types_of_method_descriptors() {
+        assert_eq!(JType::return_type_of(b"()V"), Some(JType::Void));
+        assert_eq!(
+            JType::return_type_of(b"(ILjava/lang/String;)J"),
+            Some(JType::Long)
+        );
+        assert_eq!(
+            JType::return_type_of(b"([I)[Ljava/lang/Object;"),
+            Some(JType::Object)
+        );
+        assert_eq!(JType::return_type_of(b"I"), None);
+        assert_eq!(JType::return_type_of(b"(I)"), None);
+    }
+
+    #[test]
+    fn decodes_ints_of_narrow_types() {
+        assert!(matches!(
+            JValue::from_int(2, JType::Boolean),
+            Some(JValue::Boolean(true))
+        ));
+        assert!(matches!(
+            JValue::from_int(0x1ff, JType::Byte),
+            Some(JValue::Byte(-1))
+        ));
+        assert!(matches!(
+            JValue::from_int(0x1_0041, JType::Char),
+            Some(JValue::Char(0x41))
+        ));
+        assert!(matches!(
+            JValue::from_int(-2, JType::Short),
+            Some(JValue::Short(-2))
+        ));
+        assert!(matches!(
+            JValue::from_int(7, JType::Int),
+            Some(JValue::Int(7))
+        ));
+        assert!(JValue::from_int(7, JType::Long).is_none());
+        assert!(JValue::from_int(0, JType::Object).is_none());
+    }
+
+    #[test]
+    fn widens_narrow_types_to_ints() {
+        assert_eq!(JValue::Boolean(true).as_int(), Some(1));
+        assert_eq!(JValue::Byte(-1).as_int(), Some(-1));
+        assert_eq!(JValue::Char(0xffff).as_int(), Some(0xffff));
+        assert_eq!(JValue::Short(-2).as_int(), Some(-2));
+        assert_eq!(JValue::Int(7).as_int(), Some(7));
+        assert_eq!(JValue::Long(7).as_int(), None);
+        assert_eq!(JValue::Object(None).as_int(), None);
+    }
+}
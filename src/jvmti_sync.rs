@@ -0,0 +1,395 @@
+//! Synchronization primitives built on JVM TI raw monitors.
+//!
+//! The VM knows about the threads blocked on raw monitors, which makes a [`Mutex`] and a
+//! [`Condvar`] from this module safe in situations where `std::sync` locks are not:
+//!
+//! - A thread suspended by `SuspendThread` while blocked on a raw monitor does not acquire the
+//!   monitor until it is resumed, so an agent suspending application threads cannot deadlock on a
+//!   lock that a suspended thread grabbed in the meantime.
+//! - Waiting on a raw monitor can be interrupted with `Thread.interrupt()`, in which case the wait
+//!   fails with [`MonitorError::Interrupt`].
+//!
+//! # Callback safety
+//! Locking, unlocking, waiting, and notifying only use the raw monitor functions of the JVM TI,
+//! which may be called from heap iteration callbacks and from the `GarbageCollectionStart`,
+//! `GarbageCollectionFinish`, and `ObjectFree` events, where almost no other function may be
+//! used. Creating the primitives, which needs a [`Jvm`], is only allowed in the `OnLoad` and live
+//! phases and not from those contexts. A raw monitor is owned by the thread that entered it, so
+//! the guards are neither [`Send`] nor [`Sync`] and must be dropped on the locking thread.
+
+use std::{
+    cell::UnsafeCell,
+    ffi::CString,
+    fmt::Debug,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    jvm::{errors::MonitorError, Jvm},
+    macros::call_jvmti,
+    sys,
+};
+
+/// A raw monitor that is destroyed when dropped.
+struct RawMonitor<'j> {
+    jvmti_ptr: *mut sys::jvmtiEnv,
+    monitor: sys::jrawMonitorID,
+    _jvm: PhantomData<&'j Jvm>,
+}
+
+// SAFETY: Unlike JNI environments, a JVM TI environment and its raw monitors may be used from any
+// thread.
+unsafe impl Send for RawMonitor<'_> {}
+// SAFETY: See above.
+unsafe impl Sync for RawMonitor<'_> {}
+
+impl<'j> RawMonitor<'j> {
+    /// See [`CreateRawMonitor`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#CreateRawMonitor).
+    fn new(jvm: &'j Jvm, name: &str) -> Result<Self, MonitorError> {
+        let name = CString::new(name).map_err(|_| MonitorError::IllegalArgument)?;
+        let jvmti_ptr = jvm.jvmti_ptr();
+        let mut monitor = MaybeUninit::uninit();
+        // SAFETY: `name` is a valid null-terminated string and `monitor` is a valid out-pointer.
+        unsafe {
+            call_jvmti!(
+                jvmti_ptr,
+                CreateRawMonitor,
+                name.as_ptr(),
+                monitor.as_mut_ptr()
+            )
+        }?;
+        Ok(Self {
+            jvmti_ptr,
+            // SAFETY: A successful result indicates that `monitor` has been initialized.
+            monitor: unsafe { monitor.assume_init() },
+            _jvm: PhantomData,
+        })
+    }
+
+    /// See [`RawMonitorEnter`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#RawMonitorEnter).
+    fn enter(&self) -> Result<(), MonitorError> {
+        // SAFETY: `self.monitor` is a valid raw monitor.
+        unsafe { call_jvmti!(self.jvmti_ptr, RawMonitorEnter, self.monitor) }?;
+        Ok(())
+    }
+
+    /// See [`RawMonitorExit`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#RawMonitorExit).
+    fn exit(&self) -> Result<(), MonitorError> {
+        // SAFETY: `self.monitor` is a valid raw monitor.
+        unsafe { call_jvmti!(self.jvmti_ptr, RawMonitorExit, self.monitor) }?;
+        Ok(())
+    }
+
+    /// Waits for a notification or until `millis` milliseconds have passed, or forever if `millis`
+    /// is zero.
+    /// See [`RawMonitorWait`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#RawMonitorWait).
+    fn wait(&self, millis: sys::jlong) -> Result<(), MonitorError> {
+        // SAFETY: `self.monitor` is a valid raw monitor.
+        unsafe { call_jvmti!(self.jvmti_ptr, RawMonitorWait, self.monitor, millis) }?;
+        Ok(())
+    }
+
+    /// See [`RawMonitorNotify`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#RawMonitorNotify).
+    fn notify(&self) -> Result<(), MonitorError> {
+        // SAFETY: `self.monitor` is a valid raw monitor.
+        unsafe { call_jvmti!(self.jvmti_ptr, RawMonitorNotify, self.monitor) }?;
+        Ok(())
+    }
+
+    /// See [`RawMonitorNotifyAll`](https://docs.oracle.com/en/java/javase/21/docs/specs/jvmti.html#RawMonitorNotifyAll).
+    fn notify_all(&self) -> Result<(), MonitorError> {
+        // SAFETY: `self.monitor` is a valid raw monitor.
+        unsafe { call_jvmti!(self.jvmti_ptr, RawMonitorNotifyAll, self.monitor) }?;
+        Ok(())
+    }
+}
+
+impl Drop for RawMonitor<'_> {
+    fn drop(&mut self) {
+        // SAFETY: `self.monitor` is a valid raw monitor that is not used afterwards.
+        // Nothing sensible can be done if destroying fails in a destructor.
+        let _ = unsafe { call_jvmti!(self.jvmti_ptr, DestroyRawMonitor, self.monitor) };
+    }
+}
+
+thread_local! {
+    /// A per-thread value whose address identifies the current thread as the owner of a [`Mutex`].
+    static THREAD_MARKER: u8 = const { 0 };
+}
+
+/// Returns a non-zero value identifying the current thread.
+fn current_thread_marker() -> usize {
+    THREAD_MARKER.with(|it| std::ptr::from_ref(it) as usize)
+}
+
+impl Debug for RawMonitor<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("RawMonitor@{:p}", self.monitor))
+    }
+}
+
+/// A mutual exclusion lock backed by a raw monitor, see the [module](self) documentation.
+///
+/// Unlike `std::sync::Mutex`, the lock is not poisoned when a thread panics while holding it.
+pub struct Mutex<'j, T: ?Sized> {
+    monitor: RawMonitor<'j>,
+    /// The marker of the thread holding the lock, or `0` if it is not held.
+    owner: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+/// An error returned by [`Mutex::lock`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum LockError {
+    /// The current thread already holds the lock.
+    #[error("The lock is already held by the current thread")]
+    AlreadyHeld,
+    /// The raw monitor could not be entered.
+    #[error(transparent)]
+    Monitor(#[from] MonitorError),
+}
+
+// SAFETY: The data is only accessed through a guard, which requires holding the monitor.
+unsafe impl<T: ?Sized + Send> Send for Mutex<'_, T> {}
+// SAFETY: See above.
+unsafe impl<T: ?Sized + Send> Sync for Mutex<'_, T> {}
+
+impl<'j, T> Mutex<'j, T> {
+    /// Creates a mutex protecting `value` backed by a new raw monitor named `name`.
+    /// # Errors
+    /// Returns [`MonitorError::IllegalArgument`] if `name` contains a null character.
+    /// See [`MonitorError`] for more information.
+    pub fn new(jvm: &'j Jvm, name: &str, value: T) -> Result<Self, MonitorError> {
+        Ok(Self {
+            monitor: RawMonitor::new(jvm, name)?,
+            owner: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        })
+    }
+
+    /// Consumes the mutex and returns the protected value.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<'j, T: ?Sized> Mutex<'j, T> {
+    /// Acquires the lock, blocking the current thread until it is available.
+    /// Raw monitors are reentrant, but holding two guards of the same mutex at once would alias
+    /// the data, so locking a mutex already held by the current thread fails instead.
+    /// # Errors
+    /// Returns [`LockError::AlreadyHeld`] if the current thread already holds the lock.
+    /// See [`MonitorError`] for other possible errors.
+    pub fn lock(&self) -> Result<MutexGuard<'_, 'j, T>, LockError> {
+        let marker = current_thread_marker();
+        // Only the current thread can have stored its own marker, and it clears the marker before
+        // releasing the lock, so a relaxed load suffices.
+        if self.owner.load(Ordering::Relaxed) == marker {
+            return Err(LockError::AlreadyHeld);
+        }
+        Ok(self.enter(marker)?)
+    }
+
+    /// Enters the monitor on behalf of the thread identified by `marker`, which must not hold the
+    /// lock.
+    fn enter(&self, marker: usize) -> Result<MutexGuard<'_, 'j, T>, MonitorError> {
+        self.monitor.enter()?;
+        self.owner.store(marker, Ordering::Relaxed);
+        Ok(MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Returns a mutable reference to the protected value, which needs no locking.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Debug for Mutex<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mutex")
+            .field("monitor", &self.monitor)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Holds the lock of a [`Mutex`] and gives access to the protected value.
+/// The lock is released when the guard is dropped.
+#[must_use = "the lock is released as soon as the guard is dropped"]
+pub struct MutexGuard<'a, 'j, T: ?Sized> {
+    mutex: &'a Mutex<'j, T>,
+    // Raw monitors must be exited by the thread that entered them.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, '_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The guard holds the monitor, which grants exclusive access to the data.
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, '_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The guard holds the monitor, which grants exclusive access to the data.
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, '_, T> {
+    fn drop(&mut self) {
+        self.mutex.owner.store(0, Ordering::Relaxed);
+        // Exiting only fails if the current thread does not own the monitor, which the guard
+        // rules out.
+        let _ = self.mutex.monitor.exit();
+    }
+}
+
+impl<T: ?Sized + Debug> Debug for MutexGuard<'_, '_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&**self, f)
+    }
+}
+
+/// An error returned when waiting on a [`Condvar`] fails, e.g. because the waiting thread has been
+/// interrupted. Unless the lock could not be acquired again, it is held again and can be recovered
+/// with [`WaitError::into_guard`].
+pub struct WaitError<G> {
+    guard: Option<G>,
+    error: MonitorError,
+}
+
+impl<G> WaitError<G> {
+    /// Returns the reason of the failure.
+    #[must_use]
+    pub fn error(&self) -> MonitorError {
+        self.error
+    }
+
+    /// Returns the guard of the lock, which is held again, or `None` if acquiring the lock again
+    /// failed, in which case [`WaitError::error`] tells why.
+    pub fn into_guard(self) -> Option<G> {
+        self.guard
+    }
+}
+
+impl<G> Debug for WaitError<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WaitError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<G> std::fmt::Display for WaitError<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl<G> std::error::Error for WaitError<G> {}
+
+/// A condition variable backed by a raw monitor, to be used together with a [`Mutex`].
+#[derive(Debug)]
+pub struct Condvar<'j> {
+    monitor: RawMonitor<'j>,
+}
+
+impl<'j> Condvar<'j> {
+    /// Creates a condition variable backed by a new raw monitor named `name`.
+    /// # Errors
+    /// Returns [`MonitorError::IllegalArgument`] if `name` contains a null character.
+    /// See [`MonitorError`] for more information.
+    pub fn new(jvm: &'j Jvm, name: &str) -> Result<Self, MonitorError> {
+        Ok(Self {
+            monitor: RawMonitor::new(jvm, name)?,
+        })
+    }
+
+    /// Releases the lock held by `guard`, blocks until the condition variable is notified, and
+    /// acquires the lock again. Like with `std::sync::Condvar`, spurious wake-ups are possible.
+    /// # Errors
+    /// See [`WaitError`] for more information.
+    pub fn wait<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, 'j, T>,
+    ) -> Result<MutexGuard<'a, 'j, T>, WaitError<MutexGuard<'a, 'j, T>>> {
+        self.wait_millis(guard, 0)
+    }
+
+    /// Like [`Condvar::wait`], but returns after `timeout` at the latest.
+    /// # Errors
+    /// See [`WaitError`] for more information.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, 'j, T>,
+        timeout: Duration,
+    ) -> Result<MutexGuard<'a, 'j, T>, WaitError<MutexGuard<'a, 'j, T>>> {
+        // A zero timeout means no timeout for raw monitors.
+        let millis = sys::jlong::try_from(timeout.as_millis())
+            .unwrap_or(sys::jlong::MAX)
+            .max(1);
+        self.wait_millis(guard, millis)
+    }
+
+    fn wait_millis<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, 'j, T>,
+        millis: sys::jlong,
+    ) -> Result<MutexGuard<'a, 'j, T>, WaitError<MutexGuard<'a, 'j, T>>> {
+        // The monitor of the condition variable is entered before the lock is released, so a
+        // notification sent after the lock is released cannot be lost: the notifier has to enter
+        // the monitor, which is only possible once this thread is waiting.
+        if let Err(error) = self.monitor.enter() {
+            return Err(WaitError {
+                guard: Some(guard),
+                error,
+            });
+        }
+        let mutex = guard.mutex;
+        drop(guard);
+        let result = self.monitor.wait(millis);
+        let _ = self.monitor.exit();
+        // The guard has been dropped, so the current thread does not hold the lock.
+        let guard = match mutex.enter(current_thread_marker()) {
+            Ok(guard) => guard,
+            Err(error) => return Err(WaitError { guard: None, error }),
+        };
+        match result {
+            Ok(()) => Ok(guard),
+            Err(error) => Err(WaitError {
+                guard: Some(guard),
+                error,
+            }),
+        }
+    }
+
+    /// Wakes up one thread blocked on the condition variable.
+    /// # Errors
+    /// See [`MonitorError`] for more information.
+    pub fn notify_one(&self) -> Result<(), MonitorError> {
+        self.monitor.enter()?;
+        let result = self.monitor.notify();
+        self.monitor.exit()?;
+        result
+    }
+
+    /// Wakes up all the threads blocked on the condition variable.
+    /// # Errors
+    /// See [`MonitorError`] for more information.
+    pub fn notify_all(&self) -> Result<(), MonitorError> {
+        self.monitor.enter()?;
+        let result = self.monitor.notify_all();
+        self.monitor.exit()?;
+        result
+    }
+}
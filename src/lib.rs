@@ -10,7 +10,11 @@
 //! Rust bindings for the JVM Tool Interface (JVM TI).
 
 pub mod agent_callback;
+pub mod diagnostics;
+#[cfg(feature = "class-events")]
+pub mod instrument;
 pub mod jvm;
+pub mod jvmti_sync;
 mod macros;
 mod prelude;
-mod sys;
+pub mod sys;
@@ -6,3 +6,11 @@ macro_rules! call_jvmti {
 }
 
 pub(crate) use call_jvmti;
+
+macro_rules! call_jni {
+    ($obj: expr, $func:ident $(,$($arg:expr),*)?) => {{
+        (**$obj).$func.expect(concat!(stringify!($func), " is not available"))($obj, $($($arg),*)?)
+    }};
+}
+
+pub(crate) use call_jni;
@@ -1,5 +1,8 @@
+//! The raw JVM TI and JNI bindings generated from the headers of the JDK, for the functions not
+//! wrapped by this crate.
 #![allow(
     dead_code,
+    missing_debug_implementations,
     non_upper_case_globals,
     non_camel_case_types,
     non_snake_case,